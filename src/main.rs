@@ -6,6 +6,26 @@
 //! For sound, the default output device is used. The MIDI device to use as
 //! input can be selected with the "-m <ID>" command line parameter.
 //!
+//! Without a soundcard, a different audio backend can be selected with
+//! "--audio-backend <null|file|stdout>". The file backend writes the output
+//! to the file given with "--output <FILE>", the stdout backend writes raw
+//! samples that can be piped into another program, e.g.:
+//! > yazz --audio-backend stdout | aplay -f FLOAT_LE -c 2 -r 44100
+//!
+//! # Rendering MIDI files
+//!
+//! A MIDI file can be rendered to a WAV file without using an audio device:
+//! > yazz --render song.mid --patch 3 --output song.wav
+//!
+//! The sound is taken from the factory bank unless a different bank is
+//! selected with "--bank <FILE>".
+//!
+//! # Exporting sound banks
+//!
+//! A sound bank can be saved with the data of all wavetables used by its
+//! sounds embedded, so that it can be shared without the wavetable files:
+//! > yazz --bank MyBank.ysn --export MyBank_shared.ysn
+//!
 //! # Running the tests
 //!
 //! The test code supports writing output to a logfile. Since only a single
@@ -20,15 +40,20 @@
 mod ctrl_map;
 use ctrl_map::{CtrlMap, MappingType};
 
+mod midi_file;
+
 mod midi_handler;
 use midi_handler::{MidiHandler, MidiMessage};
 
 mod modulation;
-use modulation::ModData;
+use modulation::{ModData, ModRouting, ModSourceSlot};
 
 mod parameter;
 use parameter::*;
 
+mod render;
+use render::Renderer;
+
 mod sound;
 use sound::{SoundData, SyncValue};
 
@@ -37,6 +62,7 @@ use storage::{SoundBank, SoundPatch};
 
 mod synth;
 use synth::*;
+use synth::backend::{AudioConfig, BackendType};
 use voice::Voice;
 
 mod tui;
@@ -46,6 +72,8 @@ use tui::termion_wrapper::TermionWrapper;
 mod value_range;
 use value_range::ValueRange;
 
+mod wav_file;
+
 extern crate termion;
 use termion::event::Key;
 
@@ -53,7 +81,7 @@ extern crate midir;
 use midir::MidiInputConnection;
 
 extern crate crossbeam_channel;
-use crossbeam_channel::unbounded;
+use crossbeam_channel::{unbounded, bounded};
 use crossbeam_channel::{Sender, Receiver};
 
 use flexi_logger::{Logger, opt_format};
@@ -63,11 +91,10 @@ extern crate clap;
 use clap::{Arg, App};
 
 extern crate wavetable;
-use wavetable::{WtInfo, WtManager};
+use wavetable::WtInfo;
 
 use std::io::prelude::*;
 use std::fs::File;
-use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::Duration;
 use std::vec::Vec;
@@ -83,7 +110,11 @@ pub enum SynthMessage {
     Param(SynthParam),
     Sound(SoundData),
     Wavetable(WtInfo),
+    EmbeddedWavetable(WtInfo, EmbeddedTable),
+    GeneratedWavetable(WtGenInfo),
+    Sample(SampleInfo),
     SampleBuffer(Vec<Float>, SynthParam),
+    Resample(ResampleSettings, WtInfo), // Render the current sound into the given wavetable
     Bpm(Float),
     Exit
 }
@@ -96,7 +127,9 @@ pub enum UiMessage {
     MouseHold{x: Index, y: Index},
     MouseRelease{x: Index, y: Index},
     SampleBuffer(Vec<Float>, SynthParam),
-    EngineSync(Duration, Duration),
+    EngineSync(Duration, Duration, usize), // Idle time, busy time, number of dropped voices
+    WavetableError(usize, String), // ID of the wavetable that failed to load, error message
+    Resampled(usize), // ID of the wavetable the sound was resampled into
     Exit,
 }
 
@@ -116,13 +149,19 @@ fn setup_messaging() -> (Sender<UiMessage>, Receiver<UiMessage>, Sender<SynthMes
     (to_ui_sender, ui_receiver, to_synth_sender, synth_receiver)
 }
 
-fn setup_midi(m2s_sender: Sender<SynthMessage>, m2u_sender: Sender<UiMessage>, midi_port: usize, mut midi_channel: u8) -> Result<MidiInputConnection<()>, ()> {
-    println!("Setting up MIDI... ");
+// Convert the MIDI channel from the command line (1 - 16) to the internal
+// representation (0 - 15, 16 = omni).
+fn get_midi_channel(midi_channel: u8) -> u8 {
     if midi_channel < 1 || midi_channel > 16 {
-        midi_channel = 16; // Omni
+        16 // Omni
     } else {
-        midi_channel -= 1; // 0 - 15
+        midi_channel - 1 // 0 - 15
     }
+}
+
+fn setup_midi(m2s_sender: Sender<SynthMessage>, m2u_sender: Sender<UiMessage>, midi_port: usize, midi_channel: u8) -> Result<MidiInputConnection<()>, ()> {
+    println!("Setting up MIDI... ");
+    let midi_channel = get_midi_channel(midi_channel);
     let conn_in = MidiHandler::run(m2s_sender, m2u_sender, midi_port, midi_channel);
     println!("... finished.");
     conn_in
@@ -144,18 +183,19 @@ fn setup_ui(to_synth_sender: Sender<SynthMessage>, to_ui_sender: Sender<UiMessag
     Ok((term_handle, tui_handle))
 }
 
-fn setup_synth(sample_rate: u32, s2u_sender: Sender<UiMessage>, synth_receiver: Receiver<SynthMessage>) -> (Arc<Mutex<Synth>>, std::thread::JoinHandle<()>) { 
+fn setup_synth(sample_rate: u32, config: &SynthConfig, s2u_sender: Sender<UiMessage>, synth_receiver: Receiver<SynthMessage>) -> (Synth, std::thread::JoinHandle<()>) { 
     println!("\rSetting up synth engine...");
-    let synth = Synth::new(sample_rate, s2u_sender);
-    let synth = Arc::new(Mutex::new(synth));
-    let synth_handle = Synth::run(synth.clone(), synth_receiver);
+    let (to_audio_sender, audio_receiver) = bounded::<SynthEvent>(EVENT_QUEUE_SIZE); // Control thread to audio thread
+    let control = SynthControl::new(sample_rate, s2u_sender, to_audio_sender);
+    let mut synth = Synth::new(sample_rate, control.get_wavetables(), control.get_release_sender(), audio_receiver);
+    synth.configure(config);
+    let synth_handle = SynthControl::run(control, synth_receiver);
     println!("\r... finished");
     (synth, synth_handle)
 }
 
-fn setup_audio() -> Result<(Engine, u32), ()> {
-    println!("\rSetting up audio engine...");
-    let result = Engine::new();
+fn setup_audio(config: &AudioConfig) -> Result<(Engine, u32), ()> {
+    let result = Engine::new(config);
     let engine = match result {
         Ok(e) => e,
         Err(()) => {
@@ -171,8 +211,9 @@ fn setup_audio() -> Result<(Engine, u32), ()> {
 }
 
 // Save one table of a wavetable set as a CSV file.
-fn save_wave(id: usize) -> std::io::Result<()> {
-    let wt_manager = WtManager::new(44100.0, ".");
+fn save_wave(id: usize, sample_rate: u32) -> std::io::Result<()> {
+    let mut wt_manager = WtManager::new(sample_rate as Float);
+    wt_manager.add_basic_tables(0);
     let mut filename = "synth_wave_".to_string();
     filename += &id.to_string();
     filename += ".csv";
@@ -188,15 +229,16 @@ fn save_wave(id: usize) -> std::io::Result<()> {
 }
 
 // Save one samplebuffer of a voice as CSV file.
-fn save_voice() -> std::io::Result<()> {
-    let wt_manager = WtManager::new(44100.0, ".");
+fn save_voice(sample_rate: u32) -> std::io::Result<()> {
+    let mut wt_manager = WtManager::new(sample_rate as Float);
+    wt_manager.add_basic_tables(0);
     let filename = "synth_voice.csv".to_string();
     let mut file = File::create(filename)?;
     let wt = wt_manager.get_table(0).unwrap();
-    let mut voice = Voice::new(44100, wt);
+    let mut voice = Voice::new(sample_rate, wt);
     let mut sound_global = SoundData::new();
-    let mut sound_local = SoundData::new();
     let global_state = SynthState{freq_factor: 1.0};
+    let routing = ModRouting::new();
 
     sound_global.init();
     sound_global.osc[0].level = 1.0;
@@ -205,12 +247,13 @@ fn save_voice() -> std::io::Result<()> {
     sound_global.env[0].sustain = 1.0;
     sound_global.env[0].factor = 1.0;
     sound_global.filter[0].filter_type = 0; // Bypass
+    let mut sound_local = sound_global;
 
-    voice.set_freq(21.533203125);
+    voice.set_freq(sample_rate as Float / 2048.0); // One wave cycle in 2048 samples
     voice.trigger(0, 0, &sound_global);
 
     for i in 0..2048 {
-        let value = voice.get_sample(i, &mut sound_global, &mut sound_local, &global_state);
+        let value = voice.get_sample(i, &mut sound_global, &mut sound_local, &routing, &global_state);
         let s = format!("{}, {:?}\n", i, value);
         file.write_all(s.as_bytes())?;
     }
@@ -251,12 +294,148 @@ fn main() {
                             .long("midichannel")
                             .help("Selects the MIDI channel to receive MIDI events on (1 - 16, default = omni)")
                             .takes_value(true))
+                        .arg(Arg::with_name("render")
+                            .short("r")
+                            .long("render")
+                            .help("Renders the given MIDI file to a WAV file without using an audio device")
+                            .takes_value(true))
+                        .arg(Arg::with_name("resample")
+                            .long("resample")
+                            .help("Renders the selected sound into a new wavetable with the given name in the data folder and adds it to the sound bank")
+                            .takes_value(true))
+                        .arg(Arg::with_name("sweep")
+                            .long("sweep")
+                            .help("Selects what changes between the waves when resampling: none (single wave), key or modwheel. Default none")
+                            .takes_value(true)
+                            .possible_values(&["none", "key", "modwheel"]))
+                        .arg(Arg::with_name("waves")
+                            .long("waves")
+                            .help("Selects the number of waves rendered for a resampling sweep (1 - 256, default 64)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("key")
+                            .long("key")
+                            .help("Selects the key to play when resampling (0 - 127, default 36). For a key sweep, the sweep goes from this key up 5 octaves")
+                            .takes_value(true))
+                        .arg(Arg::with_name("export")
+                            .long("export")
+                            .help("Saves the sound bank to the given file with all used wavetables embedded, so it can be shared without the wavetable files")
+                            .takes_value(true))
+                        .arg(Arg::with_name("bank")
+                            .short("b")
+                            .long("bank")
+                            .help("Selects the sound bank to use for rendering, resampling and exporting (default Yazz_FactoryBank.ysn)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("patch")
+                            .short("p")
+                            .long("patch")
+                            .help("Selects the sound to use for rendering and resampling (1 - 128, default 1)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("output")
+                            .long("output")
+                            .help("Selects the output file for rendering and the file audio backend (default yazz_render.wav)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("audiobackend")
+                            .short("a")
+                            .long("audio-backend")
+                            .help("Selects the audio output: cpal (soundcard), null (no output), file (WAV file) or stdout (raw 32 bit float stereo samples). Default cpal")
+                            .takes_value(true)
+                            .possible_values(&["cpal", "null", "file", "stdout"]))
+                        .arg(Arg::with_name("listaudiodevices")
+                            .long("list-audio-devices")
+                            .help("Lists the available audio output devices and their formats"))
+                        .arg(Arg::with_name("audiodevice")
+                            .long("audio-device")
+                            .help("Selects the audio output device by name or by number from the device list (default = system default)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("samplerate")
+                            .long("sample-rate")
+                            .help("Selects the sample rate (default = device default, 44100 without device)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("buffersize")
+                            .long("buffer-size")
+                            .help("Selects the number of frames rendered per block (default 512). The buffer size of audio devices can't be changed, for those only the engine block size is set")
+                            .takes_value(true))
+                        .arg(Arg::with_name("controlperiod")
+                            .long("control-period")
+                            .help("Selects the number of samples between modulation updates (1 - 64, default 16). Lower values give smoother modulation at a higher CPU load")
+                            .takes_value(true))
+                        .arg(Arg::with_name("voicethreads")
+                            .long("voice-threads")
+                            .help("Selects the number of threads rendering the voices (1 - 16, default 1). The output is the same for any number of threads")
+                            .takes_value(true))
+                        .arg(Arg::with_name("loadthreshold")
+                            .long("load-threshold")
+                            .help("Selects the engine load in percent above which voices are dropped to avoid audio glitches (10 - 100, default 80). 100 disables dropping voices")
+                            .takes_value(true))
+                        .arg(Arg::with_name("outputchannels")
+                            .long("output-channels")
+                            .help("Selects the first channels of the stereo pairs to send the output to on devices with more than 2 channels (e.g. 1,3 for channels 1/2 and 3/4, default 1)")
+                            .takes_value(true))
                         .get_matches();
     let midi_port = matches.value_of("midiport").unwrap_or("1");
     let midi_port: usize = midi_port.parse().unwrap_or(1);
     let midi_channel = matches.value_of("midichannel").unwrap_or("0");
     let midi_channel: u8 = midi_channel.parse().unwrap_or(0);
     let show_tui = !matches.is_present("notui");
+    let output = matches.value_of("output").unwrap_or("yazz_render.wav");
+    let backend_type = matches.value_of("audiobackend").unwrap_or("cpal");
+    let backend_type = BackendType::from_name(backend_type).unwrap_or_default();
+    let channel_pairs = matches.value_of("outputchannels").unwrap_or("1");
+    let channel_pairs: Vec<usize> = channel_pairs.split(',')
+                                                 .filter_map(|c| c.trim().parse::<usize>().ok())
+                                                 .filter(|c| *c > 0)
+                                                 .map(|c| c - 1)
+                                                 .collect();
+    let device = matches.value_of("audiodevice").map(|d| d.to_string());
+    let sample_rate = match matches.value_of("samplerate").map(|s| s.parse::<u32>()) {
+        Some(Ok(sr)) if sr > 0 => Some(sr),
+        Some(_) => {
+            println!("Invalid sample rate");
+            return;
+        }
+        None => None,
+    };
+    let buffer_size = match matches.value_of("buffersize").map(|s| s.parse::<usize>()) {
+        Some(Ok(size)) if size > 0 => Some(size),
+        Some(_) => {
+            println!("Invalid buffer size");
+            return;
+        }
+        None => None,
+    };
+    let control_period = match matches.value_of("controlperiod").map(|s| s.parse::<usize>()) {
+        Some(Ok(p)) if p > 0 && p <= MAX_BLOCK_SIZE => p,
+        Some(_) => {
+            println!("Invalid control period, must be between 1 and {}", MAX_BLOCK_SIZE);
+            return;
+        }
+        None => DEFAULT_CONTROL_PERIOD,
+    };
+    let voice_threads = match matches.value_of("voicethreads").map(|s| s.parse::<usize>()) {
+        Some(Ok(t)) if t > 0 && t <= MAX_VOICE_THREADS => t,
+        Some(_) => {
+            println!("Invalid number of voice threads, must be between 1 and {}", MAX_VOICE_THREADS);
+            return;
+        }
+        None => 1,
+    };
+    let load_threshold = match matches.value_of("loadthreshold").map(|s| s.parse::<usize>()) {
+        Some(Ok(t)) if t >= 10 && t <= 100 => t as Float / 100.0,
+        Some(_) => {
+            println!("Invalid load threshold, must be between 10 and 100");
+            return;
+        }
+        None => DEFAULT_LOAD_THRESHOLD,
+    };
+    let synth_config = SynthConfig{control_period, voice_threads, load_threshold};
+    let audio_config = AudioConfig{
+        backend: backend_type,
+        output: output.to_string(),
+        channel_pairs,
+        device,
+        sample_rate,
+        buffer_size
+    };
 
     // Show version
     if matches.is_present("version") {
@@ -264,20 +443,87 @@ fn main() {
         return;
     }
 
+    if matches.is_present("listaudiodevices") {
+        Engine::list_devices();
+        return;
+    }
+
     // For debugging: Save selected wavetable as file
     let wave_index = matches.value_of("savewave").unwrap_or("");
     if wave_index.len() > 0 {
         let wave_index: usize = wave_index.parse().unwrap_or(1);
-        save_wave(wave_index).unwrap();
+        save_wave(wave_index, sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE)).unwrap();
         return;
     }
     if matches.is_present("savevoice") {
         println!("Saving voice output");
-        save_voice().unwrap();
+        save_voice(sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE)).unwrap();
+        return;
+    }
+
+    // Export a sound bank with embedded wavetables
+    if let Some(export_file) = matches.value_of("export") {
+        let bank = matches.value_of("bank").unwrap_or("Yazz_FactoryBank.ysn");
+        if Renderer::export(bank, export_file).is_err() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Render a sound into a new wavetable
+    if let Some(name) = matches.value_of("resample") {
+        let bank = matches.value_of("bank").unwrap_or("Yazz_FactoryBank.ysn");
+        let patch = matches.value_of("patch").unwrap_or("1");
+        let patch: usize = patch.parse().unwrap_or(1);
+        let key = match matches.value_of("key").map(|k| k.parse::<u8>()) {
+            Some(Ok(k)) if k < 128 => k,
+            Some(_) => {
+                println!("Invalid key, must be between 0 and 127");
+                return;
+            }
+            None => DEFAULT_RESAMPLE_KEY,
+        };
+        let num_waves = match matches.value_of("waves").map(|w| w.parse::<usize>()) {
+            Some(Ok(w)) if w > 0 && w <= MAX_RESAMPLE_WAVES => w,
+            Some(_) => {
+                println!("Invalid number of waves, must be between 1 and {}", MAX_RESAMPLE_WAVES);
+                return;
+            }
+            None => DEFAULT_RESAMPLE_WAVES,
+        };
+        let sweep = match matches.value_of("sweep").unwrap_or("none") {
+            "key" => ResampleSweep::Key{from: key, to: key.saturating_add(KEY_SWEEP_RANGE).min(127)},
+            "modwheel" => ResampleSweep::ModWheel{from: 0.0, to: 127.0},
+            _ => ResampleSweep::None,
+        };
+        let settings = ResampleSettings{key, num_waves, sweep};
+        let renderer = Renderer::new(sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE), get_midi_channel(midi_channel), synth_config);
+        if renderer.resample(bank, patch.max(1) - 1, &settings, name).is_err() {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Render a MIDI file offline
+    if let Some(midi_file) = matches.value_of("render") {
+        let bank = matches.value_of("bank").unwrap_or("Yazz_FactoryBank.ysn");
+        let patch = matches.value_of("patch").unwrap_or("1");
+        let patch: usize = patch.parse().unwrap_or(1);
+        let renderer = Renderer::new(sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE), get_midi_channel(midi_channel), synth_config);
+        if renderer.render(bank, patch.max(1) - 1, midi_file, output).is_err() {
+            std::process::exit(1);
+        }
         return;
     }
 
-    // Do setup
+    // Do setup. The audio engine comes first, since the stdout backend needs
+    // to redirect all other output before anything else is printed.
+    let result = setup_audio(&audio_config);
+    let (mut engine, sample_rate) = match result {
+        Ok((e, s)) => (e, s),
+        Err(()) => return,
+    };
+
     let (to_ui_sender, ui_receiver, to_synth_sender, synth_receiver) = setup_messaging();
     let result = setup_midi(to_synth_sender.clone(), to_ui_sender.clone(), midi_port, midi_channel);
     let midi_connection = match result {
@@ -285,19 +531,13 @@ fn main() {
         Err(()) => return,
     };
 
-    let result = setup_audio();
-    let (mut engine, sample_rate) = match result {
-        Ok((e, s)) => (e, s),
-        Err(()) => return,
-    };
-
     let result = setup_ui(to_synth_sender, to_ui_sender.clone(), ui_receiver, show_tui);
     let (term_handle, tui_handle) = match result {
         Ok((term, tui)) => (term, tui),
         Err(_) => return, // TODO: Reset terminal to non-raw state
     };
 
-    let (synth, synth_handle) = setup_synth(sample_rate, to_ui_sender.clone(), synth_receiver);
+    let (synth, synth_handle) = setup_synth(sample_rate, &synth_config, to_ui_sender.clone(), synth_receiver);
 
     // Run
     println!("\r... finished, starting processing");
@@ -306,6 +546,7 @@ fn main() {
     // Cleanup
     term_handle.join().unwrap();
     println!("\rTerminal handler finished");
+    engine.stop();
     midi_connection.close();
     tui_handle.join().unwrap();
     println!("TUI finished");
@@ -0,0 +1,303 @@
+//! Reads Standard MIDI Files.
+//!
+//! Supports SMF format 0 and 1 with metrical (ticks per quarter note) timing.
+//! All tracks are merged into a single list of channel messages, sorted by
+//! time. Tempo changes are evaluated while merging, so the resulting event
+//! times are absolute times in seconds.
+
+use super::Float;
+use super::midi_handler::{MidiHandler, MidiMessage};
+
+use log::info;
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read};
+
+const DEFAULT_TEMPO: u32 = 500000; // Microseconds per quarter note (= 120 BPM)
+
+/// A MIDI channel message with its absolute time in seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct MidiEvent {
+    pub time: Float,
+    pub message: MidiMessage,
+}
+
+// Event as read from a track, before tick times are converted to seconds.
+enum TrackEvent {
+    Message(Vec<u8>),
+    Tempo(u32),
+}
+
+pub struct MidiFile {
+    pub events: Vec<MidiEvent>,
+}
+
+impl MidiFile {
+    /// Read a MIDI file from disk.
+    pub fn read_file(filename: &str) -> std::io::Result<MidiFile> {
+        let file = File::open(filename)?;
+        let mut reader = BufReader::new(file);
+        let mut data: Vec<u8> = vec!();
+        reader.read_to_end(&mut data)?;
+        MidiFile::parse(&data)
+    }
+
+    /// Parse the contents of a MIDI file.
+    pub fn parse(data: &[u8]) -> std::io::Result<MidiFile> {
+        let mut pos = 0;
+        let (id, header) = MidiFile::read_chunk(data, &mut pos)?;
+        if id != b"MThd" || header.len() < 6 {
+            return Err(MidiFile::invalid("Missing MIDI file header"));
+        }
+        let format = MidiFile::read_u16(&header[0..2]);
+        let num_tracks = MidiFile::read_u16(&header[2..4]);
+        let division = MidiFile::read_u16(&header[4..6]);
+        if format > 1 {
+            return Err(MidiFile::invalid("Only MIDI file formats 0 and 1 are supported"));
+        }
+        if division & 0x8000 != 0 || division == 0 {
+            return Err(MidiFile::invalid("SMPTE time division is not supported"));
+        }
+        info!("MIDI file format {}, {} tracks, {} ticks per quarter", format, num_tracks, division);
+
+        // Collect events of all tracks with their tick times
+        let mut track_events: Vec<(u64, usize, TrackEvent)> = vec!();
+        let mut tracks_read = 0;
+        while tracks_read < num_tracks && pos < data.len() {
+            let (id, track) = MidiFile::read_chunk(data, &mut pos)?;
+            if id != b"MTrk" {
+                continue; // Skip unknown chunk types
+            }
+            MidiFile::read_track(track, &mut track_events)?;
+            tracks_read += 1;
+        }
+
+        // Merge tracks. The sequence number keeps the original order of
+        // events with identical tick times.
+        track_events.sort_by_key(|(tick, seq, _)| (*tick, *seq));
+
+        // Convert ticks to seconds, following the tempo map
+        let mut events: Vec<MidiEvent> = vec!();
+        let mut tempo = DEFAULT_TEMPO;
+        let mut last_tick = 0u64;
+        let mut time: Float = 0.0;
+        for (tick, _, event) in track_events {
+            time += (tick - last_tick) as Float * tempo as Float / (division as Float * 1000000.0);
+            last_tick = tick;
+            match event {
+                TrackEvent::Tempo(t) => tempo = t,
+                TrackEvent::Message(m) => {
+                    let message = MidiHandler::get_midi_message(&m);
+                    events.push(MidiEvent{time, message});
+                }
+            }
+        }
+        Ok(MidiFile{events})
+    }
+
+    /// Duration of the file in seconds (time of the last event).
+    pub fn get_duration(&self) -> Float {
+        match self.events.last() {
+            Some(e) => e.time,
+            None => 0.0,
+        }
+    }
+
+    // Read all events of a single track.
+    fn read_track(track: &[u8], events: &mut Vec<(u64, usize, TrackEvent)>) -> std::io::Result<()> {
+        let mut pos = 0;
+        let mut tick = 0u64;
+        let mut running_status = 0u8;
+        while pos < track.len() {
+            tick += MidiFile::read_var_len(track, &mut pos)? as u64;
+            let mut status = MidiFile::read_byte(track, &mut pos)?;
+            match status {
+                0xFF => {
+                    // Meta event, cancels running status
+                    running_status = 0;
+                    let meta_type = MidiFile::read_byte(track, &mut pos)?;
+                    let len = MidiFile::read_var_len(track, &mut pos)? as usize;
+                    let meta_data = MidiFile::read_bytes(track, &mut pos, len)?;
+                    match meta_type {
+                        0x2F => break, // End of track
+                        0x51 if len == 3 => {
+                            let tempo = (meta_data[0] as u32) << 16 | (meta_data[1] as u32) << 8 | meta_data[2] as u32;
+                            events.push((tick, events.len(), TrackEvent::Tempo(tempo)));
+                        }
+                        _ => (),
+                    }
+                }
+                0xF0 | 0xF7 => {
+                    // SysEx, ignored. Cancels running status.
+                    running_status = 0;
+                    let len = MidiFile::read_var_len(track, &mut pos)? as usize;
+                    MidiFile::read_bytes(track, &mut pos, len)?;
+                }
+                0xF1..=0xFE => {
+                    // System common and realtime messages can't appear in a file
+                    return Err(MidiFile::invalid("Invalid status byte in MIDI track"));
+                }
+                _ => {
+                    if status < 0x80 {
+                        // Running status, current byte is the first data byte
+                        if running_status == 0 {
+                            return Err(MidiFile::invalid("Running status without previous status byte"));
+                        }
+                        status = running_status;
+                        pos -= 1;
+                    }
+                    running_status = status;
+                    let num_data_bytes = match status & 0xF0 {
+                        0xC0 | 0xD0 => 1,
+                        _ => 2,
+                    };
+                    let mut message = vec!(status);
+                    message.extend_from_slice(MidiFile::read_bytes(track, &mut pos, num_data_bytes)?);
+                    events.push((tick, events.len(), TrackEvent::Message(message)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_chunk<'a>(data: &'a [u8], pos: &mut usize) -> std::io::Result<(&'a [u8], &'a [u8])> {
+        let id = MidiFile::read_bytes(data, pos, 4)?;
+        let len = MidiFile::read_bytes(data, pos, 4)?;
+        let len = (len[0] as usize) << 24 | (len[1] as usize) << 16 | (len[2] as usize) << 8 | len[3] as usize;
+        let chunk = MidiFile::read_bytes(data, pos, len)?;
+        Ok((id, chunk))
+    }
+
+    fn read_var_len(data: &[u8], pos: &mut usize) -> std::io::Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = MidiFile::read_byte(data, pos)?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MidiFile::invalid("Variable length value too long"))
+    }
+
+    fn read_byte(data: &[u8], pos: &mut usize) -> std::io::Result<u8> {
+        Ok(MidiFile::read_bytes(data, pos, 1)?[0])
+    }
+
+    fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> std::io::Result<&'a [u8]> {
+        if *pos + len > data.len() {
+            return Err(MidiFile::invalid("Unexpected end of MIDI data"));
+        }
+        let result = &data[*pos..*pos + len];
+        *pos += len;
+        Ok(result)
+    }
+
+    fn read_u16(data: &[u8]) -> u16 {
+        (data[0] as u16) << 8 | data[1] as u16
+    }
+
+    fn invalid(msg: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, msg)
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{MidiFile, TrackEvent};
+use super::super::MidiMessage;
+
+const HEADER: &[u8] = &[
+    b'M', b'T', b'h', b'd', 0x00, 0x00, 0x00, 0x06,
+    0x00, 0x01, // Format 1
+    0x00, 0x02, // 2 tracks
+    0x00, 0x60, // 96 ticks per quarter
+];
+
+const TEMPO_TRACK: &[u8] = &[
+    b'M', b'T', b'r', b'k', 0x00, 0x00, 0x00, 0x0B,
+    0x00, 0xFF, 0x51, 0x03, 0x0F, 0x42, 0x40, // Tempo 1000000 us per quarter (60 BPM)
+    0x00, 0xFF, 0x2F, 0x00,                   // End of track
+];
+
+const NOTE_TRACK: &[u8] = &[
+    b'M', b'T', b'r', b'k', 0x00, 0x00, 0x00, 0x0B,
+    0x00, 0x90, 0x3C, 0x64, // Note on at tick 0
+    0x60, 0x3C, 0x00,       // Running status, note on with velocity 0 at tick 96
+    0x00, 0xFF, 0x2F, 0x00, // End of track
+];
+
+fn build_file() -> Vec<u8> {
+    let mut data = HEADER.to_vec();
+    data.extend_from_slice(TEMPO_TRACK);
+    data.extend_from_slice(NOTE_TRACK);
+    data
+}
+
+#[test]
+fn tracks_are_merged_and_converted_to_seconds() {
+    let midi = MidiFile::parse(&build_file()).unwrap();
+    assert_eq!(midi.events.len(), 2);
+    assert_eq!(midi.events[0].time, 0.0);
+    assert_eq!(midi.events[1].time, 1.0); // One quarter note at 60 BPM
+    assert_eq!(midi.get_duration(), 1.0);
+}
+
+#[test]
+fn running_status_is_handled() {
+    let midi = MidiFile::parse(&build_file()).unwrap();
+    match midi.events[1].message {
+        MidiMessage::NoteOn{channel, key, velocity} => {
+            assert_eq!(channel, 0);
+            assert_eq!(key, 0x3C);
+            assert_eq!(velocity, 0);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn running_status_is_cancelled_by_meta_and_sysex_events() {
+    let mut events = vec!();
+    let track = [
+        0x00, 0x90, 0x3C, 0x64,             // Note on
+        0x00, 0xFF, 0x01, 0x01, 0x41,       // Text meta event
+        0x00, 0x3C, 0x00,                   // Data bytes without status byte
+    ];
+    assert!(MidiFile::read_track(&track, &mut events).is_err());
+
+    let mut events = vec!();
+    let track = [
+        0x00, 0x90, 0x3C, 0x64,             // Note on
+        0x00, 0xF0, 0x02, 0x7E, 0xF7,       // SysEx
+        0x00, 0x3C, 0x00,                   // Data bytes without status byte
+    ];
+    assert!(MidiFile::read_track(&track, &mut events).is_err());
+}
+
+#[test]
+fn system_messages_are_rejected() {
+    for status in 0xF1..=0xFE {
+        let mut events = vec!();
+        let track = [0x00, status, 0x00, 0x00];
+        assert!(MidiFile::read_track(&track, &mut events).is_err(), "{:X}", status);
+    }
+    let mut events = vec!();
+    let track = [0x00, 0x90, 0x3C, 0x64, 0x00, 0xFF, 0x2F, 0x00];
+    MidiFile::read_track(&track, &mut events).unwrap();
+    assert!(matches!(events[0].2, TrackEvent::Message(_)));
+}
+
+#[test]
+fn truncated_file_is_rejected() {
+    let data = build_file();
+    assert!(MidiFile::parse(&data[0..data.len() - 3]).is_err());
+    assert!(MidiFile::parse(&data[0..10]).is_err());
+}
+
+} // mod tests
@@ -1,10 +1,11 @@
 use super::Float;
-use super::{Parameter, MenuItem, ValueRange};
-use super::{ParamId, FunctionId};
+use super::{Parameter, ParameterValue, MenuItem, ValueRange};
+use super::{ParamId, FunctionId, SynthParam};
+use super::SoundData;
 use super::voice::{NUM_OSCILLATORS, NUM_ENVELOPES, NUM_LFOS};
-use super::synth::NUM_GLOBAL_LFOS;
+use super::synth::{NUM_GLOBAL_LFOS, NUM_MODULATORS};
 
-use log::info;
+use log::{info, error};
 use serde::{Serialize, Deserialize};
 
 /*
@@ -122,3 +123,509 @@ impl ModData {
     }
 }
 
+/** Source of a compiled modulation route, with the index of the source. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModSourceSlot {
+    GlobalLfo(usize),
+    Aftertouch,
+    Pitchbend,
+    ModWheel,
+    SustainPedal,
+    Envelope(usize),
+    Lfo(usize),
+    Oscillator(usize),
+    Velocity,
+}
+
+impl Default for ModSourceSlot {
+    fn default() -> Self { ModSourceSlot::Velocity }
+}
+
+impl ModSourceSlot {
+    /** Get the slot for a modulation source.
+     *
+     * Returns None if the function is not a modulation source or the
+     * function ID is out of range, which can happen with damaged sound data.
+     */
+    pub fn from_function(function: Parameter, function_id: usize) -> Option<ModSourceSlot> {
+        let source = MOD_SOURCE.iter().find(|s| s.function == function)?;
+        if function_id > source.index_range.1 {
+            return None;
+        }
+        let id = if function_id > 0 { function_id - 1 } else { 0 };
+        let slot = match function {
+            Parameter::GlobalLfo    => ModSourceSlot::GlobalLfo(id),
+            Parameter::Aftertouch   => ModSourceSlot::Aftertouch,
+            Parameter::Pitchbend    => ModSourceSlot::Pitchbend,
+            Parameter::ModWheel     => ModSourceSlot::ModWheel,
+            Parameter::SustainPedal => ModSourceSlot::SustainPedal,
+            Parameter::Envelope     => ModSourceSlot::Envelope(id),
+            Parameter::Lfo          => ModSourceSlot::Lfo(id),
+            Parameter::Oscillator   => ModSourceSlot::Oscillator(id),
+            Parameter::Velocity     => ModSourceSlot::Velocity,
+            _ => return None,
+        };
+        Some(slot)
+    }
+}
+
+/** A modulation target.
+ *
+ * Float parameters are read and written directly in the sound data. All
+ * other parameters go the slow way through get_value/ set_parameter.
+ * Values use the same units as the parameter menu, so that the scale of
+ * the modulator can be applied directly.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModParam {
+    OscLevel(usize),
+    OscTune(usize),
+    OscFinetune(usize),
+    OscWaveIndex(usize),
+    OscSpread(usize),
+    OscModIndex(usize),
+    OscGrainPosition(usize),
+    OscPulseWidth(usize),
+    OscWarpAmount(usize),
+    EnvAttack(usize),
+    EnvDecay(usize),
+    EnvSustain(usize),
+    EnvRelease(usize),
+    EnvDelay(usize),
+    LfoFrequency(usize),
+    LfoPhase(usize),
+    LfoAmount(usize),
+    GlfoFrequency(usize),
+    GlfoPhase(usize),
+    GlfoAmount(usize),
+    FilterCutoff(usize),
+    FilterResonance(usize),
+    FilterGain(usize),
+    FilterAux(usize),
+    FilterEnvDepth(usize),
+    DelayTime,
+    DelayLevel,
+    DelayFeedback,
+    DelayTone,
+    SubLevel,
+    ModAmount(usize),
+    Other(ParamId),
+}
+
+impl Default for ModParam {
+    fn default() -> Self { ModParam::OscLevel(0) }
+}
+
+impl ModParam {
+    pub fn from_param_id(param: &ParamId) -> ModParam {
+        let id = if param.function_id > 0 { param.function_id - 1 } else { 0 };
+        match (param.function, param.parameter) {
+            (Parameter::Oscillator, Parameter::Level)     => ModParam::OscLevel(id),
+            (Parameter::Oscillator, Parameter::Tune)      => ModParam::OscTune(id),
+            (Parameter::Oscillator, Parameter::Finetune)  => ModParam::OscFinetune(id),
+            (Parameter::Oscillator, Parameter::WaveIndex) => ModParam::OscWaveIndex(id),
+            (Parameter::Oscillator, Parameter::Spread)    => ModParam::OscSpread(id),
+            (Parameter::Oscillator, Parameter::ModIndex)  => ModParam::OscModIndex(id),
+            (Parameter::Oscillator, Parameter::Position)  => ModParam::OscGrainPosition(id),
+            (Parameter::Oscillator, Parameter::PulseWidth) => ModParam::OscPulseWidth(id),
+            (Parameter::Oscillator, Parameter::WarpAmount) => ModParam::OscWarpAmount(id),
+            (Parameter::Envelope, Parameter::Attack)      => ModParam::EnvAttack(id),
+            (Parameter::Envelope, Parameter::Decay)       => ModParam::EnvDecay(id),
+            (Parameter::Envelope, Parameter::Sustain)     => ModParam::EnvSustain(id),
+            (Parameter::Envelope, Parameter::Release)     => ModParam::EnvRelease(id),
+            (Parameter::Envelope, Parameter::Delay)       => ModParam::EnvDelay(id),
+            (Parameter::Lfo, Parameter::Frequency)        => ModParam::LfoFrequency(id),
+            (Parameter::Lfo, Parameter::Phase)            => ModParam::LfoPhase(id),
+            (Parameter::Lfo, Parameter::Amount)           => ModParam::LfoAmount(id),
+            (Parameter::GlobalLfo, Parameter::Frequency)  => ModParam::GlfoFrequency(id),
+            (Parameter::GlobalLfo, Parameter::Phase)      => ModParam::GlfoPhase(id),
+            (Parameter::GlobalLfo, Parameter::Amount)     => ModParam::GlfoAmount(id),
+            (Parameter::Filter, Parameter::Cutoff)        => ModParam::FilterCutoff(id),
+            (Parameter::Filter, Parameter::Resonance)     => ModParam::FilterResonance(id),
+            (Parameter::Filter, Parameter::Gain)          => ModParam::FilterGain(id),
+            (Parameter::Filter, Parameter::Aux)           => ModParam::FilterAux(id),
+            (Parameter::Filter, Parameter::EnvDepth)      => ModParam::FilterEnvDepth(id),
+            (Parameter::Delay, Parameter::Time)           => ModParam::DelayTime,
+            (Parameter::Delay, Parameter::Level)          => ModParam::DelayLevel,
+            (Parameter::Delay, Parameter::Feedback)       => ModParam::DelayFeedback,
+            (Parameter::Delay, Parameter::Tone)           => ModParam::DelayTone,
+            (Parameter::SubOscillator, Parameter::Level)  => ModParam::SubLevel,
+            (Parameter::Modulation, Parameter::Amount)    => ModParam::ModAmount(id),
+            _ => ModParam::Other(*param),
+        }
+    }
+
+    pub fn get(&self, data: &SoundData) -> Float {
+        match *self {
+            ModParam::OscLevel(i)        => data.osc[i].level * 100.0,
+            ModParam::OscTune(i)         => data.osc[i].tune_halfsteps as Float,
+            ModParam::OscFinetune(i)     => data.osc[i].tune_cents * 100.0,
+            ModParam::OscWaveIndex(i)    => data.osc[i].wt_osc_data.wave_index,
+            ModParam::OscSpread(i)       => data.osc[i].wt_osc_data.voice_spread,
+            ModParam::OscModIndex(i)     => data.osc[i].mod_index,
+            ModParam::OscGrainPosition(i) => data.osc[i].granular_data.position,
+            ModParam::OscPulseWidth(i)   => data.osc[i].va_data.pulse_width,
+            ModParam::OscWarpAmount(i)   => data.osc[i].wt_osc_data.warp_amount,
+            ModParam::EnvAttack(i)       => data.env[i].attack,
+            ModParam::EnvDecay(i)        => data.env[i].decay,
+            ModParam::EnvSustain(i)      => data.env[i].sustain,
+            ModParam::EnvRelease(i)      => data.env[i].release,
+            ModParam::EnvDelay(i)        => data.env[i].delay,
+            ModParam::LfoFrequency(i)    => data.lfo[i].frequency,
+            ModParam::LfoPhase(i)        => data.lfo[i].phase,
+            ModParam::LfoAmount(i)       => data.lfo[i].amount,
+            ModParam::GlfoFrequency(i)   => data.glfo[i].frequency,
+            ModParam::GlfoPhase(i)       => data.glfo[i].phase,
+            ModParam::GlfoAmount(i)      => data.glfo[i].amount,
+            ModParam::FilterCutoff(i)    => data.filter[i].cutoff,
+            ModParam::FilterResonance(i) => data.filter[i].resonance,
+            ModParam::FilterGain(i)      => data.filter[i].gain,
+            ModParam::FilterAux(i)       => data.filter[i].aux,
+            ModParam::FilterEnvDepth(i)  => data.filter[i].env_depth,
+            ModParam::DelayTime          => data.delay.time,
+            ModParam::DelayLevel         => data.delay.level,
+            ModParam::DelayFeedback      => data.delay.feedback,
+            ModParam::DelayTone          => data.delay.tone,
+            ModParam::SubLevel           => data.sub.level * 100.0,
+            ModParam::ModAmount(i)       => data.modul[i].amount,
+            ModParam::Other(ref param)   => data.get_value(param).as_float(),
+        }
+    }
+
+    pub fn set(&self, data: &mut SoundData, value: Float) {
+        match *self {
+            ModParam::OscLevel(i)        => data.osc[i].level = value / 100.0,
+            ModParam::OscTune(i)         => data.osc[i].set_halfsteps(value.round() as i64),
+            ModParam::OscFinetune(i)     => data.osc[i].set_cents(value / 100.0),
+            ModParam::OscWaveIndex(i)    => data.osc[i].wt_osc_data.wave_index = value,
+            ModParam::OscSpread(i)       => data.osc[i].wt_osc_data.set_voice_spread(value),
+            ModParam::OscModIndex(i)     => data.osc[i].mod_index = value,
+            ModParam::OscGrainPosition(i) => data.osc[i].granular_data.position = value,
+            ModParam::OscPulseWidth(i)   => data.osc[i].va_data.pulse_width = value,
+            ModParam::OscWarpAmount(i)   => data.osc[i].wt_osc_data.warp_amount = value,
+            ModParam::EnvAttack(i)       => data.env[i].attack = value,
+            ModParam::EnvDecay(i)        => data.env[i].decay = value,
+            ModParam::EnvSustain(i)      => data.env[i].sustain = value,
+            ModParam::EnvRelease(i)      => data.env[i].release = value,
+            ModParam::EnvDelay(i)        => data.env[i].delay = value,
+            ModParam::LfoFrequency(i)    => data.lfo[i].frequency = value,
+            ModParam::LfoPhase(i)        => data.lfo[i].phase = value,
+            ModParam::LfoAmount(i)       => data.lfo[i].amount = value,
+            ModParam::GlfoFrequency(i)   => data.glfo[i].frequency = value,
+            ModParam::GlfoPhase(i)       => data.glfo[i].phase = value,
+            ModParam::GlfoAmount(i)      => data.glfo[i].amount = value,
+            ModParam::FilterCutoff(i)    => data.filter[i].cutoff = value,
+            ModParam::FilterResonance(i) => data.filter[i].resonance = value,
+            ModParam::FilterGain(i)      => data.filter[i].gain = value,
+            ModParam::FilterAux(i)       => data.filter[i].aux = value,
+            ModParam::FilterEnvDepth(i)  => data.filter[i].env_depth = value,
+            ModParam::DelayTime          => data.delay.time = value,
+            ModParam::DelayLevel         => data.delay.level = value,
+            ModParam::DelayFeedback      => data.delay.feedback = value,
+            ModParam::DelayTone          => data.delay.tone = value,
+            ModParam::SubLevel           => data.sub.level = value / 100.0,
+            ModParam::ModAmount(i)       => data.modul[i].amount = value,
+            ModParam::Other(param) => {
+                let mut current_val = data.get_value(&param);
+                if let ParameterValue::Float(_) | ParameterValue::Int(_) | ParameterValue::Choice(_) = current_val {
+                    current_val.set_from_float(value);
+                    let synth_param = SynthParam::new(param.function, param.function_id, param.parameter, current_val);
+                    data.set_parameter(&synth_param);
+                }
+            }
+        }
+    }
+}
+
+/** Target of compiled modulation routes, with its precalculated value range. */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModTarget {
+    pub param: ModParam,
+    pub min: Float,
+    pub max: Float,
+}
+
+impl ModTarget {
+    /** Adds a modulation value to the target, keeping it within its range. */
+    pub fn apply(&self, data: &mut SoundData, mod_val: Float) {
+        let mut val = self.param.get(data) + mod_val;
+        if val < self.min {
+            val = self.min;
+        } else if val > self.max {
+            val = self.max;
+        }
+        self.param.set(data, val);
+    }
+}
+
+/** A modulator reduced to what is needed to calculate it. */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModRoute {
+    pub source: ModSourceSlot,
+    pub target: usize, // Index into the target list
+    pub scale: Float,
+}
+
+/** Precompiled modulation routes of a sound.
+ *
+ * Looking up value ranges and converting parameter values for every
+ * modulator on every update is too slow, so the active modulators are
+ * compiled into routes once whenever the sound changes. Each route points
+ * to an entry in a flat list of targets, which can be shared by multiple
+ * routes.
+ */
+pub struct ModRouting {
+    targets: [ModTarget; NUM_MODULATORS],
+    global_routes: [ModRoute; NUM_MODULATORS],
+    local_routes: [ModRoute; NUM_MODULATORS],
+    num_targets: usize,
+    num_global: usize,
+    num_local: usize,
+}
+
+impl Default for ModRouting {
+    fn default() -> Self {
+        ModRouting::new()
+    }
+}
+
+impl ModRouting {
+    pub fn new() -> ModRouting {
+        ModRouting{
+            targets: [ModTarget::default(); NUM_MODULATORS],
+            global_routes: [ModRoute::default(); NUM_MODULATORS],
+            local_routes: [ModRoute::default(); NUM_MODULATORS],
+            num_targets: 0,
+            num_global: 0,
+            num_local: 0,
+        }
+    }
+
+    /** Compile the active modulators into routes. */
+    pub fn compile(&mut self, modul: &[ModData]) {
+        self.num_targets = 0;
+        self.num_global = 0;
+        self.num_local = 0;
+        for m in modul.iter() {
+            if !m.active {
+                continue;
+            }
+            let source = match ModSourceSlot::from_function(m.source_func, m.source_func_id) {
+                Some(s) => s,
+                None => {
+                    // Treat the modulator as disabled
+                    error!("Invalid modulation source {} {}", m.source_func, m.source_func_id);
+                    continue;
+                }
+            };
+            let param = ModParam::from_param_id(&m.get_target());
+            let target = match self.targets[..self.num_targets].iter().position(|t| t.param == param) {
+                Some(index) => index,
+                None => {
+                    let (min, max) = MenuItem::get_val_range(m.target_func, m.target_param).get_min_max();
+                    self.targets[self.num_targets] = ModTarget{param, min, max};
+                    self.num_targets += 1;
+                    self.num_targets - 1
+                }
+            };
+            let route = ModRoute{source, target, scale: m.scale};
+            if m.is_global {
+                self.global_routes[self.num_global] = route;
+                self.num_global += 1;
+            } else {
+                self.local_routes[self.num_local] = route;
+                self.num_local += 1;
+            }
+        }
+    }
+
+    pub fn get_targets(&self) -> &[ModTarget] {
+        &self.targets[..self.num_targets]
+    }
+
+    pub fn get_global_routes(&self) -> &[ModRoute] {
+        &self.global_routes[..self.num_global]
+    }
+
+    pub fn get_local_routes(&self) -> &[ModRoute] {
+        &self.local_routes[..self.num_local]
+    }
+
+    /** Set all modulation targets in data back to their values in base. */
+    pub fn reset_targets(&self, base: &SoundData, data: &mut SoundData) {
+        for t in self.get_targets() {
+            t.param.set(data, t.param.get(base));
+        }
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{ModData, ModParam, ModRouting, ModSourceSlot};
+use super::super::{Parameter, ParamId, FunctionId, SoundData};
+
+fn create_modulator(source: Parameter, target: Parameter, target_param: Parameter, amount: f64) -> ModData {
+    let mut m = ModData::new();
+    m.set_source(&FunctionId{function: source, function_id: 1, ..Default::default()});
+    m.set_target(&ParamId::new(target, 1, target_param));
+    m.set_amount(amount);
+    m.active = true;
+    m
+}
+
+#[test]
+fn only_active_modulators_are_compiled() {
+    let mut modul = [ModData::new(); 3];
+    modul[0] = create_modulator(Parameter::Lfo, Parameter::Oscillator, Parameter::Level, 0.5);
+    modul[2] = create_modulator(Parameter::GlobalLfo, Parameter::Filter, Parameter::Cutoff, 1.0);
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+
+    assert_eq!(routing.get_targets().len(), 2);
+    assert_eq!(routing.get_local_routes().len(), 1);
+    assert_eq!(routing.get_global_routes().len(), 1);
+    assert_eq!(routing.get_local_routes()[0].source, ModSourceSlot::Lfo(0));
+    assert_eq!(routing.get_targets()[0].param, ModParam::OscLevel(0));
+    assert_eq!(routing.get_targets()[1].param, ModParam::FilterCutoff(0));
+    assert_eq!(routing.get_global_routes()[0].target, 1);
+}
+
+#[test]
+fn routes_to_same_parameter_share_target() {
+    let modul = [
+        create_modulator(Parameter::Lfo, Parameter::Oscillator, Parameter::Level, 0.5),
+        create_modulator(Parameter::Envelope, Parameter::Oscillator, Parameter::Level, 0.5),
+    ];
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+
+    assert_eq!(routing.get_targets().len(), 1);
+    assert_eq!(routing.get_local_routes()[1].target, 0);
+}
+
+#[test]
+fn modulation_is_limited_to_parameter_range() {
+    let modul = [create_modulator(Parameter::Lfo, Parameter::Oscillator, Parameter::Level, 1.0)];
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+    let mut sound = SoundData::new();
+    sound.init();
+    let target = routing.get_targets()[0];
+
+    target.apply(&mut sound, 80.0);
+    assert_eq!(sound.osc[0].level, 1.0);
+    target.apply(&mut sound, -150.0);
+    assert_eq!(sound.osc[0].level, 0.0);
+}
+
+#[test]
+fn reset_restores_unmodulated_values() {
+    let modul = [create_modulator(Parameter::Lfo, Parameter::Filter, Parameter::Cutoff, 1.0)];
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+    let mut base = SoundData::new();
+    base.init();
+    let mut sound = base;
+
+    routing.get_targets()[0].apply(&mut sound, 1000.0);
+    assert_eq!(sound.filter[0].cutoff, base.filter[0].cutoff + 1000.0);
+    routing.reset_targets(&base, &mut sound);
+    assert_eq!(sound.filter[0].cutoff, base.filter[0].cutoff);
+}
+
+#[test]
+fn fm_index_is_a_modulation_target() {
+    let modul = [create_modulator(Parameter::Envelope, Parameter::Oscillator, Parameter::ModIndex, 1.0)];
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+    let mut sound = SoundData::new();
+    sound.init();
+    let target = routing.get_targets()[0];
+    assert_eq!(target.param, ModParam::OscModIndex(0));
+
+    target.apply(&mut sound, 2.5);
+    assert_eq!(sound.osc[0].mod_index, 3.5);
+    target.apply(&mut sound, 20.0);
+    assert_eq!(sound.osc[0].mod_index, 10.0);
+}
+
+#[test]
+fn grain_parameters_are_modulation_targets() {
+    let modul = [create_modulator(Parameter::Envelope, Parameter::Oscillator, Parameter::Position, 1.0),
+                 create_modulator(Parameter::Envelope, Parameter::Oscillator, Parameter::Density, 1.0)];
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+    let mut sound = SoundData::new();
+    sound.init();
+    let targets = routing.get_targets();
+    assert_eq!(targets[0].param, ModParam::OscGrainPosition(0));
+
+    targets[0].apply(&mut sound, 0.25);
+    assert_eq!(sound.osc[0].granular_data.position, 0.25);
+    targets[1].apply(&mut sound, 500.0);
+    assert_eq!(sound.osc[0].granular_data.density, 200.0);
+}
+
+#[test]
+fn sub_level_is_modulation_target() {
+    let modul = [create_modulator(Parameter::Lfo, Parameter::SubOscillator, Parameter::Level, 1.0)];
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+    let mut sound = SoundData::new();
+    sound.init();
+    let targets = routing.get_targets();
+    assert_eq!(targets[0].param, ModParam::SubLevel);
+
+    targets[0].apply(&mut sound, 50.0);
+    assert_eq!(sound.sub.level, 0.5);
+}
+
+#[test]
+fn pulse_width_is_modulation_target() {
+    let modul = [create_modulator(Parameter::Lfo, Parameter::Oscillator, Parameter::PulseWidth, 1.0)];
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+    let mut sound = SoundData::new();
+    sound.init();
+    let targets = routing.get_targets();
+    assert_eq!(targets[0].param, ModParam::OscPulseWidth(0));
+
+    targets[0].apply(&mut sound, 0.25);
+    assert_eq!(sound.osc[0].va_data.pulse_width, 0.75);
+    targets[0].apply(&mut sound, 1.0);
+    assert_eq!(sound.osc[0].va_data.pulse_width, 0.99);
+}
+
+#[test]
+fn tune_modulation_is_rounded() {
+    let modul = [create_modulator(Parameter::Lfo, Parameter::Oscillator, Parameter::Tune, 1.0)];
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+    let targets = routing.get_targets();
+    for &(mod_val, halfsteps) in [(-0.6, -1), (-0.4, 0), (0.4, 0), (1.999, 2)].iter() {
+        let mut sound = SoundData::new();
+        sound.init();
+        targets[0].apply(&mut sound, mod_val);
+        assert_eq!(sound.osc[0].tune_halfsteps, halfsteps, "{}", mod_val);
+    }
+}
+
+#[test]
+fn invalid_source_disables_modulator() {
+    let mut modul = [create_modulator(Parameter::Lfo, Parameter::Oscillator, Parameter::Level, 0.5); 3];
+    modul[0].source_func = Parameter::Level; // Not a modulation source
+    modul[1].source_func_id = 10;            // No such LFO
+    let mut routing = ModRouting::new();
+    routing.compile(&modul);
+
+    assert_eq!(routing.get_local_routes().len(), 1);
+    assert_eq!(ModSourceSlot::from_function(Parameter::Filter, 1), None);
+    assert_eq!(ModSourceSlot::from_function(Parameter::Envelope, 3), Some(ModSourceSlot::Envelope(2)));
+}
+
+} // mod tests
@@ -22,6 +22,7 @@ pub enum Parameter {
     Modulation,
     Delay,
     Patch,
+    SubOscillator,
     System,
 
     // Oscillator, Lfo
@@ -37,6 +38,52 @@ pub enum Parameter {
     Routing,
     Voices,
     Spread,
+    ModSource,
+    ModIndex,
+    SyncSource,
+    CrossMod,
+    CrossModSource,
+    Warp,
+    WarpAmount,
+    Width,
+    RandomPhase,
+    Blend,
+    Interpolation,
+    OctaveFade,
+
+    // Sample oscillator
+    Sample,
+    RootKey,
+    Start,
+    End,
+    LoopStart,
+    LoopEnd,
+    LoopMode,
+    Crossfade,
+    // Loop modes
+    Forward,
+
+    // Granular oscillator
+    Position,
+    GrainSize,
+    Density,
+    Jitter,
+    GrainPan,
+
+    // Plucked string oscillator
+    Excitation,
+    Damping,
+
+    // Analog oscillator
+    PulseWidth,
+
+    // Sub-oscillator
+    Octave,
+
+    // Noise oscillator
+    NoiseColor,
+    NoiseTone,
+    Seed,
     VelSens,
     EnvDepth,
     Phase,
@@ -98,6 +145,38 @@ pub enum Parameter {
     Noise,
     SampleHold,
 
+    // Oscillator types
+    FM,
+    PM,
+    Granular,
+    Pluck,
+    Analog,
+
+    // Warp modes
+    BendPlus,
+    BendMinus,
+    Mirror,
+    Pwm,
+    Quantize,
+    FmWarp,
+
+    // Interpolation modes
+    Fast,
+    Linear,
+    Cubic,
+    Sinc,
+
+    // Cross modulation types
+    RingMod,
+    AmpMod,
+
+    // Noise colours
+    White,
+    Pink,
+    Brown,
+    Blue,
+    Digital,
+
     // Delay
     Time,
     Feedback,
@@ -118,6 +197,7 @@ pub enum Parameter {
     // System parameters
     Idle,
     Busy,
+    Dropped,
     PlayMode,
     Poly,
     Mono,
@@ -317,7 +397,7 @@ impl MenuItem {
 }
 
 /* Top-level menu */
-pub static FUNCTIONS: [MenuItem; 8] = [
+pub static FUNCTIONS: [MenuItem; 9] = [
     MenuItem{item: Parameter::Oscillator, key: 'o', val_range: ValueRange::Int(1, NUM_OSCILLATORS as i64),  next: &OSC_PARAMS},
     MenuItem{item: Parameter::Envelope,   key: 'e', val_range: ValueRange::Int(1, NUM_ENVELOPES as i64),    next: &ENV_PARAMS},
     MenuItem{item: Parameter::Lfo,        key: 'l', val_range: ValueRange::Int(1, NUM_LFOS as i64),         next: &LFO_PARAMS},
@@ -326,9 +406,10 @@ pub static FUNCTIONS: [MenuItem; 8] = [
     MenuItem{item: Parameter::Delay,      key: 'd', val_range: ValueRange::Int(1, 1),                       next: &DELAY_PARAMS},
     MenuItem{item: Parameter::Modulation, key: 'm', val_range: ValueRange::Int(1, NUM_MODULATORS as i64),   next: &MOD_PARAMS},
     MenuItem{item: Parameter::Patch,      key: 'p', val_range: ValueRange::Int(1, 1),                       next: &PATCH_PARAMS},
+    MenuItem{item: Parameter::SubOscillator, key: 's', val_range: ValueRange::Int(1, 1),                    next: &SUB_OSC_PARAMS},
 ];
 
-pub static OSC_PARAMS: [MenuItem; 11] = [
+pub static OSC_PARAMS: [MenuItem; 44] = [
     MenuItem{item: Parameter::Level,     key: 'l', val_range: ValueRange::Float(0.0, 100.0, 1.0),       next: &[]},
     MenuItem{item: Parameter::Tune,      key: 't', val_range: ValueRange::Int(-24, 24),                 next: &[]},
     MenuItem{item: Parameter::Finetune,  key: 'f', val_range: ValueRange::Float(-100.0, 100.0, 1.0),    next: &[]},
@@ -341,6 +422,44 @@ pub static OSC_PARAMS: [MenuItem; 11] = [
     MenuItem{item: Parameter::WaveIndex, key: 'i', val_range: ValueRange::Float(0.0, 1.0, 0.01),        next: &[]},
     MenuItem{item: Parameter::Voices,    key: 'v', val_range: ValueRange::Int(1, 7),                    next: &[]},
     MenuItem{item: Parameter::Spread,    key: 'e', val_range: ValueRange::Float(0.0, 2.0, 0.01),        next: &[]},
+    MenuItem{item: Parameter::Width,     key: 'E', val_range: ValueRange::Float(0.0, 1.0, 0.01),        next: &[]},
+    MenuItem{item: Parameter::RandomPhase, key: 'H', val_range: ValueRange::Float(0.0, 1.0, 0.01),      next: &[]},
+    MenuItem{item: Parameter::Blend,     key: 'L', val_range: ValueRange::Float(-1.0, 1.0, 0.01),       next: &[]},
+    MenuItem{item: Parameter::Warp,      key: 'A', val_range: ValueRange::Choice(&WARP_MODES),          next: &[]},
+    MenuItem{item: Parameter::WarpAmount,key: 'B', val_range: ValueRange::Float(0.0, 1.0, 0.01),        next: &[]},
+    MenuItem{item: Parameter::Interpolation, key: 'I', val_range: ValueRange::Choice(&INTERPOLATION_MODES), next: &[]},
+    MenuItem{item: Parameter::OctaveFade, key: 'F', val_range: ValueRange::Int(0, 1),                   next: &[]},
+    MenuItem{item: Parameter::ModSource, key: 'o', val_range: ValueRange::Int(1, NUM_OSCILLATORS as i64), next: &[]},
+    MenuItem{item: Parameter::ModIndex,  key: 'm', val_range: ValueRange::Float(0.0, 10.0, 0.01),       next: &[]},
+    MenuItem{item: Parameter::SyncSource,key: 'S', val_range: ValueRange::Int(1, NUM_OSCILLATORS as i64), next: &[]},
+    MenuItem{item: Parameter::CrossMod,  key: 'M', val_range: ValueRange::Choice(&CROSS_MOD_TYPES),     next: &[]},
+    MenuItem{item: Parameter::CrossModSource, key: 'O', val_range: ValueRange::Int(1, NUM_OSCILLATORS as i64), next: &[]},
+
+    MenuItem{item: Parameter::Sample,    key: 'a', val_range: ValueRange::Dynamic(Parameter::Sample),   next: &[]},
+    MenuItem{item: Parameter::RootKey,   key: 'n', val_range: ValueRange::Int(0, 127),                  next: &[]},
+    MenuItem{item: Parameter::Start,     key: 'b', val_range: ValueRange::Float(0.0, 1.0, 0.001),       next: &[]},
+    MenuItem{item: Parameter::End,       key: 'd', val_range: ValueRange::Float(0.0, 1.0, 0.001),       next: &[]},
+    MenuItem{item: Parameter::LoopStart, key: 'x', val_range: ValueRange::Float(0.0, 1.0, 0.001),       next: &[]},
+    MenuItem{item: Parameter::LoopEnd,   key: 'z', val_range: ValueRange::Float(0.0, 1.0, 0.001),       next: &[]},
+    MenuItem{item: Parameter::LoopMode,  key: 'p', val_range: ValueRange::Choice(&LOOP_MODES),          next: &[]},
+    MenuItem{item: Parameter::Crossfade, key: 'c', val_range: ValueRange::Float(0.0, 0.5, 0.01),        next: &[]},
+
+    MenuItem{item: Parameter::Position,  key: 'u', val_range: ValueRange::Float(0.0, 1.0, 0.001),       next: &[]},
+    MenuItem{item: Parameter::GrainSize, key: 'g', val_range: ValueRange::Float(1.0, 1000.0, 1.0),      next: &[]},
+    MenuItem{item: Parameter::Density,   key: 'h', val_range: ValueRange::Float(1.0, 200.0, 1.0),       next: &[]},
+    MenuItem{item: Parameter::Jitter,    key: 'j', val_range: ValueRange::Float(0.0, 1.0, 0.01),        next: &[]},
+    MenuItem{item: Parameter::GrainPan,  key: 'q', val_range: ValueRange::Float(0.0, 1.0, 0.01),        next: &[]},
+
+    MenuItem{item: Parameter::Excitation,key: 'X', val_range: ValueRange::Choice(&EXCITATION_TYPES),    next: &[]},
+    MenuItem{item: Parameter::Damping,   key: 'D', val_range: ValueRange::Float(0.0, 1.0, 0.01),        next: &[]},
+    MenuItem{item: Parameter::Decay,     key: 'Y', val_range: ValueRange::Float(10.0, 20000.0, 10.0),   next: &[]},
+
+    MenuItem{item: Parameter::Waveform,  key: 'W', val_range: ValueRange::Choice(&VA_WAVEFORMS),        next: &[]},
+    MenuItem{item: Parameter::PulseWidth,key: 'P', val_range: ValueRange::Float(0.01, 0.99, 0.01),      next: &[]},
+
+    MenuItem{item: Parameter::NoiseColor,key: 'C', val_range: ValueRange::Choice(&NOISE_COLORS),        next: &[]},
+    MenuItem{item: Parameter::NoiseTone, key: 'T', val_range: ValueRange::Float(0.0, 1.0, 0.01),        next: &[]},
+    MenuItem{item: Parameter::Seed,      key: 'R', val_range: ValueRange::Int(0, 65535),                next: &[]},
 ];
 
 pub static OSC_ROUTING: [MenuItem; 3] = [
@@ -349,14 +468,70 @@ pub static OSC_ROUTING: [MenuItem; 3] = [
     MenuItem{item: Parameter::Direct,  key: 'd', val_range: ValueRange::NoRange, next: &[]},
 ];
 
-pub static OSC_TYPES: [MenuItem; 2] = [
+pub static OSC_TYPES: [MenuItem; 8] = [
     MenuItem{item: Parameter::Wavetable, key: 'w', val_range: ValueRange::NoRange, next: &[]},
     MenuItem{item: Parameter::Noise,     key: 'n', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::FM,        key: 'f', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::PM,        key: 'p', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Sample,    key: 's', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Granular,  key: 'g', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Pluck,     key: 'k', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Analog,    key: 'a', val_range: ValueRange::NoRange, next: &[]},
+];
+
+pub static LOOP_MODES: [MenuItem; 3] = [
+    MenuItem{item: Parameter::Off,       key: 'o', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Forward,   key: 'f', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::PingPong,  key: 'p', val_range: ValueRange::NoRange, next: &[]},
+];
+
+pub static EXCITATION_TYPES: [MenuItem; 2] = [
+    MenuItem{item: Parameter::Noise,     key: 'n', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Wavetable, key: 'w', val_range: ValueRange::NoRange, next: &[]},
+];
+
+pub static WARP_MODES: [MenuItem; 8] = [
+    MenuItem{item: Parameter::Off,       key: 'o', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::BendPlus,  key: 'b', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::BendMinus, key: 'n', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Mirror,    key: 'm', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Sync,      key: 's', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Pwm,       key: 'p', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Quantize,  key: 'q', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::FmWarp,    key: 'f', val_range: ValueRange::NoRange, next: &[]},
+];
+
+pub static INTERPOLATION_MODES: [MenuItem; 4] = [
+    MenuItem{item: Parameter::Fast,      key: 'f', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Linear,    key: 'l', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Cubic,     key: 'c', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Sinc,      key: 's', val_range: ValueRange::NoRange, next: &[]},
+];
+
+pub static CROSS_MOD_TYPES: [MenuItem; 3] = [
+    MenuItem{item: Parameter::Off,       key: 'o', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::RingMod,   key: 'r', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::AmpMod,    key: 'a', val_range: ValueRange::NoRange, next: &[]},
+];
+
+pub static NOISE_COLORS: [MenuItem; 5] = [
+    MenuItem{item: Parameter::White,     key: 'w', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Pink,      key: 'p', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Brown,     key: 'b', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Blue,      key: 'u', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Digital,   key: 'd', val_range: ValueRange::NoRange, next: &[]},
+];
+
+pub static VA_WAVEFORMS: [MenuItem; 4] = [
+    MenuItem{item: Parameter::Saw,       key: 's', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Square,    key: 'q', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Triangle,  key: 't', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Sine,      key: 'i', val_range: ValueRange::NoRange, next: &[]},
 ];
 
 pub static LFO_PARAMS: [MenuItem; 5] = [
     MenuItem{item: Parameter::Waveform,  key: 'w', val_range: ValueRange::Choice(&LFO_WAVEFORM), next: &[]},
-    MenuItem{item: Parameter::Frequency, key: 'f', val_range: ValueRange::Float(0.0, 44.1, 0.1), next: &[]},
+    MenuItem{item: Parameter::Frequency, key: 'f', val_range: ValueRange::Float(0.0, MAX_LFO_FREQUENCY, 0.1), next: &[]},
     MenuItem{item: Parameter::Sync,      key: 's', val_range: ValueRange::Choice(&SYNC_OPTIONS), next: &[]},
     MenuItem{item: Parameter::Phase,     key: 'p', val_range: ValueRange::Float(0.0, 1.0, 0.01), next: &[]},
     MenuItem{item: Parameter::Amount,    key: 'a', val_range: ValueRange::Float(0.0, 1.0, 0.01), next: &[]},
@@ -418,6 +593,18 @@ pub static SYNC_OPTIONS: [MenuItem; 9] = [
     MenuItem{item: Parameter::Sixteenth,    key: 's', val_range: ValueRange::NoRange, next: &[]},
 ];
 
+pub static SUB_OSC_PARAMS: [MenuItem; 4] = [
+    MenuItem{item: Parameter::Level,     key: 'l', val_range: ValueRange::Float(0.0, 100.0, 1.0),    next: &[]},
+    MenuItem{item: Parameter::Octave,    key: 'o', val_range: ValueRange::Int(-2, -1),               next: &[]},
+    MenuItem{item: Parameter::Waveform,  key: 'w', val_range: ValueRange::Choice(&SUB_WAVEFORMS),    next: &[]},
+    MenuItem{item: Parameter::Routing,   key: 'r', val_range: ValueRange::Choice(&OSC_ROUTING),      next: &[]},
+];
+
+pub static SUB_WAVEFORMS: [MenuItem; 2] = [
+    MenuItem{item: Parameter::Square,    key: 'q', val_range: ValueRange::NoRange, next: &[]},
+    MenuItem{item: Parameter::Sine,      key: 's', val_range: ValueRange::NoRange, next: &[]},
+];
+
 pub static DELAY_PARAMS: [MenuItem; 6] = [
     MenuItem{item: Parameter::Time,      key: 't', val_range: ValueRange::Float(0.01, 1.0, 0.01),    next: &[]},
     MenuItem{item: Parameter::Sync,      key: 's', val_range: ValueRange::Choice(&SYNC_OPTIONS),     next: &[]},
@@ -466,7 +653,7 @@ pub static MOD_SOURCES: [MenuItem; 9] = [
     MenuItem{item: Parameter::SustainPedal,key: 's', val_range: ValueRange::Int(1, 1), next: &LFO_PARAMS},
 ];
 
-pub static MOD_TARGETS: [MenuItem; 7] = [
+pub static MOD_TARGETS: [MenuItem; 8] = [
     MenuItem{item: Parameter::Oscillator, key: 'o', val_range: ValueRange::Int(1, 3), next: &OSC_PARAMS},
     MenuItem{item: Parameter::Envelope,   key: 'e', val_range: ValueRange::Int(1, 2), next: &ENV_PARAMS},
     MenuItem{item: Parameter::Lfo,        key: 'l', val_range: ValueRange::Int(1, 2), next: &LFO_PARAMS},
@@ -474,6 +661,7 @@ pub static MOD_TARGETS: [MenuItem; 7] = [
     MenuItem{item: Parameter::Filter,     key: 'f', val_range: ValueRange::Int(1, 2), next: &FILTER_PARAMS},
     MenuItem{item: Parameter::Delay,      key: 'd', val_range: ValueRange::Int(1, 1), next: &DELAY_PARAMS},
     MenuItem{item: Parameter::Modulation, key: 'm', val_range: ValueRange::Int(1, 16), next: &MOD_TARGET_PARAMS},
+    MenuItem{item: Parameter::SubOscillator, key: 's', val_range: ValueRange::Int(1, 1), next: &SUB_OSC_PARAMS},
 ];
 
 pub static MOD_TARGET_PARAMS: [MenuItem; 2] = [
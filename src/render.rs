@@ -0,0 +1,257 @@
+//! Offline rendering of MIDI files.
+//!
+//! Plays a Standard MIDI File through the synth engine and writes the result
+//! to a stereo WAV file. No audio or MIDI device is required, and rendering
+//! runs as fast as the machine allows.
+//!
+//! Sounds can also be rendered into a new wavetable, which is added to the
+//! wavetable list of the bank, and banks can be exported with their
+//! wavetables embedded.
+
+use super::Float;
+use super::MidiMessage;
+use super::midi_file::MidiFile;
+use super::storage::SoundBank;
+use super::synth::{AudioMessage, Synth, SynthConfig, SynthControl, SynthEvent};
+use super::synth::ResampleSettings;
+use super::wav_file::WavWriter;
+use super::{SynthMessage, UiMessage};
+use super::WtInfo;
+use super::{SOUND_DATA_VERSION, SYNTH_ENGINE_VERSION};
+
+use crossbeam_channel::unbounded;
+use log::{error, info};
+
+use std::path::Path;
+
+const BLOCK_SIZE: usize = 64;      // Number of samples between voice state updates
+const TAIL_TIME: Float = 2.0;      // Time to keep rendering after the last event
+const MAX_TAIL_TIME: Float = 30.0; // Maximum time to wait for voices to finish
+
+pub struct Renderer {
+    sample_rate: u32,
+    midi_channel: u8,      // 0 - 15, 16 = omni
+    config: SynthConfig,
+}
+
+impl Renderer {
+    pub fn new(sample_rate: u32, midi_channel: u8, config: SynthConfig) -> Renderer {
+        Renderer{sample_rate, midi_channel, config}
+    }
+
+    /// Render a MIDI file with a sound from the given bank to a WAV file.
+    ///
+    /// The patch index starts at 0.
+    pub fn render(&self, bank_file: &str, patch: usize, midi_filename: &str, output: &str) -> Result<(), ()> {
+        let bank = Renderer::load_bank(bank_file, patch)?;
+
+        let midi = match MidiFile::read_file(midi_filename) {
+            Ok(m) => m,
+            Err(e) => {
+                error!("Failed to read MIDI file {}: {}", midi_filename, e);
+                println!("Failed to read MIDI file {}: {}", midi_filename, e);
+                return Err(());
+            }
+        };
+
+        // The control only sends sample buffers for display to the UI, which
+        // aren't requested here, so the receiver is never read. Messages are
+        // passed to the synth directly instead of through its event queue.
+        let (sender, _receiver) = unbounded::<UiMessage>();
+        let (audio_sender, audio_receiver) = unbounded::<SynthEvent>();
+        let mut control = SynthControl::new(self.sample_rate, sender, audio_sender);
+        let mut synth = Synth::new(self.sample_rate, control.get_wavetables(), control.get_release_sender(), audio_receiver);
+        synth.configure(&self.config);
+        for message in Renderer::load_bank_data(&mut control, &bank) {
+            synth.handle_message(message);
+        }
+        let sound = bank.get_sound(patch);
+        info!("Rendering {} with sound {}: {}", midi_filename, patch + 1, sound.name);
+        println!("Rendering {} with sound {}: {}", midi_filename, patch + 1, sound.name);
+        synth.handle_sound_update(&sound.data);
+
+        let result = self.render_to_file(&mut synth, &midi, output);
+        if let Err(e) = result {
+            error!("Failed to write {}: {}", output, e);
+            println!("Failed to write {}: {}", output, e);
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// Render a sound from the given bank into a new wavetable.
+    ///
+    /// The wavetable is saved in the wavetable directory and added to the
+    /// wavetable list of the bank, which is then saved.
+    pub fn resample(&self, bank_file: &str, patch: usize, settings: &ResampleSettings, name: &str) -> Result<(), ()> {
+        let mut bank = Renderer::load_bank(bank_file, patch)?;
+        if bank.wt_list.iter().any(|wt| wt.name == name) {
+            println!("Wavetable {} already exists", name);
+            return Err(());
+        }
+
+        let (sender, _receiver) = unbounded::<UiMessage>();
+        let (audio_sender, _audio_receiver) = unbounded::<SynthEvent>();
+        let mut control = SynthControl::new(self.sample_rate, sender, audio_sender);
+        Renderer::load_bank_data(&mut control, &bank);
+        let sound = bank.get_sound(patch);
+        println!("Resampling sound {}: {}", patch + 1, sound.name);
+        control.handle_message(SynthMessage::Sound(sound.data));
+
+        let wt_info = WtInfo{
+            id: bank.next_wavetable_id(),
+            valid: true,
+            name: name.to_string(),
+            filename: format!("{}.wav", name)};
+        if let Err(e) = control.resample(settings, wt_info.clone()) {
+            error!("Failed to resample {}: {}", name, e);
+            println!("Failed to resample {}: {}", name, e);
+            return Err(());
+        }
+        bank.wt_list.push(wt_info);
+        if let Err(e) = bank.save_bank(bank_file) {
+            println!("Failed to save sound bank {}: {}", bank_file, e);
+            return Err(());
+        }
+        println!("Added wavetable {} to {}", name, bank_file);
+        Ok(())
+    }
+
+    /// Save a sound bank with the wavetables used by its sounds embedded.
+    pub fn export(bank_file: &str, export_file: &str) -> Result<(), ()> {
+        let bank = Renderer::load_bank(bank_file, 0)?;
+        if let Err(e) = bank.export_bank(export_file) {
+            error!("Failed to export sound bank to {}: {}", export_file, e);
+            println!("Failed to export sound bank to {}: {}", export_file, e);
+            return Err(());
+        }
+        println!("Exported {} to {}", bank_file, export_file);
+        Ok(())
+    }
+
+    // Load a sound bank and check that the patch index is valid.
+    fn load_bank(bank_file: &str, patch: usize) -> Result<SoundBank, ()> {
+        if !Path::new(bank_file).exists() {
+            error!("Sound bank {} not found", bank_file);
+            println!("Sound bank {} not found", bank_file);
+            return Err(());
+        }
+        let mut bank = SoundBank::new(SOUND_DATA_VERSION, SYNTH_ENGINE_VERSION);
+        if let Err(e) = bank.load_bank(bank_file) {
+            error!("Failed to load sound bank {}: {}", bank_file, e);
+            println!("Failed to load sound bank {}: {}", bank_file, e);
+            return Err(());
+        }
+        if patch > 127 {
+            println!("Invalid patch number {}", patch + 1);
+            return Err(());
+        }
+        Ok(bank)
+    }
+
+    // Load the wavetables and samples used by the bank into the control.
+    //
+    // Returns the messages that pass them on to the synth.
+    fn load_bank_data(control: &mut SynthControl, bank: &SoundBank) -> Vec<AudioMessage> {
+        let mut messages = Vec::new();
+        for wt_info in bank.wt_list.iter() {
+            let result = match bank.get_embedded_table(wt_info.id) {
+                Some(embedded) => control.load_embedded_wavetable(wt_info.clone(), embedded),
+                None => control.load_wavetable(wt_info.clone()),
+            };
+            match result {
+                Ok(wt) => messages.push(AudioMessage::Wavetable(wt_info.id, wt)),
+                Err(e) => println!("Using default wavetable for {}: {}", wt_info.name, e),
+            }
+        }
+        for gen_info in bank.gen_list.iter() {
+            match control.generate_wavetable(gen_info) {
+                Ok(wt) => messages.push(AudioMessage::Wavetable(gen_info.id, wt)),
+                Err(e) => println!("Using default wavetable for {}: {}", gen_info.name, e),
+            }
+        }
+        for sample_info in bank.sample_list.iter() {
+            if let Some(sample) = control.load_sample(sample_info) {
+                messages.push(AudioMessage::Sample(sample_info.id, sample));
+            }
+        }
+        messages
+    }
+
+    fn render_to_file(&self, synth: &mut Synth, midi: &MidiFile, output: &str) -> std::io::Result<()> {
+        let mut writer = WavWriter::create(output, 2, self.sample_rate)?;
+        let sample_rate = self.sample_rate as Float;
+        let end_time = ((midi.get_duration() + TAIL_TIME) * sample_rate) as i64;
+        let max_time = ((midi.get_duration() + MAX_TAIL_TIME) * sample_rate) as i64;
+        let mut events = midi.events.iter().peekable();
+        let mut sample_clock = 0i64;
+        let mut left = [0.0f32; BLOCK_SIZE];
+        let mut right = [0.0f32; BLOCK_SIZE];
+
+        loop {
+            let mut offset = 0;
+            while offset < BLOCK_SIZE {
+                // Send all events that are due at the current sample
+                while let Some(event) = events.peek() {
+                    if (event.time * sample_rate) as i64 > sample_clock {
+                        break;
+                    }
+                    self.send_event(synth, event.message);
+                    events.next();
+                }
+
+                // Render up to the next event
+                let mut len = BLOCK_SIZE - offset;
+                if let Some(event) = events.peek() {
+                    let frames_to_event = (event.time * sample_rate) as i64 - sample_clock;
+                    if frames_to_event < len as i64 {
+                        len = frames_to_event as usize;
+                    }
+                }
+                synth.process_block(&mut left[offset..offset + len], &mut right[offset..offset + len]);
+                sample_clock += len as i64;
+                offset += len;
+            }
+            for i in 0..BLOCK_SIZE {
+                writer.write_frame(&[left[i], right[i]])?;
+            }
+            synth.update(); // Update the state of the synth voices
+
+            // Keep running until all events are sent and the voices have
+            // finished, but not forever in case of hanging notes.
+            if events.peek().is_none() && sample_clock >= end_time
+            && (!synth.is_playing() || sample_clock >= max_time) {
+                break;
+            }
+        }
+        writer.finalize()?;
+        info!("Rendered {} samples", sample_clock);
+        println!("Rendered {:.2} seconds to {}", sample_clock as Float / sample_rate, output);
+        Ok(())
+    }
+
+    fn send_event(&self, synth: &mut Synth, message: MidiMessage) {
+        let channel = match message {
+            MidiMessage::NoteOff{channel, key: _, velocity: _}
+            | MidiMessage::NoteOn{channel, key: _, velocity: _}
+            | MidiMessage::KeyAT{channel, key: _, pressure: _}
+            | MidiMessage::ControlChg{channel, controller: _, value: _}
+            | MidiMessage::ProgramChg{channel, program: _}
+            | MidiMessage::ChannelAT{channel, pressure: _}
+            | MidiMessage::Pitchbend{channel, pitch: _} => channel,
+            _ => return,
+        };
+        if self.midi_channel < 16 && channel != self.midi_channel {
+            return;
+        }
+        match message {
+            // MIDI files commonly use NoteOn with velocity 0 as NoteOff
+            MidiMessage::NoteOn{channel, key, velocity: 0} => {
+                synth.handle_midi_message(MidiMessage::NoteOff{channel, key, velocity: 0});
+            }
+            // Patch selection is done on the command line
+            MidiMessage::ProgramChg{channel: _, program: _} => (),
+            _ => synth.handle_midi_message(message),
+        }
+    }
+}
@@ -61,6 +61,8 @@ pub struct SoundData {
     pub modul: [ModData; NUM_MODULATORS],
     pub delay: DelayData,
     pub patch: PatchData,
+    #[serde(default)]
+    pub sub: SubOscData,
 }
 
 impl Default for SoundData {
@@ -101,7 +103,8 @@ impl SoundData {
         ];
         let delay = DelayData{..Default::default()};
         let patch = PatchData{..Default::default()};
-        SoundData{osc, env, filter, lfo, glfo, modul, delay, patch}
+        let sub = SubOscData{..Default::default()};
+        SoundData{osc, env, filter, lfo, glfo, modul, delay, patch, sub}
     }
 
     pub fn init(&mut self) {
@@ -124,6 +127,7 @@ impl SoundData {
         self.osc[2].level = 0.0;
         self.delay.init();
         self.patch.init();
+        self.sub.init();
     }
 
     pub fn get_osc_data<'a>(&'a self, id: usize) -> &'a OscData {
@@ -152,6 +156,45 @@ impl SoundData {
                     Parameter::WaveIndex => { osc.wt_osc_data.wave_index = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
                     Parameter::Voices =>    { osc.wt_osc_data.set_voice_num(if let ParameterValue::Int(x) = msg.value { x } else { panic!() }); }
                     Parameter::Spread =>    { osc.wt_osc_data.set_voice_spread(if let ParameterValue::Float(x) = msg.value { x } else { panic!() }); }
+                    Parameter::Width =>     { osc.wt_osc_data.width = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::RandomPhase => { osc.wt_osc_data.random_phase = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Blend =>     { osc.wt_osc_data.blend = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Warp =>      { osc.wt_osc_data.warp_mode = if let ParameterValue::Choice(x) = msg.value { WarpMode::from_int(x) } else { panic!() }; }
+                    Parameter::WarpAmount => { osc.wt_osc_data.warp_amount = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Interpolation => { osc.wt_osc_data.interpolation = if let ParameterValue::Choice(x) = msg.value { Interpolation::from_int(x) } else { panic!() }; }
+                    Parameter::OctaveFade => { osc.wt_osc_data.octave_fade = if let ParameterValue::Int(x) = msg.value { x } else { panic!() }; }
+                    // FM/ PM
+                    Parameter::ModSource => { osc.mod_source = if let ParameterValue::Int(x) = msg.value { x as usize - 1 } else { panic!() }; }
+                    Parameter::ModIndex =>  { osc.mod_index = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::SyncSource => { osc.sync_source = if let ParameterValue::Int(x) = msg.value { x as usize - 1 } else { panic!() }; }
+                    Parameter::CrossMod =>  { osc.cross_mod = if let ParameterValue::Choice(x) = msg.value { CrossMod::from_int(x) } else { panic!() }; }
+                    Parameter::CrossModSource => { osc.cross_mod_source = if let ParameterValue::Int(x) = msg.value { x as usize - 1 } else { panic!() }; }
+                    // Sample
+                    Parameter::Sample =>    { osc.sample_data.sample = if let ParameterValue::Dynamic(_, x) = msg.value { x } else { panic!() }; }
+                    Parameter::RootKey =>   { osc.sample_data.root_key = if let ParameterValue::Int(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Start =>     { osc.sample_data.start = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::End =>       { osc.sample_data.end = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::LoopStart => { osc.sample_data.loop_start = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::LoopEnd =>   { osc.sample_data.loop_end = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::LoopMode =>  { osc.sample_data.loop_mode = if let ParameterValue::Choice(x) = msg.value { LoopMode::from_int(x) } else { panic!() }; }
+                    Parameter::Crossfade => { osc.sample_data.crossfade = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    // Granular
+                    Parameter::Position =>  { osc.granular_data.position = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::GrainSize => { osc.granular_data.grain_size = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Density =>   { osc.granular_data.density = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Jitter =>    { osc.granular_data.jitter = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::GrainPan =>  { osc.granular_data.pan = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    // Pluck
+                    Parameter::Excitation => { osc.pluck_data.excitation = if let ParameterValue::Choice(x) = msg.value { Excitation::from_int(x) } else { panic!() }; }
+                    Parameter::Damping =>   { osc.pluck_data.damping = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Decay =>     { osc.pluck_data.decay = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    // Analog
+                    Parameter::Waveform =>  { osc.va_data.waveform = if let ParameterValue::Choice(x) = msg.value { VaWaveform::from_int(x) } else { panic!() }; }
+                    Parameter::PulseWidth => { osc.va_data.pulse_width = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    // Noise
+                    Parameter::NoiseColor => { osc.noise_data.color = if let ParameterValue::Choice(x) = msg.value { NoiseColor::from_int(x) } else { panic!() }; }
+                    Parameter::NoiseTone => { osc.noise_data.tone = if let ParameterValue::Float(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Seed =>      { osc.noise_data.seed = if let ParameterValue::Int(x) = msg.value { x } else { panic!() }; }
                     _ => {}
                 }
             }
@@ -223,6 +266,15 @@ impl SoundData {
                     _ => {}
                 }
             }
+            Parameter::SubOscillator => {
+                match msg.parameter {
+                    Parameter::Level =>    { self.sub.level = if let ParameterValue::Float(x) = msg.value { x } else { panic!() } / 100.0; }
+                    Parameter::Octave =>   { self.sub.octave = if let ParameterValue::Int(x) = msg.value { x } else { panic!() }; }
+                    Parameter::Waveform => { self.sub.waveform = if let ParameterValue::Choice(x) = msg.value { SubWaveform::from_int(x) } else { panic!() }; }
+                    Parameter::Routing =>  { self.sub.routing = if let ParameterValue::Choice(x) = msg.value { OscRouting::from_int(x) } else { panic!() }; }
+                    _ => {}
+                }
+            }
             Parameter::Patch => {
                 match msg.parameter {
                     Parameter::Level => { self.patch.level = if let ParameterValue::Float(x) = msg.value { x } else { panic!() } / 100.0; }
@@ -263,6 +315,45 @@ impl SoundData {
                     Parameter::WaveIndex => ParameterValue::Float(osc.wt_osc_data.wave_index),
                     Parameter::Voices => ParameterValue::Int(osc.wt_osc_data.num_voices),
                     Parameter::Spread => ParameterValue::Float(osc.wt_osc_data.voice_spread),
+                    Parameter::Width => ParameterValue::Float(osc.wt_osc_data.width),
+                    Parameter::RandomPhase => ParameterValue::Float(osc.wt_osc_data.random_phase),
+                    Parameter::Blend => ParameterValue::Float(osc.wt_osc_data.blend),
+                    Parameter::Warp => ParameterValue::Choice(osc.wt_osc_data.warp_mode.to_int()),
+                    Parameter::WarpAmount => ParameterValue::Float(osc.wt_osc_data.warp_amount),
+                    Parameter::Interpolation => ParameterValue::Choice(osc.wt_osc_data.interpolation.to_int()),
+                    Parameter::OctaveFade => ParameterValue::Int(osc.wt_osc_data.octave_fade),
+                    // FM/ PM
+                    Parameter::ModSource => ParameterValue::Int(osc.mod_source as i64 + 1),
+                    Parameter::ModIndex => ParameterValue::Float(osc.mod_index),
+                    Parameter::SyncSource => ParameterValue::Int(osc.sync_source as i64 + 1),
+                    Parameter::CrossMod => ParameterValue::Choice(osc.cross_mod.to_int()),
+                    Parameter::CrossModSource => ParameterValue::Int(osc.cross_mod_source as i64 + 1),
+                    // Sample
+                    Parameter::Sample => ParameterValue::Dynamic(Parameter::Sample, osc.sample_data.sample),
+                    Parameter::RootKey => ParameterValue::Int(osc.sample_data.root_key),
+                    Parameter::Start => ParameterValue::Float(osc.sample_data.start),
+                    Parameter::End => ParameterValue::Float(osc.sample_data.end),
+                    Parameter::LoopStart => ParameterValue::Float(osc.sample_data.loop_start),
+                    Parameter::LoopEnd => ParameterValue::Float(osc.sample_data.loop_end),
+                    Parameter::LoopMode => ParameterValue::Choice(osc.sample_data.loop_mode.to_int()),
+                    Parameter::Crossfade => ParameterValue::Float(osc.sample_data.crossfade),
+                    // Granular
+                    Parameter::Position => ParameterValue::Float(osc.granular_data.position),
+                    Parameter::GrainSize => ParameterValue::Float(osc.granular_data.grain_size),
+                    Parameter::Density => ParameterValue::Float(osc.granular_data.density),
+                    Parameter::Jitter => ParameterValue::Float(osc.granular_data.jitter),
+                    Parameter::GrainPan => ParameterValue::Float(osc.granular_data.pan),
+                    // Pluck
+                    Parameter::Excitation => ParameterValue::Choice(osc.pluck_data.excitation.to_int()),
+                    Parameter::Damping => ParameterValue::Float(osc.pluck_data.damping),
+                    Parameter::Decay => ParameterValue::Float(osc.pluck_data.decay),
+                    // Analog
+                    Parameter::Waveform => ParameterValue::Choice(osc.va_data.waveform.to_int()),
+                    Parameter::PulseWidth => ParameterValue::Float(osc.va_data.pulse_width),
+                    // Noise
+                    Parameter::NoiseColor => ParameterValue::Choice(osc.noise_data.color.to_int()),
+                    Parameter::NoiseTone => ParameterValue::Float(osc.noise_data.tone),
+                    Parameter::Seed => ParameterValue::Int(osc.noise_data.seed),
                     _ => {panic!("Got ParamId {:?}", param);}
                 }
             }
@@ -337,6 +428,15 @@ impl SoundData {
                     _ => {panic!();}
                 }
             }
+            Parameter::SubOscillator => {
+                match param.parameter {
+                    Parameter::Level => ParameterValue::Float(self.sub.level * 100.0),
+                    Parameter::Octave => ParameterValue::Int(self.sub.octave),
+                    Parameter::Waveform => ParameterValue::Choice(self.sub.waveform.to_int()),
+                    Parameter::Routing => ParameterValue::Choice(self.sub.routing.to_int()),
+                    _ => {panic!();}
+                }
+            }
             Parameter::Patch => {
                 match param.parameter {
                     Parameter::Level => ParameterValue::Float(self.patch.level * 100.0),
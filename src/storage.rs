@@ -1,7 +1,9 @@
 use super::SoundData;
-use super::WtInfo;
+use super::{WtInfo, WtGenInfo};
+use super::{EmbeddedTable, WtManager, WAVETABLE_DIR};
+use super::SampleInfo;
 
-use log::info;
+use log::{info, error};
 use serde::{Serialize, Deserialize};
 
 use std::fs::File;
@@ -39,7 +41,15 @@ impl Default for SoundPatch {
 pub struct SoundBank {
     info: SoundBankInfo,     // Binary and sound version
     sounds: Vec<SoundPatch>, // List of sound patches
-    pub wt_list: Vec<WtInfo> // List of available wavetables
+    pub wt_list: Vec<WtInfo>, // List of available wavetables
+    #[serde(default)]
+    pub sample_list: Vec<SampleInfo>, // List of available samples
+    #[serde(default)]
+    pub gen_list: Vec<WtGenInfo>, // Definitions of generated wavetables
+    #[serde(default)]
+    pub embed_wavetables: bool, // Store the data of used wavetables in the bank file
+    #[serde(default)]
+    pub wt_data: Vec<EmbeddedTable>, // Data of embedded wavetables
 }
 
 impl SoundBank {
@@ -48,7 +58,10 @@ impl SoundBank {
                                  synth_engine_version: synth_engine_version.to_string()};
         let sounds = vec!(SoundPatch{..Default::default()}; 128);
         let wt_list: Vec<WtInfo> = Vec::new();
-        SoundBank{info, sounds, wt_list}
+        let sample_list: Vec<SampleInfo> = Vec::new();
+        let gen_list: Vec<WtGenInfo> = Vec::new();
+        let wt_data: Vec<EmbeddedTable> = Vec::new();
+        SoundBank{info, sounds, wt_list, sample_list, gen_list, embed_wavetables: false, wt_data}
     }
 
     pub fn load_bank(&mut self, filename: &str) -> std::io::Result<()> {
@@ -68,13 +81,88 @@ impl SoundBank {
         Ok(())
     }
 
+    /// Save the bank to a file.
+    ///
+    /// If embedding of wavetables is enabled, the wavetables used by the
+    /// sounds are stored in the file as well.
     pub fn save_bank(&self, filename: &str) -> std::io::Result<()> {
+        if self.embed_wavetables {
+            return self.export_bank(filename);
+        }
+        if !self.wt_data.is_empty() {
+            // Drop data embedded before
+            let mut bank = self.clone();
+            bank.wt_data.clear();
+            return bank.write_bank(filename);
+        }
+        self.write_bank(filename)
+    }
+
+    /// Save the bank with the wavetables used by the sounds embedded, so that
+    /// the bank can be used without the wavetable files.
+    ///
+    /// Embedding stays enabled for the saved bank.
+    pub fn export_bank(&self, filename: &str) -> std::io::Result<()> {
+        let mut bank = self.clone();
+        bank.embed_wavetables = true;
+        bank.embed_used_wavetables();
+        bank.write_bank(filename)
+    }
+
+    fn write_bank(&self, filename: &str) -> std::io::Result<()> {
         let mut file = File::create(filename)?;
         let serialized = serde_json::to_string_pretty(&self).unwrap();
         file.write_all(serialized.as_bytes())?;
         Ok(())
     }
 
+    // Store the data of all wavetables from files that are used by a sound.
+    //
+    // The data is read from the wavetable files. If a file is missing, data
+    // embedded before is kept. Data of wavetables that are no longer used is
+    // removed.
+    fn embed_used_wavetables(&mut self) {
+        let used: Vec<usize> = self.wt_list.iter()
+            .map(|wt_info| wt_info.id)
+            .filter(|id| self.sounds.iter().any(|s| s.data.osc.iter().any(|o| o.wt_osc_data.wavetable == *id)))
+            .collect();
+        self.wt_data.retain(|t| used.contains(&t.id));
+        for wt_info in self.wt_list.iter() {
+            if !used.contains(&wt_info.id) {
+                continue;
+            }
+            let filename = format!("{}/{}", WAVETABLE_DIR, wt_info.filename);
+            let table = match WtManager::read_file(&filename) {
+                Ok(table) => table,
+                Err(e) => {
+                    error!("Unable to embed wavetable {}: {}", wt_info.name, e);
+                    continue;
+                }
+            };
+            let embedded = EmbeddedTable::new(wt_info.id, &table);
+            match self.wt_data.iter_mut().find(|t| t.id == wt_info.id) {
+                Some(t) => *t = embedded,
+                None => self.wt_data.push(embedded),
+            }
+            info!("Embedded wavetable {}", wt_info.name);
+        }
+    }
+
+    /// Returns the embedded data of the wavetable with the given ID.
+    pub fn get_embedded_table(&self, id: usize) -> Option<&EmbeddedTable> {
+        self.wt_data.iter().find(|t| t.id == id)
+    }
+
+    /// Returns the ID for the next wavetable added to the bank.
+    ///
+    /// Wavetables from files and generated wavetables share the ID range.
+    /// IDs 0 and 1 are used by the built-in tables.
+    pub fn next_wavetable_id(&self) -> usize {
+        let wt_ids = self.wt_list.iter().map(|wt| wt.id);
+        let gen_ids = self.gen_list.iter().map(|wt| wt.id);
+        wt_ids.chain(gen_ids).max().map_or(2, |id| id + 1)
+    }
+
     pub fn get_sound(&self, sound_index: usize) -> &SoundPatch {
         &self.sounds[sound_index]
     }
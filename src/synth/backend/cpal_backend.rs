@@ -0,0 +1,386 @@
+extern crate cpal;
+
+use super::{AudioBackend, AudioConfig, RenderCallback};
+
+use cpal::Sample;
+use cpal::traits::{DeviceTrait, EventLoopTrait, HostTrait};
+use log::{error, info};
+
+/// Maps the stereo output of the synth to the channels of the device.
+///
+/// Mono devices get a downmix of both channels. On devices with more than two
+/// channels, left and right are sent to one or more channel pairs, all other
+/// channels stay silent.
+pub struct ChannelMap {
+    num_channels: usize,
+    pairs: Vec<usize>, // First channel of every stereo pair, starting at 0
+}
+
+impl ChannelMap {
+    pub fn new(num_channels: usize, pairs: &[usize]) -> Result<ChannelMap, ()> {
+        let pairs = if num_channels > 2 && pairs.len() > 0 { pairs.to_vec() } else { vec!(0) };
+        for p in pairs.iter() {
+            if num_channels > 2 && p + 1 >= num_channels {
+                error!("Channel pair {}/{} not available on device with {} channels", p + 1, p + 2, num_channels);
+                println!("Channel pair {}/{} not available on device with {} channels", p + 1, p + 2, num_channels);
+                return Err(());
+            }
+        }
+        Ok(ChannelMap{num_channels, pairs})
+    }
+
+    /// Number of channels of the device.
+    pub fn get_num_channels(&self) -> usize {
+        self.num_channels
+    }
+
+    /// Number of frames in a device buffer of the given length.
+    pub fn get_num_frames(&self, buffer_len: usize) -> usize {
+        buffer_len / self.num_channels
+    }
+
+    /// Write interleaved stereo samples to the device buffer.
+    pub fn write<T: Sample>(&self, stereo: &[f32], output: &mut [T]) {
+        let frames = output.chunks_mut(self.num_channels).zip(stereo.chunks(2));
+        match self.num_channels {
+            1 => {
+                for (out, s) in frames {
+                    out[0] = T::from(&((s[0] + s[1]) * 0.5));
+                }
+            }
+            2 => {
+                for (out, s) in frames {
+                    out[0] = T::from(&s[0]);
+                    out[1] = T::from(&s[1]);
+                }
+            }
+            _ => {
+                let silence = T::from(&0.0f32);
+                for (out, s) in frames {
+                    for value in out.iter_mut() {
+                        *value = silence;
+                    }
+                    for p in self.pairs.iter() {
+                        out[*p] = T::from(&s[0]);
+                        out[*p + 1] = T::from(&s[1]);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Render stereo samples and write them to the device buffer.
+//
+// The stereo buffer is allocated before the stream starts. Device buffers
+// with more frames than it holds are filled in several parts.
+fn fill_buffer<T: Sample>(output: &mut [T], stereo: &mut [f32], render: &mut RenderCallback, channel_map: &ChannelMap) {
+    let max_frames = stereo.len() / 2;
+    for part in output.chunks_mut(max_frames * channel_map.get_num_channels()) {
+        let num_frames = channel_map.get_num_frames(part.len());
+        let stereo = &mut stereo[..num_frames * 2];
+        render(stereo);
+        channel_map.write(stereo, part);
+    }
+}
+
+/// Plays the synth output on an audio device.
+pub struct CpalBackend {
+    host: cpal::Host,
+    device: cpal::Device,
+    format: cpal::Format,
+    channel_map: Option<ChannelMap>,
+    block_size: usize, // Engine block size in frames
+}
+
+impl CpalBackend {
+    pub fn new(config: &AudioConfig, block_size: usize) -> Result<CpalBackend, ()> {
+        let host = cpal::default_host();
+        println!("\r  Chose host {:?}", host.id());
+        let device = match &config.device {
+            Some(name) => CpalBackend::find_device(&host, name),
+            None => host.default_output_device(),
+        };
+        let device = match device {
+            Some(d) => d,
+            None => {
+                error!("Failed to find audio output device {:?}", config.device);
+                println!("Failed to find audio output device {}",
+                         config.device.as_ref().map(|d| d.as_str()).unwrap_or("(default)"));
+                return Err(());
+            }
+        };
+        println!("\r  Chose device {:?}", device.name());
+        let result = device.default_output_format();
+        let default_format = match result {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Failed to query audio output format: {:?}", e);
+                println!("Failed to query audio output format: {:?}", e);
+                return Err(());
+            }
+        };
+        let supported: Vec<cpal::SupportedFormat> = match device.supported_output_formats() {
+            Ok(f) => f.collect(),
+            Err(e) => {
+                error!("Failed to query supported audio output formats: {:?}", e);
+                println!("Failed to query supported audio output formats: {:?}", e);
+                return Err(());
+            }
+        };
+        let format = match CpalBackend::select_format(&default_format, &supported, config.sample_rate) {
+            Some(f) => f,
+            None => {
+                let sample_rate = config.sample_rate.unwrap_or(0);
+                error!("Sample rate {} not supported by device, supported formats: {:?}", sample_rate, supported);
+                println!("Sample rate {} is not supported by the device. Supported formats:", sample_rate);
+                CpalBackend::print_formats(&supported);
+                return Err(());
+            }
+        };
+        info!("Using output format {:?}", format);
+        println!("\r  Output format: {} channels, {:?}", format.channels, format.data_type);
+        if config.buffer_size.is_some() {
+            // cpal doesn't allow selecting the buffer size of the device, so
+            // the buffer size only changes the block size of the engine.
+            println!("\r  Note: The device buffer size can't be changed, only the engine block size is set");
+        }
+        let channel_map = ChannelMap::new(format.channels as usize, &config.channel_pairs)?;
+        Ok(CpalBackend{host, device, format, channel_map: Some(channel_map), block_size})
+    }
+
+    /// Print all available output devices with their formats.
+    ///
+    /// The device numbers can be used to select a device.
+    pub fn list_devices() {
+        let host = cpal::default_host();
+        println!("Host: {:?}", host.id());
+        let default_name = host.default_output_device().and_then(|d| d.name().ok());
+        let devices = match host.output_devices() {
+            Ok(d) => d,
+            Err(e) => {
+                println!("Failed to query audio devices: {:?}", e);
+                return;
+            }
+        };
+        for (index, device) in devices.enumerate() {
+            let name = device.name().unwrap_or("<unknown>".to_string());
+            let is_default = default_name.as_ref() == Some(&name);
+            println!("{}. \"{}\"{}", index + 1, name, if is_default { " (default)" } else { "" });
+            if let Ok(fmt) = device.default_output_format() {
+                println!("    Default format: {} channels, {} Hz, {:?}", fmt.channels, fmt.sample_rate.0, fmt.data_type);
+            }
+            if let Ok(formats) = device.supported_output_formats() {
+                let formats: Vec<cpal::SupportedFormat> = formats.collect();
+                CpalBackend::print_formats(&formats);
+            }
+        }
+    }
+
+    fn print_formats(formats: &[cpal::SupportedFormat]) {
+        for f in formats {
+            println!("    Supported: {} channels, {} - {} Hz, {:?}",
+                     f.channels, f.min_sample_rate.0, f.max_sample_rate.0, f.data_type);
+        }
+    }
+
+    // Find an output device by its number in the device list (starting at 1)
+    // or by its name. If no name matches exactly, the first device that
+    // contains the name is used.
+    fn find_device(host: &cpal::Host, name: &str) -> Option<cpal::Device> {
+        let devices: Vec<cpal::Device> = host.output_devices().ok()?.collect();
+        if let Ok(index) = name.parse::<usize>() {
+            if index > 0 && index <= devices.len() {
+                return devices.into_iter().nth(index - 1);
+            }
+            return None;
+        }
+        let names: Vec<String> = devices.iter().map(|d| d.name().unwrap_or_default()).collect();
+        let position = names.iter().position(|n| n == name)
+                            .or_else(|| names.iter().position(|n| n.to_lowercase().contains(&name.to_lowercase())));
+        devices.into_iter().nth(position?)
+    }
+
+    /// Select the output format for the requested sample rate.
+    ///
+    /// Without a requested sample rate, the default format is used. Otherwise
+    /// a supported format with this sample rate is chosen, preferring the
+    /// channel count and sample type of the default format.
+    pub fn select_format(default: &cpal::Format,
+                         supported: &[cpal::SupportedFormat],
+                         sample_rate: Option<u32>) -> Option<cpal::Format> {
+        let sample_rate = match sample_rate {
+            Some(sr) => sr,
+            None => return Some(default.clone()),
+        };
+        let candidates: Vec<&cpal::SupportedFormat> = supported.iter()
+            .filter(|f| f.min_sample_rate.0 <= sample_rate && f.max_sample_rate.0 >= sample_rate)
+            .collect();
+        let selected = candidates.iter().find(|f| f.channels == default.channels && f.data_type == default.data_type)
+            .or_else(|| candidates.iter().find(|f| f.data_type == default.data_type))
+            .or_else(|| candidates.first())?;
+        Some(cpal::Format{
+            channels: selected.channels,
+            sample_rate: cpal::SampleRate(sample_rate),
+            data_type: selected.data_type,
+        })
+    }
+}
+
+impl AudioBackend for CpalBackend {
+    fn get_sample_rate(&self) -> u32 {
+        self.format.sample_rate.0
+    }
+
+    fn run(&mut self, mut render: RenderCallback) -> Result<(), ()> {
+        let channel_map = match self.channel_map.take() {
+            Some(m) => m,
+            None => {
+                error!("Audio backend has already been started");
+                return Err(());
+            }
+        };
+        let event_loop = self.host.event_loop();
+        let stream_id = match event_loop.build_output_stream(&self.device, &self.format) {
+            Ok(id) => id,
+            Err(e) => {
+                error!("Failed to open audio output stream: {:?}", e);
+                println!("Failed to open audio output stream: {:?}", e);
+                return Err(());
+            }
+        };
+        event_loop.play_stream(stream_id.clone()).unwrap();
+
+        // Room for one engine block, the audio thread must not allocate
+        let mut stereo = vec!(0.0f32; self.block_size * 2);
+        let _handle = std::thread::spawn(move || {
+            event_loop.run(move |id, result| {
+                let data = match result {
+                    Ok(data) => data,
+                    Err(err) => {
+                        eprintln!("an error occurred on stream {:?}: {}", id, err);
+                        return;
+                    }
+                };
+                match data {
+                    cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer) } => {
+                        fill_buffer(&mut buffer, &mut stereo, &mut render, &channel_map);
+                    },
+                    cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::I16(mut buffer) } => {
+                        fill_buffer(&mut buffer, &mut stereo, &mut render, &channel_map);
+                    },
+                    cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::U16(mut buffer) } => {
+                        fill_buffer(&mut buffer, &mut stereo, &mut render, &channel_map);
+                    },
+                    _ => (),
+                }
+            });
+        });
+        Ok(())
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{ChannelMap, CpalBackend, fill_buffer};
+use super::super::RenderCallback;
+
+use cpal::{Format, SampleFormat, SampleRate, SupportedFormat};
+
+const STEREO: [f32; 4] = [0.5, -0.5, 1.0, 0.0];
+
+#[test]
+fn mono_output_is_downmixed() {
+    let map = ChannelMap::new(1, &[]).unwrap();
+    let mut output = [1.0f32; 2];
+    map.write(&STEREO, &mut output);
+    assert_eq!(output, [0.0, 0.5]);
+}
+
+#[test]
+fn stereo_output_is_copied() {
+    let map = ChannelMap::new(2, &[2]).unwrap();
+    let mut output = [0.0f32; 4];
+    map.write(&STEREO, &mut output);
+    assert_eq!(output, STEREO);
+}
+
+#[test]
+fn multichannel_output_is_routed_to_pairs() {
+    let map = ChannelMap::new(6, &[2, 4]).unwrap();
+    let mut output = [1.0f32; 12];
+    map.write(&STEREO, &mut output);
+    assert_eq!(output, [0.0, 0.0, 0.5, -0.5, 0.5, -0.5,
+                        0.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+}
+
+#[test]
+fn invalid_channel_pair_is_rejected() {
+    assert!(ChannelMap::new(4, &[3]).is_err());
+    assert!(ChannelMap::new(4, &[2]).is_ok());
+}
+
+#[test]
+fn large_device_buffer_is_filled_in_parts() {
+    let map = ChannelMap::new(1, &[]).unwrap();
+    let mut stereo = [0.0f32; 8]; // Four frames
+    let mut count = 0.0;
+    let mut render: RenderCallback = Box::new(move |buffer: &mut [f32]| {
+        count += 1.0;
+        buffer.iter_mut().for_each(|s| *s = count);
+    });
+    let mut output = [0.0f32; 10];
+    fill_buffer(&mut output, &mut stereo, &mut render, &map);
+    assert_eq!(output, [1.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0, 2.0, 3.0, 3.0]);
+}
+
+#[test]
+fn integer_formats_are_converted() {
+    let map = ChannelMap::new(2, &[]).unwrap();
+    let mut output_i16 = [0i16; 4];
+    map.write(&STEREO, &mut output_i16);
+    assert_eq!(output_i16, [16383, -16384, 32767, 0]);
+    let mut output_u16 = [0u16; 4];
+    map.write(&STEREO, &mut output_u16);
+    assert_eq!(output_u16, [49151, 16384, 65535, 32768]);
+}
+
+fn supported_format(channels: u16, min: u32, max: u32, data_type: SampleFormat) -> SupportedFormat {
+    SupportedFormat{channels, min_sample_rate: SampleRate(min), max_sample_rate: SampleRate(max), data_type}
+}
+
+fn default_format() -> Format {
+    Format{channels: 2, sample_rate: SampleRate(44100), data_type: SampleFormat::I16}
+}
+
+#[test]
+fn default_format_is_used_without_sample_rate() {
+    let format = CpalBackend::select_format(&default_format(), &[], None).unwrap();
+    assert_eq!(format, default_format());
+}
+
+#[test]
+fn format_with_requested_sample_rate_is_selected() {
+    let supported = [
+        supported_format(2, 44100, 48000, SampleFormat::F32),
+        supported_format(1, 44100, 96000, SampleFormat::I16),
+        supported_format(2, 44100, 96000, SampleFormat::I16),
+    ];
+    let format = CpalBackend::select_format(&default_format(), &supported, Some(96000)).unwrap();
+    assert_eq!(format.channels, 2);
+    assert_eq!(format.sample_rate, SampleRate(96000));
+    assert_eq!(format.data_type, SampleFormat::I16);
+}
+
+#[test]
+fn unsupported_sample_rate_is_rejected() {
+    let supported = [supported_format(2, 44100, 48000, SampleFormat::F32)];
+    assert!(CpalBackend::select_format(&default_format(), &supported, Some(96000)).is_none());
+}
+
+} // mod tests
@@ -0,0 +1,64 @@
+//! Audio output backends.
+//!
+//! The engine's render loop only talks to the AudioBackend trait, so the
+//! synth can run with a soundcard (cpal), without any audio output (null),
+//! writing to a WAV file (file) or writing raw samples to stdout (stdout).
+
+pub mod cpal_backend;
+pub mod paced_backend;
+
+pub use cpal_backend::CpalBackend;
+pub use paced_backend::{PacedBackend, NullSink, StdoutSink};
+
+/// Called by the backend to fill a buffer with interleaved stereo samples.
+pub type RenderCallback = Box<dyn FnMut(&mut [f32]) + Send>;
+
+pub trait AudioBackend {
+    /// Sample rate the backend expects the synth to render at.
+    fn get_sample_rate(&self) -> u32;
+
+    /// Start the audio output.
+    ///
+    /// The render callback is called from the backend's audio thread every
+    /// time a new block of samples is needed. This function returns after
+    /// the output has been started.
+    fn run(&mut self, render: RenderCallback) -> Result<(), ()>;
+
+    /// Stop the audio output and close the output target.
+    fn stop(&mut self) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendType {
+    Cpal,   // Soundcard output
+    Null,   // No output, only runs the render loop
+    File,   // Writes output to a WAV file
+    Stdout, // Writes raw 32 bit float stereo samples to stdout
+}
+
+impl BackendType {
+    pub fn from_name(name: &str) -> Option<BackendType> {
+        match name {
+            "cpal" => Some(BackendType::Cpal),
+            "null" => Some(BackendType::Null),
+            "file" => Some(BackendType::File),
+            "stdout" => Some(BackendType::Stdout),
+            _ => None,
+        }
+    }
+}
+
+impl Default for BackendType {
+    fn default() -> Self { BackendType::Cpal }
+}
+
+/// Audio output settings selected on the command line.
+#[derive(Clone, Debug, Default)]
+pub struct AudioConfig {
+    pub backend: BackendType,
+    pub output: String,            // Output file of the file backend
+    pub channel_pairs: Vec<usize>, // First channel of the stereo pairs, starting at 0
+    pub device: Option<String>,    // Name or index (starting at 1) of the output device
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<usize>, // Engine block size in frames
+}
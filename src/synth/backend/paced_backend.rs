@@ -0,0 +1,168 @@
+//! Backends that don't have an audio device setting the pace.
+//!
+//! The PacedBackend runs its own audio thread, which requests blocks from
+//! the render loop at the same rate a soundcard would and passes them on to
+//! a SampleSink. Running in realtime keeps the timing of MIDI input and the
+//! engine load display meaningful.
+
+extern crate libc;
+
+use super::{AudioBackend, RenderCallback};
+use crate::wav_file::WavWriter;
+
+use log::{error, info};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Receives the rendered samples as interleaved stereo 32 bit floats.
+pub trait SampleSink: Send {
+    fn write(&mut self, samples: &[f32]) -> std::io::Result<()>;
+
+    /// Called once after the last block has been written.
+    fn close(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Discards all samples.
+pub struct NullSink;
+
+impl SampleSink for NullSink {
+    fn write(&mut self, _samples: &[f32]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SampleSink for WavWriter<BufWriter<File>> {
+    fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        self.write_samples(samples)?;
+
+        // Keep the header up to date, so that the file stays valid if the
+        // synth doesn't shut down cleanly.
+        self.update_header_periodically()
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.update_header()
+    }
+}
+
+/// Writes raw little endian samples to stdout.
+///
+/// Stdout must carry only audio data, so on creation the original stdout is
+/// taken over for the samples and everything else printed afterwards (like
+/// the UI) is redirected to the terminal, or to stderr if there is none.
+pub struct StdoutSink {
+    output: BufWriter<File>,
+}
+
+impl StdoutSink {
+    pub fn new() -> std::io::Result<StdoutSink> {
+        std::io::stdout().flush()?;
+        let stdout_fd = std::io::stdout().as_raw_fd();
+        let target = OpenOptions::new().read(true).write(true).open("/dev/tty");
+        let output = unsafe {
+            let pcm_fd = libc::dup(stdout_fd);
+            if pcm_fd < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let result = match &target {
+                Ok(tty) => libc::dup2(tty.as_raw_fd(), stdout_fd),
+                Err(_) => libc::dup2(libc::STDERR_FILENO, stdout_fd),
+            };
+            if result < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            File::from_raw_fd(pcm_fd)
+        };
+        Ok(StdoutSink{output: BufWriter::new(output)})
+    }
+}
+
+impl SampleSink for StdoutSink {
+    fn write(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        for sample in samples {
+            self.output.write_all(&sample.to_le_bytes())?;
+        }
+        self.output.flush()
+    }
+}
+
+pub struct PacedBackend {
+    sample_rate: u32,
+    block_size: usize, // Number of stereo frames rendered per block
+    sink: Option<Box<dyn SampleSink>>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl PacedBackend {
+    pub fn new(sample_rate: u32, block_size: usize, sink: Box<dyn SampleSink>) -> PacedBackend {
+        PacedBackend{
+            sample_rate,
+            block_size,
+            sink: Some(sink),
+            running: Arc::new(AtomicBool::new(false)),
+            handle: None
+        }
+    }
+}
+
+impl AudioBackend for PacedBackend {
+    fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn run(&mut self, mut render: RenderCallback) -> Result<(), ()> {
+        let mut sink = match self.sink.take() {
+            Some(s) => s,
+            None => {
+                error!("Audio backend has already been started");
+                return Err(());
+            }
+        };
+        let running = self.running.clone();
+        let sample_rate = self.sample_rate as u64;
+        let block_size = self.block_size;
+        running.store(true, Ordering::Relaxed);
+
+        self.handle = Some(std::thread::spawn(move || {
+            let mut buffer = vec!(0.0f32; block_size * 2);
+            let start = Instant::now();
+            let mut num_frames = 0u64;
+            while running.load(Ordering::Relaxed) {
+                render(&mut buffer);
+                if let Err(e) = sink.write(&buffer) {
+                    error!("Failed to write audio output: {}", e);
+                    break;
+                }
+                num_frames += block_size as u64;
+
+                // Wait until the block would have been played
+                let due = Duration::from_micros(num_frames * 1000000 / sample_rate);
+                let elapsed = start.elapsed();
+                if due > elapsed {
+                    std::thread::sleep(due - elapsed);
+                }
+            }
+            if let Err(e) = sink.close() {
+                error!("Failed to close audio output: {}", e);
+            }
+            info!("Audio output stopped after {} frames", num_frames);
+        }));
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,436 @@
+//! Message handling for the synth engine.
+//!
+//! The synth itself is owned by the audio thread, which must never block.
+//! The SynthControl runs in its own thread, receives the messages from the UI
+//! and the MIDI handler and does all the work that would be too slow for the
+//! audio thread, like loading wavetables from disk or filling sample buffers
+//! for display. Everything the synth needs is passed on as an AudioMessage
+//! through a lock-free queue, which the synth empties at the start of every
+//! block. Wavetables and samples replaced in the synth are passed back and
+//! freed in this thread.
+//!
+//! Resampling a sound takes too long to keep MIDI messages waiting, so it
+//! runs in a thread of its own, which posts the result back to this thread.
+
+use super::{AudioMessage, ReleasedData, SynthEvent, RELEASE_QUEUE_SIZE};
+use super::Envelope;
+use super::Lfo;
+use super::Oscillator;
+use super::{Sample, SampleInfo, SampleRef, SAMPLE_DIR};
+use super::{Parameter, SynthParam};
+use super::{Resampler, ResampleSettings};
+use super::SoundData;
+use super::{SynthMessage, UiMessage};
+use super::Float;
+use super::{EmbeddedTable, WtGenInfo, WtManager, WAVETABLE_DIR};
+
+use std::fs;
+use std::thread::spawn;
+use std::time::Instant;
+
+use crossbeam_channel::{bounded, select, unbounded, Sender, Receiver};
+use log::{info, error};
+use wavetable::{WavetableRef, WtInfo};
+
+pub struct SynthControl {
+    sample_rate: u32,
+    sound: SoundData, // Copy of the sound played by the synth
+    wt_manager: WtManager,
+    samples: Vec<Option<SampleRef>>, // Loaded samples by ID, for resampling
+    ui_sender: Sender<UiMessage>,
+    audio_sender: Sender<SynthEvent>,
+    release_sender: Sender<ReleasedData>,   // Passed to the synth
+    release_receiver: Receiver<ReleasedData>,
+    resample_sender: Sender<(WtInfo, std::io::Result<()>)>, // Finished resampling threads
+    resample_receiver: Receiver<(WtInfo, std::io::Result<()>)>,
+
+    // Extra oscillators to display the waveshape
+    samplebuff_osc: Oscillator,
+    samplebuff_env: Envelope,
+    samplebuff_lfo: Lfo,
+    osc_wave: [WavetableRef; 3],
+}
+
+impl SynthControl {
+    pub fn new(sample_rate: u32, ui_sender: Sender<UiMessage>, audio_sender: Sender<SynthEvent>) -> Self {
+        let mut sound = SoundData::new();
+        sound.init();
+        let mut wt_manager = WtManager::new(sample_rate as Float);
+        wt_manager.add_basic_tables(0);
+        wt_manager.add_pwm_tables(1);
+        let default_table = wt_manager.get_table(0).unwrap(); // Table 0
+        let osc_wave = [default_table.clone(), default_table.clone(), default_table.clone()];
+        let (release_sender, release_receiver) = bounded::<ReleasedData>(RELEASE_QUEUE_SIZE);
+        let (resample_sender, resample_receiver) = unbounded::<(WtInfo, std::io::Result<()>)>();
+        SynthControl{
+            sample_rate,
+            sound,
+            wt_manager,
+            samples: Vec::new(),
+            ui_sender,
+            audio_sender,
+            release_sender,
+            release_receiver,
+            resample_sender,
+            resample_receiver,
+            samplebuff_osc: Oscillator::new(sample_rate, default_table.clone()),
+            samplebuff_env: Envelope::new(sample_rate as Float),
+            samplebuff_lfo: Lfo::new(sample_rate),
+            osc_wave,
+        }
+    }
+
+    /// Returns the built-in wavetables, indexed by wavetable ID.
+    ///
+    /// These are used to initialize the synth before any messages have been
+    /// exchanged.
+    pub fn get_wavetables(&self) -> Vec<Option<WavetableRef>> {
+        vec!(self.wt_manager.get_table(0), self.wt_manager.get_table(1))
+    }
+
+    /// Returns the sender the synth uses to pass replaced wavetables and
+    /// samples back for freeing.
+    pub fn get_release_sender(&self) -> Sender<ReleasedData> {
+        self.release_sender.clone()
+    }
+
+    /// Starts a thread for receiving UI and MIDI messages.
+    ///
+    /// The thread also frees the data released by the synth and loads the
+    /// wavetables of finished resampling jobs.
+    pub fn run(mut control: SynthControl, synth_receiver: Receiver<SynthMessage>) -> std::thread::JoinHandle<()> {
+        let release_receiver = control.release_receiver.clone();
+        let resample_receiver = control.resample_receiver.clone();
+        let handler = spawn(move || {
+            let mut keep_running = true;
+            while keep_running {
+                select! {
+                    recv(synth_receiver) -> msg => keep_running = control.handle_message(msg.unwrap()),
+                    recv(release_receiver) -> data => drop(data), // Last reference is freed here
+                    recv(resample_receiver) -> result => {
+                        let (wt_info, result) = result.unwrap();
+                        control.handle_resample_result(wt_info, result);
+                    }
+                }
+            }
+        });
+        handler
+    }
+
+    /// Handle a single message.
+    ///
+    /// Returns false if the message asked the synth to exit.
+    pub fn handle_message(&mut self, msg: SynthMessage) -> bool {
+        match msg {
+            SynthMessage::Param(m) => self.handle_ui_message(m),
+            SynthMessage::Midi(m)  => self.send(AudioMessage::Midi(m)),
+            SynthMessage::Sound(s) => self.handle_sound_update(s),
+            SynthMessage::Wavetable(i) => self.handle_wavetable_info(i),
+            SynthMessage::EmbeddedWavetable(i, t) => self.handle_embedded_wavetable(i, t),
+            SynthMessage::GeneratedWavetable(i) => self.handle_generated_wavetable(i),
+            SynthMessage::Sample(i) => self.handle_sample_info(i),
+            SynthMessage::SampleBuffer(m, p) => self.handle_sample_buffer(m, p),
+            SynthMessage::Resample(s, i) => self.handle_resample(s, i),
+            SynthMessage::Bpm(b) => {
+                self.sound.patch.bpm = b;
+                self.send(AudioMessage::Bpm(b));
+            }
+            SynthMessage::Exit     => {
+                self.exit();
+                return false;
+            }
+        }
+        true
+    }
+
+    fn exit(&mut self) {
+        // Do exit stuff here
+        info!("Stopping synth engine");
+    }
+
+    // Pass a message on to the synth in the audio thread.
+    fn send(&self, message: AudioMessage) {
+        let event = SynthEvent{time: Instant::now(), message};
+        if self.audio_sender.send(event).is_err() {
+            error!("Synth is not receiving messages");
+        }
+    }
+
+    fn handle_ui_message(&mut self, msg: SynthParam) {
+        self.sound.set_parameter(&msg);
+        if let Parameter::Oscillator = msg.function {
+            if let Parameter::Wavetable = msg.parameter {
+                self.update_wavetable(msg.function_id - 1);
+            }
+        }
+        self.send(AudioMessage::Param(msg));
+    }
+
+    fn handle_sound_update(&mut self, sound: SoundData) {
+        self.sound = sound;
+        self.update_wavetable(0);
+        self.update_wavetable(1);
+        self.update_wavetable(2);
+        self.send(AudioMessage::Sound(sound));
+    }
+
+    // Keep track of the wavetables used for the sample buffers.
+    fn update_wavetable(&mut self, osc_id: usize) {
+        let id = self.sound.osc[osc_id].wt_osc_data.wavetable;
+        if let Some(wt) = self.wt_manager.get_table(id) {
+            self.osc_wave[osc_id] = wt;
+        }
+    }
+
+    fn handle_wavetable_info(&mut self, wt_info: WtInfo) {
+        let id = wt_info.id;
+        let name = wt_info.name.clone();
+        if let Err(e) = self.load_wavetable(wt_info) {
+            self.ui_sender.send(UiMessage::WavetableError(id, format!("{}: {}", name, e))).ok();
+        }
+        if let Some(wt) = self.wt_manager.get_table(id) {
+            self.send(AudioMessage::Wavetable(id, wt));
+        }
+    }
+
+    /// Load a wavetable from disk.
+    ///
+    /// Returns the loaded table. If the file can't be used, the default
+    /// table is registered under the ID of the wavetable instead, so that
+    /// sounds using it still play, and the error is returned.
+    pub fn load_wavetable(&mut self, mut wt_info: WtInfo) -> std::io::Result<WavetableRef> {
+        let id = wt_info.id;
+        let fallback = self.wt_manager.get_table(0).unwrap();
+        if let Err(e) = self.wt_manager.load_table(&mut wt_info, fallback) {
+            error!("Unable to load wavetable {}: {}", wt_info.filename, e);
+            return Err(e);
+        }
+        Ok(self.wt_manager.get_table(id).unwrap())
+    }
+
+    fn handle_embedded_wavetable(&mut self, wt_info: WtInfo, embedded: EmbeddedTable) {
+        let id = wt_info.id;
+        let name = wt_info.name.clone();
+        if let Err(e) = self.load_embedded_wavetable(wt_info, &embedded) {
+            self.ui_sender.send(UiMessage::WavetableError(id, format!("{}: {}", name, e))).ok();
+        }
+        if let Some(wt) = self.wt_manager.get_table(id) {
+            self.send(AudioMessage::Wavetable(id, wt));
+        }
+    }
+
+    /// Load a wavetable that is embedded in the sound bank.
+    ///
+    /// If the embedded data is damaged, the wavetable is loaded from its
+    /// file instead.
+    pub fn load_embedded_wavetable(&mut self, wt_info: WtInfo, embedded: &EmbeddedTable) -> std::io::Result<WavetableRef> {
+        match self.wt_manager.add_embedded_table(embedded) {
+            Ok(()) => Ok(self.wt_manager.get_table(wt_info.id).unwrap()),
+            Err(e) => {
+                error!("Unable to use embedded wavetable {}: {}", wt_info.name, e);
+                self.load_wavetable(wt_info)
+            }
+        }
+    }
+
+    fn handle_generated_wavetable(&mut self, gen_info: WtGenInfo) {
+        let id = gen_info.id;
+        if let Err(e) = self.generate_wavetable(&gen_info) {
+            self.ui_sender.send(UiMessage::WavetableError(id, format!("{}: {}", gen_info.name, e))).ok();
+        }
+        if let Some(wt) = self.wt_manager.get_table(id) {
+            self.send(AudioMessage::Wavetable(id, wt));
+        }
+    }
+
+    /// Generate a wavetable from its definition.
+    ///
+    /// Like load_wavetable, the default table is registered under the ID of
+    /// the wavetable if the definition can't be used.
+    pub fn generate_wavetable(&mut self, gen_info: &WtGenInfo) -> std::io::Result<WavetableRef> {
+        let id = gen_info.id;
+        if let Err(e) = self.wt_manager.add_generated_table(gen_info) {
+            error!("Unable to generate wavetable {}: {}", gen_info.name, e);
+            let fallback = self.wt_manager.get_table(0).unwrap();
+            self.wt_manager.add_table(id, fallback);
+            return Err(e);
+        }
+        Ok(self.wt_manager.get_table(id).unwrap())
+    }
+
+    fn handle_sample_info(&mut self, sample_info: SampleInfo) {
+        let id = sample_info.id;
+        if let Some(sample) = self.load_sample(&sample_info) {
+            self.send(AudioMessage::Sample(id, sample));
+        }
+    }
+
+    /// Load a sample from disk.
+    ///
+    /// Returns the loaded sample, or None if it isn't available.
+    pub fn load_sample(&mut self, sample_info: &SampleInfo) -> Option<SampleRef> {
+        if !sample_info.valid {
+            return None;
+        }
+        let filename = format!("{}/{}", SAMPLE_DIR, sample_info.filename);
+        match Sample::load(&sample_info.name, &filename) {
+            Ok(sample) => {
+                info!("Loaded sample {} with {} frames", sample_info.name, sample.data.len());
+                let sample = SampleRef::new(sample);
+                if sample_info.id >= self.samples.len() {
+                    self.samples.resize(sample_info.id + 1, None);
+                }
+                self.samples[sample_info.id] = Some(sample.clone());
+                Some(sample)
+            }
+            Err(e) => {
+                error!("Unable to load sample {}: {}", filename, e);
+                None
+            }
+        }
+    }
+
+    // Render the wavetable file in a separate thread, the result is
+    // handled in handle_resample_result.
+    fn handle_resample(&mut self, settings: ResampleSettings, wt_info: WtInfo) {
+        let resampler = self.create_resampler();
+        let sound = self.sound;
+        let result_sender = self.resample_sender.clone();
+        spawn(move || {
+            let result = SynthControl::render_wavetable_file(&resampler, &sound, &settings, &wt_info);
+            result_sender.send((wt_info, result)).ok();
+        });
+    }
+
+    fn handle_resample_result(&mut self, wt_info: WtInfo, result: std::io::Result<()>) {
+        let id = wt_info.id;
+        let name = wt_info.name.clone();
+        match result.and_then(|()| self.load_wavetable(wt_info)) {
+            Ok(wt) => {
+                self.send(AudioMessage::Wavetable(id, wt));
+                self.ui_sender.send(UiMessage::Resampled(id)).ok();
+            }
+            Err(e) => {
+                self.ui_sender.send(UiMessage::WavetableError(id, format!("{}: {}", name, e))).ok();
+            }
+        }
+    }
+
+    /// Render the current sound into a new wavetable.
+    ///
+    /// The table is saved as WAV file under the filename of the wavetable
+    /// list entry and then loaded like any other wavetable file.
+    pub fn resample(&mut self, settings: &ResampleSettings, wt_info: WtInfo) -> std::io::Result<WavetableRef> {
+        let resampler = self.create_resampler();
+        SynthControl::render_wavetable_file(&resampler, &self.sound, settings, &wt_info)?;
+        self.load_wavetable(wt_info)
+    }
+
+    // Create a resampler with the wavetables and samples of the current sound.
+    fn create_resampler(&self) -> Resampler {
+        let samples = [self.get_osc_sample(0), self.get_osc_sample(1), self.get_osc_sample(2)];
+        Resampler::new(self.sample_rate, self.osc_wave.clone(), samples)
+    }
+
+    // Render the sound and save it under the filename of the wavetable.
+    fn render_wavetable_file(resampler: &Resampler,
+                             sound: &SoundData,
+                             settings: &ResampleSettings,
+                             wt_info: &WtInfo) -> std::io::Result<()> {
+        let waves = resampler.render(sound, settings);
+        fs::create_dir_all(WAVETABLE_DIR)?;
+        let filename = format!("{}/{}", WAVETABLE_DIR, wt_info.filename);
+        resampler.save(&filename, &waves)?;
+        info!("Resampled {} waves into {}", waves.len(), filename);
+        Ok(())
+    }
+
+    // Get the sample assigned to an oscillator of the current sound.
+    fn get_osc_sample(&self, osc_id: usize) -> Option<SampleRef> {
+        let id = self.sound.osc[osc_id].sample_data.sample;
+        self.samples.get(id).cloned().flatten()
+    }
+
+    // Fill a received buffer with samples from the model oscillator/ envelope.
+    //
+    // This puts one wave cycle of the currently selected oscillator or
+    // envelope or LFO into the buffer.
+    //
+    fn handle_sample_buffer(&mut self, mut buffer: Vec<Float>, param: SynthParam) {
+        let len = buffer.capacity();
+        let freq = self.sample_rate as Float / len as Float;
+        match param.function {
+            Parameter::Oscillator => {
+                let osc = &mut self.samplebuff_osc;
+                osc.reset(0);
+                let osc_id = param.function_id - 1;
+                osc.set_wavetable(self.osc_wave[osc_id].clone());
+                for i in 0..len {
+                    let (mut sample, _) = osc.get_sample(freq, i as i64, &self.sound.osc[osc_id], None, Some(0.0));
+
+                    // Apply clipping
+                    if self.sound.patch.drive > 0.0 {
+                        sample = (sample * self.sound.patch.drive).tanh();
+                    }
+
+                    buffer[i] = sample * self.sound.osc[param.function_id - 1].level;
+                }
+            },
+            Parameter::Envelope => {
+                let env_data = &mut self.sound.env[param.function_id - 1];
+                let mut len_total = env_data.delay + env_data.attack + env_data.decay + env_data.release;
+                if !env_data.looping {
+                    len_total += len_total / 3.0; // Add 25% duration for sustain, value is in ms
+                }
+                let mut release_point = len_total - env_data.release;
+                let samples_per_ms = self.sample_rate as Float / 1000.0;
+                len_total *= samples_per_ms;
+                release_point *= samples_per_ms;
+                let samples_per_slot = (len_total / len as Float) as usize; // Number of samples per slot in the buffer
+                let mut index: usize = 0;
+                let mut counter: usize = 0;
+                let len_total = len_total as usize;
+                let release_point = release_point as usize;
+                let mut sample = 0.0;
+                let env = &mut self.samplebuff_env;
+                env.trigger(0, env_data);
+                for i in 0..len_total {
+                    if i == release_point {
+                        env.release(i as i64, env_data);
+                    }
+                    sample += env.get_sample(i as i64, env_data);
+                    counter += 1;
+                    if counter == samples_per_slot {
+                        sample /= samples_per_slot as Float;
+                        buffer[index] = sample;
+                        index += 1;
+                        if index == len {
+                            index -= 1;
+                        }
+                        sample = 0.0;
+                        counter = 0;
+                    }
+                }
+            },
+            Parameter::Lfo | Parameter::GlobalLfo => {
+                let lfo = &mut self.samplebuff_lfo;
+                let mut sound_copy = if let Parameter::Lfo = param.function {
+                    self.sound.lfo[param.function_id - 1]
+                } else {
+                    self.sound.glfo[param.function_id - 1]
+                };
+                lfo.reset(0, sound_copy.phase);
+                sound_copy.frequency = freq;
+                // Get first sample explicitly to reset LFO (for S&H)
+                let (sample, _) = lfo.get_sample(0, &sound_copy, true);
+                buffer[0] = sample;
+                for i in 1..len {
+                    let (sample, _) = lfo.get_sample(i as i64, &sound_copy, false);
+                    buffer[i] = sample;
+                }
+            },
+            _ => {},
+        }
+        self.ui_sender.send(UiMessage::SampleBuffer(buffer, param)).unwrap();
+    }
+}
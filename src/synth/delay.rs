@@ -4,7 +4,7 @@ use super::filter::OnePole;
 
 use serde::{Serialize, Deserialize};
 
-const BUFF_LEN: usize = 44100;
+const MAX_DELAY_TIME: Float = 1.0; // Longest delay time in seconds
 
 #[derive(Serialize, Deserialize, Copy, Clone, Default, Debug)]
 pub struct DelayData {
@@ -28,8 +28,9 @@ impl DelayData {
 
 pub struct Delay {
     sample_rate: Float,
-    bb_l: [Float; BUFF_LEN], // Buffer with samples
-    bb_r: [Float; BUFF_LEN], // Second buffer with samples
+    buff_len: usize,       // Length of the buffers, the max. delay time in samples
+    bb_l: Vec<Float>,      // Buffer with samples
+    bb_r: Vec<Float>,      // Second buffer with samples
     position: Float,       // Current read/ write position
     quant_pos: usize,    // Last position, quantized to usize
     filter_l: OnePole,
@@ -41,13 +42,14 @@ impl Delay {
         let mut filter_l = OnePole::new(sample_rate);
         let mut filter_r = OnePole::new(sample_rate);
         let sample_rate = sample_rate as Float;
-        let bb_l = [0.0; BUFF_LEN];
-        let bb_r = [0.0; BUFF_LEN];
+        let buff_len = (sample_rate * MAX_DELAY_TIME) as usize;
+        let bb_l = vec!(0.0; buff_len);
+        let bb_r = vec!(0.0; buff_len);
         let position = 0.1;
         let quant_pos = 0;
         filter_l.update(2000.0); // Initial frequency at 2kHz
         filter_r.update(2000.0); // Initial frequency at 2kHz
-        Delay{sample_rate, bb_l, bb_r, position, quant_pos, filter_l, filter_r}
+        Delay{sample_rate, buff_len, bb_l, bb_r, position, quant_pos, filter_l, filter_r}
     }
 
     pub fn reset(&mut self) {
@@ -64,10 +66,10 @@ impl Delay {
     pub fn process(&mut self, sample_l: Float, sample_r: Float, _sample_clock: i64, data: &DelayData) -> (Float, Float) {
         // TODO: Calculate the passed time using sample_clock
         let step = (self.bb_l.len() as Float / data.time) / self.sample_rate; // The amount of samples we step forward, as float
-        let step = Delay::addf(step, 0.0);
-        self.position = Delay::addf(self.position, step);
-        let new_quant_pos = Delay::add(self.position.round() as usize, 0); // Add 0 to get the wrapping protection
-        let num_samples = Delay::diff(new_quant_pos, self.quant_pos); // Actual number of samples we will be stepping over
+        let step = self.addf(step, 0.0);
+        self.position = self.addf(self.position, step);
+        let new_quant_pos = self.add(self.position.round() as usize, 0); // Add 0 to get the wrapping protection
+        let num_samples = self.diff(new_quant_pos, self.quant_pos); // Actual number of samples we will be stepping over
 
         // Left side
         // ---------
@@ -76,7 +78,7 @@ impl Delay {
         let mut sample_sum_r = 0.0;
         let mut pos = self.quant_pos;
         for _ in 0..num_samples {
-            pos = Delay::add(pos, 1);
+            pos = self.add(pos, 1);
             sample_sum_l += self.bb_l[pos];
             sample_sum_r += self.bb_r[pos];
         }
@@ -94,7 +96,7 @@ impl Delay {
             // (steps through all positions that we jumped over when averaging)
             pos = self.quant_pos;
             for _ in 0..num_samples as usize {
-                pos = Delay::add(pos, 1);
+                pos = self.add(pos, 1);
                 filtered_value_l = self.filter_l.process(sample_l + self.bb_l[pos] * data.feedback);
                 filtered_value_r = self.filter_r.process(           self.bb_r[pos] * data.feedback);
                 self.bb_l[pos] = filtered_value_r;
@@ -105,7 +107,7 @@ impl Delay {
             // Mix delay signal to input and update memory
             // (steps through all positions that we jumped over when averaging)
             for _ in 0..num_samples as usize {
-                pos = Delay::add(pos, 1);
+                pos = self.add(pos, 1);
                 filtered_value_l = self.filter_l.process(sample_l + self.bb_l[pos] * data.feedback);
                 self.bb_l[pos] = filtered_value_l;
                 filtered_value_r = self.filter_r.process(sample_r + self.bb_r[pos] * data.feedback);
@@ -119,6 +121,15 @@ impl Delay {
         (mixed_sample_l, mixed_sample_r)
     }
 
+    /// Process a block of stereo samples in place.
+    pub fn process_block(&mut self, left: &mut [Float], right: &mut [Float], sample_clock: i64, data: &DelayData) {
+        for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+            let (out_l, out_r) = self.process(*l, *r, sample_clock + i as i64, data);
+            *l = out_l;
+            *r = out_r;
+        }
+    }
+
     pub fn update(&mut self, data: &DelayData) {
         self.filter_l.update(data.tone);
         self.filter_r.update(data.tone);
@@ -142,30 +153,30 @@ impl Delay {
         let time = num_sixteenths / ((bpm * 4.0) / 60.0);
         data.time = if time < 0.01 {
             0.01
-        } else if time > 1.0 {
-            1.0
+        } else if time > MAX_DELAY_TIME {
+            MAX_DELAY_TIME
         } else {
             time
         }
     }
 
-    fn add(mut value: usize, add: usize) -> usize {
+    fn add(&self, mut value: usize, add: usize) -> usize {
         value += add as usize;
-        while value >= BUFF_LEN {
-            value -= BUFF_LEN;
+        while value >= self.buff_len {
+            value -= self.buff_len;
         }
         value
     }
 
-    fn addf(mut value: Float, add: Float) -> Float {
+    fn addf(&self, mut value: Float, add: Float) -> Float {
         value += add;
-        while value >= BUFF_LEN as Float {
-            value -= BUFF_LEN as Float ;
+        while value >= self.buff_len as Float {
+            value -= self.buff_len as Float ;
         }
         value
     }
 
-    fn diff(a: usize, b: usize) -> usize {
-        if a > b { a - b } else { (a + BUFF_LEN) -  b}
+    fn diff(&self, a: usize, b: usize) -> usize {
+        if a > b { a - b } else { (a + self.buff_len) -  b}
     }
 }
@@ -1,170 +1,128 @@
 extern crate cpal;
 extern crate failure;
 
+use super::Float;
 use super::UiMessage;
 use super::synth::Synth;
+use super::backend::{AudioBackend, AudioConfig, BackendType, CpalBackend, PacedBackend, NullSink, StdoutSink};
+use crate::wav_file::WavWriter;
 
-use cpal::traits::{DeviceTrait, EventLoopTrait, HostTrait};
 use crossbeam_channel::Sender;
 use log::error;
 
-use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100; // Used by backends without a device
+const DEFAULT_BLOCK_SIZE: usize = 512;    // Number of frames between voice updates
+
 pub struct Engine {
-    sample_rate: u32,
+    backend: Box<dyn AudioBackend>,
+    block_size: usize,
 }
 
 impl Engine {
-    pub fn new() -> Result<Engine, ()> {
-        //Engine::enumerate();
-        let host = cpal::default_host();
-        println!("\r  Chose host {:?}", host.id());
-        let device = host.default_output_device().expect("Failed to find a default output device");
-        println!("\r  Chose device {:?}", device.name());
-        let result = device.default_output_format();
-        let format = match result {
-            Ok(f) => f,
-            Err(e) => {
-                error!("Failed to query audio output format: {:?}", e);
-                println!("Failed to query audio output format: {:?}", e);
-                return Err(());
+    /// Create the engine with the selected audio backend.
+    pub fn new(config: &AudioConfig) -> Result<Engine, ()> {
+        let backend_type = config.backend;
+        let output = &config.output;
+        let sample_rate = config.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        let block_size = config.buffer_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+
+        // The stdout backend takes over stdout for the audio data, so it has
+        // to be set up before anything else gets printed.
+        let mut stdout_sink = None;
+        if let BackendType::Stdout = backend_type {
+            match StdoutSink::new() {
+                Ok(s) => stdout_sink = Some(s),
+                Err(e) => {
+                    error!("Failed to redirect stdout: {}", e);
+                    eprintln!("Failed to redirect stdout: {}", e);
+                    return Err(());
+                }
+            }
+        }
+
+        println!("\rSetting up audio engine...");
+        println!("\r  Using audio backend {:?}", backend_type);
+        let backend: Box<dyn AudioBackend> = match backend_type {
+            BackendType::Cpal => Box::new(CpalBackend::new(config, block_size)?),
+            BackendType::Null => Box::new(PacedBackend::new(sample_rate, block_size, Box::new(NullSink))),
+            BackendType::File => {
+                let writer = match WavWriter::create(output, 2, sample_rate) {
+                    Ok(w) => w,
+                    Err(e) => {
+                        error!("Failed to create output file {}: {}", output, e);
+                        println!("Failed to create output file {}: {}", output, e);
+                        return Err(());
+                    }
+                };
+                println!("\r  Writing audio output to {}", output);
+                Box::new(PacedBackend::new(sample_rate, block_size, Box::new(writer)))
+            }
+            BackendType::Stdout => {
+                Box::new(PacedBackend::new(sample_rate, block_size, Box::new(stdout_sink.unwrap())))
             }
         };
-        let sample_rate = format.sample_rate.0;
-        Ok(Engine{sample_rate})
+        println!("\r  Block size: {} frames", block_size);
+        Ok(Engine{backend, block_size})
+    }
+
+    /// Print the available audio output devices with their formats.
+    pub fn list_devices() {
+        CpalBackend::list_devices();
     }
 
     pub fn get_sample_rate(&self) -> u32 {
-        self.sample_rate
+        self.backend.get_sample_rate()
     }
 
-    pub fn run(&mut self, synth: Arc<Mutex<Synth>>, to_ui_sender: Sender<UiMessage>) -> Result<(), ()> {
-        let host = cpal::default_host();
-        let device = host.default_output_device().expect("failed to find a default output device");
-        let mut sample_clock = 0i64;
+    /// Start the audio output.
+    ///
+    /// The synth is moved into the audio thread, so rendering never has to
+    /// wait for a lock. All changes reach it as messages through its event
+    /// queue. The backend calls the render loop from its audio thread. Each
+    /// call renders one buffer of interleaved stereo samples and reports the
+    /// engine load to the UI. The buffer is processed in blocks of the
+    /// configured block size, with the voice states updated after each block.
+    /// The load is passed back to the synth for its overload protection.
+    pub fn run(&mut self, mut synth: Synth, to_ui_sender: Sender<UiMessage>) -> Result<(), ()> {
         let num_channels = 2;
-
-        let event_loop = host.event_loop();
-        let result = device.default_output_format();
-        let mut format = match result {
-            Ok(f) => f,
-            Err(e) => {
-                error!("Failed to query audio output format: {:?}", e);
-                println!("Failed to query audio output format: {:?}", e);
-                return Err(());
-            }
-        };
-        if format.channels > num_channels as u16 {
-            format.channels = num_channels as u16;
-        }
-        let stream_id = event_loop.build_output_stream(&device, &format).unwrap();
+        let block_size = self.block_size;
+        let mut left = vec!(0.0f32; block_size);
+        let mut right = vec!(0.0f32; block_size);
         let mut time = SystemTime::now();
-        event_loop.play_stream(stream_id.clone()).unwrap();
-
-        let _handle = std::thread::spawn(move || {
-            event_loop.run(move |id, result| {
-                let data = match result {
-                    Ok(data) => data,
-                    Err(err) => {
-                        eprintln!("an error occurred on stream {:?}: {}", id, err);
-                        return;
-                    }
-                };
-                match data {
-                    cpal::StreamData::Output { buffer: cpal::UnknownTypeOutputBuffer::F32(mut buffer) } => {
-                        let locked_synth = &mut synth.lock().unwrap();
-
-                        let idle = time.elapsed().expect("Went back in time");
-                        time = SystemTime::now();
-
-                        for sample in buffer.chunks_mut(num_channels) {
-                            sample_clock = sample_clock + 1;
-                            let (left, right) = locked_synth.get_sample(sample_clock);
-                            sample[0] = left as f32;
-                            sample[1] = right as f32;
-                        }
-
-                        let busy = time.elapsed().expect("Went back in time");
-                        time = SystemTime::now();
-                        to_ui_sender.send(UiMessage::EngineSync(idle, busy)).unwrap();
-
-                        locked_synth.update(); // Update the state of the synth voices
-                    },
-                    _ => (),
-                }
-            });
-        });
-        Ok(())
-    }
 
-    /*
-    fn enumerate() {
-        println!("\rSupported hosts:\n\r  {:?}", cpal::ALL_HOSTS);
-        let available_hosts = cpal::available_hosts();
-        println!("\rAvailable hosts:\n\r  {:?}", available_hosts);
-
-        for host_id in available_hosts {
-            println!("\r{:?}", host_id);
-            let host = cpal::host_from_id(host_id).unwrap();
-            let default_in = host.default_input_device().map(|e| e.name().unwrap());
-            let default_out = match host.default_output_device().map(|e| e.name()) {
-                Some(n) => match n {
-                    Ok(s) => s,
-                    Err(_) => "<unknown>".to_string(),
-                }
-                None => "<unknown>".to_string(),
-            };
-            println!("\r  Default Input Device:\n\r    {:?}", default_in);
-            println!("\r  Default Output Device:\n\r    {:?}", default_out);
-
-            let devices = host.devices().unwrap();
-            println!("\r  Devices: ");
-            for (device_index, device) in devices.enumerate() {
-                let name = match device.name() {
-                    Ok(n) => n,
-                    Err(_) => "Unknown".to_string(),
-                };
-                println!("\r  {}. \"{}\"", device_index + 1, name);
+        self.backend.run(Box::new(move |buffer: &mut [f32]| {
+            let idle = time.elapsed().expect("Went back in time");
+            time = SystemTime::now();
 
-                // Input formats
-                if let Ok(fmt) = device.default_input_format() {
-                    println!("\r    Default input stream format:\n\r      {:?}", fmt);
-                }
-                let mut input_formats = match device.supported_input_formats() {
-                    Ok(f) => f.peekable(),
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                        continue;
-                    },
-                };
-                if input_formats.peek().is_some() {
-                    println!("\r    All supported input stream formats:");
-                    for (format_index, format) in input_formats.enumerate() {
-                        println!("\r      {}.{}. {:?}", device_index + 1, format_index + 1, format);
-                    }
+            for block in buffer.chunks_mut(block_size * num_channels) {
+                let num_frames = block.len() / num_channels;
+                synth.process_block(&mut left[..num_frames], &mut right[..num_frames]);
+                for (i, sample) in block.chunks_mut(num_channels).enumerate() {
+                    sample[0] = left[i];
+                    sample[1] = right[i];
                 }
+                synth.update(); // Update the state of the synth voices
+            }
 
-                // Output formats
-                if let Ok(fmt) = device.default_output_format() {
-                    println!("\r    Default output stream format:\n\r      {:?}", fmt);
-                }
-                let mut output_formats = match device.supported_output_formats() {
-                    Ok(f) => f.peekable(),
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                        continue;
-                    },
-                };
-                if output_formats.peek().is_some() {
-                    println!("\r    All supported output stream formats:");
-                    for (format_index, format) in output_formats.enumerate() {
-                        println!("\r      {}.{}. {:?}", device_index + 1, format_index + 1, format);
-                    }
-                }
+            let busy = time.elapsed().expect("Went back in time");
+            time = SystemTime::now();
+
+            // Let the synth drop voices if rendering takes too long
+            let total = (idle + busy).as_secs_f64();
+            if total > 0.0 {
+                synth.handle_load((busy.as_secs_f64() / total) as Float);
             }
-        }
+
+            // The UI might already be gone while shutting down
+            to_ui_sender.send(UiMessage::EngineSync(idle, busy, synth.get_dropped_voices())).ok();
+        }))
+    }
+
+    /// Stop the audio output.
+    pub fn stop(&mut self) {
+        self.backend.stop();
     }
-    */
-}
 
+}
@@ -130,6 +130,13 @@ impl Envelope {
         self.last_value.powf(data.factor)
     }
 
+    /// Fill a buffer with consecutive envelope values, starting at sample_time.
+    pub fn process_block(&mut self, sample_time: i64, data: &EnvelopeData, output: &mut [Float]) {
+        for (i, out) in output.iter_mut().enumerate() {
+            *out = self.get_sample(sample_time + i as i64, data);
+        }
+    }
+
     pub fn is_running(&self) -> bool {
         match self.state {
             EnvState::Idle => false,
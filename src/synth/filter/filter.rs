@@ -124,6 +124,24 @@ impl Filter {
         }
     }
 
+    /// Filter a buffer of samples in place.
+    ///
+    /// The fmod buffer holds the filter envelope value for every sample. The
+    /// cutoff moves linearly from cutoff_start to the cutoff in data over the
+    /// length of the buffer.
+    pub fn process_block(&mut self, samples: &mut [Float], data: &mut FilterData, freq: Float, fmod: &[Float], cutoff_start: Float) {
+        if data.filter_type == 0 {
+            return; // Bypass
+        }
+        let cutoff = data.cutoff;
+        let step = (cutoff - cutoff_start) / samples.len() as Float;
+        for (i, (sample, m)) in samples.iter_mut().zip(fmod.iter()).enumerate() {
+            data.cutoff = cutoff_start + step * (i + 1) as Float;
+            *sample = self.process(*sample, data, freq, *m);
+        }
+        data.cutoff = cutoff;
+    }
+
     // Called if cutoff or resonance have changed
     pub fn update(&mut self, data: &FilterData, cutoff: Float) {
         match data.filter_type {
@@ -0,0 +1,301 @@
+//! Granular oscillator.
+//!
+//! Plays many short, overlapping grains read from the selected sample, or
+//! from the wavetable of the oscillator if no sample is loaded. Every grain
+//! is shaped by a Hann window and has its own start position and stereo
+//! pan, which can be randomized with the jitter and pan settings.
+//!
+//! The pan is returned as a separate side signal, which the voice subtracts
+//! from the left and adds to the right channel.
+
+use super::Float;
+use super::{NoiseRng, Sample, SampleOsc, SampleRef, WtOsc};
+use wavetable::WavetableRef;
+
+use serde::{Serialize, Deserialize};
+
+pub const MAX_GRAINS: usize = 32; // Max. number of grains playing at the same time
+
+/// Sound data for the granular oscillator
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct GranularOscData {
+    pub position: Float,   // Start position of new grains as fraction of the source
+    pub grain_size: Float, // Length of a grain in ms
+    pub density: Float,    // Number of grains started per second
+    pub jitter: Float,     // Random variation of grain position and start time, 0.0 - 1.0
+    pub pan: Float,        // Random stereo spread of the grains, 0.0 - 1.0
+}
+
+impl Default for GranularOscData {
+    fn default() -> Self {
+        let mut data = GranularOscData{position: 0.0, grain_size: 0.0, density: 0.0, jitter: 0.0, pan: 0.0};
+        data.init();
+        data
+    }
+}
+
+impl GranularOscData {
+    pub fn init(&mut self) {
+        self.position = 0.0;
+        self.grain_size = 50.0;
+        self.density = 20.0;
+        self.jitter = 0.0;
+        self.pan = 0.0;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Grain {
+    active: bool,
+    position: Float, // Read position in frames of the sample or wave
+    wave: Float,     // Wave index for wavetable sources, 0.0 - 1.0
+    age: Float,      // Number of samples played
+    length: Float,   // Total length in samples
+    pan: Float,      // -1.0 = left, 1.0 = right
+}
+
+// The audio data the grains are read from.
+enum Source<'a> {
+    Sample(&'a Sample),
+    Wave(&'a WavetableRef, usize), // Wavetable and octave table index
+}
+
+impl<'a> Source<'a> {
+    // Read the value at the given position of a grain, interpolating
+    // linearly. Samples are silent outside of their data, waves repeat.
+    fn read(&self, grain: &Grain) -> Float {
+        match self {
+            Source::Sample(sample) => {
+                let data = &sample.data;
+                if grain.position < 0.0 || grain.position >= (data.len() - 1) as Float {
+                    return 0.0;
+                }
+                let index = grain.position as usize;
+                let frac = grain.position - index as Float;
+                data[index] + (data[index + 1] - data[index]) * frac
+            }
+            Source::Wave(wave, table_index) => {
+                let translated_index = (wave.table.len() - 1) as Float * grain.wave;
+                let lower_wave = translated_index as usize;
+                let upper_fract = translated_index - lower_wave as Float;
+                let position = grain.position % wave.num_samples as Float;
+                let mut result = Source::read_wave(&wave.table[lower_wave], wave.num_values, *table_index, position) * (1.0 - upper_fract);
+                if upper_fract > 0.0 {
+                    result += Source::read_wave(&wave.table[lower_wave + 1], wave.num_values, *table_index, position) * upper_fract;
+                }
+                result
+            }
+        }
+    }
+
+    fn read_wave(table: &[Float], num_values: usize, table_index: usize, position: Float) -> Float {
+        let index = position as usize;
+        let frac = position - index as Float;
+        let index = index + table_index * num_values;
+        table[index] + (table[index + 1] - table[index]) * frac
+    }
+}
+
+pub struct GranularOsc {
+    pub sample_rate: Float,
+    sample: Option<SampleRef>,
+    wave: WavetableRef,
+    grains: [Grain; MAX_GRAINS],
+    next_grain: Float, // Number of samples until the next grain starts
+    rng: NoiseRng,     // Random values for grain positions, start times and pans
+}
+
+impl GranularOsc {
+    pub fn new(sample_rate: u32, wave: WavetableRef) -> GranularOsc {
+        GranularOsc{sample_rate: sample_rate as Float,
+                    sample: None,
+                    wave,
+                    grains: [Grain::default(); MAX_GRAINS],
+                    next_grain: 0.0,
+                    rng: NoiseRng::new(rand::random::<u64>())}
+    }
+
+    pub fn set_wavetable(&mut self, wavetable: WavetableRef) {
+        self.wave = wavetable;
+    }
+
+    pub fn set_sample(&mut self, sample: Option<SampleRef>) {
+        self.sample = sample;
+        self.reset();
+    }
+
+    /// Stop all grains and start a new one with the next sample.
+    pub fn reset(&mut self) {
+        for grain in self.grains.iter_mut() {
+            grain.active = false;
+        }
+        self.next_grain = 0.0;
+    }
+
+    // Start a new grain, unless all grains are busy.
+    fn start_grain(&mut self, data: &GranularOscData, num_frames: Option<usize>) {
+        let grain = match self.grains.iter_mut().find(|g| !g.active) {
+            Some(g) => g,
+            None => return,
+        };
+        let position = (data.position + data.jitter * self.rng.next_value() * 0.5).clamp(0.0, 1.0);
+        *grain = match num_frames {
+            Some(len) => Grain{position: position * (len - 1) as Float, wave: 0.0, ..*grain},
+            None => {
+                // Waves are periodic, so grains start at a random phase instead
+                let phase = data.jitter * (self.rng.next_value() + 1.0) * 0.5 * self.wave.num_samples as Float;
+                Grain{position: phase, wave: position, ..*grain}
+            }
+        };
+        grain.active = true;
+        grain.age = 0.0;
+        grain.length = (data.grain_size * self.sample_rate / 1000.0).max(1.0);
+        grain.pan = data.pan.clamp(0.0, 1.0) * self.rng.next_value();
+    }
+
+    /// Fill a buffer with consecutive samples.
+    ///
+    /// The first sample is dt samples after the last calculated one. The
+    /// playback speed of the grains is interpolated from freq_start to
+    /// frequency over the block. Samples play at their original pitch at the
+    /// frequency of the root key. The side buffer receives the stereo part of
+    /// the output, the complete flag is set when a new grain starts.
+    pub fn process_block(&mut self,
+                         freq_start: Float,
+                         frequency: Float,
+                         dt: i64,
+                         data: &GranularOscData,
+                         root_key: i64,
+                         output: &mut [Float],
+                         side: &mut [Float],
+                         complete: &mut [bool]) {
+        let sample = self.sample.clone().filter(|s| s.data.len() > 1);
+        let wave = self.wave.clone();
+        let (source, speed, num_frames) = match &sample {
+            Some(s) => {
+                let speed = (s.sample_rate / self.sample_rate) / SampleOsc::get_root_frequency(root_key);
+                (Source::Sample(s), speed, Some(s.data.len()))
+            }
+            None => {
+                let table_index = WtOsc::get_table_index(wave.num_octaves, frequency);
+                (Source::Wave(&wave, table_index), wave.num_samples as Float / self.sample_rate, None)
+            }
+        };
+        let speed_start = freq_start * speed;
+        let speed_step = (frequency - freq_start) * speed / output.len() as Float;
+        let interval = self.sample_rate / data.density.max(0.1);
+
+        // Keep the level roughly constant for uncorrelated grains
+        let overlap = data.density * data.grain_size / 1000.0;
+        let gain = 1.0 / overlap.max(1.0).sqrt();
+
+        for (j, (out, (s, comp))) in output.iter_mut().zip(side.iter_mut().zip(complete.iter_mut())).enumerate() {
+            let steps = if j == 0 { dt as Float } else { 1.0 };
+            let step = (speed_start + speed_step * (j + 1) as Float) * steps;
+
+            // Advance the running grains
+            for grain in self.grains.iter_mut().filter(|g| g.active) {
+                grain.age += steps;
+                grain.position += step;
+                if grain.age >= grain.length {
+                    grain.active = false;
+                }
+            }
+
+            // Start new grains when they are due
+            *comp = false;
+            self.next_grain -= steps;
+            while self.next_grain < 0.0 {
+                self.start_grain(data, num_frames);
+                self.next_grain += interval * (1.0 + data.jitter * self.rng.next_value() * 0.5);
+                *comp = true;
+            }
+
+            let mut mid = 0.0;
+            let mut stereo = 0.0;
+            for grain in self.grains.iter().filter(|g| g.active) {
+                let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * grain.age / grain.length).cos();
+                let value = source.read(grain) * window;
+                mid += value;
+                stereo += value * grain.pan;
+            }
+            *out = mid * gain;
+            *s = stereo * gain;
+        }
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{GranularOsc, GranularOscData};
+use super::super::{Float, Sample};
+
+use wavetable::{Wavetable, WavetableRef};
+
+use std::sync::Arc;
+
+fn create_wavetable() -> WavetableRef {
+    let mut wt = Wavetable::new(1, 11, 2048);
+    for i in 0..11 {
+        let table = &mut wt.get_wave_mut(0)[i * 2049..(i + 1) * 2049];
+        Wavetable::add_sine_wave(table, 1.0, 1.0);
+    }
+    Arc::new(wt)
+}
+
+fn render(osc: &mut GranularOsc, data: &GranularOscData, len: usize) -> (Vec<Float>, Vec<Float>, Vec<bool>) {
+    let mut output = vec!(0.0; len);
+    let mut side = vec!(0.0; len);
+    let mut complete = vec!(false; len);
+    osc.process_block(440.0, 440.0, 1, data, 69, &mut output, &mut side, &mut complete);
+    (output, side, complete)
+}
+
+#[test]
+fn grains_start_at_density() {
+    let mut osc = GranularOsc::new(44100, create_wavetable());
+    let mut data = GranularOscData::default();
+    data.density = 100.0; // One grain every 441 samples
+    let (_, _, complete) = render(&mut osc, &data, 4410);
+    assert_eq!(complete.iter().filter(|c| **c).count(), 10);
+    assert!(complete[0]);
+    assert!(complete[441]);
+}
+
+#[test]
+fn grains_are_windowed() {
+    let ramp: Vec<Float> = vec!(1.0; 44100);
+    let mut osc = GranularOsc::new(44100, create_wavetable());
+    osc.set_sample(Some(Arc::new(Sample::new("dc", 44100.0, ramp))));
+    let mut data = GranularOscData::default();
+    data.grain_size = 10.0; // 441 samples
+    data.density = 10.0;    // No overlap
+    let (output, side, _) = render(&mut osc, &data, 882);
+    assert!(output[0].abs() < 0.001);
+    assert!((output[220] - 1.0).abs() < 0.001);
+    assert!(output[500].abs() < 0.001); // Between grains
+    assert!(side.iter().all(|s| *s == 0.0));
+}
+
+#[test]
+fn pan_spreads_grains() {
+    let dc: Vec<Float> = vec!(1.0; 44100);
+    let mut osc = GranularOsc::new(44100, create_wavetable());
+    osc.set_sample(Some(Arc::new(Sample::new("dc", 44100.0, dc))));
+    let mut data = GranularOscData::default();
+    data.grain_size = 10.0;
+    data.density = 50.0; // Grains don't overlap
+    data.pan = 1.0;
+    let (output, side, _) = render(&mut osc, &data, 4410);
+    // Every grain has a constant pan within the stereo field
+    let pans: Vec<Float> = (0..5).map(|i| side[i * 882 + 220] / output[i * 882 + 220]).collect();
+    assert!(pans.iter().all(|p| p.abs() <= 1.0));
+    assert!(pans.iter().any(|p| (p - pans[0]).abs() > 1e-6));
+}
+
+} // mod tests
@@ -4,6 +4,8 @@ use super::{Float, SyncValue};
 
 use serde::{Serialize, Deserialize};
 
+pub const MAX_LFO_FREQUENCY: Float = 44.1; // Highest selectable LFO speed in Hz
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum LfoWaveform {
     Sine,
@@ -1,31 +1,58 @@
+pub mod backend;
+pub mod control;
 pub mod delay;
 pub mod engine;
 pub mod envelope;
 pub mod filter;
+pub mod granular_oscillator;
 pub mod lfo;
+pub mod noise_oscillator;
 pub mod oscillator;
+pub mod pluck_oscillator;
+pub mod resampler;
+pub mod sample_oscillator;
 pub mod sample_generator;
+pub mod sub_oscillator;
 pub mod synth;
+pub mod va_oscillator;
 pub mod voice;
+pub mod voice_pool;
+pub mod wt_codec;
+pub mod wt_generator;
+pub mod wt_manager;
 pub mod wt_oscillator;
 
 pub use delay::{Delay, DelayData};
-pub use engine::Engine;
+pub use control::SynthControl;
+pub use engine::{Engine, DEFAULT_SAMPLE_RATE};
 pub use envelope::{Envelope, EnvelopeData};
 pub use filter::{Filter, FilterData, OnePole};
-pub use lfo::{Lfo, LfoData};
-pub use oscillator::{Oscillator, OscData, OscType, OscRouting};
+pub use granular_oscillator::{GranularOsc, GranularOscData};
+pub use lfo::{Lfo, LfoData, MAX_LFO_FREQUENCY};
+pub use noise_oscillator::{NoiseColor, NoiseOsc, NoiseOscData, NoiseRng};
+pub use oscillator::{Oscillator, OscData, OscType, OscRouting, CrossMod};
+pub use pluck_oscillator::{Excitation, PluckOsc, PluckOscData};
+pub use resampler::{Resampler, ResampleSettings, ResampleSweep, DEFAULT_RESAMPLE_KEY, DEFAULT_RESAMPLE_WAVES, MAX_RESAMPLE_WAVES, KEY_SWEEP_RANGE};
 pub use sample_generator::SampleGenerator;
+pub use sample_oscillator::{Sample, SampleInfo, SampleOsc, SampleOscData, SampleRef, LoopMode, SAMPLE_DIR};
+pub use sub_oscillator::{SubOsc, SubOscData, SubWaveform};
 pub use synth::{
-    Synth, PatchData, SynthState,
+    Synth, SynthConfig, PatchData, SynthState, AudioMessage, SynthEvent, ReleasedData,
     PlayMode, FilterRouting, VoiceAllocation, PanOrigin,
-    NUM_VOICES, NUM_GLOBAL_LFOS, NUM_MODULATORS
+    NUM_VOICES, NUM_GLOBAL_LFOS, NUM_MODULATORS, MAX_BLOCK_SIZE, EVENT_QUEUE_SIZE, RELEASE_QUEUE_SIZE,
+    DEFAULT_CONTROL_PERIOD, DEFAULT_LOAD_THRESHOLD
 };
-pub use wt_oscillator::{WtOsc, WtOscData};
+pub use va_oscillator::{VaOsc, VaOscData, VaWaveform};
+pub use voice_pool::MAX_VOICE_THREADS;
+pub use wt_codec::EmbeddedTable;
+pub use wt_generator::{WtGenDef, WtGenInfo, GENERATOR_EXTENSION};
+pub use wt_manager::{WtManager, WAVETABLE_DIR};
+pub use wt_oscillator::{Interpolation, WarpMode, WtOsc, WtOscData, WtModMode};
 
 use super::Float;
 use super::MidiMessage;
-use super::{Parameter, SynthParam, ParamId, MenuItem};
+use super::{Parameter, SynthParam};
 use super::{SoundData, SyncValue};
+use super::{ModRouting, ModSourceSlot};
 use super::SynthMessage;
 use super::UiMessage;
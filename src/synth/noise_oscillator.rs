@@ -0,0 +1,348 @@
+//! Noise oscillator.
+//!
+//! Generates noise in different colours. White noise has equal energy at
+//! all frequencies, pink and brown noise fall off towards high frequencies
+//! by 3 and 6 dB per octave, blue noise rises by 3 dB per octave. Digital
+//! noise holds each random value until the next cycle of the oscillator
+//! frequency, like noise played back at a reduced sample rate.
+//!
+//! The tone control tilts the spectrum with a one-pole filter: values below
+//! 0.5 cut the highs, values above 0.5 cut the lows, 0.5 leaves the noise
+//! unfiltered.
+//!
+//! The random numbers come from a small generator of our own, so that noise
+//! with a fixed seed sounds the same in every build.
+
+use super::Float;
+use super::OnePole;
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum NoiseColor {
+    White,
+    Pink,
+    Brown,
+    Blue,
+    Digital,
+}
+
+impl NoiseColor {
+    pub fn from_int(param: usize) -> NoiseColor {
+        match param {
+            0 => NoiseColor::White,
+            1 => NoiseColor::Pink,
+            2 => NoiseColor::Brown,
+            3 => NoiseColor::Blue,
+            4 => NoiseColor::Digital,
+            _ => panic!(),
+        }
+    }
+
+    pub fn to_int(&self) -> usize {
+        match self {
+            NoiseColor::White => 0,
+            NoiseColor::Pink => 1,
+            NoiseColor::Brown => 2,
+            NoiseColor::Blue => 3,
+            NoiseColor::Digital => 4,
+        }
+    }
+}
+
+impl Default for NoiseColor {
+    fn default() -> Self { NoiseColor::White }
+}
+
+/// Sound data for the noise oscillator
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct NoiseOscData {
+    pub color: NoiseColor,
+    pub tone: Float, // Spectral tilt, 0.0 = dark, 0.5 = neutral, 1.0 = bright
+    pub seed: i64,   // Start value of the random generator, 0 = random
+}
+
+impl Default for NoiseOscData {
+    fn default() -> Self {
+        let mut data = NoiseOscData{color: NoiseColor::White, tone: 0.0, seed: 0};
+        data.init();
+        data
+    }
+}
+
+impl NoiseOscData {
+    pub fn init(&mut self) {
+        self.color = NoiseColor::White;
+        self.tone = 0.5;
+        self.seed = 0;
+    }
+}
+
+/// Xorshift64* random number generator.
+pub struct NoiseRng {
+    state: u64,
+}
+
+impl NoiseRng {
+    pub fn new(seed: u64) -> NoiseRng {
+        let mut rng = NoiseRng{state: 0};
+        rng.seed(seed);
+        rng
+    }
+
+    /// Restart the sequence for the given seed.
+    pub fn seed(&mut self, seed: u64) {
+        // Spread the bits of small seeds, the state must never be zero
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        self.state = (z ^ (z >> 31)) | 1;
+    }
+
+    /// Get the next value in the range -1.0 - 1.0.
+    pub fn next_value(&mut self) -> Float {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let value = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11; // 53 bits
+        (value as Float / (1u64 << 52) as Float) - 1.0
+    }
+}
+
+const MIN_LOWPASS_CUTOFF: Float = 100.0;    // Lowpass cutoff for tone 0.0
+const MIN_HIGHPASS_CUTOFF: Float = 20.0;    // Highpass cutoff just above tone 0.5
+const MAX_HIGHPASS_CUTOFF: Float = 10000.0; // Highpass cutoff for tone 1.0
+
+pub struct NoiseOsc {
+    pub sample_rate: Float,
+    rng: NoiseRng,
+    reseed: bool,       // Restart the random sequence with the next sample
+    pink: [Float; 7],   // Filter states for pink noise
+    last_pink: Float,   // Previous pink value, for blue noise
+    brown: Float,       // Integrator state for brown noise
+    phase: Float,       // Position in the current hold cycle of digital noise
+    hold: Float,        // Current value of digital noise
+    tone_filter: OnePole,
+    tone: Float,        // Tone setting the filter was last updated for
+}
+
+impl NoiseOsc {
+    pub fn new(sample_rate: u32) -> NoiseOsc {
+        NoiseOsc{sample_rate: sample_rate as Float,
+                 rng: NoiseRng::new(0),
+                 reseed: true,
+                 pink: [0.0; 7],
+                 last_pink: 0.0,
+                 brown: 0.0,
+                 phase: 0.0,
+                 hold: 0.0,
+                 tone_filter: OnePole::new(sample_rate),
+                 tone: -1.0}
+    }
+
+    /// Restart the random sequence with the next sample.
+    pub fn reset(&mut self) {
+        self.reseed = true;
+    }
+
+    fn restart(&mut self, data: &NoiseOscData) {
+        let seed = if data.seed == 0 { rand::random::<u64>() } else { data.seed as u64 };
+        self.rng.seed(seed);
+        self.pink = [0.0; 7];
+        self.last_pink = 0.0;
+        self.brown = 0.0;
+        self.phase = 1.0; // Take a new value for digital noise right away
+        self.reseed = false;
+    }
+
+    // Pink noise with Paul Kellet's filter.
+    fn next_pink(&mut self) -> Float {
+        let white = self.rng.next_value();
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink * 0.22
+    }
+
+    // Calculate one sample of uncoloured noise.
+    //
+    // Returns the value and whether a new digital noise value was taken.
+    fn next_sample(&mut self, frequency: Float, color: NoiseColor) -> (Float, bool) {
+        match color {
+            NoiseColor::White => (self.rng.next_value(), false),
+            NoiseColor::Pink => (self.next_pink(), false),
+            NoiseColor::Brown => {
+                self.brown = (self.brown + self.rng.next_value() * 0.02) / 1.02;
+                (self.brown * 7.0, false)
+            }
+            NoiseColor::Blue => {
+                // Differentiating adds 6 dB per octave to the pink spectrum
+                let pink = self.next_pink();
+                let blue = pink - self.last_pink;
+                self.last_pink = pink;
+                (blue * 2.0, false)
+            }
+            NoiseColor::Digital => {
+                self.phase += frequency.abs() / self.sample_rate;
+                let complete = self.phase >= 1.0;
+                if complete {
+                    self.phase -= self.phase.floor();
+                    self.hold = self.rng.next_value();
+                }
+                (self.hold, complete)
+            }
+        }
+    }
+
+    fn update_tone(&mut self, tone: Float) {
+        if tone == self.tone {
+            return;
+        }
+        self.tone = tone;
+        let max_cutoff = self.sample_rate * 0.45;
+        let cutoff = if tone < 0.5 {
+            max_cutoff * (MIN_LOWPASS_CUTOFF / max_cutoff).powf((0.5 - tone) * 2.0)
+        } else {
+            MIN_HIGHPASS_CUTOFF * (MAX_HIGHPASS_CUTOFF / MIN_HIGHPASS_CUTOFF).powf((tone - 0.5) * 2.0)
+        };
+        self.tone_filter.update(cutoff.min(max_cutoff));
+    }
+
+    /// Fill a buffer with consecutive samples.
+    ///
+    /// The first sample is dt samples after the last calculated one, all
+    /// skipped samples are calculated as well. The frequency is only used by
+    /// digital noise, which sets the complete flag whenever it takes a new
+    /// value.
+    pub fn process_block(&mut self,
+                         freq_start: Float,
+                         frequency: Float,
+                         dt: i64,
+                         data: &NoiseOscData,
+                         output: &mut [Float],
+                         complete: &mut [bool]) {
+        if self.reseed {
+            self.restart(data);
+        }
+        let tone = data.tone.clamp(0.0, 1.0);
+        self.update_tone(tone);
+        let freq_step = (frequency - freq_start) / output.len() as Float;
+        for (j, (out, comp)) in output.iter_mut().zip(complete.iter_mut()).enumerate() {
+            let freq = freq_start + freq_step * (j + 1) as Float;
+            let steps = if j == 0 { dt.clamp(1, self.sample_rate as i64) } else { 1 };
+            *comp = false;
+            for _ in 0..steps {
+                let (value, c) = self.next_sample(freq, data.color);
+                *out = if tone == 0.5 {
+                    value
+                } else {
+                    let lowpass = self.tone_filter.process(value);
+                    if tone < 0.5 { lowpass } else { value - lowpass }
+                };
+                *comp |= c;
+            }
+        }
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{NoiseColor, NoiseOsc, NoiseOscData, NoiseRng};
+use super::super::Float;
+
+const SAMPLE_RATE: u32 = 44100;
+
+fn render(data: &NoiseOscData, len: usize) -> Vec<Float> {
+    let mut osc = NoiseOsc::new(SAMPLE_RATE);
+    let mut output = vec!(0.0; len);
+    let mut complete = vec!(false; len);
+    osc.process_block(441.0, 441.0, 1, data, &mut output, &mut complete);
+    output
+}
+
+fn create_data(color: NoiseColor, tone: Float) -> NoiseOscData {
+    NoiseOscData{color, tone, seed: 1234}
+}
+
+// Get the energy of the difference between neighbouring samples relative
+// to the total energy. About 2.0 for white noise, less for darker noise.
+fn get_brightness(samples: &[Float]) -> Float {
+    let total: Float = samples.iter().map(|s| s * s).sum();
+    let diff: Float = samples.windows(2).map(|w| (w[1] - w[0]) * (w[1] - w[0])).sum();
+    diff / total
+}
+
+#[test]
+fn rng_values_are_in_range() {
+    let mut rng = NoiseRng::new(0);
+    let values: Vec<Float> = (0..10000).map(|_| rng.next_value()).collect();
+    assert!(values.iter().all(|v| *v >= -1.0 && *v < 1.0));
+    let mean = values.iter().sum::<Float>() / values.len() as Float;
+    assert!(mean.abs() < 0.05);
+}
+
+#[test]
+fn seeded_noise_is_reproducible() {
+    let data = create_data(NoiseColor::White, 0.5);
+    assert_eq!(render(&data, 1000), render(&data, 1000));
+
+    let mut osc = NoiseOsc::new(SAMPLE_RATE);
+    let mut first = vec!(0.0; 100);
+    let mut second = vec!(0.0; 100);
+    let mut complete = vec!(false; 100);
+    osc.process_block(441.0, 441.0, 1, &data, &mut first, &mut complete);
+    osc.reset();
+    osc.process_block(441.0, 441.0, 1, &data, &mut second, &mut complete);
+    assert_eq!(first, second);
+
+    let mut other = data;
+    other.seed = 4321;
+    assert_ne!(render(&data, 1000), render(&other, 1000));
+
+    // Seed 0 starts a new random sequence every time
+    other.seed = 0;
+    assert_ne!(render(&other, 1000), render(&other, 1000));
+}
+
+#[test]
+fn colours_have_expected_brightness() {
+    let mut brightness = vec!();
+    for &color in [NoiseColor::Brown, NoiseColor::Pink, NoiseColor::White, NoiseColor::Blue].iter() {
+        let output = render(&create_data(color, 0.5), 44100);
+        let rms = (output.iter().map(|s| s * s).sum::<Float>() / output.len() as Float).sqrt();
+        assert!(rms > 0.1 && rms < 1.0, "{:?}: {}", color, rms);
+        brightness.push(get_brightness(&output));
+    }
+    assert!(brightness.windows(2).all(|b| b[0] < b[1]), "{:?}", brightness);
+}
+
+#[test]
+fn tone_tilts_spectrum() {
+    let dark = get_brightness(&render(&create_data(NoiseColor::White, 0.0), 44100));
+    let neutral = get_brightness(&render(&create_data(NoiseColor::White, 0.5), 44100));
+    let bright = get_brightness(&render(&create_data(NoiseColor::White, 1.0), 44100));
+    assert!(dark < neutral * 0.1);
+    assert!(bright > neutral);
+}
+
+#[test]
+fn digital_noise_holds_values() {
+    // 441 Hz at 44.1 kHz takes a new value every 100 samples
+    let output = render(&create_data(NoiseColor::Digital, 0.5), 1000);
+    let changes = output.windows(2).filter(|w| w[0] != w[1]).count();
+    assert!(changes <= 10, "{}", changes);
+    assert!(changes >= 9, "{}", changes);
+}
+
+} // mod tests
@@ -1,5 +1,11 @@
 use super::Float;
-use super::{WtOsc, WtOscData};
+use super::{WtOsc, WtOscData, WtModMode};
+use super::{SampleOsc, SampleOscData, SampleRef};
+use super::{GranularOsc, GranularOscData};
+use super::{PluckOsc, PluckOscData};
+use super::{VaOsc, VaOscData};
+use super::{NoiseOsc, NoiseOscData};
+use super::MAX_BLOCK_SIZE;
 use wavetable::WavetableRef;
 
 use serde::{Serialize, Deserialize};
@@ -7,7 +13,13 @@ use serde::{Serialize, Deserialize};
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum OscType {
     Wavetable,
-    Noise
+    Noise,
+    FM, // Wavetable with frequency modulated by another oscillator
+    PM, // Wavetable with phase modulated by another oscillator
+    Sample,
+    Granular,
+    Pluck,
+    Analog, // Band-limited saw, square and triangle
 }
 
 impl OscType {
@@ -15,6 +27,12 @@ impl OscType {
         match param {
             0 => OscType::Wavetable,
             1 => OscType::Noise,
+            2 => OscType::FM,
+            3 => OscType::PM,
+            4 => OscType::Sample,
+            5 => OscType::Granular,
+            6 => OscType::Pluck,
+            7 => OscType::Analog,
             _ => panic!(),
         }
     }
@@ -23,6 +41,12 @@ impl OscType {
         match self {
             OscType::Wavetable => 0,
             OscType::Noise => 1,
+            OscType::FM => 2,
+            OscType::PM => 3,
+            OscType::Sample => 4,
+            OscType::Granular => 5,
+            OscType::Pluck => 6,
+            OscType::Analog => 7,
         }
     }
 }
@@ -65,6 +89,47 @@ impl Default for OscRouting {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum CrossMod {
+    Off,
+    Ring, // Output multiplied by the source oscillator
+    Am,   // Output multiplied by the source oscillator shifted to 0.0 - 1.0
+}
+
+impl CrossMod {
+    pub fn from_int(param: usize) -> CrossMod {
+        match param {
+            0 => CrossMod::Off,
+            1 => CrossMod::Ring,
+            2 => CrossMod::Am,
+            _ => panic!(),
+        }
+    }
+
+    pub fn to_int(&self) -> usize {
+        match self {
+            CrossMod::Off => 0,
+            CrossMod::Ring => 1,
+            CrossMod::Am => 2,
+        }
+    }
+
+    /** Modulate a sample with the output of the source oscillator. */
+    pub fn apply(&self, value: Float, modulator: Float) -> Float {
+        match self {
+            CrossMod::Off => value,
+            CrossMod::Ring => value * modulator,
+            CrossMod::Am => value * (modulator + 1.0) * 0.5,
+        }
+    }
+}
+
+impl Default for CrossMod {
+    fn default() -> Self {
+        CrossMod::Off
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
 pub struct OscData {
     pub level: Float,
@@ -75,9 +140,29 @@ pub struct OscData {
     pub key_follow: i64,
     pub routing: OscRouting,
     pub osc_type: OscType,
+    #[serde(default)]
+    pub mod_source: usize, // Oscillator modulating this one for FM/ PM, own index for feedback
+    #[serde(default)]
+    pub mod_index: Float,  // FM/ PM modulation depth
+    #[serde(default)]
+    pub sync_source: usize,      // Oscillator resetting this one if sync is on
+    #[serde(default)]
+    pub cross_mod: CrossMod,     // Ring or amplitude modulation by another oscillator
+    #[serde(default)]
+    pub cross_mod_source: usize, // Oscillator used for ring/ amplitude modulation
 
     // Oscillator-specific data
     pub wt_osc_data: WtOscData,
+    #[serde(default)]
+    pub sample_data: SampleOscData,
+    #[serde(default)]
+    pub granular_data: GranularOscData,
+    #[serde(default)]
+    pub pluck_data: PluckOscData,
+    #[serde(default)]
+    pub va_data: VaOscData,
+    #[serde(default)]
+    pub noise_data: NoiseOscData,
 }
 
 impl OscData {
@@ -87,7 +172,36 @@ impl OscData {
         self.set_cents(0.0);
         self.sync = 0;
         self.key_follow = 1;
+        self.mod_source = 0;
+        self.mod_index = 1.0;
+        self.sync_source = 0;
+        self.cross_mod = CrossMod::Off;
+        self.cross_mod_source = 0;
         self.wt_osc_data.init();
+        self.sample_data.init();
+        self.granular_data.init();
+        self.pluck_data.init();
+        self.va_data.init();
+        self.noise_data.init();
+    }
+
+    /** True if the oscillator is modulated by another oscillator. */
+    pub fn is_modulated(&self) -> bool {
+        matches!(self.osc_type, OscType::FM | OscType::PM)
+    }
+
+    /** True if the oscillator handles sync resets without aliasing. */
+    pub fn has_smooth_sync(&self) -> bool {
+        matches!(self.osc_type, OscType::Analog)
+    }
+
+    /** True if the oscillator has a stereo output. */
+    pub fn is_stereo(&self) -> bool {
+        match self.osc_type {
+            OscType::Wavetable | OscType::FM | OscType::PM => self.wt_osc_data.is_stereo(),
+            OscType::Granular => self.granular_data.pan > 0.0,
+            _ => false,
+        }
     }
 
     /** Coarse tuning of oscillator (+/- 2 octaves). */
@@ -113,13 +227,27 @@ pub struct Oscillator {
     last_update: i64,
     last_sample: Float,
     last_complete: bool,
+    last_side: Float,
+    last_sync_offset: Float,
 
     // Values to control the signal routing
     pub filter1_out: Float,
     pub filter2_out: Float,
     pub direct_out: Float,
 
+    // Stereo part of the output of the last block, see is_stereo()
+    side: [Float; MAX_BLOCK_SIZE],
+    stereo: bool,
+
+    // Time since the end of the wave cycle for samples that completed one
+    sync_offset: [Float; MAX_BLOCK_SIZE],
+
     wt_osc: WtOsc,
+    sample_osc: SampleOsc,
+    granular_osc: GranularOsc,
+    pluck_osc: PluckOsc,
+    va_osc: VaOsc,
+    noise_osc: NoiseOsc,
 }
 
 impl Oscillator {
@@ -128,15 +256,30 @@ impl Oscillator {
             last_update: 0,
             last_sample: 0.0,
             last_complete: false,
+            last_side: 0.0,
+            last_sync_offset: 0.0,
             filter1_out: 1.0,
             filter2_out: 0.0,
             direct_out: 0.0,
-            wt_osc: WtOsc::new(sample_rate, default_wt)
+            side: [0.0; MAX_BLOCK_SIZE],
+            stereo: false,
+            sync_offset: [0.0; MAX_BLOCK_SIZE],
+            wt_osc: WtOsc::new(sample_rate, default_wt.clone()),
+            sample_osc: SampleOsc::new(sample_rate),
+            granular_osc: GranularOsc::new(sample_rate, default_wt.clone()),
+            pluck_osc: PluckOsc::new(sample_rate, default_wt),
+            va_osc: VaOsc::new(sample_rate),
+            noise_osc: NoiseOsc::new(sample_rate),
         }
     }
 
-    pub fn get_sample(&mut self, frequency: Float, sample_clock: i64, data: &OscData, reset: bool) -> (Float, bool) {
-        if reset {
+    /// Get the next sample.
+    ///
+    /// The modulator is the current output of the modulating oscillator for
+    /// FM/ PM, or None if the oscillator modulates itself. The reset value is
+    /// the sync signal, see process_block().
+    pub fn get_sample(&mut self, frequency: Float, sample_clock: i64, data: &OscData, reset: Option<Float>, modulator: Option<Float>) -> (Float, bool) {
+        if reset.is_some() && !data.has_smooth_sync() {
             self.reset(sample_clock - 1);
         }
 
@@ -147,9 +290,58 @@ impl Oscillator {
         }
 
         let dt = sample_clock - self.last_update;
+        self.last_sync_offset = 0.0;
         let (result, complete) = match data.osc_type {
-            OscType::Wavetable => self.wt_osc.get_sample(frequency, dt, &data.wt_osc_data),
-            OscType::Noise => (Oscillator::get_sample_noise(), false),
+            OscType::Wavetable => {
+                let (result, side, complete) = self.wt_osc.get_sample(frequency, dt, &data.wt_osc_data);
+                self.last_side = side;
+                (result, complete)
+            }
+            OscType::Noise => {
+                let mut output = [0.0];
+                let mut complete = [false];
+                self.noise_osc.process_block(frequency, frequency, dt, &data.noise_data, &mut output, &mut complete);
+                (output[0], complete[0])
+            }
+            OscType::FM | OscType::PM => {
+                let mut output = [0.0];
+                let mut side = [0.0];
+                let mut complete = [false];
+                let modulator = modulator.map(|m| [m]);
+                self.wt_osc.process_block_mod(frequency, frequency, dt, &data.wt_osc_data, Oscillator::get_mod_mode(data), data.mod_index,
+                                              modulator.as_ref().map(|m| &m[..]), &mut output, &mut side, &mut complete);
+                self.last_side = side[0];
+                (output[0], complete[0])
+            }
+            OscType::Sample => {
+                let mut output = [0.0];
+                let mut complete = [false];
+                self.sample_osc.process_block(frequency, frequency, dt, &data.sample_data, &mut output, &mut complete);
+                (output[0], complete[0])
+            }
+            OscType::Granular => {
+                let mut output = [0.0];
+                let mut side = [0.0];
+                let mut complete = [false];
+                self.granular_osc.process_block(frequency, frequency, dt, &data.granular_data, data.sample_data.root_key,
+                                                &mut output, &mut side, &mut complete);
+                self.last_side = side[0];
+                (output[0], complete[0])
+            }
+            OscType::Pluck => {
+                let mut output = [0.0];
+                let mut complete = [false];
+                self.pluck_osc.process_block(frequency, frequency, dt, &data.pluck_data, data.wt_osc_data.wave_index, &mut output, &mut complete);
+                (output[0], complete[0])
+            }
+            OscType::Analog => {
+                let mut output = [0.0];
+                let mut complete = [false];
+                let mut sync_offset = [0.0];
+                self.va_osc.process_block(frequency, frequency, dt, &data.va_data, &[reset], &mut output, &mut complete, &mut sync_offset);
+                self.last_sync_offset = sync_offset[0];
+                (output[0], complete[0])
+            }
         };
 
         self.last_update += dt;
@@ -158,17 +350,197 @@ impl Oscillator {
         (result, complete)
     }
 
+    /// Fill a buffer with consecutive samples, starting at sample_clock.
+    ///
+    /// The reset buffer contains the sync signal for every sample: the time
+    /// in samples since the sync source completed its wave cycle, if it did.
+    /// Most oscillators just restart at that sample, but the analog one
+    /// resets at the exact position in between samples. The complete buffer
+    /// receives the wave cycle completion flags. The modulator is only used
+    /// for FM/ PM, see get_sample().
+    ///
+    /// The block must not be longer than MAX_BLOCK_SIZE. For stereo
+    /// oscillators, the stereo part of the output is available with
+    /// get_side() afterwards.
+    pub fn process_block(&mut self,
+                         freq_start: Float,
+                         frequency: Float,
+                         sample_clock: i64,
+                         data: &OscData,
+                         reset: &[Option<Float>],
+                         modulator: Option<&[Float]>,
+                         output: &mut [Float],
+                         complete: &mut [bool]) {
+        let len = output.len();
+        let mut start = 0;
+        self.stereo = data.is_stereo();
+        let smooth_sync = data.has_smooth_sync();
+        while start < len {
+            let clock = sample_clock + start as i64;
+            if reset[start].is_some() && !smooth_sync {
+                self.reset(clock - 1);
+            }
+
+            // The first sample might already have been calculated when the
+            // oscillator is used as modulation source.
+            if clock == self.last_update {
+                output[start] = self.last_sample;
+                complete[start] = self.last_complete;
+                self.side[start] = self.last_side;
+                self.sync_offset[start] = self.last_sync_offset;
+                start += 1;
+                continue;
+            }
+
+            // Process everything up to the next sync reset in one go
+            let mut end = start + 1;
+            while end < len && (smooth_sync || reset[end].is_none()) {
+                end += 1;
+            }
+            let dt = clock - self.last_update;
+            self.sync_offset[start..end].iter_mut().for_each(|o| *o = 0.0);
+            match data.osc_type {
+                OscType::Wavetable => {
+                    let freq_step = (frequency - freq_start) / len as Float;
+                    let from = freq_start + freq_step * start as Float;
+                    let to = freq_start + freq_step * end as Float;
+                    self.wt_osc.process_block(from, to, dt, &data.wt_osc_data, &mut output[start..end], &mut self.side[start..end], &mut complete[start..end]);
+                    self.last_side = self.side[end - 1];
+                }
+                OscType::Noise => {
+                    let freq_step = (frequency - freq_start) / len as Float;
+                    let from = freq_start + freq_step * start as Float;
+                    let to = freq_start + freq_step * end as Float;
+                    self.noise_osc.process_block(from, to, dt, &data.noise_data, &mut output[start..end], &mut complete[start..end]);
+                }
+                OscType::FM | OscType::PM => {
+                    let freq_step = (frequency - freq_start) / len as Float;
+                    let from = freq_start + freq_step * start as Float;
+                    let to = freq_start + freq_step * end as Float;
+                    self.wt_osc.process_block_mod(from, to, dt, &data.wt_osc_data, Oscillator::get_mod_mode(data), data.mod_index,
+                                                  modulator.map(|m| &m[start..end]), &mut output[start..end], &mut self.side[start..end], &mut complete[start..end]);
+                    self.last_side = self.side[end - 1];
+                }
+                OscType::Sample => {
+                    let freq_step = (frequency - freq_start) / len as Float;
+                    let from = freq_start + freq_step * start as Float;
+                    let to = freq_start + freq_step * end as Float;
+                    self.sample_osc.process_block(from, to, dt, &data.sample_data, &mut output[start..end], &mut complete[start..end]);
+                }
+                OscType::Granular => {
+                    let freq_step = (frequency - freq_start) / len as Float;
+                    let from = freq_start + freq_step * start as Float;
+                    let to = freq_start + freq_step * end as Float;
+                    self.granular_osc.process_block(from, to, dt, &data.granular_data, data.sample_data.root_key,
+                                                    &mut output[start..end], &mut self.side[start..end], &mut complete[start..end]);
+                    self.last_side = self.side[end - 1];
+                }
+                OscType::Pluck => {
+                    let freq_step = (frequency - freq_start) / len as Float;
+                    let from = freq_start + freq_step * start as Float;
+                    let to = freq_start + freq_step * end as Float;
+                    self.pluck_osc.process_block(from, to, dt, &data.pluck_data, data.wt_osc_data.wave_index,
+                                                 &mut output[start..end], &mut complete[start..end]);
+                }
+                OscType::Analog => {
+                    let freq_step = (frequency - freq_start) / len as Float;
+                    let from = freq_start + freq_step * start as Float;
+                    let to = freq_start + freq_step * end as Float;
+                    self.va_osc.process_block(from, to, dt, &data.va_data, &reset[start..end],
+                                              &mut output[start..end], &mut complete[start..end], &mut self.sync_offset[start..end]);
+                }
+            }
+            self.last_update = sample_clock + end as i64 - 1;
+            self.last_sample = output[end - 1];
+            self.last_complete = complete[end - 1];
+            self.last_sync_offset = self.sync_offset[end - 1];
+            start = end;
+        }
+    }
+
+    fn get_mod_mode(data: &OscData) -> WtModMode {
+        if let OscType::PM = data.osc_type {
+            WtModMode::Phase
+        } else {
+            WtModMode::Frequency
+        }
+    }
+
+    /// Returns the most recently calculated sample.
+    pub fn get_last_sample(&self) -> Float {
+        self.last_sample
+    }
+
+    /// Returns the stereo part of the most recently calculated sample.
+    ///
+    /// The value is 0.0 for mono oscillators. The left channel is the
+    /// sample minus this value, the right channel the sample plus it.
+    pub fn get_last_side(&self, data: &OscData) -> Float {
+        if data.is_stereo() { self.last_side } else { 0.0 }
+    }
+
+    /// Returns the time since the end of the wave cycle for the most
+    /// recently calculated sample.
+    ///
+    /// Only valid if it completed a wave cycle. The value is 0.0 for
+    /// oscillators that don't track cycles within samples.
+    pub fn get_last_sync_offset(&self) -> Float {
+        self.last_sync_offset
+    }
+
+    /// Returns the time since the end of the wave cycle for the samples of
+    /// the last block, see get_last_sync_offset().
+    pub fn get_sync_offsets(&self, len: usize) -> &[Float] {
+        &self.sync_offset[..len]
+    }
+
+    /// Returns the stereo part of the last block, or None if the oscillator
+    /// is mono.
+    pub fn get_side(&self, len: usize) -> Option<&[Float]> {
+        if self.stereo { Some(&self.side[..len]) } else { None }
+    }
+
     pub fn reset(&mut self, sample_clock: i64) {
         self.wt_osc.reset();
+        self.sample_osc.reset();
+        self.granular_osc.reset();
+        self.pluck_osc.reset();
+        self.va_osc.reset();
+        self.noise_osc.reset();
         self.last_update = sample_clock;
     }
 
-    fn get_sample_noise() -> Float {
-        (rand::random::<Float>() * 2.0) - 1.0
+    /// Restart the sample and grain playback, pluck the string and restart
+    /// the noise sequence again when a note is triggered.
+    ///
+    /// Unlike reset(), this keeps the phase of the wavetable oscillator.
+    pub fn restart(&mut self) {
+        self.sample_osc.reset();
+        self.granular_osc.reset();
+        self.pluck_osc.reset();
+        self.noise_osc.reset();
+    }
+
+    /// Move the unison voices of the wavetable oscillator to random start
+    /// positions when a note starts on a silent voice.
+    pub fn randomize_phase(&mut self) {
+        self.wt_osc.randomize_phase();
     }
 
     pub fn set_wavetable(&mut self, wavetable: WavetableRef) {
-        self.wt_osc.set_wavetable(wavetable);
+        self.wt_osc.set_wavetable(wavetable.clone());
+        self.granular_osc.set_wavetable(wavetable.clone());
+        self.pluck_osc.set_wavetable(wavetable);
+    }
+
+    /// Limit the number of unison voices of the wavetable oscillator.
+    pub fn set_unison_limit(&mut self, limit: usize) {
+        self.wt_osc.set_voice_limit(limit);
+    }
+
+    pub fn set_sample(&mut self, sample: Option<SampleRef>) {
+        self.sample_osc.set_sample(sample.clone());
+        self.granular_osc.set_sample(sample);
     }
 
     pub fn update_routing(&mut self, data: &OscData) {
@@ -0,0 +1,294 @@
+//! Plucked string oscillator.
+//!
+//! A Karplus-Strong string model: a delay line tuned to the played frequency
+//! is filled with a short excitation when the note starts, and the signal is
+//! fed back through a lowpass filter, so that it decays like a plucked
+//! string. The excitation is either a burst of noise or one cycle of the
+//! current wave of the oscillator's wavetable.
+
+use super::Float;
+use super::{NoiseRng, WtOsc};
+use wavetable::WavetableRef;
+
+use serde::{Serialize, Deserialize};
+
+const MIN_FREQUENCY: Float = 20.0; // Lowest frequency the delay line supports
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Excitation {
+    Noise,     // Random values
+    Wavetable, // One cycle of the current wave
+}
+
+impl Excitation {
+    pub fn from_int(param: usize) -> Excitation {
+        match param {
+            0 => Excitation::Noise,
+            1 => Excitation::Wavetable,
+            _ => panic!(),
+        }
+    }
+
+    pub fn to_int(&self) -> usize {
+        match self {
+            Excitation::Noise => 0,
+            Excitation::Wavetable => 1,
+        }
+    }
+}
+
+impl Default for Excitation {
+    fn default() -> Self { Excitation::Noise }
+}
+
+/// Sound data for the plucked string oscillator
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct PluckOscData {
+    pub excitation: Excitation,
+    pub damping: Float, // Loss of high frequencies, 0.0 - 1.0
+    pub decay: Float,   // Time in ms until the string has decayed by 60 dB
+}
+
+impl Default for PluckOscData {
+    fn default() -> Self {
+        let mut data = PluckOscData{excitation: Excitation::Noise, damping: 0.0, decay: 0.0};
+        data.init();
+        data
+    }
+}
+
+impl PluckOscData {
+    pub fn init(&mut self) {
+        self.excitation = Excitation::Noise;
+        self.damping = 0.5;
+        self.decay = 2000.0;
+    }
+}
+
+pub struct PluckOsc {
+    pub sample_rate: Float,
+    wave: WavetableRef,
+    buffer: Vec<Float>, // Delay line
+    write_pos: usize,
+    phase: Float,       // Position in the current wave cycle, for the sync signal
+    excite: bool,       // Fill the delay line with the next sample
+    rng: NoiseRng,      // Random values for the noise excitation
+}
+
+impl PluckOsc {
+    pub fn new(sample_rate: u32, wave: WavetableRef) -> PluckOsc {
+        let sample_rate = sample_rate as Float;
+        let len = (sample_rate / MIN_FREQUENCY) as usize + 2;
+        PluckOsc{sample_rate,
+                 wave,
+                 buffer: vec!(0.0; len),
+                 write_pos: 0,
+                 phase: 0.0,
+                 excite: true,
+                 rng: NoiseRng::new(rand::random::<u64>())}
+    }
+
+    pub fn set_wavetable(&mut self, wavetable: WavetableRef) {
+        self.wave = wavetable;
+    }
+
+    /// Pluck the string again with the next sample.
+    pub fn reset(&mut self) {
+        self.excite = true;
+    }
+
+    // Get the length of one period in samples.
+    fn get_period(&self, frequency: Float) -> Float {
+        let max_period = (self.buffer.len() - 2) as Float;
+        (self.sample_rate / frequency.abs().max(MIN_FREQUENCY)).clamp(2.0, max_period)
+    }
+
+    // Fill the delay line with one period of the excitation signal.
+    fn pluck(&mut self, frequency: Float, data: &PluckOscData, wave_index: Float) {
+        let num_values = self.get_period(frequency).round() as usize;
+        let len = self.buffer.len();
+        self.buffer.iter_mut().for_each(|b| *b = 0.0);
+        // The excitation is written directly into the last num_values
+        // entries before the write position.
+        let start = self.write_pos + len - num_values;
+        let buffer = &mut self.buffer;
+        match data.excitation {
+            Excitation::Noise => {
+                for i in 0..num_values {
+                    buffer[(start + i) % len] = self.rng.next_value();
+                }
+            }
+            Excitation::Wavetable => {
+                let wave = &self.wave;
+                let table_index = WtOsc::get_table_index(wave.num_octaves, frequency);
+                let table = &wave.table[((wave.table.len() - 1) as Float * wave_index.clamp(0.0, 1.0)) as usize];
+                let offset = table_index * wave.num_values;
+                for i in 0..num_values {
+                    let position = i as Float * wave.num_samples as Float / num_values as Float;
+                    let index = position as usize;
+                    let frac = position - index as Float;
+                    buffer[(start + i) % len] = table[offset + index] + (table[offset + index + 1] - table[offset + index]) * frac;
+                }
+            }
+        }
+
+        // Remove the DC offset, which would otherwise decay very slowly
+        let mean = (0..num_values).map(|i| buffer[(start + i) % len]).sum::<Float>() / num_values as Float;
+        for i in 0..num_values {
+            buffer[(start + i) % len] -= mean;
+        }
+        self.phase = 0.0;
+        self.excite = false;
+    }
+
+    // Read the delay line the given number of samples back, interpolating
+    // linearly.
+    fn read(&self, delay: Float) -> Float {
+        let len = self.buffer.len();
+        let index = delay as usize;
+        let frac = delay - index as Float;
+        let a = self.buffer[(self.write_pos + len - index) % len];
+        let b = self.buffer[(self.write_pos + len - index - 1) % len];
+        a + (b - a) * frac
+    }
+
+    // Calculate the next sample and feed it back into the delay line.
+    fn next_sample(&mut self, frequency: Float, data: &PluckOscData) -> (Float, bool) {
+        let period = self.get_period(frequency);
+
+        // The two-point lowpass delays the signal by `stretch` samples, which
+        // is subtracted from the delay to keep the string in tune.
+        let stretch = data.damping.clamp(0.0, 1.0) * 0.5;
+        let delay = period - stretch;
+        let decay = (data.decay / 1000.0).max(0.001);
+        let gain = (10.0 as Float).powf(-3.0 / (decay * self.sample_rate / period));
+        let value = (self.read(delay) * (1.0 - stretch) + self.read(delay + 1.0) * stretch) * gain;
+        self.buffer[self.write_pos] = value;
+        self.write_pos = (self.write_pos + 1) % self.buffer.len();
+
+        self.phase += 1.0 / period;
+        let complete = self.phase >= 1.0;
+        if complete {
+            self.phase -= 1.0;
+        }
+        (value, complete)
+    }
+
+    /// Fill a buffer with consecutive samples.
+    ///
+    /// The first sample is dt samples after the last calculated one, all
+    /// skipped samples are calculated as well. The frequency is interpolated
+    /// from freq_start to frequency over the block. The string is plucked
+    /// with the first sample after a reset. The complete flag is set after
+    /// every period.
+    pub fn process_block(&mut self,
+                         freq_start: Float,
+                         frequency: Float,
+                         dt: i64,
+                         data: &PluckOscData,
+                         wave_index: Float,
+                         output: &mut [Float],
+                         complete: &mut [bool]) {
+        let freq_step = (frequency - freq_start) / output.len() as Float;
+        let max_steps = self.buffer.len() as i64;
+        for (j, (out, comp)) in output.iter_mut().zip(complete.iter_mut()).enumerate() {
+            let freq = freq_start + freq_step * (j + 1) as Float;
+            if self.excite {
+                self.pluck(freq, data, wave_index);
+            }
+            let steps = if j == 0 { dt.clamp(1, max_steps) } else { 1 };
+            *comp = false;
+            for _ in 0..steps {
+                let (value, c) = self.next_sample(freq, data);
+                *out = value;
+                *comp |= c;
+            }
+        }
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{Excitation, PluckOsc, PluckOscData};
+use super::super::Float;
+
+use wavetable::{Wavetable, WavetableRef};
+
+use std::sync::Arc;
+
+fn create_wavetable() -> WavetableRef {
+    let mut wt = Wavetable::new(1, 11, 2048);
+    for i in 0..11 {
+        let table = &mut wt.get_wave_mut(0)[i * 2049..(i + 1) * 2049];
+        Wavetable::add_sine_wave(table, 1.0, 1.0);
+    }
+    Arc::new(wt)
+}
+
+fn render(osc: &mut PluckOsc, data: &PluckOscData, frequency: Float, len: usize) -> Vec<Float> {
+    let mut output = vec!(0.0; len);
+    let mut complete = vec!(false; len);
+    osc.process_block(frequency, frequency, 1, data, 0.0, &mut output, &mut complete);
+    output
+}
+
+fn get_energy(samples: &[Float]) -> Float {
+    samples.iter().map(|s| s * s).sum()
+}
+
+#[test]
+fn string_repeats_at_played_frequency() {
+    let mut osc = PluckOsc::new(44100, create_wavetable());
+    let mut data = PluckOscData::default();
+    data.damping = 0.0;
+    data.decay = 100000.0;
+    let output = render(&mut osc, &data, 441.0, 400);
+    for i in 0..200 {
+        assert!((output[i] - output[i + 100]).abs() < 0.001);
+    }
+}
+
+#[test]
+fn string_decays() {
+    let mut osc = PluckOsc::new(44100, create_wavetable());
+    let mut data = PluckOscData::default();
+    data.decay = 100.0;
+    let output = render(&mut osc, &data, 441.0, 4410);
+    let start = get_energy(&output[..441]);
+    let end = get_energy(&output[3969..]);
+    // About 60 dB over 100 ms
+    assert!(end < start * 1e-5);
+    assert!(end > 0.0);
+}
+
+#[test]
+fn wave_excitation_plays_wave_cycle() {
+    let mut osc = PluckOsc::new(44100, create_wavetable());
+    let mut data = PluckOscData::default();
+    data.excitation = Excitation::Wavetable;
+    data.damping = 0.0;
+    data.decay = 100000.0;
+    let output = render(&mut osc, &data, 441.0, 100);
+    for (i, value) in output.iter().enumerate() {
+        let expected = (2.0 * std::f64::consts::PI * i as Float / 100.0).sin();
+        assert!((value - expected).abs() < 0.01, "{} != {} at {}", value, expected, i);
+    }
+}
+
+#[test]
+fn reset_plucks_string_again() {
+    let mut osc = PluckOsc::new(44100, create_wavetable());
+    let mut data = PluckOscData::default();
+    data.decay = 50.0;
+    let first = render(&mut osc, &data, 441.0, 4410);
+    osc.reset();
+    let second = render(&mut osc, &data, 441.0, 441);
+    assert!(get_energy(&second) > get_energy(&first[3969..]) * 1000.0);
+}
+
+} // mod tests
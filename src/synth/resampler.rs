@@ -0,0 +1,231 @@
+//! Renders the output of a voice into a new wavetable.
+//!
+//! A note of the current sound is played through a single voice. After the
+//! envelopes have reached their sustain phase, one wave cycle of the output
+//! is captured and resampled to the wavetable length. For a table with more
+//! than one wave, this is repeated for a number of steps across a key range
+//! or a range of mod wheel positions.
+
+use super::Float;
+use super::{ModRouting, ModSourceSlot};
+use super::{SampleRef, SoundData, SynthState};
+use super::voice::{Voice, NUM_OSCILLATORS};
+use super::wt_manager::{WtManager, WAVE_LENGTH};
+use crate::wav_file::WavWriter;
+
+use wavetable::WavetableRef;
+
+pub const DEFAULT_RESAMPLE_KEY: u8 = 36;      // Key to play if the key isn't swept (C2)
+pub const DEFAULT_RESAMPLE_WAVES: usize = 64; // Number of waves for sweeps
+pub const MAX_RESAMPLE_WAVES: usize = 256;
+pub const KEY_SWEEP_RANGE: u8 = 60;           // Default range of a key sweep in semitones
+
+const MAX_SETTLE_TIME: Float = 2.0; // Max. time in seconds to wait for the envelopes to settle
+
+/// What changes from one wave of the table to the next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResampleSweep {
+    None,                             // Only a single wave is rendered
+    Key{from: u8, to: u8},            // Play a different key for every wave
+    ModWheel{from: Float, to: Float}, // Move the mod wheel (0 - 127) for every wave
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResampleSettings {
+    pub key: u8,          // Key to play if the key isn't swept
+    pub num_waves: usize, // Number of waves to render for a sweep
+    pub sweep: ResampleSweep,
+}
+
+impl Default for ResampleSettings {
+    fn default() -> Self {
+        ResampleSettings{key: DEFAULT_RESAMPLE_KEY, num_waves: 1, sweep: ResampleSweep::None}
+    }
+}
+
+impl ResampleSettings {
+    /// Settings for a sweep over the given number of waves.
+    pub fn sweep(sweep: ResampleSweep, num_waves: usize) -> Self {
+        ResampleSettings{sweep, num_waves, ..Default::default()}
+    }
+
+    // Number of waves that will be rendered.
+    fn get_num_waves(&self) -> usize {
+        match self.sweep {
+            ResampleSweep::None => 1,
+            _ => self.num_waves.clamp(1, MAX_RESAMPLE_WAVES),
+        }
+    }
+
+    // Key and mod wheel position for a single wave.
+    fn get_wave_params(&self, wave_index: usize) -> (u8, Float) {
+        let num_waves = self.get_num_waves();
+        let pos = if num_waves > 1 { wave_index as Float / (num_waves - 1) as Float } else { 0.0 };
+        match self.sweep {
+            ResampleSweep::None => (self.key, 0.0),
+            ResampleSweep::Key{from, to} => {
+                let key = from as Float + (to as Float - from as Float) * pos;
+                (key.round() as u8, 0.0)
+            }
+            ResampleSweep::ModWheel{from, to} => (self.key, from + (to - from) * pos),
+        }
+    }
+}
+
+pub struct Resampler {
+    sample_rate: u32,
+    wavetables: [WavetableRef; NUM_OSCILLATORS],
+    samples: [Option<SampleRef>; NUM_OSCILLATORS],
+}
+
+impl Resampler {
+    /// Create a resampler using the wavetables and samples assigned to the
+    /// oscillators of the sound.
+    pub fn new(sample_rate: u32,
+               wavetables: [WavetableRef; NUM_OSCILLATORS],
+               samples: [Option<SampleRef>; NUM_OSCILLATORS]) -> Resampler {
+        Resampler{sample_rate, wavetables, samples}
+    }
+
+    /// Render all waves of a table.
+    ///
+    /// Every wave is normalized individually.
+    pub fn render(&self, sound: &SoundData, settings: &ResampleSettings) -> Vec<Vec<Float>> {
+        (0..settings.get_num_waves()).map(|i| {
+            let (key, mod_wheel) = settings.get_wave_params(i);
+            let mut wave = self.render_wave(sound, key, mod_wheel);
+            WtManager::normalize(&mut wave);
+            wave
+        }).collect()
+    }
+
+    /// Save rendered waves as a mono WAV file that can be loaded as a
+    /// wavetable.
+    pub fn save(&self, filename: &str, waves: &[Vec<Float>]) -> std::io::Result<()> {
+        let mut writer = WavWriter::create(filename, 1, self.sample_rate)?;
+        for wave in waves {
+            let samples: Vec<f32> = wave.iter().map(|s| *s as f32).collect();
+            writer.write_samples(&samples)?;
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+
+    // Render a single wave cycle of the sound with the given key and mod
+    // wheel position.
+    fn render_wave(&self, sound: &SoundData, key: u8, mod_wheel: Float) -> Vec<Float> {
+        let mut voice = Voice::new(self.sample_rate, self.wavetables[0].clone());
+        for i in 0..NUM_OSCILLATORS {
+            voice.set_wavetable(i, self.wavetables[i].clone());
+            voice.set_sample(i, self.samples[i].clone());
+            voice.update_routing(i, &sound.osc[i]);
+        }
+        voice.update_sub_routing(&sound.sub);
+
+        // The mod wheel is the only global modulation source that is used,
+        // all others stay at 0.
+        let mut routing = ModRouting::new();
+        routing.compile(&sound.modul);
+        let mut sound_global = *sound;
+        for route in routing.get_global_routes() {
+            let mod_val = match route.source {
+                ModSourceSlot::ModWheel => mod_wheel,
+                _ => 0.0,
+            } * route.scale;
+            routing.get_targets()[route.target].apply(&mut sound_global, mod_val);
+        }
+        let mut sound_local = sound_global;
+        let global_state = SynthState{freq_factor: 1.0};
+
+        let freq = 440.0 * (2.0 as Float).powf((key as Float - 69.0) / 12.0);
+        voice.set_key(key);
+        voice.set_freq(freq);
+        voice.set_velocity(127, sound.patch.vel_sens);
+        voice.trigger(0, 0, &sound_global);
+
+        // Render until the envelopes have settled, plus one full cycle and
+        // the samples needed for interpolation.
+        let sample_rate = self.sample_rate as Float;
+        let cycle_length = sample_rate / freq;
+        let settle_time = sound.env.iter()
+            .map(|e| (e.delay + e.attack + e.decay) / 1000.0)
+            .fold(0.0, Float::max)
+            .min(MAX_SETTLE_TIME);
+        let start = (settle_time * sample_rate).ceil() as usize + 1;
+        let num_samples = start + cycle_length.ceil() as usize + 2;
+        let output: Vec<Float> = (0..num_samples).map(|i| {
+            let (left, right) = voice.get_sample(i as i64, &sound_global, &mut sound_local, &routing, &global_state);
+            left + right
+        }).collect();
+
+        let step = cycle_length / WAVE_LENGTH as Float;
+        (0..WAVE_LENGTH).map(|i| Resampler::read_cubic(&output, start as Float + i as Float * step)).collect()
+    }
+
+    // Read a value between samples with cubic Hermite interpolation.
+    //
+    // The position must have at least one sample before and two after it.
+    fn read_cubic(buffer: &[Float], position: Float) -> Float {
+        let index = position as usize;
+        let frac = position - index as Float;
+        let (xm1, x0, x1, x2) = (buffer[index - 1], buffer[index], buffer[index + 1], buffer[index + 2]);
+        let c1 = 0.5 * (x1 - xm1);
+        let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+        let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+        ((c3 * frac + c2) * frac + c1) * frac + x0
+    }
+}
+
+// Sound with only oscillator 1 playing the given wave of the basic table,
+// unfiltered and at full level.
+#[cfg(test)]
+fn create_test_sound(wave_index: Float) -> SoundData {
+    let mut sound = SoundData::new();
+    sound.init();
+    sound.osc[0].level = 1.0;
+    sound.osc[0].wt_osc_data.wave_index = wave_index;
+    sound.env[0].attack = 0.0;
+    sound.env[0].decay = 0.0;
+    sound.env[0].sustain = 1.0;
+    sound.env[0].factor = 1.0;
+    sound.filter[0].filter_type = 0; // Bypass
+    sound
+}
+
+#[cfg(test)]
+fn create_test_resampler() -> Resampler {
+    let mut wt_manager = WtManager::new(44100.0);
+    wt_manager.add_basic_tables(0);
+    let wt = wt_manager.get_table(0).unwrap();
+    Resampler::new(44100, [wt.clone(), wt.clone(), wt], [None, None, None])
+}
+
+#[test]
+fn single_cycle_is_resampled_to_table_length() {
+    let resampler = create_test_resampler();
+    let waves = resampler.render(&create_test_sound(0.0), &ResampleSettings::default());
+    assert_eq!(waves.len(), 1);
+    assert_eq!(waves[0].len(), WAVE_LENGTH);
+
+    // The sine wave of the basic table comes out as a normalized sine, with
+    // the phase depending on the start of the capture.
+    let peak = waves[0].iter().fold(0.0, |max: Float, x| max.max(x.abs()));
+    assert!((peak - 1.0).abs() < 1e-9);
+    let quarter = WAVE_LENGTH / 4;
+    for i in 0..quarter {
+        let sum = waves[0][i] + waves[0][i + 2 * quarter];
+        assert!(sum.abs() < 0.01, "{}: {} + {}", i, waves[0][i], waves[0][i + 2 * quarter]);
+    }
+}
+
+#[test]
+fn sweep_renders_requested_number_of_waves() {
+    let resampler = create_test_resampler();
+    let settings = ResampleSettings::sweep(ResampleSweep::Key{from: 36, to: 60}, 3);
+    assert_eq!(settings.get_wave_params(1), (48, 0.0));
+    let waves = resampler.render(&create_test_sound(0.0), &settings);
+    assert_eq!(waves.len(), 3);
+
+    let settings = ResampleSettings::sweep(ResampleSweep::ModWheel{from: 0.0, to: 127.0}, 5);
+    assert_eq!(settings.get_wave_params(4), (DEFAULT_RESAMPLE_KEY, 127.0));
+}
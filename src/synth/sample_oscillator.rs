@@ -0,0 +1,376 @@
+//! Sample playback oscillator.
+//!
+//! Plays a sample loaded from a WAV file, transposed relative to its root
+//! key. The sample can be played once between its start and end points, or
+//! loop between the loop points, either forward with an optional crossfade
+//! or back and forth.
+
+use super::Float;
+use crate::wav_file::WavFile;
+
+use serde::{Serialize, Deserialize};
+
+use std::sync::Arc;
+
+pub const SAMPLE_DIR: &str = "samples"; // Directory containing the sample files
+
+/// Entry in the list of available samples.
+///
+/// ID 0 is reserved for "no sample".
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SampleInfo {
+    pub id: usize,
+    pub valid: bool,
+    pub name: String,
+    pub filename: String,
+}
+
+/// A loaded sample, mixed down to mono.
+pub struct Sample {
+    pub name: String,
+    pub sample_rate: Float,
+    pub data: Vec<Float>,
+}
+
+pub type SampleRef = Arc<Sample>;
+
+impl Sample {
+    pub fn new(name: &str, sample_rate: Float, data: Vec<Float>) -> Sample {
+        Sample{name: name.to_string(), sample_rate, data}
+    }
+
+    /// Load a sample from a WAV file.
+    ///
+    /// Files with more than one channel are mixed down to mono.
+    pub fn load(name: &str, filename: &str) -> std::io::Result<Sample> {
+        let wav = WavFile::read_file(filename)?;
+        let num_channels = wav.num_channels as usize;
+        let data = wav.samples
+            .chunks_exact(num_channels)
+            .map(|frame| frame.iter().map(|s| *s as Float).sum::<Float>() / num_channels as Float)
+            .collect();
+        Ok(Sample::new(name, wav.sample_rate as Float, data))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum LoopMode {
+    Off,      // Play from start to end once
+    Forward,  // Jump back to the loop start at the loop end
+    PingPong, // Reverse direction at both loop points
+}
+
+impl LoopMode {
+    pub fn from_int(param: usize) -> LoopMode {
+        match param {
+            0 => LoopMode::Off,
+            1 => LoopMode::Forward,
+            2 => LoopMode::PingPong,
+            _ => panic!(),
+        }
+    }
+
+    pub fn to_int(&self) -> usize {
+        match self {
+            LoopMode::Off => 0,
+            LoopMode::Forward => 1,
+            LoopMode::PingPong => 2,
+        }
+    }
+}
+
+impl Default for LoopMode {
+    fn default() -> Self { LoopMode::Off }
+}
+
+/// Sound data for the sample oscillator
+///
+/// All positions are given as fraction of the sample length.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct SampleOscData {
+    pub sample: usize,     // ID of the sample, 0 = none
+    pub root_key: i64,     // Key at which the sample plays at its original pitch
+    pub start: Float,
+    pub end: Float,
+    pub loop_start: Float,
+    pub loop_end: Float,
+    pub loop_mode: LoopMode,
+    pub crossfade: Float,  // Crossfade length of forward loops as fraction of the loop length
+}
+
+impl Default for SampleOscData {
+    fn default() -> Self {
+        let mut data = SampleOscData{sample: 0, root_key: 0, start: 0.0, end: 0.0,
+                                     loop_start: 0.0, loop_end: 0.0,
+                                     loop_mode: LoopMode::Off, crossfade: 0.0};
+        data.init();
+        data
+    }
+}
+
+impl SampleOscData {
+    pub fn init(&mut self) {
+        self.sample = 0;
+        self.root_key = 60;
+        self.start = 0.0;
+        self.end = 1.0;
+        self.loop_start = 0.0;
+        self.loop_end = 1.0;
+        self.loop_mode = LoopMode::Off;
+        self.crossfade = 0.0;
+    }
+}
+
+// Playback boundaries in frames, derived from the sound data.
+struct Bounds {
+    start: Float,
+    end: Float,
+    loop_start: Float,
+    loop_end: Float,
+    crossfade: Float,
+    mode: LoopMode, // Off if the loop can't be reached
+}
+
+impl Bounds {
+    fn new(data: &SampleOscData, num_frames: usize) -> Bounds {
+        let last = (num_frames - 1) as Float;
+        let limit = |x: Float| x.clamp(0.0, 1.0) * last;
+        let start = limit(data.start);
+        let end = limit(data.end).max(start);
+        let loop_start = limit(data.loop_start);
+        let loop_end = limit(data.loop_end).min(end);
+        let loop_len = loop_end - loop_start;
+        let mut mode = data.loop_mode;
+        if loop_len < 1.0 || start >= loop_end {
+            mode = LoopMode::Off;
+        }
+        // The crossfade reads samples before the loop start
+        let crossfade = (data.crossfade.clamp(0.0, 0.5) * loop_len).min(loop_start);
+        Bounds{start, end, loop_start, loop_end, crossfade, mode}
+    }
+}
+
+pub struct SampleOsc {
+    pub sample_rate: Float,
+    sample: Option<SampleRef>,
+    position: Float,  // Current playback position in frames
+    forward: bool,    // Playback direction
+    restart: bool,    // Start from the beginning with the next sample
+    finished: bool,   // End of the sample has been reached
+}
+
+impl SampleOsc {
+    pub fn new(sample_rate: u32) -> SampleOsc {
+        SampleOsc{sample_rate: sample_rate as Float,
+                  sample: None,
+                  position: 0.0,
+                  forward: true,
+                  restart: true,
+                  finished: false}
+    }
+
+    pub fn set_sample(&mut self, sample: Option<SampleRef>) {
+        self.sample = sample;
+        self.restart = true;
+    }
+
+    /// Restart playback at the start point.
+    pub fn reset(&mut self) {
+        self.restart = true;
+    }
+
+    /// Get the frequency of the root key with equal temperament.
+    pub fn get_root_frequency(root_key: i64) -> Float {
+        const TWO: Float = 2.0;
+        440.0 * TWO.powf((root_key - 69) as Float / 12.0)
+    }
+
+    // Read the sample value at a position, interpolating linearly.
+    fn read(data: &[Float], position: Float) -> Float {
+        let index = position as usize;
+        let frac = position - index as Float;
+        let a = data[index];
+        let b = if index + 1 < data.len() { data[index + 1] } else { a };
+        a + (b - a) * frac
+    }
+
+    // Move the playback position forward by the given number of frames.
+    //
+    // Returns true if the position jumped back to the loop start.
+    fn advance(&mut self, step: Float, b: &Bounds) -> bool {
+        match b.mode {
+            LoopMode::Off => {
+                self.position += step;
+                if self.position > b.end {
+                    self.finished = true;
+                }
+                false
+            }
+            LoopMode::Forward => {
+                self.position += step;
+                if self.position >= b.loop_end {
+                    let loop_len = b.loop_end - b.loop_start;
+                    self.position = b.loop_start + (self.position - b.loop_end) % loop_len;
+                    return true;
+                }
+                false
+            }
+            LoopMode::PingPong => {
+                let loop_len = b.loop_end - b.loop_start;
+                let step = step % (loop_len * 2.0);
+                let mut complete = false;
+                if self.forward {
+                    self.position += step;
+                    if self.position >= b.loop_end {
+                        self.position = b.loop_end - (self.position - b.loop_end);
+                        self.forward = false;
+                    }
+                } else {
+                    self.position -= step;
+                }
+                if !self.forward && self.position <= b.loop_start {
+                    self.position = b.loop_start + (b.loop_start - self.position);
+                    self.forward = true;
+                    complete = true;
+                }
+                self.position = self.position.max(b.loop_start.min(b.start)).min(b.loop_end);
+                complete
+            }
+        }
+    }
+
+    /// Fill a buffer with consecutive samples.
+    ///
+    /// The first sample is dt samples after the last calculated one. The
+    /// playback speed is interpolated from freq_start to frequency over the
+    /// block. The complete flag is set when a loop starts over.
+    pub fn process_block(&mut self,
+                         freq_start: Float,
+                         frequency: Float,
+                         dt: i64,
+                         data: &SampleOscData,
+                         output: &mut [Float],
+                         complete: &mut [bool]) {
+        let sample = match &self.sample {
+            Some(s) if !s.data.is_empty() => s.clone(),
+            _ => {
+                output.iter_mut().for_each(|o| *o = 0.0);
+                complete.iter_mut().for_each(|c| *c = false);
+                return;
+            }
+        };
+        let bounds = Bounds::new(data, sample.data.len());
+        let speed = (sample.sample_rate / self.sample_rate) / SampleOsc::get_root_frequency(data.root_key);
+        let speed_start = freq_start * speed;
+        let speed_step = (frequency - freq_start) * speed / output.len() as Float;
+
+        for (j, (out, comp)) in output.iter_mut().zip(complete.iter_mut()).enumerate() {
+            *comp = false;
+            if self.restart {
+                self.position = bounds.start;
+                self.forward = true;
+                self.finished = false;
+                self.restart = false;
+            } else if !self.finished {
+                let steps = if j == 0 { dt as Float } else { 1.0 };
+                let step = (speed_start + speed_step * (j + 1) as Float) * steps;
+                *comp = self.advance(step, &bounds);
+            }
+            if self.finished {
+                *out = 0.0;
+                continue;
+            }
+            let mut value = SampleOsc::read(&sample.data, self.position);
+            let fade_start = bounds.loop_end - bounds.crossfade;
+            if let LoopMode::Forward = bounds.mode {
+                if bounds.crossfade > 0.0 && self.position > fade_start {
+                    // Blend into the part before the loop start, which
+                    // continues seamlessly after the jump back
+                    let ratio = (self.position - fade_start) / bounds.crossfade;
+                    let loop_len = bounds.loop_end - bounds.loop_start;
+                    let other = SampleOsc::read(&sample.data, self.position - loop_len);
+                    value = value * (1.0 - ratio) + other * ratio;
+                }
+            }
+            *out = value;
+        }
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{LoopMode, Sample, SampleOsc, SampleOscData};
+use super::super::Float;
+
+use std::sync::Arc;
+
+// Plays a ramp sample with one frame per sample at the root key.
+fn play(data: &SampleOscData, len: usize) -> Vec<Float> {
+    let ramp: Vec<Float> = (0..10).map(|i| i as Float).collect();
+    let mut osc = SampleOsc::new(44100);
+    osc.set_sample(Some(Arc::new(Sample::new("ramp", 44100.0, ramp))));
+    let mut output = vec!(0.0; len);
+    let mut complete = vec!(false; len);
+    osc.process_block(440.0, 440.0, 1, data, &mut output, &mut complete);
+    output
+}
+
+fn create_data(loop_mode: LoopMode) -> SampleOscData {
+    let mut data = SampleOscData::default();
+    data.root_key = 69;
+    data.loop_mode = loop_mode;
+    data.loop_start = 2.0 / 9.0;
+    data.loop_end = 5.0 / 9.0;
+    data
+}
+
+fn assert_close(actual: &[Float], expected: &[Float]) {
+    assert_eq!(actual.len(), expected.len());
+    for (a, e) in actual.iter().zip(expected.iter()) {
+        assert!((a - e).abs() < 1e-9, "{:?} != {:?}", actual, expected);
+    }
+}
+
+#[test]
+fn sample_without_loop_stops_at_end() {
+    let output = play(&create_data(LoopMode::Off), 12);
+    assert_close(&output, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 0.0, 0.0]);
+}
+
+#[test]
+fn forward_loop_jumps_to_loop_start() {
+    let output = play(&create_data(LoopMode::Forward), 10);
+    assert_close(&output, &[0.0, 1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 2.0, 3.0]);
+}
+
+#[test]
+fn ping_pong_loop_reverses_direction() {
+    let output = play(&create_data(LoopMode::PingPong), 10);
+    assert_close(&output, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 4.0, 3.0, 2.0, 3.0]);
+}
+
+#[test]
+fn crossfade_blends_into_loop_start() {
+    let mut data = create_data(LoopMode::Forward);
+    data.loop_start = 4.0 / 9.0;
+    data.loop_end = 8.0 / 9.0;
+    data.crossfade = 0.5; // Two frames
+    let output = play(&data, 10);
+    // Frame 7 is halfway through the fade from 7 to 3
+    assert_close(&output, &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 5.0, 4.0, 5.0]);
+}
+
+#[test]
+fn playback_speed_follows_frequency() {
+    let mut data = create_data(LoopMode::Off);
+    data.root_key = 57; // One octave below the played frequency
+    let output = play(&data, 6);
+    assert_close(&output, &[0.0, 2.0, 4.0, 6.0, 8.0, 0.0]);
+}
+
+} // mod tests
@@ -0,0 +1,200 @@
+//! Sub-oscillator.
+//!
+//! Plays a square or sine wave one or two octaves below oscillator 1 to
+//! reinforce the bass. The waveform is generated by a virtual analog
+//! oscillator, which is reset at the start of every second or fourth wave
+//! cycle of oscillator 1, so the sub-oscillator stays locked in phase with
+//! it. It only needs the wave completion flags of oscillator 1 and follows
+//! all of its pitch changes.
+
+use super::Float;
+use super::{OscRouting, VaOsc, VaOscData, VaWaveform};
+use super::MAX_BLOCK_SIZE;
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SubWaveform {
+    Square,
+    Sine,
+}
+
+impl SubWaveform {
+    pub fn from_int(param: usize) -> SubWaveform {
+        match param {
+            0 => SubWaveform::Square,
+            1 => SubWaveform::Sine,
+            _ => panic!(),
+        }
+    }
+
+    pub fn to_int(&self) -> usize {
+        match self {
+            SubWaveform::Square => 0,
+            SubWaveform::Sine => 1,
+        }
+    }
+}
+
+impl Default for SubWaveform {
+    fn default() -> Self { SubWaveform::Square }
+}
+
+/// Sound data for the sub-oscillator
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct SubOscData {
+    pub level: Float,
+    pub octave: i64, // -1 or -2
+    pub waveform: SubWaveform,
+    pub routing: OscRouting,
+}
+
+impl Default for SubOscData {
+    fn default() -> Self {
+        let mut data = SubOscData{level: 0.0, octave: 0, waveform: SubWaveform::Square, routing: OscRouting::Filter1};
+        data.init();
+        data
+    }
+}
+
+impl SubOscData {
+    pub fn init(&mut self) {
+        self.level = 0.0;
+        self.octave = -1;
+        self.waveform = SubWaveform::Square;
+        self.routing = OscRouting::Filter1;
+    }
+
+    /** Get the number of cycles of oscillator 1 per cycle of the sub-oscillator. */
+    pub fn get_divider(&self) -> usize {
+        if self.octave <= -2 { 4 } else { 2 }
+    }
+}
+
+pub struct SubOsc {
+    osc: VaOsc,
+    va_data: VaOscData,
+    cycle: usize, // Number of completed cycles of oscillator 1 in the current sub cycle
+
+    // Values to control the signal routing
+    pub filter1_out: Float,
+    pub filter2_out: Float,
+    pub direct_out: Float,
+}
+
+impl SubOsc {
+    pub fn new(sample_rate: u32) -> SubOsc {
+        SubOsc{osc: VaOsc::new(sample_rate),
+               va_data: VaOscData::default(),
+               cycle: 0,
+               filter1_out: 1.0,
+               filter2_out: 0.0,
+               direct_out: 0.0}
+    }
+
+    /// Start a new cycle together with oscillator 1.
+    pub fn reset(&mut self) {
+        self.osc.reset();
+        self.cycle = 0;
+    }
+
+    pub fn update_routing(&mut self, data: &SubOscData) {
+        match data.routing {
+            OscRouting::Filter1 => { self.filter1_out = 1.0; self.filter2_out = 0.0; self.direct_out = 0.0; }
+            OscRouting::Filter2 => { self.filter1_out = 0.0; self.filter2_out = 1.0; self.direct_out = 0.0; }
+            OscRouting::Direct => { self.filter1_out = 0.0; self.filter2_out = 0.0; self.direct_out = 1.0; }
+        }
+    }
+
+    /// Fill a buffer with consecutive samples.
+    ///
+    /// The frequencies are the ones of oscillator 1, interpolated over the
+    /// block like for the other oscillators. The sync buffer holds the wave
+    /// completions of oscillator 1, in the same format as the reset signal
+    /// of Oscillator::process_block().
+    pub fn process_block(&mut self,
+                         freq_start: Float,
+                         frequency: Float,
+                         data: &SubOscData,
+                         sync: &[Option<Float>],
+                         output: &mut [Float]) {
+        let len = output.len();
+        let divider = data.get_divider();
+        let mut reset = [None; MAX_BLOCK_SIZE];
+        for (r, s) in reset.iter_mut().zip(sync.iter()) {
+            if let Some(offset) = s {
+                self.cycle += 1;
+                if self.cycle >= divider {
+                    self.cycle = 0;
+                    *r = Some(*offset);
+                }
+            }
+        }
+        self.va_data.waveform = match data.waveform {
+            SubWaveform::Square => VaWaveform::Square,
+            SubWaveform::Sine => VaWaveform::Sine,
+        };
+        let divider = divider as Float;
+        let mut complete = [false; MAX_BLOCK_SIZE];
+        let mut sync_offset = [0.0; MAX_BLOCK_SIZE];
+        self.osc.process_block(freq_start / divider, frequency / divider, 1, &self.va_data, &reset[..len],
+                               output, &mut complete[..len], &mut sync_offset[..len]);
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{SubOsc, SubOscData, SubWaveform};
+use super::super::Float;
+
+// Renders the sub-oscillator for a 441 Hz oscillator 1, which completes a
+// cycle every 100 samples, but is detuned against it by the given factor.
+fn render(data: &SubOscData, detune: Float, len: usize) -> Vec<Float> {
+    let mut osc = SubOsc::new(44100);
+    let mut output = vec!(0.0; len);
+    for (i, chunk) in output.chunks_mut(50).enumerate() {
+        let sync: Vec<Option<Float>> = (0..chunk.len())
+            .map(|j| if (i * 50 + j) % 100 == 99 { Some(0.0) } else { None })
+            .collect();
+        let freq = 441.0 * detune;
+        osc.process_block(freq, freq, data, &sync, chunk);
+    }
+    output
+}
+
+#[test]
+fn square_follows_oscillator_one() {
+    let mut data = SubOscData::default();
+    for &octave in [-1, -2].iter() {
+        data.octave = octave;
+        let period = if octave == -1 { 200 } else { 400 };
+        let output = render(&data, 1.0, 2000);
+        for i in 10..1500 {
+            assert!((output[i] - output[i + period]).abs() < 0.01, "{} at {}", octave, i);
+        }
+        // High for the first half of the sub cycle
+        assert!(output[period / 4] > 0.9);
+        assert!(output[period * 3 / 4] < -0.9);
+    }
+}
+
+#[test]
+fn stays_locked_when_detuned() {
+    // A wrong frequency would let the phase drift, the resets pull it back
+    // to the start of the sub cycle.
+    let mut data = SubOscData::default();
+    data.waveform = SubWaveform::Sine;
+    let output = render(&data, 1.02, 4000);
+    for cycle in 1..19 {
+        let start = cycle * 200 + 1; // Output is delayed by one sample
+        assert!(output[start].abs() < 0.05, "{}: {}", cycle, output[start]);
+        assert!(output[start + 50] > 0.9);
+    }
+}
+
+} // mod tests
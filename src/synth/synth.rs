@@ -1,29 +1,85 @@
 use super::Delay;
-use super::{SynthMessage, UiMessage};
-use super::Envelope;
 use super::Lfo;
 use super::MidiMessage;
-use super::{Parameter, ParamId, SynthParam, MenuItem};
+use super::{Parameter, SynthParam};
+use super::{ModRouting, ModSourceSlot};
+use super::SampleRef;
 use super::SoundData;
 use super::voice::Voice;
-use super::Oscillator;
+use super::voice_pool::{VoiceBlock, VoicePool, MAX_POOL_BLOCK_SIZE};
+use super::wt_oscillator::MAX_VOICES as MAX_UNISON_VOICES;
 use super::Float;
 
-use std::sync::{Arc, Mutex};
-use std::thread::spawn;
+use std::time::Instant;
 
-use crossbeam_channel::{Sender, Receiver};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
 use log::{info, error};
 use rand::{Rng, thread_rng};
 use serde::{Serialize, Deserialize};
-use wavetable::{WtManager, WavetableRef, WtInfo};
+use wavetable::WavetableRef;
 
 pub const NUM_VOICES: usize = 32;
 const NUM_KEYS: usize = 128;
 pub const NUM_MODULATORS: usize = 16;
 pub const NUM_GLOBAL_LFOS: usize = 2;
+pub const MAX_BLOCK_SIZE: usize = 64; // Max. number of samples rendered with the same modulation values
+pub const DEFAULT_CONTROL_PERIOD: usize = 16; // Default number of samples between modulation updates
+pub const EVENT_QUEUE_SIZE: usize = 1024; // Max. number of messages handled per block
+pub const RELEASE_QUEUE_SIZE: usize = 64; // Max. number of replaced tables waiting to be freed
+pub const MAX_WAVETABLES: usize = 256;    // Number of wavetable IDs the synth has slots for
+pub const MAX_SAMPLES: usize = 128;       // Number of sample IDs the synth has slots for
+pub const DEFAULT_LOAD_THRESHOLD: Float = 0.8; // Share of the buffer time spent rendering above which voices are dropped
+const LOAD_RECOVERY_FACTOR: Float = 0.7; // Load relative to the threshold below which unison voices are restored
+const LOAD_RECOVERY_PERIODS: usize = 50; // Number of low load reports before restoring a unison voice
+const FADE_OUT_TIME: Float = 0.005;      // Seconds to fade out a dropped voice
 const REF_FREQUENCY: Float = 440.0;
 
+/// Engine settings of the synth, selected on the command line.
+#[derive(Clone, Copy, Debug)]
+pub struct SynthConfig {
+    pub control_period: usize, // Number of samples between modulation updates
+    pub voice_threads: usize,  // Number of threads rendering the voices
+    pub load_threshold: Float, // Overload protection threshold, 0.0 - 1.0
+}
+
+impl Default for SynthConfig {
+    fn default() -> Self {
+        SynthConfig{
+            control_period: DEFAULT_CONTROL_PERIOD,
+            voice_threads: 1,
+            load_threshold: DEFAULT_LOAD_THRESHOLD,
+        }
+    }
+}
+
+/// Messages processed by the synth in the audio thread.
+pub enum AudioMessage {
+    Midi(MidiMessage),
+    Param(SynthParam),
+    Sound(SoundData),
+    Wavetable(usize, WavetableRef), // ID and table that has been loaded
+    Sample(usize, SampleRef),       // ID and sample that has been loaded
+    Bpm(Float),
+}
+
+/// Data replaced in the synth, sent back to the control thread to be freed.
+///
+/// Dropping the last reference to a table frees its memory, which must not
+/// happen in the audio thread.
+pub enum ReleasedData {
+    Wavetable(WavetableRef),
+    Sample(SampleRef),
+}
+
+/// A message together with the time it was sent.
+///
+/// The time is used to place the message at the right frame in the next
+/// rendered block.
+pub struct SynthEvent {
+    pub time: Instant,
+    pub message: AudioMessage,
+}
+
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
 pub enum PlayMode {
     Poly,   // Polyphonic
@@ -187,8 +243,14 @@ pub struct Synth {
     sound: SoundData,        // Sound patch as loaded from disk
     sound_global: SoundData, // Sound with global modulators applied
     sound_local: SoundData,  // Sound with voice-local modulators applied
+    routing: ModRouting,     // Compiled modulators of the sound
+    control_period: usize,   // Number of samples between modulation updates
+    voice_pool: Option<VoicePool>, // Worker threads for rendering voices in parallel
+    sound_snapshots: Vec<SoundData>, // Global sound of every control period in a parallel block
     keymap: [Float; NUM_KEYS],
-    wt_manager: WtManager,
+    wavetables: Vec<Option<WavetableRef>>, // Loaded wavetables by ID
+    samples: Vec<Option<SampleRef>>,       // Loaded samples by ID
+    release_sender: Sender<ReleasedData>,  // Returns replaced tables to the control thread
 
     // Signal chain
     voice: [Voice; NUM_VOICES],
@@ -204,30 +266,42 @@ pub struct Synth {
     mod_wheel: Float,
     aftertouch: Float,
     sustain_pedal: Float, // Use a float, so that we can use it as mod source
-    sender: Sender<UiMessage>,
     global_state: SynthState,
     key_stack: Vec<u16>, // List of currently pressed keys (for Mono/ Legato modes)
     last_voice: usize, // Last voice selected with RoundRobin voice allocation
 
-    // Extra oscillators to display the waveshape
-    samplebuff_osc: Oscillator,
-    samplebuff_env: Envelope,
-    samplebuff_lfo: Lfo,
-    osc_wave: [WavetableRef; 3],
+    // Overload protection
+    load_threshold: Float,    // Load above which voices are dropped
+    load_calm_periods: usize, // Number of consecutive low load reports
+    unison_limit: i64,        // Max. number of unison voices per oscillator
+    dropped_voices: usize,    // Number of voices stopped because of overload
+
+    // Message handling
+    event_receiver: Receiver<SynthEvent>,
+    events: Vec<(usize, AudioMessage)>, // Messages for the current block with their frame offset
+    last_block_time: Option<Instant>,   // Time the previous block was started
 }
 
 impl Synth {
-    pub fn new(sample_rate: u32, sender: Sender<UiMessage>) -> Self {
+    /// Create a new synth.
+    ///
+    /// The wavetables are the tables available at startup, with table 0
+    /// being the default table for all oscillators. Messages sent to the
+    /// event receiver are handled at the start of the next rendered block.
+    /// Tables that get replaced are passed to the release sender.
+    pub fn new(sample_rate: u32,
+               mut wavetables: Vec<Option<WavetableRef>>,
+               release_sender: Sender<ReleasedData>,
+               event_receiver: Receiver<SynthEvent>) -> Self {
         let mut sound = SoundData::new();
         let mut sound_global = SoundData::new();
         let mut sound_local = SoundData::new();
         sound.init();
         sound_global.init();
         sound_local.init();
-        let mut wt_manager = WtManager::new(sample_rate as Float, "data");
-        wt_manager.add_basic_tables(0);
-        wt_manager.add_pwm_tables(1, 64);
-        let default_table = wt_manager.get_table(0).unwrap(); // Table 0
+        let default_table = wavetables[0].clone().unwrap(); // Table 0
+        // All slots are allocated here, to avoid allocations in the audio thread
+        wavetables.resize(MAX_WAVETABLES, None);
         let mut voice = [
             Voice::new(sample_rate, default_table.clone()), Voice::new(sample_rate, default_table.clone()), Voice::new(sample_rate, default_table.clone()), Voice::new(sample_rate, default_table.clone()),
             Voice::new(sample_rate, default_table.clone()), Voice::new(sample_rate, default_table.clone()), Voice::new(sample_rate, default_table.clone()), Voice::new(sample_rate, default_table.clone()),
@@ -246,14 +320,19 @@ impl Synth {
         ];
         let mut keymap: [Float; NUM_KEYS] = [0.0; NUM_KEYS];
         Synth::calculate_keymap(&mut keymap, REF_FREQUENCY);
-        let osc_wave = [default_table.clone(), default_table.clone(), default_table.clone()];
         Synth{
             sample_rate,
             sound,
             sound_global,
             sound_local,
+            routing: ModRouting::new(),
+            control_period: DEFAULT_CONTROL_PERIOD,
+            voice_pool: None,
+            sound_snapshots: Vec::with_capacity(MAX_POOL_BLOCK_SIZE / DEFAULT_CONTROL_PERIOD),
             keymap,
-            wt_manager,
+            wavetables,
+            samples: vec!(None; MAX_SAMPLES),
+            release_sender,
             voice,
             delay: Delay::new(sample_rate),
             glfo,
@@ -265,44 +344,17 @@ impl Synth {
             mod_wheel: 0.0,
             aftertouch: 0.0,
             sustain_pedal: 0.0,
-            sender,
             global_state: SynthState{freq_factor: 1.0},
             key_stack: vec!(0; 128),
             last_voice: NUM_VOICES,
-            samplebuff_osc: Oscillator::new(sample_rate, default_table.clone()),
-            samplebuff_env: Envelope::new(sample_rate as Float),
-            samplebuff_lfo: Lfo::new(sample_rate),
-            osc_wave,
-        }
-    }
-
-    /// Starts a thread for receiving UI and MIDI messages.
-    pub fn run(synth: Arc<Mutex<Synth>>, synth_receiver: Receiver<SynthMessage>) -> std::thread::JoinHandle<()> {
-        let handler = spawn(move || {
-            let mut keep_running = true;
-            while keep_running {
-                let msg = synth_receiver.recv().unwrap();
-                let mut locked_synth = synth.lock().unwrap();
-                match msg {
-                    SynthMessage::Param(m) => locked_synth.handle_ui_message(m),
-                    SynthMessage::Midi(m)  => locked_synth.handle_midi_message(m),
-                    SynthMessage::Sound(s) => locked_synth.handle_sound_update(&s),
-                    SynthMessage::Wavetable(i) => locked_synth.handle_wavetable_info(i),
-                    SynthMessage::SampleBuffer(m, p) => locked_synth.handle_sample_buffer(m, p),
-                    SynthMessage::Bpm(b) => locked_synth.handle_bpm(b),
-                    SynthMessage::Exit     => {
-                        keep_running = false;
-                        locked_synth.exit();
-                    }
-                }
-            }
-        });
-        handler
-    }
-
-    fn exit(&mut self) {
-        // Do exit stuff here
-        info!("Stopping synth engine");
+            load_threshold: DEFAULT_LOAD_THRESHOLD,
+            load_calm_periods: 0,
+            unison_limit: MAX_UNISON_VOICES as i64,
+            dropped_voices: 0,
+            event_receiver,
+            events: Vec::with_capacity(EVENT_QUEUE_SIZE),
+            last_block_time: None,
+        }
     }
 
     fn reset(&mut self) {
@@ -311,55 +363,146 @@ impl Synth {
         self.key_stack.clear();
     }
 
+    /// Set the number of samples between modulation updates.
+    ///
+    /// The value is limited to 1 - MAX_BLOCK_SIZE. Level, pitch and cutoff
+    /// changes are interpolated between updates.
+    pub fn set_control_period(&mut self, period: usize) {
+        self.control_period = std::cmp::min(std::cmp::max(period, 1), MAX_BLOCK_SIZE);
+        let num_periods = (MAX_POOL_BLOCK_SIZE + self.control_period - 1) / self.control_period;
+        self.sound_snapshots.reserve(num_periods);
+        info!("Modulation control period set to {} samples", self.control_period);
+    }
+
+    /// Set the number of threads used for rendering the voices.
+    ///
+    /// With more than one thread, the active voices are split between the
+    /// audio thread and a pool of worker threads. The output is the same as
+    /// with a single thread. Must not be called from the audio thread, since
+    /// it starts and stops threads.
+    pub fn set_voice_threads(&mut self, num_threads: usize) {
+        self.voice_pool = if num_threads > 1 {
+            Some(VoicePool::new(num_threads))
+        } else {
+            None
+        };
+    }
+
+    /// Set the load above which the overload protection drops voices.
+    ///
+    /// The load is the share of the buffer time spent rendering. A value of
+    /// 1.0 or more disables the overload protection.
+    pub fn set_load_threshold(&mut self, threshold: Float) {
+        self.load_threshold = threshold;
+        info!("Overload threshold set to {}", threshold);
+    }
+
+    /// Apply the engine settings.
+    pub fn configure(&mut self, config: &SynthConfig) {
+        self.set_control_period(config.control_period);
+        self.set_voice_threads(config.voice_threads);
+        self.set_load_threshold(config.load_threshold);
+    }
+
+    /// Adapt the work done by the synth to the load of the audio thread.
+    ///
+    /// Called by the audio engine after every buffer, with the share of the
+    /// buffer time that was spent rendering. While the load is above the
+    /// threshold, the oldest released voice is faded out with every call. If
+    /// no released voice is left, the number of unison voices is reduced
+    /// instead. Unison voices are restored one at a time once the load has
+    /// stayed low for a while.
+    pub fn handle_load(&mut self, load: Float) {
+        if load > self.load_threshold {
+            self.load_calm_periods = 0;
+            if let Some(voice_id) = self.find_oldest_released_voice() {
+                let num_samples = (FADE_OUT_TIME * self.sample_rate as Float) as usize;
+                self.voice[voice_id].fade_out(num_samples);
+                self.dropped_voices += 1;
+                info!("Load {:.2}, dropping voice {}", load, voice_id);
+            } else if self.unison_limit > 1 {
+                self.unison_limit -= 1;
+                self.apply_unison_limit();
+                info!("Load {:.2}, limiting unison to {} voices", load, self.unison_limit);
+            }
+        } else if load < self.load_threshold * LOAD_RECOVERY_FACTOR
+               && self.unison_limit < MAX_UNISON_VOICES as i64 {
+            self.load_calm_periods += 1;
+            if self.load_calm_periods >= LOAD_RECOVERY_PERIODS {
+                self.load_calm_periods = 0;
+                self.unison_limit += 1;
+                self.apply_unison_limit();
+                info!("Load {:.2}, limiting unison to {} voices", load, self.unison_limit);
+            }
+        } else {
+            self.load_calm_periods = 0;
+        }
+    }
+
+    /// Returns the number of voices dropped because of overload.
+    pub fn get_dropped_voices(&self) -> usize {
+        self.dropped_voices
+    }
+
+    // Find the voice that was triggered first among the released voices
+    // that are still playing.
+    fn find_oldest_released_voice(&self) -> Option<usize> {
+        let mut oldest: Option<usize> = None;
+        for (i, v) in self.voice.iter().enumerate() {
+            if !v.is_running() || v.is_triggered() || v.is_fading() {
+                continue;
+            }
+            match oldest {
+                Some(o) if self.voice[o].trigger_seq <= v.trigger_seq => (),
+                _ => oldest = Some(i),
+            }
+        }
+        oldest
+    }
+
+    // Limit the number of unison voices in the oscillators.
+    //
+    // The oscillators apply the limit to the modulated sound data, so it
+    // also holds if the number of voices is a modulation target.
+    fn apply_unison_limit(&mut self) {
+        let limit = self.unison_limit as usize;
+        self.voice.iter_mut().for_each(|v| v.set_unison_limit(limit));
+    }
+
     // Get global modulation values.
     //
     // Calculates the values for global modulation sources and applies them to
-    // the global sound data.
+    // the global sound data. Only the modulated parameters are touched, the
+    // rest of the global sound is kept up to date by update_modulation().
     //
     fn get_mod_values(&mut self, sample_clock: i64) {
-        // Reset the global sound copy to the main sound, discarding values that
-        // were modulated for the previous sample. Complete copy is faster than
-        // looping over the modulators.
-        self.sound_global = self.sound;
+        // Discard the values that were modulated for the previous update
+        self.routing.reset_targets(&self.sound, &mut self.sound_global);
 
         // Then apply global modulators
-        let mut param_id = ParamId{..Default::default()};
-        let mut synth_param = SynthParam{..Default::default()};
-        for m in self.sound.modul.iter() {
-            if !m.active || !m.is_global {
-                continue;
-            }
-
-            // Get modulator source output
-            let mod_val: Float = match m.source_func {
-                Parameter::GlobalLfo => {
-                    let (val, _) = self.glfo[m.source_func_id - 1].get_sample(sample_clock, &self.sound_global.glfo[m.source_func_id - 1], false);
+        for route in self.routing.get_global_routes() {
+            let mod_val: Float = match route.source {
+                ModSourceSlot::GlobalLfo(id) => {
+                    let (val, _) = self.glfo[id].get_sample(sample_clock, &self.sound_global.glfo[id], false);
                     val
                 },
-                Parameter::Aftertouch => self.aftertouch,
-                Parameter::Pitchbend => self.pitch_bend,
-                Parameter::ModWheel => self.mod_wheel,
-                Parameter::SustainPedal => self.sustain_pedal,
+                ModSourceSlot::Aftertouch => self.aftertouch,
+                ModSourceSlot::Pitchbend => self.pitch_bend,
+                ModSourceSlot::ModWheel => self.mod_wheel,
+                ModSourceSlot::SustainPedal => self.sustain_pedal,
                 _ => 0.0,
-            } * m.scale;
-
-
-            // Get current value of target parameter
-            param_id.set(m.target_func, m.target_func_id, m.target_param);
-            let mut current_val = self.sound_global.get_value(&param_id);
-            let mut val = current_val.as_float();
-
-            // Update value
-            let dest_range = MenuItem::get_val_range(param_id.function, param_id.parameter);
-            val = dest_range.safe_add(val, mod_val);
-
-            // Update parameter in global sound data
-            current_val.set_from_float(val);
-            synth_param.set(m.target_func, m.target_func_id, m.target_param, current_val);
-            self.sound_global.set_parameter(&synth_param);
+            } * route.scale;
+            self.routing.get_targets()[route.target].apply(&mut self.sound_global, mod_val);
         }
     }
 
+    // The sound has changed, update the modulated copies and the routes.
+    fn update_modulation(&mut self) {
+        self.sound_global = self.sound;
+        self.sound_local = self.sound;
+        self.routing.compile(&self.sound.modul);
+    }
+
     /// Called by the audio engine to get the next sample to be output.
     pub fn get_sample(&mut self, sample_clock: i64) -> (Float, Float) {
         let mut value_l: Float = 0.0;
@@ -371,7 +514,7 @@ impl Synth {
         if self.voices_playing > 0 {
             for i in 0..32 {
                 if self.voices_playing & (1 << i) > 0 {
-                    let (sample_l, sample_r) = self.voice[i].get_sample(sample_clock, &self.sound_global, &mut self.sound_local, &self.global_state);
+                    let (sample_l, sample_r) = self.voice[i].get_sample(sample_clock, &self.sound_global, &mut self.sound_local, &self.routing, &self.global_state);
                     value_l += sample_l;
                     value_r += sample_r;
                 }
@@ -394,6 +537,216 @@ impl Synth {
         (value_l, value_r)
     }
 
+    /// Render a block of samples into the output buffers.
+    ///
+    /// Messages received since the last call are handled first. Each of them
+    /// is applied at the frame matching the time it was sent during the
+    /// previous block, so the timing of events is kept at the cost of one
+    /// block of latency.
+    pub fn process_block(&mut self, left: &mut [f32], right: &mut [f32]) {
+        self.receive_events(left.len());
+
+        // Render up to the next event, then handle the event
+        let mut events = std::mem::replace(&mut self.events, Vec::new());
+        let mut pending = events.drain(..).peekable();
+        let mut offset = 0;
+        loop {
+            while let Some((frame, _)) = pending.peek() {
+                if *frame > offset {
+                    break;
+                }
+                if let Some((_, message)) = pending.next() {
+                    self.handle_message(message);
+                }
+            }
+            if offset >= left.len() {
+                break;
+            }
+            let end = match pending.peek() {
+                Some((frame, _)) => *frame,
+                None => left.len(),
+            };
+            self.render_block(&mut left[offset..end], &mut right[offset..end]);
+            offset = end;
+        }
+        drop(pending);
+        self.events = events; // Keep the allocated buffer
+    }
+
+    // Get pending messages from the queue and calculate their frame offsets.
+    //
+    // A message keeps the distance to the start of the block during which it
+    // was sent, limited to the length of the current block.
+    fn receive_events(&mut self, num_frames: usize) {
+        let block_start = self.last_block_time.replace(Instant::now());
+        let last_frame = if num_frames > 0 { num_frames - 1 } else { 0 };
+        let mut min_frame = 0;
+        while self.events.len() < EVENT_QUEUE_SIZE {
+            let event = match self.event_receiver.try_recv() {
+                Ok(e) => e,
+                Err(_) => break,
+            };
+            let frame = match block_start {
+                Some(start) if event.time > start => {
+                    ((event.time - start).as_secs_f64() * self.sample_rate as f64) as usize
+                }
+                _ => 0,
+            };
+            // Keep the order in which the messages were sent
+            let frame = std::cmp::max(std::cmp::min(frame, last_frame), min_frame);
+            min_frame = frame;
+            self.events.push((frame, event.message));
+        }
+    }
+
+    /// Handle a message received from the control thread.
+    pub fn handle_message(&mut self, message: AudioMessage) {
+        match message {
+            AudioMessage::Param(m) => self.handle_ui_message(m),
+            AudioMessage::Midi(m)  => self.handle_midi_message(m),
+            AudioMessage::Sound(s) => self.handle_sound_update(&s),
+            AudioMessage::Wavetable(id, wt) => self.handle_wavetable(id, wt),
+            AudioMessage::Sample(id, sample) => self.handle_sample(id, sample),
+            AudioMessage::Bpm(b) => self.handle_bpm(b),
+        }
+    }
+
+    // Render a block of samples without handling any messages.
+    //
+    // Continues at the sample following the last rendered one. Modulation
+    // values are updated every control period, so longer buffers are split
+    // into multiple blocks.
+    fn render_block(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if self.voice_pool.is_some() && self.voices_playing.count_ones() > 1 {
+            self.render_block_parallel(left, right);
+            return;
+        }
+        let mut mix_l = [0.0; MAX_BLOCK_SIZE];
+        let mut mix_r = [0.0; MAX_BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < left.len() {
+            let len = std::cmp::min(self.control_period, left.len() - offset);
+            let sample_clock = self.last_clock + 1;
+            let mix_l = &mut mix_l[..len];
+            let mix_r = &mut mix_r[..len];
+            for (l, r) in mix_l.iter_mut().zip(mix_r.iter_mut()) {
+                *l = 0.0;
+                *r = 0.0;
+            }
+
+            self.get_mod_values(sample_clock);
+
+            // Get samples of all active voices
+            if self.voices_playing > 0 {
+                for i in 0..32 {
+                    if self.voices_playing & (1 << i) > 0 {
+                        self.voice[i].process_block(sample_clock, &self.sound_global, &mut self.sound_local, &self.routing, &self.global_state, mix_l, mix_r);
+                    }
+                }
+            }
+
+            // Apply clipping
+            if self.sound_global.patch.drive > 0.0 {
+                for (l, r) in mix_l.iter_mut().zip(mix_r.iter_mut()) {
+                    *l = (*l * self.sound_global.patch.drive).tanh();
+                    *r = (*r * self.sound_global.patch.drive).tanh();
+                }
+            }
+
+            // Pass samples into global effects
+            self.delay.process_block(mix_l, mix_r, sample_clock, &self.sound_global.delay);
+
+            let level = self.sound_global.patch.level;
+            for i in 0..len {
+                left[offset + i] = (mix_l[i] * level) as f32;
+                right[offset + i] = (mix_r[i] * level) as f32;
+            }
+            self.last_clock += len as i64;
+            offset += len;
+        }
+    }
+
+    // Render a block of samples with the voices split between threads.
+    //
+    // Global modulation has to be calculated in order, so the global sound
+    // of every control period is stored before the voices are rendered. The
+    // voice outputs are then mixed in voice order, giving exactly the same
+    // result as render_block() with a single thread.
+    fn render_block_parallel(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let mut pool = match self.voice_pool.take() {
+            Some(p) => p,
+            None => return,
+        };
+        let mut mix_l = [0.0; MAX_BLOCK_SIZE];
+        let mut mix_r = [0.0; MAX_BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < left.len() {
+            let len = std::cmp::min(MAX_POOL_BLOCK_SIZE, left.len() - offset);
+            let sample_clock = self.last_clock + 1;
+
+            self.sound_snapshots.clear();
+            let mut start = 0;
+            while start < len {
+                self.get_mod_values(sample_clock + start as i64);
+                self.sound_snapshots.push(self.sound_global);
+                start += self.control_period;
+            }
+
+            let block = VoiceBlock{
+                sample_clock,
+                len,
+                control_period: self.control_period,
+                sound_global: &self.sound_snapshots,
+                sound_local: &self.sound_local,
+                routing: &self.routing,
+                global_state: &self.global_state,
+            };
+            pool.render(&mut self.voice, self.voices_playing, &block);
+
+            for (period, sound) in self.sound_snapshots.iter().enumerate() {
+                let start = period * self.control_period;
+                let period_len = std::cmp::min(self.control_period, len - start);
+                let mix_l = &mut mix_l[..period_len];
+                let mix_r = &mut mix_r[..period_len];
+                for (l, r) in mix_l.iter_mut().zip(mix_r.iter_mut()) {
+                    *l = 0.0;
+                    *r = 0.0;
+                }
+
+                // Sum the voices in the same order as a single thread would
+                for i in 0..NUM_VOICES {
+                    if self.voices_playing & (1 << i) > 0 {
+                        let (voice_l, voice_r) = pool.get_output(i, len);
+                        for j in 0..period_len {
+                            mix_l[j] += voice_l[start + j];
+                            mix_r[j] += voice_r[start + j];
+                        }
+                    }
+                }
+
+                // Apply clipping
+                if sound.patch.drive > 0.0 {
+                    for (l, r) in mix_l.iter_mut().zip(mix_r.iter_mut()) {
+                        *l = (*l * sound.patch.drive).tanh();
+                        *r = (*r * sound.patch.drive).tanh();
+                    }
+                }
+
+                // Pass samples into global effects
+                self.delay.process_block(mix_l, mix_r, sample_clock + start as i64, &sound.delay);
+
+                let level = sound.patch.level;
+                for i in 0..period_len {
+                    left[offset + start + i] = (mix_l[i] * level) as f32;
+                    right[offset + start + i] = (mix_r[i] * level) as f32;
+                }
+            }
+            self.last_clock += len as i64;
+            offset += len;
+        }
+        self.voice_pool = Some(pool);
+    }
+
     /// Update the bitmap with currently active voices.
     pub fn update(&mut self) {
         self.voices_playing = 0;
@@ -404,6 +757,11 @@ impl Synth {
         }
     }
 
+    /// Returns true if any voice is still producing sound.
+    pub fn is_playing(&self) -> bool {
+        self.voices_playing > 0
+    }
+
     // Calculates the frequencies for the default keymap with equal temperament.
     fn calculate_keymap(map: &mut[Float; 128], reference_pitch: Float) {
         for i in 0..128 {
@@ -430,6 +788,10 @@ impl Synth {
                         let osc_id = msg.function_id - 1;
                         self.update_wavetable(osc_id);
                     }
+                    Parameter::Sample => {
+                        let osc_id = msg.function_id - 1;
+                        self.update_sample(osc_id);
+                    }
                     Parameter::Routing => {
                         // Oscillator routing has changed
                         let osc_id = msg.function_id - 1;
@@ -445,6 +807,11 @@ impl Synth {
                     _ => ()
                 }
             }
+            Parameter::SubOscillator => {
+                if let Parameter::Routing = msg.parameter {
+                    self.update_sub_routing();
+                }
+            }
             Parameter::Patch => {
                 match msg.parameter {
                     Parameter::Bpm => self.delay.update_bpm(&mut self.sound.delay, self.sound.patch.bpm),
@@ -455,6 +822,7 @@ impl Synth {
             }
             _ => ()
         }
+        self.update_modulation();
     }
 
     fn update_delay_speed(&mut self) {
@@ -464,22 +832,42 @@ impl Synth {
     fn update_wavetable(&mut self, osc_id: usize) {
         let id = self.sound.osc[osc_id].wt_osc_data.wavetable;
         info!("Updating oscillator {} to wavetable {}", osc_id, id);
-        let result = self.wt_manager.get_table(id);
+        let result = self.wavetables.get(id);
         match result {
-            Some(wt) => {
+            Some(Some(wt)) => {
                 self.voice.iter_mut().for_each(|v| v.set_wavetable(osc_id, wt.clone()));
-                self.osc_wave[osc_id] = wt.clone();
             }
-            None => error!("Unable to find wavetable {}",id),
+            _ => error!("Unable to find wavetable {}",id),
         }
     }
 
+    // The assigned sample of an oscillator has changed.
+    fn update_sample(&mut self, osc_id: usize) {
+        let id = self.sound.osc[osc_id].sample_data.sample;
+        let sample = match self.samples.get(id) {
+            Some(Some(s)) => Some(s.clone()),
+            _ => {
+                if id > 0 {
+                    error!("Unable to find sample {}", id);
+                }
+                None
+            }
+        };
+        self.voice.iter_mut().for_each(|v| v.set_sample(osc_id, sample.clone()));
+    }
+
     fn update_routing(&mut self, osc_id: usize) {
         for v in self.voice.iter_mut() {
             v.update_routing(osc_id, &self.sound.osc[osc_id]);
         }
     }
 
+    fn update_sub_routing(&mut self) {
+        for v in self.voice.iter_mut() {
+            v.update_sub_routing(&self.sound.sub);
+        }
+    }
+
     fn update_voice_allocation(&mut self) {
         let num_voices = self.sound.patch.num_voices;
         let spread = self.sound.patch.voice_spread;
@@ -519,7 +907,7 @@ impl Synth {
         }
     }
 
-    fn handle_midi_message(&mut self, msg: MidiMessage) {
+    pub fn handle_midi_message(&mut self, msg: MidiMessage) {
         match msg {
             MidiMessage::NoteOn{channel: _, key, velocity} => self.handle_note_on(key, velocity),
             MidiMessage::NoteOff{channel: _, key, velocity} => self.handle_note_off(key, velocity),
@@ -539,28 +927,74 @@ impl Synth {
     }
 
     // UI has sent a new sound patch.
-    fn handle_sound_update(&mut self, sound: &SoundData) {
+    pub fn handle_sound_update(&mut self, sound: &SoundData) {
         self.reset();
         self.sound = *sound;
-        self.sound_global = self.sound;
-        self.sound_local = self.sound;
+        self.update_modulation();
         self.update_wavetable(0);
         self.update_wavetable(1);
         self.update_wavetable(2);
+        self.update_sample(0);
+        self.update_sample(1);
+        self.update_sample(2);
         self.update_routing(0);
         self.update_routing(1);
         self.update_routing(2);
+        self.update_sub_routing();
         self.update_voice_allocation();
     }
 
-    fn handle_wavetable_info(&mut self, mut wt_info: WtInfo) {
-        self.wt_manager.load_table(&mut wt_info, self.wt_manager.get_table(0).unwrap(), false);
+    // A wavetable has been loaded by the control thread.
+    fn handle_wavetable(&mut self, id: usize, wt: WavetableRef) {
+        if id >= MAX_WAVETABLES {
+            error!("Wavetable ID {} is out of range", id);
+            self.release(ReleasedData::Wavetable(wt));
+            return;
+        }
+        if let Some(old_wt) = self.wavetables[id].replace(wt) {
+            self.release(ReleasedData::Wavetable(old_wt));
+        }
+
+        // Oscillators might already be using an older version of the table
+        for osc_id in 0..3 {
+            if self.sound.osc[osc_id].wt_osc_data.wavetable == id {
+                self.update_wavetable(osc_id);
+            }
+        }
+    }
+
+    // A sample has been loaded by the control thread.
+    fn handle_sample(&mut self, id: usize, sample: SampleRef) {
+        if id >= MAX_SAMPLES {
+            error!("Sample ID {} is out of range", id);
+            self.release(ReleasedData::Sample(sample));
+            return;
+        }
+        if let Some(old_sample) = self.samples[id].replace(sample) {
+            self.release(ReleasedData::Sample(old_sample));
+        }
+
+        for osc_id in 0..3 {
+            if self.sound.osc[osc_id].sample_data.sample == id {
+                self.update_sample(osc_id);
+            }
+        }
+    }
+
+    // Pass replaced data to the control thread for freeing.
+    //
+    // If the queue is full, the data is dropped here as a last resort.
+    fn release(&self, data: ReleasedData) {
+        if let Err(TrySendError::Full(_)) = self.release_sender.try_send(data) {
+            error!("Release queue is full, freeing data in the audio thread");
+        }
     }
 
     /// Received updated BPM by TimingClock MIDI message
     fn handle_bpm(&mut self, bpm: Float) {
         self.sound.patch.bpm = bpm;
         self.delay.update_bpm(&mut self.sound.delay, bpm);
+        self.update_modulation();
     }
 
     fn handle_note_on(&mut self, key: u8, velocity: u8) {
@@ -725,86 +1159,740 @@ impl Synth {
         self.last_voice = rand.gen_range(0, self.sound.patch.num_voices);
         self.select_voice_round_robin()
     }
+}
 
-    // Fill a received buffer with samples from the model oscillator/ envelope.
-    //
-    // This puts one wave cycle of the currently selected oscillator or
-    // envelope or LFO into the buffer.
-    //
-    fn handle_sample_buffer(&mut self, mut buffer: Vec<Float>, param: SynthParam) {
-        let len = buffer.capacity();
-        let freq = self.sample_rate as Float / len as Float;
-        match param.function {
-            Parameter::Oscillator => {
-                let osc = &mut self.samplebuff_osc;
-                osc.reset(0);
-                let osc_id = param.function_id - 1;
-                osc.set_wavetable(self.osc_wave[osc_id].clone());
-                for i in 0..len {
-                    let (mut sample, _) = osc.get_sample(freq, i as i64, &self.sound.osc[osc_id], false);
-
-                    // Apply clipping
-                    if self.sound_global.patch.drive > 0.0 {
-                        sample = (sample * self.sound_global.patch.drive).tanh();
-                    }
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
 
-                    buffer[i] = sample * self.sound.osc[param.function_id - 1].level;
-                }
-            },
-            Parameter::Envelope => {
-                let env_data = &mut self.sound.env[param.function_id - 1];
-                let mut len_total = env_data.delay + env_data.attack + env_data.decay + env_data.release;
-                if !env_data.looping {
-                    len_total += len_total / 3.0; // Add 25% duration for sustain, value is in ms
-                }
-                let mut release_point = len_total - env_data.release;
-                len_total *= 44.1; // Samples per second
-                release_point *= 44.1;
-                let samples_per_slot = (len_total / len as Float) as usize; // Number of samples per slot in the buffer
-                let mut index: usize = 0;
-                let mut counter: usize = 0;
-                let len_total = len_total as usize;
-                let release_point = release_point as usize;
-                let mut sample = 0.0;
-                let env = &mut self.samplebuff_env;
-                env.trigger(0, env_data);
-                for i in 0..len_total {
-                    if i == release_point {
-                        env.release(i as i64, env_data);
-                    }
-                    sample += env.get_sample(i as i64, env_data);
-                    counter += 1;
-                    if counter == samples_per_slot {
-                        sample /= samples_per_slot as Float;
-                        buffer[index] = sample;
-                        index += 1;
-                        if index == len {
-                            index -= 1;
-                        }
-                        sample = 0.0;
-                        counter = 0;
-                    }
-                }
-            },
-            Parameter::Lfo | Parameter::GlobalLfo => {
-                let lfo = &mut self.samplebuff_lfo;
-                let mut sound_copy = if let Parameter::Lfo = param.function {
-                    self.sound.lfo[param.function_id - 1]
-                } else {
-                    self.sound.glfo[param.function_id - 1]
-                };
-                lfo.reset(0, sound_copy.phase);
-                sound_copy.frequency = freq;
-                // Get first sample explicitly to reset LFO (for S&H)
-                let (sample, _) = lfo.get_sample(0, &sound_copy, true);
-                buffer[0] = sample;
-                for i in 1..len {
-                    let (sample, _) = lfo.get_sample(i as i64, &sound_copy, false);
-                    buffer[i] = sample;
-                }
-            },
-            _ => {},
+#[cfg(test)]
+mod tests {
+
+use super::{Synth, SynthEvent, AudioMessage, PlayMode, ReleasedData, LOAD_RECOVERY_PERIODS, MAX_SAMPLES, RELEASE_QUEUE_SIZE};
+use super::super::Float;
+use super::super::MidiMessage;
+use super::super::SoundData;
+use super::super::{CrossMod, Excitation, OscType, Sample, SubWaveform, VaWaveform};
+
+use std::sync::Arc;
+use super::super::{SynthControl, UiMessage};
+use crate::{Parameter, ParamId, FunctionId};
+
+use crossbeam_channel::{bounded, unbounded, Sender};
+
+use std::time::{Duration, Instant};
+
+fn create_empty_synth() -> (Synth, Sender<SynthEvent>) {
+    let (ui_sender, _ui_receiver) = unbounded::<UiMessage>();
+    let (sender, receiver) = unbounded::<SynthEvent>();
+    let control = SynthControl::new(44100, ui_sender, sender.clone());
+    let synth = Synth::new(44100, control.get_wavetables(), control.get_release_sender(), receiver);
+    (synth, sender)
+}
+
+fn create_synth() -> Synth {
+    let (mut synth, _sender) = create_empty_synth();
+    let mut sound = synth.sound;
+    sound.osc[0].wt_osc_data.set_voice_num(3);
+    sound.osc[0].wt_osc_data.set_voice_spread(0.2);
+    sound.osc[1].sync = 1;
+    sound.osc[1].set_halfsteps(7);
+    sound.delay.level = 0.5;
+    synth.handle_sound_update(&sound);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 60, velocity: 100});
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 64, velocity: 80});
+    synth
+}
+
+// The synth is too big for the default stack of the test threads
+fn run_with_big_stack(test: fn()) {
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(test)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+// Renders the given number of samples in blocks of 64 and returns the left
+// channel.
+fn render_blocks(synth: &mut Synth, len: usize) -> Vec<f32> {
+    let mut left = vec!(0.0f32; len);
+    let mut right = vec!(0.0f32; len);
+    for offset in (0..len).step_by(64) {
+        let end = std::cmp::min(offset + 64, len);
+        synth.process_block(&mut left[offset..end], &mut right[offset..end]);
+        synth.update();
+    }
+    left
+}
+
+#[test]
+fn block_rendering_matches_single_samples() {
+    run_with_big_stack(block_rendering_matches_single_samples_test);
+}
+
+fn block_rendering_matches_single_samples_test() {
+    let mut synth_single = create_synth();
+    let mut synth_block = create_synth();
+    let mut left = [0.0f32; 100];
+    let mut right = [0.0f32; 100];
+    for block in 0..20 {
+        synth_block.process_block(&mut left, &mut right);
+        for i in 0..100 {
+            let (l, r) = synth_single.get_sample(block * 100 + i as i64 + 1);
+            assert_eq!(l as f32, left[i]);
+            assert_eq!(r as f32, right[i]);
+        }
+        if block == 10 {
+            synth_single.handle_midi_message(MidiMessage::NoteOff{channel: 0, key: 60, velocity: 0});
+            synth_block.handle_midi_message(MidiMessage::NoteOff{channel: 0, key: 60, velocity: 0});
         }
-        self.sender.send(UiMessage::SampleBuffer(buffer, param)).unwrap();
+        synth_single.update();
+        synth_block.update();
+    }
+}
+
+// Adds a local and a global modulator to the sound of the synth.
+fn add_modulators(synth: &mut Synth) {
+    let mut sound = synth.sound;
+    sound.modul[0].set_source(&FunctionId{function: Parameter::Lfo, function_id: 1, ..Default::default()});
+    sound.modul[0].set_target(&ParamId::new(Parameter::Oscillator, 1, Parameter::Level));
+    sound.modul[0].set_amount(0.5);
+    sound.modul[0].active = true;
+    sound.modul[1].set_source(&FunctionId{function: Parameter::GlobalLfo, function_id: 1, ..Default::default()});
+    sound.modul[1].set_target(&ParamId::new(Parameter::Filter, 1, Parameter::Cutoff));
+    sound.modul[1].set_amount(0.2);
+    sound.modul[1].active = true;
+    sound.lfo[0].frequency = 50.0;
+    sound.glfo[0].frequency = 30.0;
+    synth.handle_sound_update(&sound);
+}
+
+#[test]
+fn control_period_one_matches_single_samples() {
+    run_with_big_stack(control_period_one_matches_single_samples_test);
+}
+
+fn control_period_one_matches_single_samples_test() {
+    let mut synth_single = create_synth();
+    let mut synth_block = create_synth();
+    add_modulators(&mut synth_single);
+    add_modulators(&mut synth_block);
+    synth_block.set_control_period(1);
+    synth_single.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 67, velocity: 100});
+    synth_block.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 67, velocity: 100});
+    let mut left = [0.0f32; 100];
+    let mut right = [0.0f32; 100];
+    for block in 0..20 {
+        synth_block.process_block(&mut left, &mut right);
+        for i in 0..100 {
+            let (l, r) = synth_single.get_sample(block * 100 + i as i64 + 1);
+            assert!((l as f32 - left[i]).abs() < 1e-5);
+            assert!((r as f32 - right[i]).abs() < 1e-5);
+        }
+        synth_single.update();
+        synth_block.update();
+    }
+}
+
+#[test]
+fn modulation_changes_output() {
+    run_with_big_stack(modulation_changes_output_test);
+}
+
+fn modulation_changes_output_test() {
+    let mut synth_plain = create_synth();
+    let mut synth_mod = create_synth();
+    add_modulators(&mut synth_mod);
+    synth_plain.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 67, velocity: 100});
+    synth_mod.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 67, velocity: 100});
+    let mut left_plain = [0.0f32; 512];
+    let mut right_plain = [0.0f32; 512];
+    let mut left_mod = [0.0f32; 512];
+    let mut right_mod = [0.0f32; 512];
+    synth_plain.process_block(&mut left_plain, &mut right_plain);
+    synth_mod.process_block(&mut left_mod, &mut right_mod);
+    assert!(left_plain.iter().zip(left_mod.iter()).any(|(a, b)| a != b));
+
+    // The modulated values must not leak into the unmodulated sound
+    assert_eq!(synth_mod.sound.osc[0].level, synth_plain.sound.osc[0].level);
+    assert_eq!(synth_mod.sound.filter[0].cutoff, synth_plain.sound.filter[0].cutoff);
+}
+
+#[test]
+fn events_are_applied_at_their_frame_offset() {
+    run_with_big_stack(events_are_applied_at_their_frame_offset_test);
+}
+
+fn events_are_applied_at_their_frame_offset_test() {
+    let note_on = MidiMessage::NoteOn{channel: 0, key: 60, velocity: 100};
+    let (mut synth_queued, sender) = create_empty_synth();
+    let (mut synth_direct, _) = create_empty_synth();
+
+    // Message sent 20 frames after the start of the previous block
+    let start = Instant::now();
+    synth_queued.last_block_time = Some(start);
+    let time = start + Duration::from_secs_f64(20.5 / 44100.0);
+    sender.send(SynthEvent{time, message: AudioMessage::Midi(note_on)}).unwrap();
+
+    let mut left = [0.0f32; 64];
+    let mut right = [0.0f32; 64];
+    synth_queued.process_block(&mut left, &mut right);
+
+    let mut expected_left = [0.0f32; 64];
+    let mut expected_right = [0.0f32; 64];
+    synth_direct.process_block(&mut expected_left[..20], &mut expected_right[..20]);
+    synth_direct.handle_midi_message(note_on);
+    synth_direct.process_block(&mut expected_left[20..], &mut expected_right[20..]);
+
+    assert!(left[..20].iter().all(|s| *s == 0.0));
+    assert!(left[20..].iter().any(|s| *s != 0.0));
+    assert_eq!(left, expected_left);
+    assert_eq!(right, expected_right);
+}
+
+#[test]
+fn parallel_rendering_matches_single_thread() {
+    run_with_big_stack(parallel_rendering_matches_single_thread_test);
+}
+
+fn parallel_rendering_matches_single_thread_test() {
+    check_parallel_rendering(false);
+}
+
+// Render the same notes with and without worker threads and compare the
+// output, optionally with one of the workers stopped.
+fn check_parallel_rendering(stop_worker: bool) {
+    let mut synth_single = create_synth();
+    let mut synth_parallel = create_synth();
+    add_modulators(&mut synth_single);
+    add_modulators(&mut synth_parallel);
+    synth_parallel.set_voice_threads(3);
+    if stop_worker {
+        synth_parallel.voice_pool.as_mut().unwrap().stop_worker(1);
+    }
+    for key in 65..72 {
+        synth_single.handle_midi_message(MidiMessage::NoteOn{channel: 0, key, velocity: key});
+        synth_parallel.handle_midi_message(MidiMessage::NoteOn{channel: 0, key, velocity: key});
+    }
+    synth_single.update();
+    synth_parallel.update();
+
+    // Blocks longer than the pool block size and not a multiple of the control period
+    let mut left_single = [0.0f32; 300];
+    let mut right_single = [0.0f32; 300];
+    let mut left_parallel = [0.0f32; 300];
+    let mut right_parallel = [0.0f32; 300];
+    for block in 0..10 {
+        synth_single.process_block(&mut left_single, &mut right_single);
+        synth_parallel.process_block(&mut left_parallel, &mut right_parallel);
+        assert!(left_single[..].iter().zip(left_parallel.iter()).all(|(a, b)| a == b));
+        assert!(right_single[..].iter().zip(right_parallel.iter()).all(|(a, b)| a == b));
+        if block == 5 {
+            synth_single.handle_midi_message(MidiMessage::NoteOff{channel: 0, key: 60, velocity: 0});
+            synth_parallel.handle_midi_message(MidiMessage::NoteOff{channel: 0, key: 60, velocity: 0});
+        }
+        synth_single.update();
+        synth_parallel.update();
+    }
+    assert!(left_parallel.iter().any(|s| *s != 0.0));
+}
+
+// Returns the keys of all voices that are still playing.
+fn get_playing_keys(synth: &Synth) -> Vec<u8> {
+    synth.voice.iter().filter(|v| v.is_running()).map(|v| v.key).collect()
+}
+
+#[test]
+fn overload_drops_oldest_released_voice() {
+    run_with_big_stack(overload_drops_oldest_released_voice_test);
+}
+
+fn overload_drops_oldest_released_voice_test() {
+    let mut synth = create_synth(); // Keys 60 and 64
+    synth.sound.env[0].release = 2000.0; // Keep released voices playing
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 67, velocity: 100});
+    synth.handle_midi_message(MidiMessage::NoteOff{channel: 0, key: 64, velocity: 0});
+    synth.handle_midi_message(MidiMessage::NoteOff{channel: 0, key: 60, velocity: 0});
+    let mut left = [0.0f32; 512];
+    let mut right = [0.0f32; 512];
+
+    // Low load doesn't change anything
+    synth.handle_load(0.5);
+    synth.process_block(&mut left, &mut right);
+    synth.update();
+    assert_eq!(get_playing_keys(&synth).len(), 3);
+
+    synth.handle_load(0.9);
+    synth.process_block(&mut left, &mut right);
+    synth.update();
+    let mut keys = get_playing_keys(&synth);
+    keys.sort();
+    assert_eq!(keys, vec!(64, 67));
+    assert_eq!(synth.get_dropped_voices(), 1);
+
+    // Triggered voices are never dropped
+    synth.handle_load(0.9);
+    synth.handle_load(0.9);
+    synth.process_block(&mut left, &mut right);
+    synth.update();
+    assert_eq!(get_playing_keys(&synth), vec!(67));
+    assert_eq!(synth.get_dropped_voices(), 2);
+    assert!(left.iter().any(|s| *s != 0.0));
+}
+
+#[test]
+fn overload_reduces_unison_voices() {
+    run_with_big_stack(overload_reduces_unison_voices_test);
+}
+
+fn overload_reduces_unison_voices_test() {
+    let mut synth = create_synth(); // Osc 1 with 3 unison voices
+    let mut reference = create_synth();
+    let mut sound = reference.sound;
+    sound.osc[0].wt_osc_data.set_voice_num(2);
+    reference.handle_sound_update(&sound);
+    for _ in 0..5 {
+        synth.handle_load(0.9);
+    }
+    assert_eq!(synth.unison_limit, 2);
+    assert_eq!(synth.sound.osc[0].wt_osc_data.num_voices, 3);
+
+    // The limit is kept when the sound changes
+    let sound = synth.sound;
+    synth.handle_sound_update(&sound);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 60, velocity: 100});
+    reference.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 60, velocity: 100});
+    let (mut left, mut right) = ([0.0f32; 256], [0.0f32; 256]);
+    let (mut ref_left, mut ref_right) = ([0.0f32; 256], [0.0f32; 256]);
+    synth.process_block(&mut left, &mut right);
+    reference.process_block(&mut ref_left, &mut ref_right);
+    assert!(left.iter().any(|s| *s != 0.0));
+    assert_eq!(left, ref_left); // Plays like a sound with 2 voices
+
+    // Voices are restored one at a time after the load went down
+    for _ in 0..LOAD_RECOVERY_PERIODS {
+        synth.handle_load(0.1);
     }
+    assert_eq!(synth.unison_limit, 3);
+    assert_eq!(synth.get_dropped_voices(), 0);
 }
+
+// Render one note of a plain sine patch and return the left channel.
+//
+// The note is held for half a second, followed by another half second of
+// release.
+fn render_note(sample_rate: u32) -> Vec<f32> {
+    let (ui_sender, _ui_receiver) = unbounded::<UiMessage>();
+    let (sender, receiver) = unbounded::<SynthEvent>();
+    let control = SynthControl::new(sample_rate, ui_sender, sender);
+    let mut synth = Synth::new(sample_rate, control.get_wavetables(), control.get_release_sender(), receiver);
+    let mut sound = synth.sound;
+    sound.osc[0].level = 1.0;
+    sound.osc[0].wt_osc_data.wave_index = 0.0; // Sine
+    sound.osc[1].level = 0.0;
+    sound.osc[2].level = 0.0;
+    sound.filter[0].filter_type = 0; // Bypass
+    sound.filter[1].filter_type = 0;
+    sound.env[0].attack = 100.0;
+    sound.env[0].decay = 1.0;
+    sound.env[0].sustain = 1.0;
+    sound.env[0].release = 200.0;
+    synth.handle_sound_update(&sound);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 81, velocity: 127});
+
+    let note_len = sample_rate as usize / 2;
+    let mut left = vec!(0.0f32; note_len * 2);
+    let mut right = vec!(0.0f32; note_len * 2);
+    let mut offset = 0;
+    while offset < left.len() {
+        if offset == note_len {
+            synth.handle_midi_message(MidiMessage::NoteOff{channel: 0, key: 81, velocity: 0});
+        }
+        let mut len = std::cmp::min(512, left.len() - offset);
+        if offset < note_len {
+            len = std::cmp::min(len, note_len - offset);
+        }
+        synth.process_block(&mut left[offset..offset + len], &mut right[offset..offset + len]);
+        synth.update();
+        offset += len;
+    }
+    left
+}
+
+// Time in seconds of the first 2 ms window after start in which the peak
+// level is above (rising) or below (falling) the given level.
+fn find_level_time(samples: &[f32], sample_rate: u32, start: usize, level: f32, rising: bool) -> Float {
+    let window = sample_rate as usize / 500;
+    for (i, chunk) in samples[start..].chunks(window).enumerate() {
+        let peak = chunk.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+        if (rising && peak >= level) || (!rising && peak < level) {
+            return (start + i * window) as Float / sample_rate as Float;
+        }
+    }
+    samples.len() as Float / sample_rate as Float
+}
+
+// Frequency measured from the upward zero crossings between start and end.
+fn measure_frequency(samples: &[f32], sample_rate: u32, start: usize, end: usize) -> Float {
+    let mut first: Option<Float> = None;
+    let mut last = 0.0;
+    let mut num_crossings = 0;
+    for i in start..end {
+        let (a, b) = (samples[i] as Float, samples[i + 1] as Float);
+        if a < 0.0 && b >= 0.0 {
+            let time = i as Float + a / (a - b);
+            if first.is_none() {
+                first = Some(time);
+            }
+            last = time;
+            num_crossings += 1;
+        }
+    }
+    (num_crossings - 1) as Float * sample_rate as Float / (last - first.unwrap())
+}
+
+#[test]
+fn timing_and_pitch_are_independent_of_sample_rate() {
+    run_with_big_stack(timing_and_pitch_are_independent_of_sample_rate_test);
+}
+
+fn timing_and_pitch_are_independent_of_sample_rate_test() {
+    let mut reference: Option<(Float, Float)> = None;
+    for sample_rate in [44100, 48000, 96000, 192000].iter() {
+        let sample_rate = *sample_rate;
+        let samples = render_note(sample_rate);
+        assert!(samples.iter().all(|s| s.is_finite()), "Invalid samples at {}", sample_rate);
+        let max = samples.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+        assert!(max > 0.1);
+
+        let frequency = measure_frequency(&samples, sample_rate, sample_rate as usize / 5, sample_rate as usize * 2 / 5);
+        assert!((frequency - 880.0).abs() < 0.5, "{} Hz at {}", frequency, sample_rate);
+
+        let attack = find_level_time(&samples, sample_rate, 0, max * 0.9, true);
+        let release = find_level_time(&samples, sample_rate, samples.len() / 2, max * 0.1, false) - 0.5;
+        match reference {
+            None => reference = Some((attack, release)),
+            Some((ref_attack, ref_release)) => {
+                assert!((attack - ref_attack).abs() <= 0.002, "Attack {} at {}, {} at 44100", attack, sample_rate, ref_attack);
+                assert!((release - ref_release).abs() <= 0.002, "Release {} at {}, {} at 44100", release, sample_rate, ref_release);
+            }
+        }
+    }
+    let (attack, release) = reference.unwrap();
+    assert!(attack > 0.05 && attack <= 0.1);
+    assert!(release > 0.05 && release <= 0.2);
+}
+
+#[test]
+fn replaced_data_is_released() {
+    run_with_big_stack(replaced_data_is_released_test);
+}
+
+fn replaced_data_is_released_test() {
+    let (ui_sender, _ui_receiver) = unbounded::<UiMessage>();
+    let (sender, receiver) = unbounded::<SynthEvent>();
+    let (release_sender, release_receiver) = bounded::<ReleasedData>(RELEASE_QUEUE_SIZE);
+    let control = SynthControl::new(44100, ui_sender, sender);
+    let mut synth = Synth::new(44100, control.get_wavetables(), release_sender, receiver);
+
+    let sample = Arc::new(Sample::new("first", 44100.0, vec!(0.5; 100)));
+    synth.handle_message(AudioMessage::Sample(1, sample.clone()));
+    assert!(release_receiver.try_recv().is_err());
+    synth.handle_message(AudioMessage::Sample(1, Arc::new(Sample::new("second", 44100.0, vec!(0.5; 100)))));
+    match release_receiver.try_recv() {
+        Ok(ReleasedData::Sample(s)) => assert!(Arc::ptr_eq(&s, &sample)),
+        _ => panic!("Replaced sample was not released"),
+    }
+
+    // Data with an ID outside of the preallocated slots is released as well
+    synth.handle_message(AudioMessage::Sample(MAX_SAMPLES, sample));
+    assert!(matches!(release_receiver.try_recv(), Ok(ReleasedData::Sample(_))));
+
+    let wt = control.get_wavetables()[1].clone().unwrap();
+    synth.handle_message(AudioMessage::Wavetable(0, wt));
+    assert!(matches!(release_receiver.try_recv(), Ok(ReleasedData::Wavetable(_))));
+}
+
+#[test]
+fn stopped_worker_share_is_rendered_by_audio_thread() {
+    run_with_big_stack(stopped_worker_share_is_rendered_by_audio_thread_test);
+}
+
+fn stopped_worker_share_is_rendered_by_audio_thread_test() {
+    check_parallel_rendering(true);
+}
+
+// Sets up the oscillators of a sound.
+type Configuration = fn(&mut SoundData);
+
+// Oscillator setups, which have to sound the same when rendered in blocks
+// and sample by sample.
+const OSCILLATOR_CONFIGURATIONS: &[(&str, Configuration)] = &[
+    // Osc 2 modulates osc 1, osc 3 uses phase modulation with feedback
+    ("FM", |sound| {
+        sound.osc[0].osc_type = OscType::FM;
+        sound.osc[0].mod_source = 1;
+        sound.osc[0].mod_index = 2.0;
+        sound.osc[1].set_halfsteps(12);
+        sound.osc[2].level = 0.5;
+        sound.osc[2].osc_type = OscType::PM;
+        sound.osc[2].mod_source = 2;
+        sound.osc[2].mod_index = 2.0;
+    }),
+    // Plucked string excited by a sine wave
+    ("Pluck", |sound| {
+        sound.osc[0].osc_type = OscType::Pluck;
+        sound.osc[0].pluck_data.excitation = Excitation::Wavetable;
+    }),
+    // Osc 1 is ring modulated by osc 2, osc 3 is amplitude modulated by
+    // osc 1 and synced to osc 2
+    ("Cross mod", |sound| {
+        sound.osc[0].cross_mod = CrossMod::Ring;
+        sound.osc[0].cross_mod_source = 1;
+        sound.osc[1].set_halfsteps(7);
+        sound.osc[2].level = 0.5;
+        sound.osc[2].set_halfsteps(12);
+        sound.osc[2].cross_mod = CrossMod::Am;
+        sound.osc[2].cross_mod_source = 0;
+        sound.osc[2].sync = 1;
+        sound.osc[2].sync_source = 1;
+    }),
+    // Sine sub-oscillator two octaves below osc 1
+    ("Sub", |sound| {
+        sound.sub.level = 0.5;
+        sound.sub.octave = -2;
+        sound.sub.waveform = SubWaveform::Sine;
+    }),
+    // Five unison voices spread over the full stereo width
+    ("Unison", |sound| {
+        sound.osc[0].wt_osc_data.set_voice_num(5);
+        sound.osc[0].wt_osc_data.set_voice_spread(0.5);
+        sound.osc[0].wt_osc_data.width = 1.0;
+        sound.osc[0].wt_osc_data.blend = 0.5;
+    }),
+];
+
+#[test]
+fn oscillator_block_rendering_matches_single_samples() {
+    run_with_big_stack(oscillator_block_rendering_matches_single_samples_test);
+}
+
+fn oscillator_block_rendering_matches_single_samples_test() {
+    for (name, configure) in OSCILLATOR_CONFIGURATIONS.iter() {
+        let create_synth = || {
+            let (mut synth, _sender) = create_empty_synth();
+            let mut sound = synth.sound;
+            configure(&mut sound);
+            synth.handle_sound_update(&sound);
+            synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 60, velocity: 100});
+            synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 64, velocity: 80});
+            synth
+        };
+        let mut synth_single = create_synth();
+        let mut synth_block = create_synth();
+        let mut left = [0.0f32; 100];
+        let mut right = [0.0f32; 100];
+        let mut total = 0.0;
+        for block in 0..20 {
+            synth_block.process_block(&mut left, &mut right);
+            for i in 0..100 {
+                let (l, r) = synth_single.get_sample(block * 100 + i as i64 + 1);
+                assert!((l as f32 - left[i]).abs() < 1e-5, "{}: Left differs at {}", name, block * 100 + i as i64);
+                assert!((r as f32 - right[i]).abs() < 1e-5, "{}: Right differs at {}", name, block * 100 + i as i64);
+                total += left[i].abs();
+            }
+            synth_single.update();
+            synth_block.update();
+        }
+        assert!(total > 10.0, "{}: No output", name);
+    }
+}
+
+#[test]
+fn sample_oscillator_plays_loaded_sample() {
+    run_with_big_stack(sample_oscillator_plays_loaded_sample_test);
+}
+
+fn sample_oscillator_plays_loaded_sample_test() {
+    let (mut synth, _sender) = create_empty_synth();
+    let sample = Sample::new("dc", 44100.0, vec!(0.5; 1000));
+    synth.handle_message(AudioMessage::Sample(1, Arc::new(sample)));
+    let mut sound = synth.sound;
+    sound.osc[0].osc_type = OscType::Sample;
+    sound.osc[0].sample_data.sample = 1;
+    sound.osc[0].sample_data.root_key = 69;
+    sound.filter[0].filter_type = 0; // Bypass
+    sound.env[0].attack = 1.0;
+    sound.env[0].decay = 1.0;
+    synth.handle_sound_update(&sound);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 69, velocity: 127});
+
+    let mut left = [0.0f32; 1500];
+    let mut right = [0.0f32; 1500];
+    synth.process_block(&mut left, &mut right);
+    assert!(left[500] > 0.01);
+    // The sample has ended while the note is still held
+    assert!(left[1100..].iter().all(|x| *x == 0.0));
+}
+
+// Renders a note with a granular oscillator and returns the ratio between
+// the left and right channel for every sample.
+fn render_granular_note(pan: Float) -> Vec<f32> {
+    let (mut synth, _sender) = create_empty_synth();
+    let mut sound = synth.sound;
+    sound.osc[0].osc_type = OscType::Granular;
+    sound.osc[0].granular_data.density = 100.0;
+    sound.osc[0].granular_data.pan = pan;
+    sound.filter[0].filter_type = 0; // Bypass
+    sound.env[0].attack = 1.0;
+    sound.env[0].decay = 1.0;
+    sound.env[0].sustain = 1.0;
+    synth.handle_sound_update(&sound);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 69, velocity: 127});
+
+    let mut left = [0.0f32; 4410];
+    let mut right = [0.0f32; 4410];
+    for offset in (0..left.len()).step_by(63) {
+        synth.process_block(&mut left[offset..offset + 63], &mut right[offset..offset + 63]);
+        synth.update();
+    }
+    left.iter().zip(right.iter())
+        .filter(|(_, r)| r.abs() > 0.001)
+        .map(|(l, r)| l / r)
+        .collect()
+}
+
+#[test]
+fn granular_pan_spreads_grains_in_stereo() {
+    run_with_big_stack(granular_pan_spreads_grains_in_stereo_test);
+}
+
+fn granular_pan_spreads_grains_in_stereo_test() {
+    let centred = render_granular_note(0.0);
+    assert!(centred.len() > 1000);
+    assert!(centred.iter().all(|r| (r - centred[0]).abs() < 1e-3));
+    let spread = render_granular_note(1.0);
+    assert!(spread.iter().any(|r| (r - centred[0]).abs() > 0.1));
+}
+
+#[test]
+fn pluck_honours_retrigger_and_key_follow() {
+    run_with_big_stack(pluck_honours_retrigger_and_key_follow_test);
+}
+
+fn pluck_honours_retrigger_and_key_follow_test() {
+    // Mono synth playing a short plucked string excited by a sine wave
+    let create_mono_synth = |key_follow: i64| {
+        let (mut synth, _sender) = create_empty_synth();
+        let mut sound = synth.sound;
+        sound.patch.play_mode = PlayMode::Mono;
+        sound.osc[0].osc_type = OscType::Pluck;
+        sound.osc[0].key_follow = key_follow;
+        sound.osc[0].pluck_data.excitation = Excitation::Wavetable;
+        sound.osc[0].pluck_data.decay = 50.0;
+        sound.filter[0].filter_type = 0; // Bypass
+        sound.env[0].attack = 1.0;
+        sound.env[0].decay = 1.0;
+        sound.env[0].sustain = 1.0;
+        synth.handle_sound_update(&sound);
+        synth
+    };
+
+    // A new note plucks the decayed string again
+    let mut synth = create_mono_synth(1);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 69, velocity: 127});
+    let first = render_blocks(&mut synth, 8820);
+    let energy = |s: &[f32]| s.iter().map(|x| x * x).sum::<f32>();
+    assert!(energy(&first[8000..]) < energy(&first[..800]) * 1e-4);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 69, velocity: 127});
+    let second = render_blocks(&mut synth, 800);
+    assert!(energy(&second) > energy(&first[8000..]) * 1e4);
+
+    // Without key follow, all keys play the same string
+    let mut outputs = vec!();
+    for &key_follow in [0, 1].iter() {
+        for &key in [60, 72].iter() {
+            let mut synth = create_mono_synth(key_follow);
+            synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key, velocity: 127});
+            outputs.push(render_blocks(&mut synth, 2000));
+        }
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert_ne!(outputs[2], outputs[3]);
+}
+
+// Renders an analog saw a fifth above an analog master oscillator,
+// optionally synced to it.
+fn render_analog_sync(slave: usize, master: usize, sync: i64) -> Vec<f32> {
+    let (mut synth, _sender) = create_empty_synth();
+    let mut sound = synth.sound;
+    for osc in sound.osc.iter_mut() {
+        osc.osc_type = OscType::Analog;
+        osc.level = 0.0;
+    }
+    sound.osc[slave].level = 1.0;
+    sound.osc[slave].set_halfsteps(7);
+    sound.osc[slave].sync = sync;
+    sound.osc[slave].sync_source = master;
+    sound.filter[0].filter_type = 0; // Bypass
+    sound.filter[1].filter_type = 0;
+    sound.env[0].attack = 1.0;
+    sound.env[0].decay = 1.0;
+    sound.env[0].sustain = 1.0;
+    synth.handle_sound_update(&sound);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 69, velocity: 127});
+    render_blocks(&mut synth, 4410)
+}
+
+#[test]
+fn analog_sync_follows_master_period() {
+    run_with_big_stack(analog_sync_follows_master_period_test);
+}
+
+fn analog_sync_follows_master_period_test() {
+    // 2205 samples are exactly 22 cycles of the 440 Hz master
+    let max_diff = |s: &[f32]| (200..2205).map(|i| (s[i] - s[i + 2205]).abs()).fold(0.0f32, f32::max);
+    let synced = render_analog_sync(1, 0, 1);
+    assert!(max_diff(&synced) < 0.01, "{}", max_diff(&synced));
+    let free = render_analog_sync(1, 0, 0);
+    assert!(max_diff(&free) > 0.1);
+
+    // Any oscillator can be the sync source, also one with a higher index
+    let synced = render_analog_sync(0, 2, 1);
+    assert!(max_diff(&synced) < 0.01, "{}", max_diff(&synced));
+}
+
+#[test]
+fn amplitude_modulation_follows_source() {
+    run_with_big_stack(amplitude_modulation_follows_source_test);
+}
+
+fn amplitude_modulation_follows_source_test() {
+    // Osc 2 is gated by a 110 Hz square wave on osc 1, which is high for
+    // the first half of its cycle of about 401 samples.
+    let (mut synth, _sender) = create_empty_synth();
+    let mut sound = synth.sound;
+    sound.osc[0].osc_type = OscType::Analog;
+    sound.osc[0].va_data.waveform = VaWaveform::Square;
+    sound.osc[0].level = 0.0;
+    sound.osc[0].set_halfsteps(-24);
+    sound.osc[1].level = 1.0;
+    sound.osc[1].cross_mod = CrossMod::Am;
+    sound.osc[1].cross_mod_source = 0;
+    sound.osc[2].level = 0.0;
+    sound.filter[0].filter_type = 0; // Bypass
+    sound.filter[1].filter_type = 0;
+    sound.env[0].attack = 1.0;
+    sound.env[0].decay = 1.0;
+    sound.env[0].sustain = 1.0;
+    synth.handle_sound_update(&sound);
+    synth.handle_midi_message(MidiMessage::NoteOn{channel: 0, key: 69, velocity: 127});
+    let output = render_blocks(&mut synth, 1300);
+    let energy = |s: &[f32]| s.iter().map(|x| x * x).sum::<f32>();
+    assert!(energy(&output[820..980]) > 1.0);
+    assert!(energy(&output[1020..1180]) < 1e-6);
+}
+
+} // mod tests
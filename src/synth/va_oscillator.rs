@@ -0,0 +1,318 @@
+//! Virtual analog oscillator.
+//!
+//! Generates the classic analog waveforms directly instead of reading them
+//! from a wavetable. Aliasing is reduced with PolyBLEP/ PolyBLAMP: every
+//! jump in the waveform (and every corner of the triangle) is smoothed with a
+//! polynomial correction over the two samples around it. This works at any
+//! position within a sample, so the pulse width can change continuously and
+//! hard sync resets are band-limited as well.
+//!
+//! The corrections need the sample before the discontinuity, so the output
+//! is delayed by one sample.
+
+use super::Float;
+
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VaWaveform {
+    Saw,
+    Square,
+    Triangle,
+    Sine,
+}
+
+impl VaWaveform {
+    pub fn from_int(param: usize) -> VaWaveform {
+        match param {
+            0 => VaWaveform::Saw,
+            1 => VaWaveform::Square,
+            2 => VaWaveform::Triangle,
+            3 => VaWaveform::Sine,
+            _ => panic!(),
+        }
+    }
+
+    pub fn to_int(&self) -> usize {
+        match self {
+            VaWaveform::Saw => 0,
+            VaWaveform::Square => 1,
+            VaWaveform::Triangle => 2,
+            VaWaveform::Sine => 3,
+        }
+    }
+}
+
+impl Default for VaWaveform {
+    fn default() -> Self { VaWaveform::Saw }
+}
+
+/// Sound data for the virtual analog oscillator
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct VaOscData {
+    pub waveform: VaWaveform,
+    pub pulse_width: Float, // Length of the high part of the square wave, 0.0 - 1.0
+}
+
+impl Default for VaOscData {
+    fn default() -> Self {
+        let mut data = VaOscData{waveform: VaWaveform::Saw, pulse_width: 0.0};
+        data.init();
+        data
+    }
+}
+
+impl VaOscData {
+    pub fn init(&mut self) {
+        self.waveform = VaWaveform::Saw;
+        self.pulse_width = 0.5;
+    }
+}
+
+const MIN_PULSE_WIDTH: Float = 0.01;
+const MAX_PULSE_WIDTH: Float = 0.99;
+
+pub struct VaOsc {
+    pub sample_rate: Float,
+    phase: Float,      // Position in the wave cycle, 0.0 - 1.0
+    prev: Float,       // Previous sample, still receiving corrections
+    correction: Float, // Corrections for the current sample
+}
+
+impl VaOsc {
+    pub fn new(sample_rate: u32) -> VaOsc {
+        VaOsc{sample_rate: sample_rate as Float,
+              phase: 0.0,
+              prev: 0.0,
+              correction: 0.0}
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
+    // Naive value of the waveform at the given phase.
+    fn get_value(waveform: VaWaveform, pulse_width: Float, phase: Float) -> Float {
+        match waveform {
+            VaWaveform::Saw => 2.0 * phase - 1.0,
+            VaWaveform::Square => if phase < pulse_width { 1.0 } else { -1.0 },
+            VaWaveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            VaWaveform::Sine => (2.0 * std::f64::consts::PI * phase).sin(),
+        }
+    }
+
+    // Slope of the waveform at the given phase, per sample.
+    fn get_slope(waveform: VaWaveform, phase: Float, inc: Float) -> Float {
+        match waveform {
+            VaWaveform::Saw => 2.0 * inc,
+            VaWaveform::Square => 0.0,
+            VaWaveform::Triangle => if phase < 0.5 { 4.0 * inc } else { -4.0 * inc },
+            VaWaveform::Sine => 2.0 * std::f64::consts::PI * inc * (2.0 * std::f64::consts::PI * phase).cos(),
+        }
+    }
+
+    // Get the next discontinuity after the current phase.
+    //
+    // Returns the phase at which it happens, the jump of the value and the
+    // change of the slope.
+    fn get_next_edge(&self, waveform: VaWaveform, pulse_width: Float, inc: Float) -> (Float, Float, Float) {
+        match waveform {
+            VaWaveform::Saw => (1.0, -2.0, 0.0),
+            VaWaveform::Square => if self.phase < pulse_width { (pulse_width, -2.0, 0.0) } else { (1.0, 2.0, 0.0) },
+            VaWaveform::Triangle => if self.phase < 0.5 { (0.5, 0.0, -8.0 * inc) } else { (1.0, 0.0, 8.0 * inc) },
+            VaWaveform::Sine => (1.0, 0.0, 0.0), // Only the end of the cycle
+        }
+    }
+
+    // Add the PolyBLEP and PolyBLAMP corrections for a discontinuity that
+    // happened the given number of samples before the current sample.
+    fn add_correction(&mut self, distance: Float, jump: Float, slope: Float) {
+        let d = distance;
+        let e = 1.0 - distance;
+        self.prev += jump * d * d / 2.0 + slope * d * d * d / 6.0;
+        self.correction += -jump * e * e / 2.0 + slope * e * e * e / 6.0;
+    }
+
+    // Move the phase forward by the given time, starting at time_start
+    // within the current sample period.
+    //
+    // Returns the time since the end of the wave cycle if one was completed.
+    fn advance(&mut self, waveform: VaWaveform, pulse_width: Float, inc: Float, time_start: Float, duration: Float) -> Option<Float> {
+        let mut time = time_start;
+        let mut remaining = duration;
+        let mut completed = None;
+        if inc <= 0.0 {
+            return None;
+        }
+        loop {
+            let (edge, jump, slope) = self.get_next_edge(waveform, pulse_width, inc);
+            let time_to_edge = (edge - self.phase) / inc;
+            if time_to_edge > remaining {
+                self.phase += inc * remaining;
+                break;
+            }
+            self.phase = edge;
+            time += time_to_edge;
+            remaining -= time_to_edge;
+            if self.phase >= 1.0 {
+                self.phase -= 1.0;
+                completed = Some(1.0 - time);
+            }
+            self.add_correction(1.0 - time, jump, slope);
+        }
+        completed
+    }
+
+    // Calculate one sample.
+    //
+    // The sync value is the time since the sync source completed its wave
+    // cycle, if it did during this sample.
+    fn next_sample(&mut self, frequency: Float, data: &VaOscData, sync: Option<Float>) -> (Float, Option<Float>) {
+        let waveform = data.waveform;
+        let pulse_width = data.pulse_width.clamp(MIN_PULSE_WIDTH, MAX_PULSE_WIDTH);
+        let inc = (frequency / self.sample_rate).clamp(0.0, 0.5);
+        self.correction = 0.0;
+        let completed = match sync {
+            Some(offset) => {
+                let offset = offset.clamp(0.0, 1.0);
+                let completed = self.advance(waveform, pulse_width, inc, 0.0, 1.0 - offset);
+
+                // Hard sync: jump back to the start of the cycle
+                let jump = VaOsc::get_value(waveform, pulse_width, 0.0) - VaOsc::get_value(waveform, pulse_width, self.phase);
+                let slope = VaOsc::get_slope(waveform, 0.0, inc) - VaOsc::get_slope(waveform, self.phase, inc);
+                self.add_correction(offset, jump, slope);
+                self.phase = 0.0;
+                self.advance(waveform, pulse_width, inc, 1.0 - offset, offset).or(completed)
+            }
+            None => self.advance(waveform, pulse_width, inc, 0.0, 1.0),
+        };
+        let output = self.prev;
+        self.prev = VaOsc::get_value(waveform, pulse_width, self.phase) + self.correction;
+        (output, completed)
+    }
+
+    /// Fill a buffer with consecutive samples.
+    ///
+    /// The first sample is dt samples after the last calculated one, all
+    /// skipped samples are calculated as well. The frequency is interpolated
+    /// from freq_start to frequency over the block.
+    ///
+    /// The reset buffer holds the sync signal: the time in samples since the
+    /// sync source completed its cycle, for every sample where it did. The
+    /// complete buffer receives the same for this oscillator, the value is
+    /// only valid where the complete flag is set.
+    pub fn process_block(&mut self,
+                         freq_start: Float,
+                         frequency: Float,
+                         dt: i64,
+                         data: &VaOscData,
+                         reset: &[Option<Float>],
+                         output: &mut [Float],
+                         complete: &mut [bool],
+                         sync_offset: &mut [Float]) {
+        let freq_step = (frequency - freq_start) / output.len() as Float;
+        for (j, out) in output.iter_mut().enumerate() {
+            let freq = freq_start + freq_step * (j + 1) as Float;
+            let steps = if j == 0 { dt.clamp(1, self.sample_rate as i64) } else { 1 };
+            complete[j] = false;
+            for step in 0..steps {
+                let sync = if step == steps - 1 { reset[j] } else { None };
+                let (value, completed) = self.next_sample(freq, data, sync);
+                *out = value;
+                if let Some(offset) = completed {
+                    complete[j] = true;
+                    sync_offset[j] = offset;
+                }
+            }
+        }
+    }
+}
+
+// ----------------------------------------------
+//                  Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+
+use super::{VaOsc, VaOscData, VaWaveform};
+use super::super::Float;
+
+const SAMPLE_RATE: Float = 44100.0;
+const NUM_SAMPLES: usize = 4410; // Frequency resolution of 10 Hz
+
+fn render(data: &VaOscData, frequency: Float, reset: &[Option<Float>]) -> Vec<Float> {
+    let mut osc = VaOsc::new(SAMPLE_RATE as u32);
+    let mut output = vec!(0.0; reset.len());
+    let mut complete = vec!(false; reset.len());
+    let mut offset = vec!(0.0; reset.len());
+    osc.process_block(frequency, frequency, 1, data, reset, &mut output, &mut complete, &mut offset);
+    output
+}
+
+// Get the part of the signal energy that is not at a multiple of the base
+// frequency (including DC), which must be a multiple of 10 Hz.
+fn get_alias_ratio(samples: &[Float], base_freq: Float) -> Float {
+    let n = samples.len() as Float;
+    let total: Float = samples.iter().map(|s| s * s).sum();
+    let dc: Float = samples.iter().sum();
+    let mut harmonic = dc * dc / n;
+    let mut freq = base_freq;
+    while freq < SAMPLE_RATE / 2.0 {
+        let w = 2.0 * std::f64::consts::PI * freq / SAMPLE_RATE;
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, s) in samples.iter().enumerate() {
+            re += s * (w * i as Float).cos();
+            im -= s * (w * i as Float).sin();
+        }
+        harmonic += 2.0 * (re * re + im * im) / n;
+        freq += base_freq;
+    }
+    1.0 - harmonic / total
+}
+
+fn create_data(waveform: VaWaveform, pulse_width: Float) -> VaOscData {
+    VaOscData{waveform, pulse_width}
+}
+
+#[test]
+fn waveforms_have_little_aliasing() {
+    let no_reset = vec!(None; NUM_SAMPLES);
+    for &waveform in [VaWaveform::Saw, VaWaveform::Square, VaWaveform::Triangle, VaWaveform::Sine].iter() {
+        let output = render(&create_data(waveform, 0.5), 3000.0, &no_reset);
+        let ratio = get_alias_ratio(&output, 3000.0);
+        assert!(ratio < 0.005, "{:?}: {}", waveform, ratio);
+    }
+}
+
+#[test]
+fn pulse_width_changes_continuously() {
+    let no_reset = vec!(None; NUM_SAMPLES);
+    for &pulse_width in [0.1, 0.3137, 0.5, 0.77].iter() {
+        let output = render(&create_data(VaWaveform::Square, pulse_width), 1000.0, &no_reset);
+        let mean = output.iter().sum::<Float>() / output.len() as Float;
+        assert!((mean - (2.0 * pulse_width - 1.0)).abs() < 0.01, "{}: {}", pulse_width, mean);
+    }
+}
+
+#[test]
+fn hard_sync_has_little_aliasing() {
+    // Sync to a 1 kHz source, which completes a cycle every 44.1 samples
+    let reset: Vec<Option<Float>> = (0..NUM_SAMPLES)
+        .map(|i| {
+            let cycles = i as Float / 44.1;
+            let since = (cycles - cycles.floor()) * 44.1;
+            if since < 1.0 && i > 0 { Some(since) } else { None }
+        })
+        .collect();
+    let output = render(&create_data(VaWaveform::Saw, 0.5), 2345.0, &reset);
+    let ratio = get_alias_ratio(&output, 1000.0);
+    assert!(ratio < 0.01, "{}", ratio);
+
+    // Much better than resetting at the next sample
+    let quantized: Vec<Option<Float>> = reset.iter().map(|r| r.map(|_| 0.0)).collect();
+    let output = render(&create_data(VaWaveform::Saw, 0.5), 2345.0, &quantized);
+    assert!(get_alias_ratio(&output, 1000.0) > ratio * 5.0);
+}
+
+} // mod tests
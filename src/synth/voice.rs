@@ -2,10 +2,11 @@ use super::Envelope;
 use super::Filter;
 use super::Float;
 use super::Lfo;
-use super::{Parameter, ParamId, SynthParam, MenuItem};
+use super::{ModRouting, ModSourceSlot};
 use super::{PlayMode, FilterRouting};
-use super::SynthState;
-use super::{Oscillator, OscData};
+use super::{SynthState, MAX_BLOCK_SIZE};
+use super::{Oscillator, OscData, CrossMod, SampleRef};
+use super::{SubOsc, SubOscData};
 use super::SoundData;
 
 use wavetable::{Wavetable, WavetableRef};
@@ -20,8 +21,10 @@ pub const NUM_LFOS: usize = 2;
 pub struct Voice {
     // Components
     osc: [Oscillator; NUM_OSCILLATORS],
+    sub: SubOsc,
     env: [Envelope; NUM_ENVELOPES],
     pub filter: [Filter; NUM_FILTERS],
+    side_filter: [Filter; NUM_FILTERS], // Filters for the stereo part of the oscillators
     lfo: [Lfo; NUM_LFOS],
 
     // Static config
@@ -36,6 +39,15 @@ pub struct Voice {
     scaled_vel: Float,    // Velocity scaled according to sound settings (for use as amplifier)
     input_freq: Float,    // Frequency to play as received from Synth
     last_update: i64,
+    fade_level: Float,    // Level of a voice that is being faded out
+    fade_step: Float,     // Decrease of the fade level per sample, 0.0 if not fading
+
+    // Values at the end of the last block, used to interpolate changes
+    last_level: [Float; NUM_OSCILLATORS],
+    last_freq: [Float; NUM_OSCILLATORS],
+    last_sub_level: Float,
+    last_cutoff: [Float; NUM_FILTERS],
+    interpolate: bool, // False if there are no values from a previous block
 }
 
 impl Voice {
@@ -54,14 +66,20 @@ impl Voice {
             Filter::new(sample_rate),
             Filter::new(sample_rate),
         ];
+        let side_filter = [
+            Filter::new(sample_rate),
+            Filter::new(sample_rate),
+        ];
         let lfo = [
             Lfo::new(sample_rate),
             Lfo::new(sample_rate),
         ];
         let voice = Voice{
                 osc,
+                sub: SubOsc::new(sample_rate),
                 env,
                 filter,
+                side_filter,
                 lfo,
                 pan_l: 0.5,
                 pan_r: 0.5,
@@ -71,16 +89,25 @@ impl Voice {
                 velocity: 0.0,
                 scaled_vel: 0.0,
                 input_freq: 440.0,
-                last_update: 0i64};
+                last_update: 0i64,
+                fade_level: 1.0,
+                fade_step: 0.0,
+                last_level: [0.0; NUM_OSCILLATORS],
+                last_freq: [0.0; NUM_OSCILLATORS],
+                last_sub_level: 0.0,
+                last_cutoff: [0.0; NUM_FILTERS],
+                interpolate: false};
         voice
     }
 
     pub fn reset(&mut self) {
         self.triggered = false;
+        self.fade_level = 1.0;
+        self.fade_step = 0.0;
         for e in &mut self.env {
             e.reset();
         }
-        for f in &mut self.filter {
+        for f in self.filter.iter_mut().chain(self.side_filter.iter_mut()) {
             f.reset();
         }
     }
@@ -95,61 +122,125 @@ impl Voice {
         freq
     }
 
-    fn get_mod_values(&mut self, sample_clock: i64, sound_global: &SoundData, sound_local: &mut SoundData) {
-        // Get modulated values from global sound and discard values that were
-        // modulated for the previous sample. Complete copy is faster than
-        // looping over the modulators.
-        *sound_local = *sound_global;
+    // Get the index of the oscillator modulating the given one for FM/ PM.
+    //
+    // Returns None if the oscillator isn't modulated.
+    fn get_mod_source(data: &OscData) -> Option<usize> {
+        if data.is_modulated() {
+            Some(std::cmp::min(data.mod_source, NUM_OSCILLATORS - 1))
+        } else {
+            None
+        }
+    }
 
-        // Then update the local sound with mod values
-        let mut param_id = ParamId{..Default::default()};
-        let mut synth_param = SynthParam{..Default::default()};
-        for m in sound_global.modul.iter() {
+    // Get the index of the oscillator resetting the given one.
+    //
+    // Returns None if sync is off or the oscillator would sync to itself.
+    fn get_sync_source(data: &OscData, osc_id: usize) -> Option<usize> {
+        let src = std::cmp::min(data.sync_source, NUM_OSCILLATORS - 1);
+        if data.sync == 1 && src != osc_id {
+            Some(src)
+        } else {
+            None
+        }
+    }
 
-            if !m.active {
-                continue;
-            }
+    // Get the index of the oscillator used for ring or amplitude modulation
+    // of the given one.
+    //
+    // Returns None if cross modulation is off. An oscillator can modulate
+    // itself.
+    fn get_cross_mod_source(data: &OscData) -> Option<usize> {
+        if data.cross_mod != CrossMod::Off {
+            Some(std::cmp::min(data.cross_mod_source, NUM_OSCILLATORS - 1))
+        } else {
+            None
+        }
+    }
 
-            // Get current value of target parameter
-            param_id.set(m.target_func, m.target_func_id, m.target_param);
-            let mut current_val = sound_local.get_value(&param_id);
-
-            if !m.is_global {
-
-                // Get modulator source output
-                let mod_val: Float = match m.source_func {
-                    Parameter::Oscillator => {
-                        let id = m.source_func_id - 1;
-                        let freq = Voice::get_frequency(&sound_local.osc[id], self.input_freq);
-                        let (val, _) = self.osc[id].get_sample(freq, sample_clock, &sound_local.osc[id], false);
-                        val
-                    },
-                    Parameter::Lfo => {
-                        let (val, _) = self.lfo[m.source_func_id - 1].get_sample(sample_clock, &sound_local.lfo[m.source_func_id - 1], false);
-                        val
-                    },
-                    Parameter::Envelope => {
-                        self.env[m.source_func_id - 1].get_sample(sample_clock, &sound_local.env[m.source_func_id - 1])
-                    }
-                    Parameter::Velocity => {
-                        self.velocity
-                    }
-                    _ => 0.0,
-                } * m.scale;
+    // Check if oscillator a needs the output of oscillator b, either as
+    // modulator or as sync source.
+    fn depends_on(sound: &SoundData, a: usize, b: usize) -> bool {
+        if a == b {
+            return false;
+        }
+        let data = &sound.osc[a];
+        Voice::get_mod_source(data) == Some(b)
+            || Voice::get_sync_source(data, a) == Some(b)
+            || Voice::get_cross_mod_source(data) == Some(b)
+    }
+
+    // Get the order in which the oscillators have to be rendered.
+    //
+    // Oscillators are rendered after the oscillators they depend on. In case
+    // of a circular dependency, the oscillator that comes first is rendered
+    // first and gets the previous output of the others.
+    fn get_render_order(sound: &SoundData) -> [usize; NUM_OSCILLATORS] {
+        let mut order = [0; NUM_OSCILLATORS];
+        let mut placed = [false; NUM_OSCILLATORS];
+        for slot in order.iter_mut() {
+            let mut next = None;
+            for i in 0..NUM_OSCILLATORS {
+                if placed[i] {
+                    continue;
+                }
+                if next.is_none() {
+                    next = Some(i); // Fallback for circular dependencies
+                }
+                if (0..NUM_OSCILLATORS).all(|j| placed[j] || !Voice::depends_on(sound, i, j)) {
+                    next = Some(i);
+                    break;
+                }
+            }
+            let next = next.unwrap();
+            placed[next] = true;
+            *slot = next;
+        }
+        order
+    }
 
-                let mut val = current_val.as_float();
+    // Get the current modulator value for an FM/ PM oscillator.
+    //
+    // Returns None if the oscillator modulates itself.
+    fn get_modulator(&self, sound: &SoundData, osc_id: usize) -> Option<Float> {
+        match Voice::get_mod_source(&sound.osc[osc_id]) {
+            Some(src) if src != osc_id => Some(self.osc[src].get_last_sample()),
+            _ => None,
+        }
+    }
 
-                // Update value
-                let dest_range = MenuItem::get_val_range(param_id.function, param_id.parameter);
-                val = dest_range.safe_add(val, mod_val);
+    fn get_mod_values(&mut self, sample_clock: i64, sound_global: &SoundData, sound_local: &mut SoundData, routing: &ModRouting) {
+        // Get modulated values from global sound and discard values that were
+        // modulated for the previous update or by another voice. Only the
+        // modulation targets need to be copied.
+        routing.reset_targets(sound_global, sound_local);
 
-                // Update parameter in voice sound data
-                current_val.set_from_float(val);
-            }
+        // Then update the local sound with mod values
+        for route in routing.get_local_routes() {
+
+            // Get modulator source output
+            let mod_val: Float = match route.source {
+                ModSourceSlot::Oscillator(id) => {
+                    let freq = Voice::get_frequency(&sound_local.osc[id], self.input_freq);
+                    let modulator = self.get_modulator(sound_local, id);
+                    let (val, _) = self.osc[id].get_sample(freq, sample_clock, &sound_local.osc[id], None, modulator);
+                    val
+                },
+                ModSourceSlot::Lfo(id) => {
+                    let (val, _) = self.lfo[id].get_sample(sample_clock, &sound_local.lfo[id], false);
+                    val
+                },
+                ModSourceSlot::Envelope(id) => {
+                    self.env[id].get_sample(sample_clock, &sound_local.env[id])
+                }
+                ModSourceSlot::Velocity => {
+                    self.velocity
+                }
+                _ => 0.0,
+            } * route.scale;
 
-            // TODO: Too much copying
-            synth_param.set(m.target_func, m.target_func_id, m.target_param, current_val);
-            sound_local.set_parameter(&synth_param);
+            // Update parameter in voice sound data
+            routing.get_targets()[route.target].apply(sound_local, mod_val);
         }
     }
 
@@ -157,6 +248,7 @@ impl Voice {
                       sample_clock: i64,
                       sound_global: &SoundData,
                       sound_local: &mut SoundData,
+                      routing: &ModRouting,
                       global_state: &SynthState) -> (Float, Float) {
         if !self.is_running() {
             return (0.0, 0.0);
@@ -164,62 +256,335 @@ impl Voice {
         let mut input_f1 = 0.0;
         let mut input_f2 = 0.0;
         let mut result_direct = 0.0;
+        let mut side_f1 = 0.0;
+        let mut side_f2 = 0.0;
+        let mut side_direct = 0.0;
+        let mut stereo = false;
         self.last_update = sample_clock;
-        let mut reset = false;
+        let mut sync_reset = [None; NUM_OSCILLATORS];
+        let mut osc_out = [0.0; NUM_OSCILLATORS];
+        let mut rendered = [false; NUM_OSCILLATORS];
         let input_freq = self.input_freq * global_state.freq_factor;
         let mut freq: Float;
 
         // Prepare modulation values
-        self.get_mod_values(sample_clock, sound_global, sound_local);
+        self.get_mod_values(sample_clock, sound_global, sound_local, routing);
 
         // Get mixed output from oscillators
-        for (i, osc) in self.osc.iter_mut().enumerate() {
+        for &i in Voice::get_render_order(sound_local).iter() {
             freq = Voice::get_frequency(&sound_local.osc[i], input_freq);
-            let (sample, wave_complete) = osc.get_sample(freq, sample_clock, &sound_local.osc[i], reset);
-            // TODO: Add panning here
+            let modulator = self.get_modulator(sound_local, i);
+            let reset = match Voice::get_sync_source(&sound_local.osc[i], i) {
+                Some(src) => sync_reset[src],
+                None => None,
+            };
+            let cross_mod = sound_local.osc[i].cross_mod;
+            let cross_mod_in = match Voice::get_cross_mod_source(&sound_local.osc[i]) {
+                Some(src) if rendered[src] => osc_out[src],
+                Some(src) => self.osc[src].get_last_sample(),
+                None => 0.0,
+            };
+            let osc = &mut self.osc[i];
+            let (sample, wave_complete) = osc.get_sample(freq, sample_clock, &sound_local.osc[i], reset, modulator);
+            let sample = cross_mod.apply(sample, cross_mod_in);
             let sample_amped = sample * sound_local.osc[i].level * self.scaled_vel;
             input_f1      += sample_amped * osc.filter1_out;
             input_f2      += sample_amped * osc.filter2_out;
             result_direct += sample_amped * osc.direct_out;
-            // Sync oscillator 1 to 0
-            reset = i == 0 && wave_complete && sound_local.osc[1].sync == 1;
+            if sound_local.osc[i].is_stereo() {
+                let side = cross_mod.apply(osc.get_last_side(&sound_local.osc[i]), cross_mod_in);
+                let side_amped = side * sound_local.osc[i].level * self.scaled_vel;
+                side_f1     += side_amped * osc.filter1_out;
+                side_f2     += side_amped * osc.filter2_out;
+                side_direct += side_amped * osc.direct_out;
+                stereo = true;
+            }
+            if wave_complete {
+                sync_reset[i] = Some(osc.get_last_sync_offset());
+            }
+            osc_out[i] = sample;
+            rendered[i] = true;
+        }
+
+        // The sub-oscillator follows oscillator 1
+        if sound_local.sub.level > 0.0 {
+            let mut sample = [0.0];
+            let freq = Voice::get_frequency(&sound_local.osc[0], input_freq);
+            self.sub.process_block(freq, freq, &sound_local.sub, &[sync_reset[0]], &mut sample);
+            let sample_amped = sample[0] * sound_local.sub.level * self.scaled_vel;
+            input_f1      += sample_amped * self.sub.filter1_out;
+            input_f2      += sample_amped * self.sub.filter2_out;
+            result_direct += sample_amped * self.sub.direct_out;
         }
 
         // Feed it into the filters
-        let mut result: Float = self.apply_filter(sample_clock,
+        let filter_env = self.env[1].get_sample(sample_clock, &sound_local.env[1]); // Env2 is normaled to filter cutoff
+        let mut result: Float = self.apply_filter(filter_env,
                                                   sound_local,
                                                   input_f1,
                                                   input_f2,
                                                   input_freq);
         result += result_direct;
+        let mut side = 0.0;
+        if stereo {
+            side = Voice::run_filters(&mut self.side_filter, sound_local, side_f1, side_f2, input_freq, filter_env);
+            side += side_direct;
+        }
 
         // Apply the volume envelope
         let env_amp = self.env[0].get_sample(sample_clock, &sound_local.env[0]);
         if sound_local.patch.env_depth > 0.0 {
             result *= env_amp * sound_local.patch.env_depth;
+            side *= env_amp * sound_local.patch.env_depth;
         }
-        if result > 1.0 {
-            result = 1.0;
-        } else if result < -1.0 {
-            result = -1.0;
+        let mut result_l = (result - side).clamp(-1.0, 1.0);
+        let mut result_r = (result + side).clamp(-1.0, 1.0);
+        if self.fade_step > 0.0 {
+            let fade_level = self.next_fade_level();
+            result_l *= fade_level;
+            result_r *= fade_level;
+            if self.fade_level == 0.0 {
+                self.reset();
+            }
         }
 
         // Pan result
         // TODO: Use actual panning algorithm
-        let result_l = result * self.pan_l;
-        let result_r = result * self.pan_r;
+        (result_l * self.pan_l, result_r * self.pan_r)
+    }
+
+    /// Render a block of samples and add them to the output buffers.
+    ///
+    /// Modulation values are calculated once at the start of the block, so
+    /// the block length must not exceed MAX_BLOCK_SIZE. Changes of the
+    /// oscillator levels and frequencies and of the filter cutoffs since the
+    /// previous block are interpolated over the block to avoid zipper noise.
+    pub fn process_block(&mut self,
+                         sample_clock: i64,
+                         sound_global: &SoundData,
+                         sound_local: &mut SoundData,
+                         routing: &ModRouting,
+                         global_state: &SynthState,
+                         out_l: &mut [Float],
+                         out_r: &mut [Float]) {
+        if !self.is_running() {
+            return;
+        }
+        let len = out_l.len();
+        let mut osc_out = [[0.0; MAX_BLOCK_SIZE]; NUM_OSCILLATORS];
+        let mut mod_in = [0.0; MAX_BLOCK_SIZE];
+        let mut cross_mod_in = [0.0; MAX_BLOCK_SIZE];
+        let mut sub_out = [0.0; MAX_BLOCK_SIZE];
+        let mut sub_freq = (0.0, 0.0);
+        let mut input_f1 = [0.0; MAX_BLOCK_SIZE];
+        let mut input_f2 = [0.0; MAX_BLOCK_SIZE];
+        let mut result_direct = [0.0; MAX_BLOCK_SIZE];
+        let mut side_f1 = [0.0; MAX_BLOCK_SIZE];
+        let mut side_f2 = [0.0; MAX_BLOCK_SIZE];
+        let mut side_direct = [0.0; MAX_BLOCK_SIZE];
+        let mut stereo = false;
+        let mut env_out = [0.0; MAX_BLOCK_SIZE];
+        let no_reset = [None; MAX_BLOCK_SIZE];
+        let mut sync_reset = [[None; MAX_BLOCK_SIZE]; NUM_OSCILLATORS];
+        let mut rendered = [false; NUM_OSCILLATORS];
+        let mut complete = [false; MAX_BLOCK_SIZE];
+        self.last_update = sample_clock + len as i64 - 1;
+        let input_freq = self.input_freq * global_state.freq_factor;
+
+        // Prepare modulation values
+        self.get_mod_values(sample_clock, sound_global, sound_local, routing);
+
+        // Get mixed output from oscillators. FM/ PM and cross modulated
+        // oscillators get the output of their modulator for this block if it
+        // has already been rendered, the last sample of it otherwise. Sync
+        // only works if the sync source has been rendered.
+        for &i in Voice::get_render_order(sound_local).iter() {
+            let modulator = match Voice::get_mod_source(&sound_local.osc[i]) {
+                Some(src) if src != i => {
+                    if rendered[src] {
+                        mod_in[..len].copy_from_slice(&osc_out[src][..len]);
+                    } else {
+                        let value = self.osc[src].get_last_sample();
+                        mod_in[..len].iter_mut().for_each(|m| *m = value);
+                    }
+                    Some(&mod_in[..len])
+                }
+                _ => None,
+            };
+            let reset = match Voice::get_sync_source(&sound_local.osc[i], i) {
+                Some(src) if rendered[src] => &sync_reset[src][..len],
+                _ => &no_reset[..len],
+            };
+            let cross_mod = sound_local.osc[i].cross_mod;
+            if let Some(src) = Voice::get_cross_mod_source(&sound_local.osc[i]) {
+                if rendered[src] {
+                    cross_mod_in[..len].copy_from_slice(&osc_out[src][..len]);
+                } else {
+                    let value = self.osc[src].get_last_sample();
+                    cross_mod_in[..len].iter_mut().for_each(|m| *m = value);
+                }
+            }
+            let osc = &mut self.osc[i];
+            let osc_out = &mut osc_out[i];
+            let freq = Voice::get_frequency(&sound_local.osc[i], input_freq);
+            let level = sound_local.osc[i].level;
+            let (freq_start, level_start) = if self.interpolate {
+                (self.last_freq[i], self.last_level[i])
+            } else {
+                (freq, level)
+            };
+            let level_step = (level - level_start) / len as Float;
+            osc.process_block(freq_start, freq, sample_clock, &sound_local.osc[i], reset, modulator, &mut osc_out[..len], &mut complete[..len]);
+            if i == 0 {
+                sub_freq = (freq_start, freq);
+            }
+            if cross_mod != CrossMod::Off {
+                for j in 0..len {
+                    osc_out[j] = cross_mod.apply(osc_out[j], cross_mod_in[j]);
+                }
+            }
+            for j in 0..len {
+                let level = level_start + level_step * (j + 1) as Float;
+                let sample_amped = osc_out[j] * level * self.scaled_vel;
+                input_f1[j]      += sample_amped * osc.filter1_out;
+                input_f2[j]      += sample_amped * osc.filter2_out;
+                result_direct[j] += sample_amped * osc.direct_out;
+            }
+            if let Some(side) = osc.get_side(len) {
+                for j in 0..len {
+                    let level = level_start + level_step * (j + 1) as Float;
+                    let side_amped = cross_mod.apply(side[j], cross_mod_in[j]) * level * self.scaled_vel;
+                    side_f1[j]     += side_amped * osc.filter1_out;
+                    side_f2[j]     += side_amped * osc.filter2_out;
+                    side_direct[j] += side_amped * osc.direct_out;
+                }
+                stereo = true;
+            }
+            let sync_offset = osc.get_sync_offsets(len);
+            for j in 0..len {
+                sync_reset[i][j] = if complete[j] { Some(sync_offset[j]) } else { None };
+            }
+            rendered[i] = true;
+            self.last_freq[i] = freq;
+            self.last_level[i] = level;
+        }
+
+        // The sub-oscillator follows oscillator 1
+        let level = sound_local.sub.level;
+        let level_start = if self.interpolate { self.last_sub_level } else { level };
+        if level > 0.0 || level_start > 0.0 {
+            let (freq_start, freq) = sub_freq;
+            self.sub.process_block(freq_start, freq, &sound_local.sub, &sync_reset[0][..len], &mut sub_out[..len]);
+            let level_step = (level - level_start) / len as Float;
+            for j in 0..len {
+                let level = level_start + level_step * (j + 1) as Float;
+                let sample_amped = sub_out[j] * level * self.scaled_vel;
+                input_f1[j]      += sample_amped * self.sub.filter1_out;
+                input_f2[j]      += sample_amped * self.sub.filter2_out;
+                result_direct[j] += sample_amped * self.sub.direct_out;
+            }
+        }
+        self.last_sub_level = level;
+
+        // Feed it into the filters, Env2 is normaled to filter cutoff
+        let mut cutoff_start = [sound_local.filter[0].cutoff, sound_local.filter[1].cutoff];
+        if self.interpolate {
+            cutoff_start = self.last_cutoff;
+        }
+        self.last_cutoff = [sound_local.filter[0].cutoff, sound_local.filter[1].cutoff];
+        self.interpolate = true;
+        self.env[1].process_block(sample_clock, &sound_local.env[1], &mut env_out[..len]);
+        Voice::filter_block(&mut self.filter, sound_local, &mut input_f1[..len], &mut input_f2[..len], input_freq, &env_out[..len], &cutoff_start);
+        if stereo {
+            Voice::filter_block(&mut self.side_filter, sound_local, &mut side_f1[..len], &mut side_f2[..len], input_freq, &env_out[..len], &cutoff_start);
+        }
+
+        // Apply the volume envelope and pan the result
+        self.env[0].process_block(sample_clock, &sound_local.env[0], &mut env_out[..len]);
+        for j in 0..len {
+            let mut result = input_f1[j] + input_f2[j];
+            result += result_direct[j];
+            let mut side = side_f1[j] + side_f2[j] + side_direct[j];
+            if sound_local.patch.env_depth > 0.0 {
+                result *= env_out[j] * sound_local.patch.env_depth;
+                side *= env_out[j] * sound_local.patch.env_depth;
+            }
+            let mut result_l = (result - side).clamp(-1.0, 1.0);
+            let mut result_r = (result + side).clamp(-1.0, 1.0);
+            if self.fade_step > 0.0 {
+                let fade_level = self.next_fade_level();
+                result_l *= fade_level;
+                result_r *= fade_level;
+            }
+            out_l[j] += result_l * self.pan_l;
+            out_r[j] += result_r * self.pan_r;
+        }
+        if self.fade_step > 0.0 && self.fade_level == 0.0 {
+            self.reset();
+        }
+    }
+
+    // Run a block of samples through a pair of filters.
+    //
+    // With serial routing, the output of the first filter is moved to the
+    // input of the second one, leaving the first buffer silent.
+    fn filter_block(filter: &mut [Filter; NUM_FILTERS],
+                    sound_local: &mut SoundData,
+                    input_f1: &mut [Float],
+                    input_f2: &mut [Float],
+                    input_freq: Float,
+                    env_out: &[Float],
+                    cutoff_start: &[Float; NUM_FILTERS]) {
+        filter[0].process_block(input_f1, &mut sound_local.filter[0], input_freq, env_out, cutoff_start[0]);
+        if let FilterRouting::Serial = sound_local.patch.filter_routing {
+            for (f1, f2) in input_f1.iter_mut().zip(input_f2.iter_mut()) {
+                *f2 += *f1;
+                *f1 = 0.0;
+            }
+        }
+        filter[1].process_block(input_f2, &mut sound_local.filter[1], input_freq, env_out, cutoff_start[1]);
+    }
+
+    // Advance the fade out by one sample.
+    fn next_fade_level(&mut self) -> Float {
+        self.fade_level -= self.fade_step;
+        if self.fade_level < 0.0 {
+            self.fade_level = 0.0;
+        }
+        self.fade_level
+    }
+
+    /// Quickly fade the voice out and stop it.
+    ///
+    /// Used to free the voice when the engine is overloaded. The voice stops
+    /// after the given number of samples, unless it is triggered again.
+    pub fn fade_out(&mut self, num_samples: usize) {
+        if self.fade_step == 0.0 {
+            self.fade_step = 1.0 / std::cmp::max(num_samples, 1) as Float;
+        }
+    }
 
-        (result_l, result_r)
+    pub fn is_fading(&self) -> bool {
+        self.fade_step > 0.0
     }
 
     pub fn apply_filter(&mut self,
-                        sample_clock: i64,
+                        filter_env: Float,
                         sound_local: &mut SoundData,
                         input_f1: Float,
-                        mut input_f2: Float,
+                        input_f2: Float,
                         input_freq: Float) -> Float {
-        let filter_env = self.env[1].get_sample(sample_clock, &sound_local.env[1]); // Env2 is normaled to filter cutoff
-        let output_f1  = self.filter[0].process(input_f1, &mut sound_local.filter[0], input_freq, filter_env);
+        Voice::run_filters(&mut self.filter, sound_local, input_f1, input_f2, input_freq, filter_env)
+    }
+
+    // Run a single sample through a pair of filters.
+    fn run_filters(filter: &mut [Filter; NUM_FILTERS],
+                   sound_local: &mut SoundData,
+                   input_f1: Float,
+                   mut input_f2: Float,
+                   input_freq: Float,
+                   filter_env: Float) -> Float {
+        let output_f1  = filter[0].process(input_f1, &mut sound_local.filter[0], input_freq, filter_env);
         let mut result = match sound_local.patch.filter_routing {
             FilterRouting::Parallel => {
                 output_f1
@@ -229,7 +594,7 @@ impl Voice {
                 0.0
             }
         };
-        result += self.filter[1].process(input_f2, &mut sound_local.filter[1], input_freq, filter_env);
+        result += filter[1].process(input_f2, &mut sound_local.filter[1], input_freq, filter_env);
         result
     }
 
@@ -252,6 +617,15 @@ impl Voice {
         self.osc[osc_id].set_wavetable(wt);
     }
 
+    pub fn set_sample(&mut self, osc_id: usize, sample: Option<SampleRef>) {
+        self.osc[osc_id].set_sample(sample);
+    }
+
+    /// Limit the number of unison voices of all oscillators.
+    pub fn set_unison_limit(&mut self, limit: usize) {
+        self.osc.iter_mut().for_each(|o| o.set_unison_limit(limit));
+    }
+
     // Set panning. 0.0 = left, 1.0 = right
     pub fn set_pan(&mut self, pan: Float) {
         self.pan_l = 1.0 - pan;
@@ -262,6 +636,10 @@ impl Voice {
         self.osc[osc_id].update_routing(osc_data);
     }
 
+    pub fn update_sub_routing(&mut self, sub_data: &SubOscData) {
+        self.sub.update_routing(sub_data);
+    }
+
     pub fn trigger(&mut self, trigger_seq: u64, trigger_time: i64, sound: &SoundData) {
         let trigger = match sound.patch.play_mode {
             PlayMode::Poly => true, // Poly: Always retrigger
@@ -271,10 +649,19 @@ impl Voice {
             }
         };
         self.trigger_seq = trigger_seq;
+        self.fade_level = 1.0;
+        self.fade_step = 0.0;
         if trigger {
             if !self.is_running() {
                 for osc in self.osc.iter_mut() {
                     osc.reset(trigger_time);
+                    osc.randomize_phase();
+                }
+                self.sub.reset();
+                self.interpolate = false;
+            } else {
+                for osc in self.osc.iter_mut() {
+                    osc.restart();
                 }
             }
             for i in 0..NUM_ENVELOPES {
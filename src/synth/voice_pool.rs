@@ -0,0 +1,256 @@
+//! Rendering voices in parallel.
+//!
+//! The voices active in a block are split between the audio thread and a
+//! small pool of worker threads. Every voice renders into its own buffer,
+//! and the buffers are summed in voice order afterwards, so the output is
+//! bit-identical to rendering all voices in a single thread.
+//!
+//! The audio thread never waits for a worker that hasn't started its job.
+//! Jobs that are not picked up in time, or can't be handed to a worker at
+//! all, are taken back and rendered by the audio thread itself. Panics in a
+//! worker are caught, so a job that was started always reports back.
+
+use super::Float;
+use super::ModRouting;
+use super::SoundData;
+use super::{SynthState, NUM_VOICES};
+use super::voice::Voice;
+
+use crossbeam_channel::{bounded, Sender, Receiver};
+use log::{error, info};
+
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub const MAX_POOL_BLOCK_SIZE: usize = 256; // Max. number of frames rendered per dispatch
+pub const MAX_VOICE_THREADS: usize = 16;
+
+const WORKER_START_TIMEOUT: Duration = Duration::from_millis(1); // Time after which unstarted jobs are rendered by the audio thread
+const NO_JOB: usize = 0; // Job state of a worker without a job to claim
+
+/// Data shared by all voices while rendering one block.
+pub struct VoiceBlock<'a> {
+    pub sample_clock: i64,
+    pub len: usize,
+    pub control_period: usize,
+    pub sound_global: &'a [SoundData], // Global sound for every control period of the block
+    pub sound_local: &'a SoundData,
+    pub routing: &'a ModRouting,
+    pub global_state: &'a SynthState,
+}
+
+// Render one voice for a complete block into the output buffers.
+fn render_voice(voice: &mut Voice,
+                block: &VoiceBlock,
+                sound_local: &mut SoundData,
+                out_l: &mut [Float],
+                out_r: &mut [Float]) {
+    for (l, r) in out_l.iter_mut().zip(out_r.iter_mut()) {
+        *l = 0.0;
+        *r = 0.0;
+    }
+    let mut offset = 0;
+    let mut chunk = 0;
+    while offset < block.len {
+        let len = std::cmp::min(block.control_period, block.len - offset);
+        voice.process_block(block.sample_clock + offset as i64,
+                            &block.sound_global[chunk],
+                            sound_local,
+                            block.routing,
+                            block.global_state,
+                            &mut out_l[offset..offset + len],
+                            &mut out_r[offset..offset + len]);
+        offset += len;
+        chunk += 1;
+    }
+}
+
+// A share of the voices of one block, to be rendered by a single thread.
+//
+// The pointers are only valid during the VoicePool::render() call that
+// created the job. A worker may only use them after claiming the job by
+// swapping its sequence number out of the job state, and render() doesn't
+// return before every claimed job has reported back. Each job works on a
+// different set of voices and output buffers, so no data is written by more
+// than one thread.
+struct VoiceJob {
+    voices: *mut Voice,
+    output: *mut Float,
+    block: *const (), // The VoiceBlock passed to render()
+    active: u32,   // Bitmap with the voices to render
+    index: usize,  // Every num_threads'th active voice, starting with this one, is rendered
+    num_threads: usize,
+    seq: usize,    // Sequence number of the block, to detect outdated jobs
+}
+
+unsafe impl Send for VoiceJob {}
+
+impl VoiceJob {
+    unsafe fn run(&self, sound_local: &mut SoundData) {
+        let block = &*(self.block as *const VoiceBlock);
+        *sound_local = *block.sound_local;
+        let mut count = 0;
+        for i in 0..NUM_VOICES {
+            if self.active & (1 << i) == 0 {
+                continue;
+            }
+            if count % self.num_threads == self.index {
+                let voice = &mut *self.voices.add(i);
+                let output = std::slice::from_raw_parts_mut(self.output.add(i * 2 * MAX_POOL_BLOCK_SIZE), 2 * MAX_POOL_BLOCK_SIZE);
+                let (out_l, out_r) = output.split_at_mut(MAX_POOL_BLOCK_SIZE);
+                render_voice(voice, block, sound_local, &mut out_l[..block.len], &mut out_r[..block.len]);
+            }
+            count += 1;
+        }
+    }
+}
+
+pub struct VoicePool {
+    num_threads: usize, // Including the audio thread
+    senders: Vec<Sender<VoiceJob>>,
+    job_states: Vec<Arc<AtomicUsize>>, // Sequence number of the job a worker may claim, or NO_JOB
+    done_receiver: Receiver<usize>,    // Index of a worker that finished its job
+    handles: Vec<JoinHandle<()>>,
+    seq: usize,                        // Sequence number of the last rendered block
+    output: Vec<Float>, // Left and right output buffer of every voice
+    sound_local: Box<SoundData>, // Local sound of the share rendered by the audio thread
+}
+
+impl VoicePool {
+    /// Create a pool for rendering the voices with the given number of
+    /// threads, including the calling thread.
+    pub fn new(num_threads: usize) -> VoicePool {
+        let num_threads = std::cmp::max(num_threads, 1);
+        let (done_sender, done_receiver) = bounded::<usize>(MAX_VOICE_THREADS);
+        let mut senders = vec!();
+        let mut job_states = vec!();
+        let mut handles = vec!();
+        for i in 1..num_threads {
+            let (sender, receiver) = bounded::<VoiceJob>(1);
+            let done_sender = done_sender.clone();
+            let job_state = Arc::new(AtomicUsize::new(NO_JOB));
+            let worker_state = job_state.clone();
+            let handle = std::thread::Builder::new()
+                .name(format!("voice worker {}", i))
+                .spawn(move || {
+                    let mut sound_local = Box::new(SoundData::new());
+                    while let Ok(job) = receiver.recv() {
+                        // The job might have been taken back by the audio thread
+                        if worker_state.compare_exchange(job.seq, NO_JOB, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                            continue;
+                        }
+                        let result = catch_unwind(AssertUnwindSafe(|| unsafe { job.run(&mut sound_local) }));
+                        if result.is_err() {
+                            error!("Voice worker {} failed to render a block", i);
+                        }
+                        if done_sender.send(i - 1).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .unwrap();
+            senders.push(sender);
+            job_states.push(job_state);
+            handles.push(handle);
+        }
+        info!("Rendering voices with {} threads", num_threads);
+        VoicePool{
+            num_threads,
+            senders,
+            job_states,
+            done_receiver,
+            handles,
+            seq: NO_JOB,
+            output: vec!(0.0; NUM_VOICES * 2 * MAX_POOL_BLOCK_SIZE),
+            sound_local: Box::new(SoundData::new()),
+        }
+    }
+
+    pub fn get_num_threads(&self) -> usize {
+        self.num_threads
+    }
+
+    /// Render all active voices into their output buffers.
+    ///
+    /// Returns after all voices have been rendered. The block length must
+    /// not exceed MAX_POOL_BLOCK_SIZE.
+    pub fn render(&mut self, voices: &mut [Voice; NUM_VOICES], active: u32, block: &VoiceBlock) {
+        let voices = voices.as_mut_ptr();
+        let output = self.output.as_mut_ptr();
+        let block = block as *const VoiceBlock as *const ();
+        let num_threads = self.num_threads;
+        self.seq = self.seq.wrapping_add(1).max(NO_JOB + 1);
+        let seq = self.seq;
+        let create_job = |index: usize| VoiceJob{voices, output, block, active, index, num_threads, seq};
+
+        // Hand out the jobs. A worker that still holds an outdated job or has
+        // stopped can't take a new one, so that share is rendered here.
+        let mut pending = 0u32; // Bitmap of workers with a job that hasn't reported back
+        let mut in_place = 0u32; // Bitmap of workers whose job is rendered here
+        for (i, sender) in self.senders.iter().enumerate() {
+            self.job_states[i].store(seq, Ordering::Release);
+            if sender.try_send(create_job(i + 1)).is_ok() {
+                pending |= 1 << i;
+            } else {
+                self.job_states[i].store(NO_JOB, Ordering::Release);
+                in_place |= 1 << i;
+            }
+        }
+        unsafe { create_job(0).run(&mut self.sound_local) };
+
+        // Wait a short time for the workers, then take back all jobs that
+        // haven't been started yet. Started jobs always report back.
+        let deadline = Instant::now() + WORKER_START_TIMEOUT;
+        while pending != 0 {
+            match self.done_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(i) => pending &= !(1 << i),
+                Err(_) => break,
+            }
+        }
+        for (i, job_state) in self.job_states.iter().enumerate() {
+            if pending & (1 << i) != 0 && job_state.compare_exchange(seq, NO_JOB, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                pending &= !(1 << i);
+                in_place |= 1 << i;
+            }
+        }
+        for i in 0..self.senders.len() {
+            if in_place & (1 << i) != 0 {
+                unsafe { create_job(i + 1).run(&mut self.sound_local) };
+            }
+        }
+        while pending != 0 {
+            match self.done_receiver.recv() {
+                Ok(i) => pending &= !(1 << i),
+                Err(_) => break, // All workers are gone, nothing is using the block anymore
+            }
+        }
+    }
+
+    // Stop a worker thread, as if it had died.
+    #[cfg(test)]
+    pub fn stop_worker(&mut self, index: usize) {
+        let (sender, _receiver) = bounded::<VoiceJob>(1);
+        self.senders[index] = sender;
+        self.handles.remove(index).join().ok();
+    }
+
+    /// Get the output buffers of a voice rendered in the last block.
+    pub fn get_output(&self, voice: usize, len: usize) -> (&[Float], &[Float]) {
+        let start = voice * 2 * MAX_POOL_BLOCK_SIZE;
+        (&self.output[start..start + len],
+         &self.output[start + MAX_POOL_BLOCK_SIZE..start + MAX_POOL_BLOCK_SIZE + len])
+    }
+}
+
+impl Drop for VoicePool {
+    fn drop(&mut self) {
+        // Closing the job channels stops the workers
+        self.senders.clear();
+        for handle in self.handles.drain(..) {
+            handle.join().ok();
+        }
+    }
+}
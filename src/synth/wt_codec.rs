@@ -0,0 +1,175 @@
+//! Compact encoding of wavetables for embedding them in sound banks.
+//!
+//! Samples are quantized to 24 bits and predicted from the two previous
+//! samples of the wave. Only the prediction error is stored, as a
+//! variable-length integer, which takes one or two bytes per sample for
+//! smooth waves instead of four for a float value. Since sound banks are
+//! JSON files, the result is stored as base64 text.
+
+use super::Float;
+use super::wt_manager::{WtManager, WAVE_LENGTH};
+
+use serde::{Serialize, Deserialize};
+use wavetable::WavetableRef;
+
+use std::io::{Error, ErrorKind};
+
+const SCALE: Float = 8_388_607.0; // Max. value of a 24 bit sample
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Wavetable data stored in a sound bank.
+///
+/// Belongs to the entry with the same ID in the wavetable list of the bank.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EmbeddedTable {
+    pub id: usize,
+    pub num_waves: usize,
+    pub data: String, // Encoded samples of all waves
+}
+
+impl EmbeddedTable {
+    /// Encode the waves of a wavetable with a single octave table per wave.
+    pub fn new(id: usize, wt: &WavetableRef) -> EmbeddedTable {
+        let mut bytes = Vec::new();
+        for wave in wt.table.iter() {
+            let (mut prev1, mut prev2) = (0i64, 0i64);
+            for value in wave.iter().take(WAVE_LENGTH) {
+                let sample = (value.clamp(-1.0, 1.0) * SCALE).round() as i64;
+                EmbeddedTable::write_varint(&mut bytes, sample - (2 * prev1 - prev2));
+                prev2 = prev1;
+                prev1 = sample;
+            }
+        }
+        EmbeddedTable{id, num_waves: wt.table.len(), data: EmbeddedTable::to_base64(&bytes)}
+    }
+
+    /// Decode the embedded data into a wavetable.
+    pub fn to_wavetable(&self) -> std::io::Result<WavetableRef> {
+        let bytes = EmbeddedTable::from_base64(&self.data)?;
+        let mut pos = 0;
+        let mut tables = Vec::with_capacity(self.num_waves);
+        for _ in 0..self.num_waves {
+            let (mut prev1, mut prev2) = (0i64, 0i64);
+            let mut table = Vec::with_capacity(WAVE_LENGTH + 1);
+            for _ in 0..WAVE_LENGTH {
+                let sample = EmbeddedTable::read_varint(&bytes, &mut pos)? + (2 * prev1 - prev2);
+                table.push(sample as Float / SCALE);
+                prev2 = prev1;
+                prev1 = sample;
+            }
+            table.push(table[0]); // Duplicate first entry as last entry for easy interpolation
+            tables.push(table);
+        }
+        if pos != bytes.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "Embedded wavetable has extra data"));
+        }
+        WtManager::create_table(tables)
+    }
+
+    // Write a signed value as zigzag-encoded variable-length integer.
+    fn write_varint(bytes: &mut Vec<u8>, value: i64) {
+        let mut v = ((value << 1) ^ (value >> 63)) as u64;
+        while v >= 0x80 {
+            bytes.push((v as u8 & 0x7F) | 0x80);
+            v >>= 7;
+        }
+        bytes.push(v as u8);
+    }
+
+    fn read_varint(bytes: &[u8], pos: &mut usize) -> std::io::Result<i64> {
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = match bytes.get(*pos) {
+                Some(b) if shift < 64 => *b,
+                _ => return Err(Error::new(ErrorKind::InvalidData, "Embedded wavetable is truncated")),
+            };
+            *pos += 1;
+            v |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn to_base64(bytes: &[u8]) -> String {
+        let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+            let n = (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize;
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    text.push(BASE64_CHARS[(n >> (18 - 6 * i)) & 0x3F] as char);
+                } else {
+                    text.push('=');
+                }
+            }
+        }
+        text
+    }
+
+    fn from_base64(text: &str) -> std::io::Result<Vec<u8>> {
+        let text = text.trim_end_matches('=');
+        let mut bytes = Vec::with_capacity(text.len() * 3 / 4);
+        let mut n = 0usize;
+        let mut num_bits = 0;
+        for c in text.bytes() {
+            let value = match BASE64_CHARS.iter().position(|x| *x == c) {
+                Some(v) => v,
+                None => return Err(Error::new(ErrorKind::InvalidData, "Invalid character in embedded wavetable")),
+            };
+            n = (n << 6) | value;
+            num_bits += 6;
+            if num_bits >= 8 {
+                num_bits -= 8;
+                bytes.push((n >> num_bits) as u8);
+                n &= (1 << num_bits) - 1;
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+#[test]
+fn embedded_table_is_restored() {
+    let pi = std::f64::consts::PI;
+    let waves: Vec<Vec<Float>> = (1..4).map(|h| {
+        (0..=WAVE_LENGTH).map(|i| (2.0 * pi * (h * i) as Float / WAVE_LENGTH as Float).sin()).collect()
+    }).collect();
+    let wt = WtManager::create_table(waves.clone()).unwrap();
+    let embedded = EmbeddedTable::new(5, &wt);
+    assert_eq!(embedded.num_waves, 3);
+
+    // Smooth waves need less space than 32 bit float values, even as text
+    assert!(embedded.data.len() < 3 * WAVE_LENGTH * 3, "{} bytes", embedded.data.len());
+
+    let restored = embedded.to_wavetable().unwrap();
+    assert_eq!(restored.table.len(), 3);
+    for (wave, restored_wave) in waves.iter().zip(restored.table.iter()) {
+        assert_eq!(restored_wave.len(), WAVE_LENGTH + 1);
+        for (a, b) in wave.iter().zip(restored_wave.iter()) {
+            assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+        }
+    }
+}
+
+#[test]
+fn base64_round_trip() {
+    for len in 0..8 {
+        let bytes: Vec<u8> = (0..len).map(|i| (i * 97 + 200) as u8).collect();
+        let text = EmbeddedTable::to_base64(&bytes);
+        assert_eq!(text.len() % 4, 0);
+        assert_eq!(EmbeddedTable::from_base64(&text).unwrap(), bytes);
+    }
+    assert_eq!(EmbeddedTable::to_base64(b"Yazz"), "WWF6eg==");
+}
+
+#[test]
+fn damaged_data_is_rejected() {
+    let embedded = EmbeddedTable{id: 2, num_waves: 1, data: "AAAA".to_string()};
+    assert!(embedded.to_wavetable().is_err());
+    let embedded = EmbeddedTable{id: 2, num_waves: 1, data: "AA*A".to_string()};
+    assert!(embedded.to_wavetable().is_err());
+}
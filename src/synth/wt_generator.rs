@@ -0,0 +1,300 @@
+//! Generates wavetables from lists of harmonics.
+//!
+//! A generated wavetable is described by a definition instead of sample data:
+//! either explicit harmonic spectra for a few key waves, which are
+//! interpolated to fill the table, or a formula like a morph between two
+//! basic shapes or a formant sweeping over a basic shape. The definition is
+//! small enough to be stored in the sound bank, so sounds using a generated
+//! table don't depend on any external files.
+//!
+//! Every wave gets one bandlimited table per octave, like the built-in
+//! tables.
+
+use super::Float;
+use super::wt_manager::WAVE_LENGTH;
+
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use wavetable::{Wavetable, WavetableRef};
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+
+pub const GENERATOR_EXTENSION: &str = "ywt"; // File extension of wavetable definitions
+pub const MAX_GENERATED_WAVES: usize = 256;  // Max. number of waves in a generated table
+
+const NUM_OCTAVES: usize = 11;
+const MAX_HARMONIC: usize = WAVE_LENGTH / 2 - 1; // Highest harmonic that fits into a table
+
+lazy_static! {
+    static ref SINE_TABLE: Vec<Float> = (0..WAVE_LENGTH)
+        .map(|i| (2.0 * std::f64::consts::PI * i as Float / WAVE_LENGTH as Float).sin())
+        .collect();
+}
+
+/// Entry in the list of generated wavetables of a sound bank.
+///
+/// Shares the ID range with the wavetables loaded from files.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WtGenInfo {
+    pub id: usize,
+    pub name: String,
+    pub def: WtGenDef,
+}
+
+/// Definition of a generated wavetable.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WtGenDef {
+    pub num_waves: usize,
+    pub source: HarmonicSource,
+}
+
+/// A single harmonic of a wave.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Harmonic {
+    pub amplitude: Float,
+    #[serde(default)]
+    pub phase: Float, // Phase offset in fractions of a cycle (0.0 - 1.0)
+}
+
+/// Waveshapes used by the harmonic formulas.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum BasicShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+}
+
+/// Where the harmonics of the waves come from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum HarmonicSource {
+    // Harmonics of key waves, starting with the fundamental. The key waves
+    // are spread evenly over the table, the waves in between are
+    // interpolated.
+    Spectra(Vec<Vec<Harmonic>>),
+
+    // Morph from one basic shape to another.
+    Morph{from: BasicShape, to: BasicShape},
+
+    // Basic shape with a resonant peak that sweeps from the start harmonic
+    // to the end harmonic. Width is the width of the peak in harmonics, gain
+    // the boost of the harmonics at the center of the peak.
+    Formant{shape: BasicShape, start: Float, end: Float, width: Float, gain: Float},
+}
+
+// Harmonic as sine and cosine coefficients, which can be interpolated
+// without phase jumps.
+type Coefficient = (Float, Float);
+
+impl Harmonic {
+    fn to_coefficient(self) -> Coefficient {
+        let angle = self.phase * 2.0 * std::f64::consts::PI;
+        (self.amplitude * angle.cos(), self.amplitude * angle.sin())
+    }
+}
+
+impl BasicShape {
+    // Sine coefficient of harmonic n (starting at 1) of the shape.
+    //
+    // All shapes start at 0 and rise in the first half of the cycle like a
+    // sine wave, so morphing between them doesn't cancel harmonics.
+    fn get_harmonic(&self, n: usize) -> Float {
+        let odd = n & 0x01 == 1;
+        match self {
+            BasicShape::Sine => if n == 1 { 1.0 } else { 0.0 },
+            BasicShape::Triangle if odd => {
+                let sign = if (n / 2) & 0x01 == 0 { 1.0 } else { -1.0 };
+                sign / (n * n) as Float
+            }
+            BasicShape::Triangle => 0.0,
+            BasicShape::Saw => 1.0 / n as Float,
+            BasicShape::Square => if odd { 1.0 / n as Float } else { 0.0 },
+        }
+    }
+}
+
+impl WtGenDef {
+    /// Read a definition from a JSON file.
+    pub fn read_file(filename: &str) -> std::io::Result<WtGenDef> {
+        let reader = BufReader::new(File::open(filename)?);
+        let def: WtGenDef = serde_json::from_reader(reader)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        def.validate()?;
+        Ok(def)
+    }
+
+    /// Check that the definition can be used to create a table.
+    pub fn validate(&self) -> std::io::Result<()> {
+        if self.num_waves == 0 || self.num_waves > MAX_GENERATED_WAVES {
+            let msg = format!("Number of waves must be between 1 and {}", MAX_GENERATED_WAVES);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        match &self.source {
+            HarmonicSource::Spectra(spectra) if spectra.is_empty() => {
+                Err(Error::new(ErrorKind::InvalidData, "No spectra given"))
+            }
+            HarmonicSource::Formant{start, end, width, ..} if *start <= 0.0 || *end <= 0.0 || *width <= 0.0 => {
+                Err(Error::new(ErrorKind::InvalidData, "Formant start, end and width must be greater than 0"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Create the wavetable described by this definition.
+    pub fn create_table(&self, sample_rate: Float) -> std::io::Result<WavetableRef> {
+        self.validate()?;
+        let num_values = WAVE_LENGTH + 1;
+        let mut tables = vec!(vec!(0.0; num_values * NUM_OCTAVES); self.num_waves);
+        for (wave_index, table) in tables.iter_mut().enumerate() {
+            let coefficients = self.get_coefficients(wave_index);
+            WtGenDef::insert_octaves(table, &coefficients, sample_rate);
+        }
+        Ok(Wavetable::new_from_vector(self.num_waves, NUM_OCTAVES, WAVE_LENGTH, tables))
+    }
+
+    // Position of the wave in the table, 0.0 for the first and 1.0 for the
+    // last wave.
+    fn get_position(&self, wave_index: usize) -> Float {
+        if self.num_waves > 1 {
+            wave_index as Float / (self.num_waves - 1) as Float
+        } else {
+            0.0
+        }
+    }
+
+    // Calculate the harmonics of a single wave, starting with the
+    // fundamental.
+    fn get_coefficients(&self, wave_index: usize) -> Vec<Coefficient> {
+        let pos = self.get_position(wave_index);
+        match &self.source {
+            HarmonicSource::Spectra(spectra) => {
+                let key_pos = pos * (spectra.len() - 1) as Float;
+                let index = key_pos as usize;
+                let next = (index + 1).min(spectra.len() - 1);
+                let frac = key_pos - index as Float;
+                let len = spectra[index].len().max(spectra[next].len()).min(MAX_HARMONIC);
+                let get = |spectrum: &Vec<Harmonic>, n: usize| {
+                    spectrum.get(n).map(|h| h.to_coefficient()).unwrap_or((0.0, 0.0))
+                };
+                (0..len).map(|n| {
+                    let (a, b) = (get(&spectra[index], n), get(&spectra[next], n));
+                    (a.0 + (b.0 - a.0) * frac, a.1 + (b.1 - a.1) * frac)
+                }).collect()
+            }
+            HarmonicSource::Morph{from, to} => {
+                (1..=MAX_HARMONIC).map(|n| {
+                    let (a, b) = (from.get_harmonic(n), to.get_harmonic(n));
+                    (a + (b - a) * pos, 0.0)
+                }).collect()
+            }
+            HarmonicSource::Formant{shape, start, end, width, gain} => {
+                let center = start * (end / start).powf(pos); // Sweep evenly in pitch
+                (1..=MAX_HARMONIC).map(|n| {
+                    let distance = (n as Float - center) / width;
+                    let boost = 1.0 + gain * (-0.5 * distance * distance).exp();
+                    (shape.get_harmonic(n) * boost, 0.0)
+                }).collect()
+            }
+        }
+    }
+
+    // Fill the octave tables of one wave with the given harmonics.
+    //
+    // Every octave table only gets the harmonics that stay below the Nyquist
+    // frequency in its octave, the fundamental is always added. Starting
+    // with the highest octave, which has the fewest harmonics, the wave is
+    // built up step by step, so every harmonic is only calculated once.
+    fn insert_octaves(table: &mut [Float], coefficients: &[Coefficient], sample_rate: Float) {
+        let num_values = WAVE_LENGTH + 1;
+        let mut wave = vec!(0.0; WAVE_LENGTH);
+        let mut num_done = 0;
+        let start_freq = Wavetable::get_start_frequency(440.0);
+        for octave in (0..NUM_OCTAVES).rev() {
+            let max_freq = start_freq * (1 << (octave + 1)) as Float;
+            let num_harmonics = Wavetable::calc_num_harmonics(max_freq, sample_rate)
+                .max(1)
+                .min(coefficients.len());
+            for (i, (s, c)) in coefficients.iter().enumerate().take(num_harmonics).skip(num_done) {
+                if *s == 0.0 && *c == 0.0 {
+                    continue;
+                }
+                let n = i + 1;
+                for (j, value) in wave.iter_mut().enumerate() {
+                    *value += s * SINE_TABLE[(n * j) % WAVE_LENGTH]
+                            + c * SINE_TABLE[(n * j + WAVE_LENGTH / 4) % WAVE_LENGTH];
+                }
+            }
+            num_done = num_done.max(num_harmonics);
+            let octave_table = &mut table[octave * num_values..(octave + 1) * num_values];
+            octave_table[..WAVE_LENGTH].copy_from_slice(&wave);
+            octave_table[WAVE_LENGTH] = wave[0]; // Duplicate first entry as last entry for easy interpolation
+            Wavetable::normalize(octave_table);
+        }
+    }
+}
+
+#[test]
+fn spectra_are_interpolated() {
+    let h = |amplitude, phase| Harmonic{amplitude, phase};
+    let def = WtGenDef{
+        num_waves: 3,
+        source: HarmonicSource::Spectra(vec!(vec!(h(1.0, 0.0)), vec!(h(0.0, 0.0), h(1.0, 0.25)))),
+    };
+    let c = def.get_coefficients(0);
+    assert_eq!(c, vec!((1.0, 0.0), (0.0, 0.0)));
+    let c = def.get_coefficients(1);
+    assert!((c[0].0 - 0.5).abs() < 1e-12 && c[0].1.abs() < 1e-12);
+    assert!(c[1].0.abs() < 1e-12 && (c[1].1 - 0.5).abs() < 1e-12);
+}
+
+#[test]
+fn morph_ends_with_target_shape() {
+    let def = WtGenDef{num_waves: 4, source: HarmonicSource::Morph{from: BasicShape::Saw, to: BasicShape::Square}};
+    let first = def.get_coefficients(0);
+    let last = def.get_coefficients(3);
+    assert_eq!(first[1].0, 0.5);
+    assert_eq!(last[1].0, 0.0); // No even harmonics in a square wave
+    assert_eq!(last[2].0, 1.0 / 3.0);
+}
+
+#[test]
+fn octave_tables_are_bandlimited() {
+    let def = WtGenDef{num_waves: 1, source: HarmonicSource::Morph{from: BasicShape::Saw, to: BasicShape::Saw}};
+    let wt = def.create_table(44100.0).unwrap();
+    assert_eq!(wt.num_octaves, NUM_OCTAVES);
+    let num_values = WAVE_LENGTH + 1;
+    // The highest octaves only hold the fundamental
+    let top = &wt.table[0][(NUM_OCTAVES - 1) * num_values..];
+    for (i, value) in top.iter().enumerate().step_by(64) {
+        let expected = SINE_TABLE[i % WAVE_LENGTH];
+        assert!((value - expected).abs() < 1e-9, "{}: {} != {}", i, value, expected);
+    }
+    // The lowest octave is a full saw wave
+    let bottom = &wt.table[0][..num_values];
+    assert_eq!(bottom[0], bottom[WAVE_LENGTH]);
+    assert!(bottom[1] > 0.9);
+}
+
+#[test]
+fn invalid_definition_is_rejected() {
+    let def = WtGenDef{num_waves: 0, source: HarmonicSource::Morph{from: BasicShape::Sine, to: BasicShape::Saw}};
+    assert!(def.create_table(44100.0).is_err());
+    let def = WtGenDef{num_waves: 8, source: HarmonicSource::Spectra(vec!())};
+    assert!(def.create_table(44100.0).is_err());
+    let def = WtGenDef{
+        num_waves: 8,
+        source: HarmonicSource::Formant{shape: BasicShape::Saw, start: 0.0, end: 20.0, width: 2.0, gain: 4.0},
+    };
+    assert!(def.create_table(44100.0).is_err());
+}
+
+#[test]
+fn definition_is_read_from_json() {
+    let json = r#"{"num_waves": 16, "source": {"Spectra": [[{"amplitude": 1.0}, {"amplitude": 0.5, "phase": 0.5}]]}}"#;
+    let def: WtGenDef = serde_json::from_str(json).unwrap();
+    assert_eq!(def.num_waves, 16);
+    assert_eq!(def.source, HarmonicSource::Spectra(vec!(vec!(
+        Harmonic{amplitude: 1.0, phase: 0.0},
+        Harmonic{amplitude: 0.5, phase: 0.5}))));
+}
@@ -0,0 +1,280 @@
+//! Manages the wavetables available to the synth.
+//!
+//! The manager holds the built-in tables and the tables imported from WAV
+//! files in a cache, handing out references to them.
+//!
+//! Imported files can use any sample format supported by WavFile. Files with
+//! more than one channel are mixed down to mono. The length of a wave cycle
+//! is taken from the "clm " chunk if the file has one, otherwise it is
+//! detected from the length of the file. Cycles that are not 2048 samples
+//! long are resampled to 2048 samples.
+//!
+//! Detection only knows the cycle lengths in FRAME_SIZES. Multi-cycle files
+//! with other cycle lengths need a "clm " chunk, without one they are read
+//! as a single cycle or rejected, depending on their length.
+
+use super::Float;
+use super::{EmbeddedTable, WtGenInfo};
+use crate::wav_file::WavFile;
+
+use log::info;
+use wavetable::{Wavetable, WavetableRef, WtCreator, WtInfo};
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+pub const WAVETABLE_DIR: &str = "data"; // Directory containing the wavetable files
+pub const WAVE_LENGTH: usize = 2048;    // Samples per wave cycle in a table
+
+const NUM_PWM_TABLES: usize = 64;
+const FRAME_SIZES: [usize; 4] = [2048, 1024, 512, 256]; // Cycle lengths detected from the file length, in order of preference
+const MAX_SINGLE_CYCLE: usize = 4096; // Longest file that is read as a single wave cycle of any length
+
+pub struct WtManager {
+    sample_rate: Float,
+    cache: HashMap<usize, WavetableRef>,
+}
+
+impl WtManager {
+    pub fn new(sample_rate: Float) -> WtManager {
+        WtManager{sample_rate, cache: HashMap::new()}
+    }
+
+    /// Add the table with sine, triangle, saw and square waves with the
+    /// given ID.
+    pub fn add_basic_tables(&mut self, id: usize) {
+        self.add_table(id, WtCreator::create_default_waves(self.sample_rate));
+    }
+
+    /// Add the table with square waves of different pulse widths with the
+    /// given ID.
+    pub fn add_pwm_tables(&mut self, id: usize) {
+        self.add_table(id, WtCreator::create_pwm_waves(self.sample_rate, NUM_PWM_TABLES));
+    }
+
+    /// Add the table generated from the definition of a wavetable list
+    /// entry with the ID of the entry.
+    pub fn add_generated_table(&mut self, gen_info: &WtGenInfo) -> std::io::Result<()> {
+        let table = gen_info.def.create_table(self.sample_rate)?;
+        info!("Generated wavetable {} with {} waves", gen_info.name, table.table.len());
+        self.add_table(gen_info.id, table);
+        Ok(())
+    }
+
+    /// Add a table that was embedded in a sound bank with the ID of the
+    /// embedded table.
+    pub fn add_embedded_table(&mut self, embedded: &EmbeddedTable) -> std::io::Result<()> {
+        let table = embedded.to_wavetable()?;
+        self.add_table(embedded.id, table);
+        Ok(())
+    }
+
+    /// Add a table with the given ID, replacing any table with the same ID.
+    pub fn add_table(&mut self, id: usize, table: WavetableRef) {
+        self.cache.insert(id, table);
+    }
+
+    pub fn get_table(&self, id: usize) -> Option<WavetableRef> {
+        self.cache.get(&id).cloned()
+    }
+
+    /// Load the file of a wavetable list entry and add it to the cache.
+    ///
+    /// If the file can't be read, the fallback table is added instead, so
+    /// that sounds using the table still play, and the error is returned.
+    /// The valid flag of the entry is updated accordingly.
+    pub fn load_table(&mut self, wt_info: &mut WtInfo, fallback: WavetableRef) -> std::io::Result<()> {
+        let filename = format!("{}/{}", WAVETABLE_DIR, wt_info.filename);
+        let result = WtManager::read_file(&filename);
+        wt_info.valid = result.is_ok();
+        match result {
+            Ok(table) => {
+                info!("Loaded wavetable {} with {} waves", wt_info.name, table.table.len());
+                self.add_table(wt_info.id, table);
+                Ok(())
+            }
+            Err(e) => {
+                self.add_table(wt_info.id, fallback);
+                Err(e)
+            }
+        }
+    }
+
+    /// Read a wavetable from a WAV file.
+    pub fn read_file(filename: &str) -> std::io::Result<WavetableRef> {
+        let wav = WavFile::read_file(filename)?;
+        WtManager::convert(&wav)
+    }
+
+    /// Convert the contents of a WAV file to a wavetable.
+    ///
+    /// Every wave cycle in the file becomes one wave of the table. The waves
+    /// are normalized individually and not bandlimited.
+    pub fn convert(wav: &WavFile) -> std::io::Result<WavetableRef> {
+        let num_channels = wav.num_channels as usize;
+        let samples: Vec<Float> = wav.samples
+            .chunks_exact(num_channels)
+            .map(|frame| frame.iter().map(|s| *s as Float).sum::<Float>() / num_channels as Float)
+            .collect();
+        let cycle_length = match wav.cycle_length {
+            Some(len) => len,
+            None => WtManager::detect_cycle_length(samples.len())?,
+        };
+        if samples.len() < cycle_length {
+            let msg = format!("File is shorter than one wave cycle of {} samples", cycle_length);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        if samples.len() % cycle_length != 0 {
+            let msg = format!("{} samples are not a multiple of the wave cycle length {}", samples.len(), cycle_length);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        let tables: Vec<Vec<Float>> = samples
+            .chunks_exact(cycle_length)
+            .map(|cycle| {
+                let mut table = WtManager::resample(cycle, WAVE_LENGTH);
+                table.push(table[0]); // Duplicate first entry as last entry for easy interpolation
+                WtManager::normalize(&mut table);
+                table
+            })
+            .collect();
+        WtManager::create_table(tables)
+    }
+
+    /// Create a wavetable with a single octave table per wave.
+    ///
+    /// Every wave must hold WAVE_LENGTH samples plus a copy of the first
+    /// sample at the end.
+    pub fn create_table(tables: Vec<Vec<Float>>) -> std::io::Result<WavetableRef> {
+        if tables.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Wavetable contains no waves"));
+        }
+        if tables.iter().any(|t| t.len() != WAVE_LENGTH + 1) {
+            return Err(Error::new(ErrorKind::InvalidData, "Wavetable contains waves of the wrong length"));
+        }
+        Ok(Wavetable::new_from_vector(tables.len(), 1, WAVE_LENGTH, tables))
+    }
+
+    // Guess the wave cycle length of a file without cycle length info.
+    //
+    // Uses the first of the common cycle lengths that the file length is a
+    // multiple of. Short files that don't match any of them are treated as a
+    // single wave cycle.
+    fn detect_cycle_length(num_samples: usize) -> std::io::Result<usize> {
+        if num_samples == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "File contains no samples"));
+        }
+        match FRAME_SIZES.iter().find(|size| num_samples % *size == 0) {
+            Some(size) => Ok(*size),
+            None if num_samples <= MAX_SINGLE_CYCLE => Ok(num_samples),
+            None => {
+                let msg = format!("Unable to detect the wave cycle length of {} samples, \
+                                   cycles other than 2048, 1024, 512 or 256 samples need a \"clm \" chunk", num_samples);
+                Err(Error::new(ErrorKind::InvalidData, msg))
+            }
+        }
+    }
+
+    // Resample a single wave cycle to the given length.
+    //
+    // Uses cubic Hermite interpolation, wrapping around at the end of the
+    // cycle.
+    fn resample(cycle: &[Float], len: usize) -> Vec<Float> {
+        if cycle.len() == len {
+            return cycle.to_vec();
+        }
+        let num_samples = cycle.len() as isize;
+        let step = cycle.len() as Float / len as Float;
+        let sample = |i: isize| cycle[i.rem_euclid(num_samples) as usize];
+        (0..len).map(|i| {
+            let position = i as Float * step;
+            let index = position as isize;
+            let frac = position - index as Float;
+            let (xm1, x0, x1, x2) = (sample(index - 1), sample(index), sample(index + 1), sample(index + 2));
+            let c1 = 0.5 * (x1 - xm1);
+            let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+            let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+            ((c3 * frac + c2) * frac + c1) * frac + x0
+        }).collect()
+    }
+
+    /// Scale a wave to a peak value of 1.0, leaving silent waves untouched.
+    pub fn normalize(table: &mut [Float]) {
+        let max = table.iter().fold(0.0, |max: Float, x| max.max(x.abs()));
+        if max > 0.0 {
+            table.iter_mut().for_each(|x| *x /= max);
+        }
+    }
+}
+
+// Wrap samples into a WAV file structure.
+#[cfg(test)]
+fn create_wav(num_channels: u16, samples: Vec<f32>, cycle_length: Option<usize>) -> WavFile {
+    WavFile{num_channels, sample_rate: 44100, bits_per_sample: 16, samples, cycle_length}
+}
+
+#[test]
+fn cycle_length_is_detected_from_file_length() {
+    assert_eq!(WtManager::detect_cycle_length(2048 * 3).unwrap(), 2048);
+    assert_eq!(WtManager::detect_cycle_length(512 * 3).unwrap(), 512);
+    assert_eq!(WtManager::detect_cycle_length(600).unwrap(), 600); // Single cycle
+    assert!(WtManager::detect_cycle_length(5000).is_err());
+    assert!(WtManager::detect_cycle_length(0).is_err());
+}
+
+#[test]
+fn stereo_file_is_mixed_down() {
+    let samples: Vec<f32> = (0..2048).flat_map(|i| vec![i as f32, -(i as f32) / 2.0]).collect();
+    let wt = WtManager::convert(&create_wav(2, samples, None)).unwrap();
+    assert_eq!(wt.table.len(), 1);
+    assert_eq!(wt.table[0].len(), WAVE_LENGTH + 1);
+    assert_eq!(wt.table[0][2047], 1.0); // Normalized peak
+    assert!((wt.table[0][1024] - 1024.0 / 2047.0).abs() < 1e-12);
+    assert_eq!(wt.table[0][2048], wt.table[0][0]);
+}
+
+#[test]
+fn cycles_are_resampled_to_table_length() {
+    // Two cycles of a sine with 256 samples each
+    let pi = std::f64::consts::PI;
+    let samples: Vec<f32> = (0..512).map(|i| (2.0 * pi * i as Float / 256.0).sin() as f32).collect();
+    let wt = WtManager::convert(&create_wav(1, samples, Some(256))).unwrap();
+    assert_eq!(wt.table.len(), 2);
+    for table in wt.table.iter() {
+        for (i, value) in table.iter().enumerate().step_by(64) {
+            let expected = (2.0 * pi * i as Float / 2048.0).sin();
+            assert!((value - expected).abs() < 0.001, "{}: {} != {}", i, value, expected);
+        }
+    }
+}
+
+#[test]
+fn cycle_length_from_clm_chunk_is_not_limited_to_frame_sizes() {
+    // Three cycles of a sine with 1000 samples each
+    let pi = std::f64::consts::PI;
+    let samples: Vec<f32> = (0..3000).map(|i| (2.0 * pi * i as Float / 1000.0).sin() as f32).collect();
+    let wt = WtManager::convert(&create_wav(1, samples.clone(), Some(1000))).unwrap();
+    assert_eq!(wt.table.len(), 3);
+    for table in wt.table.iter() {
+        for (i, value) in table.iter().enumerate().step_by(64) {
+            let expected = (2.0 * pi * i as Float / 2048.0).sin();
+            assert!((value - expected).abs() < 0.001, "{}: {} != {}", i, value, expected);
+        }
+    }
+
+    // Without the chunk, the same file is read as a single cycle
+    let wt = WtManager::convert(&create_wav(1, samples.clone(), None)).unwrap();
+    assert_eq!(wt.table.len(), 1);
+
+    // and a longer one is rejected
+    let samples: Vec<f32> = samples.iter().cycle().take(5000).cloned().collect();
+    assert!(WtManager::convert(&create_wav(1, samples.clone(), None)).is_err());
+    assert_eq!(WtManager::convert(&create_wav(1, samples, Some(1000))).unwrap().table.len(), 5);
+}
+
+#[test]
+fn mismatching_cycle_length_is_rejected() {
+    let wav = create_wav(1, vec![0.5; 3000], Some(2048));
+    assert!(WtManager::convert(&wav).is_err());
+    let wav = create_wav(1, vec![0.5; 1000], Some(2048));
+    assert!(WtManager::convert(&wav).is_err());
+}
@@ -1,9 +1,91 @@
 use super::Float;
+use super::NoiseRng;
 use wavetable::WavetableRef;
 
+use lazy_static::lazy_static;
 use serde::{Serialize, Deserialize};
 
-const MAX_VOICES: usize = 7;
+pub const MAX_VOICES: usize = 7; // Max. number of unison voices
+
+/// Ways of distorting the read position in the wave.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum WarpMode {
+    Off,
+    BendPlus,  // Stretch the middle of the wave, squeeze the edges
+    BendMinus, // Squeeze the middle of the wave, stretch the edges
+    Mirror,    // Play the wave forwards, then backwards
+    Sync,      // Play the wave several times per cycle, like hard sync
+    Pwm,       // Move the middle of the wave, like pulse width on a square
+    Quantize,  // Reduce the number of read positions per cycle
+    Fm,        // Modulate the read position with a sine, like FM
+}
+
+impl WarpMode {
+    pub fn from_int(param: usize) -> WarpMode {
+        match param {
+            0 => WarpMode::Off,
+            1 => WarpMode::BendPlus,
+            2 => WarpMode::BendMinus,
+            3 => WarpMode::Mirror,
+            4 => WarpMode::Sync,
+            5 => WarpMode::Pwm,
+            6 => WarpMode::Quantize,
+            7 => WarpMode::Fm,
+            _ => panic!(),
+        }
+    }
+
+    pub fn to_int(&self) -> usize {
+        match self {
+            WarpMode::Off => 0,
+            WarpMode::BendPlus => 1,
+            WarpMode::BendMinus => 2,
+            WarpMode::Mirror => 3,
+            WarpMode::Sync => 4,
+            WarpMode::Pwm => 5,
+            WarpMode::Quantize => 6,
+            WarpMode::Fm => 7,
+        }
+    }
+}
+
+impl Default for WarpMode {
+    fn default() -> Self { WarpMode::Off }
+}
+
+/// Ways of reading values in between the samples of a wave.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Fast,   // Linear, but snaps to a sample when close to it
+    Linear, // Linear between the two neighbouring samples
+    Cubic,  // Cubic Hermite spline through four samples
+    Sinc,   // Windowed sinc over eight samples
+}
+
+impl Interpolation {
+    pub fn from_int(param: usize) -> Interpolation {
+        match param {
+            0 => Interpolation::Fast,
+            1 => Interpolation::Linear,
+            2 => Interpolation::Cubic,
+            3 => Interpolation::Sinc,
+            _ => panic!(),
+        }
+    }
+
+    pub fn to_int(&self) -> usize {
+        match self {
+            Interpolation::Fast => 0,
+            Interpolation::Linear => 1,
+            Interpolation::Cubic => 2,
+            Interpolation::Sinc => 3,
+        }
+    }
+}
+
+impl Default for Interpolation {
+    fn default() -> Self { Interpolation::Fast }
+}
 
 /// Sound data for the wavetable oscillator
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
@@ -12,12 +94,33 @@ pub struct WtOscData {
     pub voice_spread: Float,
     pub wave_index: Float, // Index into the wave tables
     pub wavetable: usize,
+    #[serde(default)]
+    pub warp_mode: WarpMode,
+    #[serde(default)]
+    pub warp_amount: Float, // Strength of the warp, 0.0 - 1.0
+    #[serde(default)]
+    pub width: Float,        // Stereo spread of the unison voices, 0.0 - 1.0
+    #[serde(default)]
+    pub random_phase: Float, // Random start position of the unison voices, 0.0 - 1.0
+    #[serde(default)]
+    pub blend: Float,        // Level of centre vs. detuned voices, -1.0 - 1.0
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    #[serde(default)]
+    pub octave_fade: i64,    // Crossfade between the octave tables, 0 or 1
 }
 
 impl WtOscData {
     pub fn init(&mut self) {
         self.set_voice_num(1);
         self.wave_index = 0.0;
+        self.warp_mode = WarpMode::Off;
+        self.warp_amount = 0.0;
+        self.width = 0.0;
+        self.random_phase = 0.0;
+        self.blend = 0.0;
+        self.interpolation = Interpolation::Fast;
+        self.octave_fade = 0;
     }
 
     /** Number of detuned voices per oscillator. */
@@ -29,14 +132,190 @@ impl WtOscData {
     pub fn set_voice_spread(&mut self, spread: Float) {
         self.voice_spread = spread;
     }
+
+    /** True if the unison voices are spread across the stereo field. */
+    pub fn is_stereo(&self) -> bool {
+        self.width > 0.0 && self.num_voices > 1
+    }
+
+    /** Get the level and the stereo position of every unison voice.
+     *
+     * Voice 0 is the centre voice, it is never detuned or panned. The
+     * detuned voices alternate between left and right, moving further out
+     * with every pair. The position is scaled by the width, -1.0 is left,
+     * 1.0 is right. The blend turns down either the centre voice (blend >
+     * 0.0) or the detuned voices (blend < 0.0). Only the given number of
+     * voices is played.
+     */
+    pub fn get_voice_mix(&self, num_voices: usize) -> ([Float; MAX_VOICES], [Float; MAX_VOICES]) {
+        let mut level = [0.0; MAX_VOICES];
+        let mut pan = [0.0; MAX_VOICES];
+        let num_voices = num_voices.clamp(1, MAX_VOICES);
+        if num_voices == 1 {
+            level[0] = 1.0;
+            return (level, pan);
+        }
+        let blend = self.blend.clamp(-1.0, 1.0);
+        let width = self.width.clamp(0.0, 1.0);
+        let num_pairs = (num_voices / 2) as Float;
+        level[0] = (1.0 - blend).min(1.0);
+        for i in 1..num_voices {
+            level[i] = (1.0 + blend).min(1.0);
+            let distance = ((i + 1) / 2) as Float / num_pairs;
+            pan[i] = if i & 0x01 == 1 { -distance } else { distance } * width;
+        }
+        (level, pan)
+    }
+}
+
+/// Ways of modulating the wave with the output of another oscillator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WtModMode {
+    Frequency, // Linear through-zero FM, the index scales the carrier frequency
+    Phase,     // PM, the index is the phase deviation in radians
 }
 
 const NUM_SAMPLES_PER_TABLE: usize = 2048;
 const NUM_VALUES_PER_TABLE: usize = NUM_SAMPLES_PER_TABLE + 1; // Add one sample for easier interpolation on last sample
 
+const MAX_BEND_EXPONENT: Float = 4.0; // Curve of the bend modes at full amount
+const MAX_SYNC_RATIO: Float = 16.0;   // Number of wave cycles per cycle at full amount
+const MIN_PULSE_WIDTH: Float = 0.01;  // Width of the first half of the wave at full amount
+const FM_WARP_RATIO: Float = 2.0;     // Modulator cycles per wave cycle in FM mode
+const MAX_FM_WARP_DEPTH: Float = 0.5; // Max. deviation of the read position in FM mode, in wave cycles
+
+const SINC_TAPS: usize = 8;     // Number of samples used for sinc interpolation
+const SINC_PHASES: usize = 256; // Number of precalculated kernels between two samples
+
+lazy_static! {
+    static ref SINC_KERNEL: Vec<[Float; SINC_TAPS]> = create_sinc_kernel();
+}
+
+// Calculate the Blackman-windowed sinc kernels for all fractional positions.
+//
+// Kernel i holds the weights of the samples at offsets -3 to 4 for a read
+// position i / SINC_PHASES after sample 0. There is one extra kernel for
+// interpolating between the phases. Every kernel is normalized to unity
+// gain.
+fn create_sinc_kernel() -> Vec<[Float; SINC_TAPS]> {
+    let half = (SINC_TAPS / 2) as Float;
+    let pi = std::f64::consts::PI;
+    let mut kernel = vec![[0.0; SINC_TAPS]; SINC_PHASES + 1];
+    for (i, weights) in kernel.iter_mut().enumerate() {
+        let frac = i as Float / SINC_PHASES as Float;
+        for (j, w) in weights.iter_mut().enumerate() {
+            let x = j as Float - (half - 1.0) - frac;
+            let sinc = if x == 0.0 { 1.0 } else { (pi * x).sin() / (pi * x) };
+            let window = 0.42 + 0.5 * (pi * x / half).cos() + 0.08 * (2.0 * pi * x / half).cos();
+            *w = sinc * window;
+        }
+        let sum: Float = weights.iter().sum();
+        weights.iter_mut().for_each(|w| *w /= sum);
+    }
+    kernel
+}
+
+/// Parameters for reading from the octave tables of a wave.
+///
+/// Calculated once per block from the frequency. With octave fading, the
+/// next higher octave table is mixed in as the frequency approaches the
+/// end of the octave, so that the number of harmonics changes smoothly.
+#[derive(Clone, Copy, Debug)]
+struct TableReader {
+    interpolation: Interpolation,
+    octave: usize,
+    fade: Float, // Weight of the next higher octave table
+}
+
+impl TableReader {
+    fn new(data: &WtOscData, num_octaves: usize, frequency: Float) -> TableReader {
+        let (octave, fade) = WtOsc::get_table_position(num_octaves, frequency);
+        let fade = if data.octave_fade > 0 { fade } else { 0.0 };
+        TableReader{interpolation: data.interpolation, octave, fade}
+    }
+
+    fn read(&self, table: &[Float], position: Float) -> Float {
+        let mut result = WtOsc::read_wave_sample(table, self.octave, position, self.interpolation);
+        if self.fade > 0.0 {
+            let upper = WtOsc::read_wave_sample(table, self.octave + 1, position, self.interpolation);
+            result += (upper - result) * self.fade;
+        }
+        result
+    }
+}
+
+/// Phase distortion applied to the read position in the wave.
+///
+/// Maps a position in the range 0 - NUM_SAMPLES_PER_TABLE to another
+/// position in the same range. The curves are calculated once per block
+/// from the warp mode and amount.
+#[derive(Clone, Copy, Debug)]
+struct PhaseWarp {
+    mode: WarpMode,
+    amount: Float,
+    value: Float, // Mode-dependent parameter derived from the amount
+}
+
+impl PhaseWarp {
+    fn new(data: &WtOscData) -> PhaseWarp {
+        let amount = data.warp_amount.clamp(0.0, 1.0);
+        let mode = if amount > 0.0 { data.warp_mode } else { WarpMode::Off };
+        let value = match mode {
+            WarpMode::BendPlus => 1.0 + (MAX_BEND_EXPONENT - 1.0) * amount,
+            WarpMode::BendMinus => 1.0 / (1.0 + (MAX_BEND_EXPONENT - 1.0) * amount),
+            WarpMode::Sync => 1.0 + (MAX_SYNC_RATIO - 1.0) * amount,
+            WarpMode::Pwm => 0.5 - (0.5 - MIN_PULSE_WIDTH) * amount,
+            WarpMode::Quantize => (2.0 as Float).powf(11.0 - 10.0 * amount).round(), // 2048 down to 2 steps
+            WarpMode::Fm => MAX_FM_WARP_DEPTH * amount,
+            WarpMode::Off | WarpMode::Mirror => 0.0,
+        };
+        PhaseWarp{mode, amount, value}
+    }
+
+    fn apply(&self, position: Float) -> Float {
+        if let WarpMode::Off = self.mode {
+            return position;
+        }
+        let table_len = NUM_SAMPLES_PER_TABLE as Float;
+        let phase = position / table_len;
+        let warped = match self.mode {
+            WarpMode::BendPlus | WarpMode::BendMinus => {
+                // Power curve, symmetric around the middle of the wave
+                let x = phase * 2.0 - 1.0;
+                (x.signum() * x.abs().powf(self.value) + 1.0) * 0.5
+            }
+            WarpMode::Mirror => {
+                let mirrored = if phase < 0.5 { phase * 2.0 } else { 2.0 - phase * 2.0 };
+                phase + (mirrored - phase) * self.amount
+            }
+            WarpMode::Sync => (phase * self.value).fract(),
+            WarpMode::Pwm => {
+                if phase < self.value {
+                    phase * 0.5 / self.value
+                } else {
+                    0.5 + (phase - self.value) * 0.5 / (1.0 - self.value)
+                }
+            }
+            WarpMode::Quantize => (phase * self.value).floor() / self.value,
+            WarpMode::Fm => {
+                // The modulator completes whole cycles, so the wave stays
+                // periodic. Positions moved past the ends wrap around.
+                let modulator = (phase * FM_WARP_RATIO * 2.0 * std::f64::consts::PI).sin();
+                return (phase + modulator * self.value).rem_euclid(1.0) * table_len;
+            }
+            WarpMode::Off => phase,
+        };
+        warped.clamp(0.0, 1.0) * table_len
+    }
+}
+
 pub struct WtOsc {
     pub sample_rate: Float,
     last_pos: [Float; MAX_VOICES], // State for up to MAX_VOICES oscillators running in sync
+    last_output: Float, // Previous output, used as modulator for feedback
+    randomize: bool,    // Move the voices to random start positions with the next sample
+    voice_limit: usize, // Max. number of unison voices played, regardless of the sound data
+    rng: NoiseRng,      // Random start positions of the voices
     wave: WavetableRef,
 }
 
@@ -55,6 +334,10 @@ impl WtOsc {
         let last_pos = [0.0; MAX_VOICES];
         WtOsc{sample_rate,
               last_pos,
+              last_output: 0.0,
+              randomize: false,
+              voice_limit: MAX_VOICES,
+              rng: NoiseRng::new(rand::random::<u64>()),
               wave}
     }
 
@@ -62,6 +345,18 @@ impl WtOsc {
         self.wave = wavetable;
     }
 
+    /// Limit the number of unison voices, e.g. to reduce the CPU load.
+    ///
+    /// The limit also applies if the number of voices is modulated.
+    pub fn set_voice_limit(&mut self, limit: usize) {
+        self.voice_limit = limit.clamp(1, MAX_VOICES);
+    }
+
+    // Number of unison voices to play with the given (modulated) sound data.
+    fn get_num_voices(&self, data: &WtOscData) -> usize {
+        data.num_voices.clamp(1, self.voice_limit as i64) as usize
+    }
+
     // Interpolate between two sample values with the given ratio.
     fn interpolate(val_a: Float, val_b: Float, ratio: Float) -> Float {
         val_a + ((val_b - val_a) * ratio)
@@ -90,12 +385,62 @@ impl WtOsc {
         }
     }
 
-    pub fn get_sample(&mut self, frequency: Float, dt: i64, data: &WtOscData) -> (Float, bool) {
+    // Get a sample from the given table at the given position with the
+    // given interpolation.
+    //
+    // The table wraps around, so the samples around the read position can
+    // be taken from both ends of the wave.
+    fn read_wave_sample(table: &[Float], table_index: usize, position: Float, interpolation: Interpolation) -> Float {
+        if let Interpolation::Fast = interpolation {
+            return WtOsc::get_wave_sample(table, table_index, position);
+        }
+        let offset = table_index * NUM_VALUES_PER_TABLE;
+        let table = &table[offset..offset + NUM_SAMPLES_PER_TABLE];
+        let floor_pos = position.floor();
+        let frac = position - floor_pos;
+        let index = floor_pos as isize;
+        let sample = |i: isize| table[(index + i).rem_euclid(NUM_SAMPLES_PER_TABLE as isize) as usize];
+        match interpolation {
+            Interpolation::Linear => WtOsc::interpolate(sample(0), sample(1), frac),
+            Interpolation::Cubic => {
+                let (xm1, x0, x1, x2) = (sample(-1), sample(0), sample(1), sample(2));
+                let c1 = 0.5 * (x1 - xm1);
+                let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+                let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+                ((c3 * frac + c2) * frac + c1) * frac + x0
+            }
+            Interpolation::Sinc => {
+                let phase = frac * SINC_PHASES as Float;
+                let phase_index = phase as usize;
+                let phase_frac = phase - phase_index as Float;
+                let lower = &SINC_KERNEL[phase_index];
+                let upper = &SINC_KERNEL[phase_index + 1];
+                let mut result = 0.0;
+                for j in 0..SINC_TAPS {
+                    let weight = lower[j] + (upper[j] - lower[j]) * phase_frac;
+                    result += sample(j as isize + 1 - (SINC_TAPS / 2) as isize) * weight;
+                }
+                result
+            }
+            Interpolation::Fast => unreachable!(),
+        }
+    }
+
+    /// Get the next sample.
+    ///
+    /// Returns the sample, its stereo part (see process_block()) and whether
+    /// a wave cycle was completed.
+    pub fn get_sample(&mut self, frequency: Float, dt: i64, data: &WtOscData) -> (Float, Float, bool) {
         let dt_f = dt as Float;
         let mut result = 0.0;
+        let mut side = 0.0;
         let mut complete = false;
+        let warp = PhaseWarp::new(data);
+        let num_voices = self.get_num_voices(data);
+        let (level, pan) = data.get_voice_mix(num_voices);
+        self.start_voices(data);
 
-        for i in 0..data.num_voices {
+        for i in 0..num_voices as i64 {
             let mut last_pos = self.last_pos[i as usize];
             let freq_diff = (frequency / 100.0) * (data.voice_spread * i as Float) * (1 - (i & 0x01 * 2)) as Float;
             let frequency = frequency + freq_diff;
@@ -114,20 +459,188 @@ impl WtOsc {
             let lower_fract: Float = 1.0 - (translated_index - lower_wave_float);
             let upper_fract: Float = if lower_fract != 1.0 { 1.0 - lower_fract } else { 0.0 };
 
-            let table_index = WtOsc::get_table_index(self.wave.num_octaves, frequency);
+            let reader = TableReader::new(data, self.wave.num_octaves, frequency);
+            let position = warp.apply(last_pos);
 
-            let mut voice_result = WtOsc::get_wave_sample(&self.wave.table[lower_wave], table_index, last_pos) * lower_fract;
+            let mut voice_result = reader.read(&self.wave.table[lower_wave], position) * lower_fract;
             if upper_fract > 0.0 {
-                voice_result += WtOsc::get_wave_sample(&self.wave.table[lower_wave + 1], table_index, last_pos) * upper_fract;
+                voice_result += reader.read(&self.wave.table[lower_wave + 1], position) * upper_fract;
             }
+            voice_result *= level[i as usize];
             result += voice_result;
+            side += voice_result * pan[i as usize];
+            self.last_pos[i as usize] = last_pos;
+        }
+        (result, side, complete)
+    }
+
+    /// Fill a buffer with consecutive samples.
+    ///
+    /// The first sample is dt samples after the last calculated one, all
+    /// following samples are one sample apart. Gives the same results as
+    /// calling get_sample for every sample, but calculates the table lookup
+    /// parameters only once per block.
+    ///
+    /// The side buffer receives the stereo part of the unison voices. The
+    /// left channel is the output minus the side value, the right channel the
+    /// output plus it.
+    pub fn process_block(&mut self,
+                         freq_start: Float,
+                         frequency: Float,
+                         dt: i64,
+                         data: &WtOscData,
+                         output: &mut [Float],
+                         side: &mut [Float],
+                         complete: &mut [bool]) {
+        for ((out, s), comp) in output.iter_mut().zip(side.iter_mut()).zip(complete.iter_mut()) {
+            *out = 0.0;
+            *s = 0.0;
+            *comp = false;
+        }
+        let num_voices = self.get_num_voices(data);
+        let (level, pan) = data.get_voice_mix(num_voices);
+        self.start_voices(data);
+
+        let translated_index = (self.wave.table.len() - 1) as Float * data.wave_index;
+        let lower_wave = translated_index as usize;
+        let lower_wave_float = lower_wave as Float;
+        let lower_fract: Float = 1.0 - (translated_index - lower_wave_float);
+        let upper_fract: Float = if lower_fract != 1.0 { 1.0 - lower_fract } else { 0.0 };
+        let lower_table = &self.wave.table[lower_wave];
+        let upper_table = if upper_fract > 0.0 { &self.wave.table[lower_wave + 1] } else { lower_table };
+        let warp = PhaseWarp::new(data);
+
+        for i in 0..num_voices as i64 {
+            let mut last_pos = self.last_pos[i as usize];
+            let spread = data.voice_spread * i as Float;
+            let sign = (1 - (i & 0x01 * 2)) as Float;
+            let freq_diff = (frequency / 100.0) * spread * sign;
+            let frequency = frequency + freq_diff;
+            let freq_speed = frequency * (NUM_SAMPLES_PER_TABLE as Float / self.sample_rate);
+            let start_diff = (freq_start / 100.0) * spread * sign;
+            let start_speed = (freq_start + start_diff) * (NUM_SAMPLES_PER_TABLE as Float / self.sample_rate);
+            let speed_step = (freq_speed - start_speed) / output.len() as Float;
+            let reader = TableReader::new(data, self.wave.num_octaves, frequency);
+            let mut diff = (start_speed + speed_step) * dt as Float;
+            let voice_level = level[i as usize];
+            let voice_pan = pan[i as usize];
+
+            for (j, ((out, s), comp)) in output.iter_mut().zip(side.iter_mut()).zip(complete.iter_mut()).enumerate() {
+                last_pos += diff;
+                diff = start_speed + speed_step * (j + 2) as Float;
+                if last_pos > (NUM_SAMPLES_PER_TABLE as Float) {
+                    // Completed one wave cycle
+                    last_pos -= NUM_SAMPLES_PER_TABLE as Float;
+                    *comp = true; // Sync signal for other oscillators
+                }
+                let position = warp.apply(last_pos);
+                let mut voice_result = reader.read(lower_table, position) * lower_fract;
+                if upper_fract > 0.0 {
+                    voice_result += reader.read(upper_table, position) * upper_fract;
+                }
+                voice_result *= voice_level;
+                *out += voice_result;
+                *s += voice_result * voice_pan;
+            }
             self.last_pos[i as usize] = last_pos;
         }
-        (result, complete)
     }
 
-    // Look up the octave table matching the current frequency.
-    fn get_table_index(num_octaves: usize, freq: Float) -> usize {
+    /// Fill a buffer with samples of a frequency or phase modulated wave.
+    ///
+    /// The modulator contains one value per output sample, usually the
+    /// output of another oscillator. Without a modulator, the oscillator
+    /// modulates itself with its previous output sample (feedback). The
+    /// frequency is interpolated over the block and the stereo part is
+    /// written to the side buffer like in process_block().
+    pub fn process_block_mod(&mut self,
+                             freq_start: Float,
+                             frequency: Float,
+                             dt: i64,
+                             data: &WtOscData,
+                             mode: WtModMode,
+                             index: Float,
+                             modulator: Option<&[Float]>,
+                             output: &mut [Float],
+                             side: &mut [Float],
+                             complete: &mut [bool]) {
+        self.start_voices(data);
+        let table_len = NUM_SAMPLES_PER_TABLE as Float;
+        let translated_index = (self.wave.table.len() - 1) as Float * data.wave_index;
+        let lower_wave = translated_index as usize;
+        let lower_wave_float = lower_wave as Float;
+        let lower_fract: Float = 1.0 - (translated_index - lower_wave_float);
+        let upper_fract: Float = if lower_fract != 1.0 { 1.0 - lower_fract } else { 0.0 };
+        let lower_table = &self.wave.table[lower_wave];
+        let upper_table = if upper_fract > 0.0 { &self.wave.table[lower_wave + 1] } else { lower_table };
+        let phase_scale = index * table_len / (2.0 * std::f64::consts::PI);
+        let warp = PhaseWarp::new(data);
+        let num_voices = self.get_num_voices(data);
+        let (level, pan) = data.get_voice_mix(num_voices);
+
+        // Speed of the unison voices in table positions per sample
+        let mut start_speed = [0.0; MAX_VOICES];
+        let mut speed_step = [0.0; MAX_VOICES];
+        let mut reader = [TableReader::new(data, self.wave.num_octaves, frequency); MAX_VOICES];
+        for i in 0..num_voices {
+            let spread = data.voice_spread * i as Float;
+            let sign = (1 - (i as i64 & 0x02)) as Float; // Same detuning as in process_block()
+            let voice_freq = frequency + (frequency / 100.0) * spread * sign;
+            let voice_start = freq_start + (freq_start / 100.0) * spread * sign;
+            start_speed[i] = voice_start * (table_len / self.sample_rate);
+            speed_step[i] = (voice_freq * (table_len / self.sample_rate) - start_speed[i]) / output.len() as Float;
+            reader[i] = TableReader::new(data, self.wave.num_octaves, voice_freq);
+        }
+
+        for (j, ((out, s), comp)) in output.iter_mut().zip(side.iter_mut()).zip(complete.iter_mut()).enumerate() {
+            let mod_value = match modulator {
+                Some(m) => m[j],
+                None => self.last_output,
+            };
+            let steps = if j == 0 { dt as Float } else { 1.0 };
+            let mut result = 0.0;
+            let mut stereo = 0.0;
+            *comp = false;
+            for i in 0..num_voices {
+                let speed = start_speed[i] + speed_step[i] * (j + 1) as Float;
+                let mut last_pos = self.last_pos[i];
+                let position = match mode {
+                    WtModMode::Frequency => {
+                        last_pos += speed * (1.0 + index * mod_value) * steps;
+                        if last_pos >= table_len || last_pos < 0.0 {
+                            // Completed one wave cycle, possibly backwards
+                            *comp |= last_pos >= table_len;
+                            last_pos = last_pos.rem_euclid(table_len);
+                        }
+                        last_pos
+                    }
+                    WtModMode::Phase => {
+                        last_pos += speed * steps;
+                        if last_pos >= table_len {
+                            last_pos -= table_len;
+                            *comp = true;
+                        }
+                        (last_pos + mod_value * phase_scale).rem_euclid(table_len)
+                    }
+                };
+                let position = warp.apply(position);
+                let mut voice_result = reader[i].read(lower_table, position) * lower_fract;
+                if upper_fract > 0.0 {
+                    voice_result += reader[i].read(upper_table, position) * upper_fract;
+                }
+                voice_result *= level[i];
+                result += voice_result;
+                stereo += voice_result * pan[i];
+                self.last_pos[i] = last_pos;
+            }
+            *out = result;
+            *s = stereo;
+            self.last_output = result;
+        }
+    }
+
+    /// Look up the octave table matching the given frequency.
+    pub fn get_table_index(num_octaves: usize, freq: Float) -> usize {
         let two: Float = 2.0;
         let mut compare_freq = (440.0 / 32.0) * (two.powf((-9.0) / 12.0));
         for i in 0..num_octaves {
@@ -139,10 +652,51 @@ impl WtOsc {
         num_octaves - 1
     }
 
+    /// Look up the octave table matching the given frequency, and how far
+    /// the frequency is towards the next octave table (0.0 - 1.0).
+    ///
+    /// The position moves linearly with the pitch and reaches the next table
+    /// exactly at the start of its octave. It is always 0.0 for the highest
+    /// octave table.
+    pub fn get_table_position(num_octaves: usize, freq: Float) -> (usize, Float) {
+        let index = WtOsc::get_table_index(num_octaves, freq);
+        if index + 1 >= num_octaves {
+            return (index, 0.0);
+        }
+        let two: Float = 2.0;
+        let octave_start = (440.0 / 32.0) * two.powf(index as Float - 9.0 / 12.0);
+        (index, (freq / octave_start).log2().clamp(0.0, 1.0))
+    }
+
     pub fn reset(&mut self) {
         for i in 0..MAX_VOICES {
             self.last_pos[i] = 0.0;
         }
+        self.last_output = 0.0;
+    }
+
+    /// Move the unison voices to random start positions with the next
+    /// sample, if enabled with WtOscData::random_phase.
+    ///
+    /// Called when a new note starts. Sync resets always restart the voices
+    /// at the start of the wave.
+    pub fn randomize_phase(&mut self) {
+        self.randomize = true;
+    }
+
+    // Apply a pending phase randomization.
+    fn start_voices(&mut self, data: &WtOscData) {
+        if !self.randomize {
+            return;
+        }
+        self.randomize = false;
+        let amount = data.random_phase.clamp(0.0, 1.0);
+        if amount > 0.0 {
+            for pos in self.last_pos.iter_mut() {
+                let random = (self.rng.next_value() + 1.0) * 0.5;
+                *pos = (*pos + random * amount * NUM_SAMPLES_PER_TABLE as Float) % NUM_SAMPLES_PER_TABLE as Float;
+            }
+        }
     }
 
 }
@@ -183,3 +737,281 @@ fn test_get_sample() {
     assert_eq!(WtOsc::get_wave_sample(&table, 0, 0.09), 2.0); // Close to first
     assert_eq!(WtOsc::get_wave_sample(&table, 0, 0.99), 3.0); // Close to second
 }
+
+#[test]
+fn test_phase_warp() {
+    let len = NUM_SAMPLES_PER_TABLE as Float;
+    let warp = |mode: WarpMode, amount: Float, phase: Float| -> Float {
+        let mut data = WtOscData::default();
+        data.warp_mode = mode;
+        data.warp_amount = amount;
+        PhaseWarp::new(&data).apply(phase * len) / len
+    };
+    let modes = [WarpMode::Off, WarpMode::BendPlus, WarpMode::BendMinus, WarpMode::Mirror,
+                 WarpMode::Sync, WarpMode::Pwm, WarpMode::Quantize, WarpMode::Fm];
+    for mode in modes.iter() {
+        assert_eq!(warp(*mode, 0.0, 0.3), 0.3); // No change without amount
+        assert_eq!(warp(*mode, 1.0, 0.0), 0.0); // Cycle always starts at the start of the wave
+    }
+    assert!((warp(WarpMode::BendPlus, 1.0, 0.5) - 0.5).abs() < 1e-9);
+    assert!((warp(WarpMode::BendPlus, 1.0, 0.25) - 0.46875).abs() < 1e-9);
+    assert!((warp(WarpMode::BendMinus, 1.0, 0.46875) - 0.25).abs() < 1e-9);
+    assert!((warp(WarpMode::Mirror, 1.0, 0.75) - 0.5).abs() < 1e-9);
+    assert!((warp(WarpMode::Mirror, 0.5, 0.75) - 0.625).abs() < 1e-9);
+    assert!((warp(WarpMode::Sync, 1.0, 1.0 / 32.0) - 0.5).abs() < 1e-9);
+    assert!((warp(WarpMode::Pwm, 1.0, 0.01) - 0.5).abs() < 1e-9); // Square turns into a narrow pulse
+    assert!((warp(WarpMode::Pwm, 1.0, 1.0) - 1.0).abs() < 1e-9);
+    assert!((warp(WarpMode::Quantize, 1.0, 0.3) - 0.0).abs() < 1e-9);
+    assert!((warp(WarpMode::Quantize, 1.0, 0.7) - 0.5).abs() < 1e-9);
+    assert!((warp(WarpMode::Fm, 1.0, 0.125) - 0.625).abs() < 1e-9); // Modulator at its peak
+    assert!((warp(WarpMode::Fm, 0.5, 0.375) - 0.125).abs() < 1e-9); // Modulator at its trough
+    assert!((warp(WarpMode::Fm, 1.0, 0.75) - 0.75).abs() < 1e-9);   // Modulator crosses zero
+}
+
+#[test]
+fn test_get_voice_mix() {
+    let mut data = WtOscData::default();
+    data.init();
+    data.set_voice_num(1);
+    data.width = 1.0;
+    data.blend = 1.0;
+    assert!(!data.is_stereo());
+    let (level, pan) = data.get_voice_mix(data.num_voices as usize);
+    assert_eq!(level[0], 1.0); // A single voice is never turned down
+    assert_eq!(pan[0], 0.0);
+
+    data.set_voice_num(5);
+    assert!(data.is_stereo());
+    let (level, pan) = data.get_voice_mix(data.num_voices as usize);
+    assert_eq!(level[..5], [0.0, 1.0, 1.0, 1.0, 1.0]);
+    assert_eq!(pan[..5], [0.0, -0.5, 0.5, -1.0, 1.0]);
+
+    data.width = 0.5;
+    data.blend = -0.25;
+    let (level, pan) = data.get_voice_mix(data.num_voices as usize);
+    assert_eq!(level[..5], [1.0, 0.75, 0.75, 0.75, 0.75]);
+    assert_eq!(pan[..5], [0.0, -0.25, 0.25, -0.5, 0.5]);
+}
+
+#[test]
+fn test_get_table_position() {
+    let (index, fade) = WtOsc::get_table_position(11, 8.0);
+    assert_eq!((index, fade), (0, 0.0)); // Below the first octave
+    let (index, fade) = WtOsc::get_table_position(11, 440.0);
+    assert_eq!(index, 5);
+    assert!((fade - 0.75).abs() < 1e-6); // A4 is 9 semitones into the octave
+    let (index, fade) = WtOsc::get_table_position(11, 20000.0);
+    assert_eq!((index, fade), (10, 0.0)); // No fading beyond the last table
+
+    // The position is continuous at the octave boundaries
+    let boundary = 261.6255653;
+    let (index_below, fade_below) = WtOsc::get_table_position(11, boundary - 0.001);
+    let (index_above, fade_above) = WtOsc::get_table_position(11, boundary + 0.001);
+    assert_eq!(index_below + 1, index_above);
+    assert!(fade_below > 0.9999);
+    assert!(fade_above < 0.0001);
+}
+
+#[test]
+fn test_read_wave_sample() {
+    let mut table = [0.0; NUM_VALUES_PER_TABLE];
+    for (i, value) in table.iter_mut().enumerate() {
+        *value = (i as Float * 0.1).sin();
+    }
+    table[NUM_SAMPLES_PER_TABLE] = table[0];
+    let modes = [Interpolation::Fast, Interpolation::Linear, Interpolation::Cubic, Interpolation::Sinc];
+    for mode in modes.iter() {
+        // All modes hit the samples exactly
+        assert!((WtOsc::read_wave_sample(&table, 0, 7.0, *mode) - table[7]).abs() < 1e-12);
+        assert!((WtOsc::read_wave_sample(&table, 0, 2047.0, *mode) - table[2047]).abs() < 1e-12);
+    }
+    assert_eq!(WtOsc::read_wave_sample(&table, 0, 7.05, Interpolation::Fast), table[7]); // Snaps to the sample
+    assert_eq!(WtOsc::read_wave_sample(&table, 0, 7.05, Interpolation::Linear), WtOsc::interpolate(table[7], table[8], 0.05));
+
+    // Positions before the end of the wave read across the wrap-around
+    let mut wrapped = [0.0; NUM_VALUES_PER_TABLE];
+    wrapped[0] = 1.0;
+    wrapped[NUM_SAMPLES_PER_TABLE] = 1.0;
+    let value = WtOsc::read_wave_sample(&wrapped, 0, 2046.5, Interpolation::Cubic);
+    assert!(value < 0.0); // Undershoot ahead of the impulse at the start
+    let value = WtOsc::read_wave_sample(&wrapped, 0, 2047.5, Interpolation::Sinc);
+    assert!(value > 0.5 && value < 0.7);
+}
+
+// Test harness for measuring the quality of the wavetable oscillator.
+//
+// Renders a wave from the default table set at a frequency that fits an
+// integer number of wave cycles into the analysis window, so that all
+// harmonics fall exactly onto DFT bins, and returns the power per bin.
+#[cfg(test)]
+const TEST_SAMPLE_RATE: u32 = 48000;
+#[cfg(test)]
+const TEST_WINDOW: usize = 4800; // 10 Hz per bin
+
+#[cfg(test)]
+fn render_spectrum(data: &WtOscData, cycles: usize) -> Vec<Float> {
+    let wave = wavetable::WtCreator::create_default_waves(TEST_SAMPLE_RATE as Float);
+    let mut osc = WtOsc::new(TEST_SAMPLE_RATE, wave);
+    let frequency = (cycles * TEST_SAMPLE_RATE as usize / TEST_WINDOW) as Float;
+    let mut output = vec![0.0; TEST_WINDOW];
+    let mut side = vec![0.0; TEST_WINDOW];
+    let mut complete = vec![false; TEST_WINDOW];
+    osc.process_block(frequency, frequency, 1, data, &mut output, &mut side, &mut complete);
+
+    let pi = std::f64::consts::PI;
+    let cos: Vec<Float> = (0..TEST_WINDOW).map(|n| (2.0 * pi * n as Float / TEST_WINDOW as Float).cos()).collect();
+    let sin: Vec<Float> = (0..TEST_WINDOW).map(|n| (2.0 * pi * n as Float / TEST_WINDOW as Float).sin()).collect();
+    (0..TEST_WINDOW / 2).map(|bin| {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (n, x) in output.iter().enumerate() {
+            let k = (n * bin) % TEST_WINDOW;
+            re += x * cos[k];
+            im -= x * sin[k];
+        }
+        re * re + im * im
+    }).collect()
+}
+
+// Level of all bins rejected by the filter relative to the total power, in dB.
+#[cfg(test)]
+fn unwanted_level(spectrum: &[Float], wanted: impl Fn(usize) -> bool) -> Float {
+    let total: Float = spectrum.iter().sum();
+    let unwanted: Float = spectrum.iter().enumerate().filter(|(bin, _)| !wanted(*bin)).map(|(_, p)| p).sum();
+    10.0 * (unwanted / total).log10()
+}
+
+// Spectral centroid in multiples of the fundamental.
+#[cfg(test)]
+fn brightness(spectrum: &[Float], cycles: usize) -> Float {
+    let total: Float = spectrum.iter().sum();
+    spectrum.iter().enumerate().map(|(bin, p)| p * bin as Float).sum::<Float>() / total / cycles as Float
+}
+
+#[cfg(test)]
+fn create_test_data(wave_index: Float, interpolation: Interpolation, octave_fade: i64) -> WtOscData {
+    let mut data = WtOscData::default();
+    data.init();
+    data.wave_index = wave_index;
+    data.interpolation = interpolation;
+    data.octave_fade = octave_fade;
+    data
+}
+
+#[test]
+fn test_interpolation_distortion() {
+    // THD+N of a sine: everything except the fundamental
+    let cycles = 101;
+    let modes = [Interpolation::Fast, Interpolation::Linear, Interpolation::Cubic, Interpolation::Sinc];
+    let thd: Vec<Float> = modes.iter().map(|m| {
+        unwanted_level(&render_spectrum(&create_test_data(0.0, *m, 0), cycles), |bin| bin == cycles)
+    }).collect();
+    assert!(thd[0] > -90.0, "THD+N fast {:.1} dB", thd[0]); // Snapping is clearly audible in the noise floor
+    assert!(thd[1] < -120.0, "THD+N linear {:.1} dB", thd[1]);
+    assert!(thd[2] < -120.0, "THD+N cubic {:.1} dB", thd[2]);
+    assert!(thd[3] < -100.0, "THD+N sinc {:.1} dB", thd[3]);
+
+    // Aliasing of a saw: everything that is not a harmonic of the fundamental.
+    // The sample rate is not a multiple of the frequency, so aliases don't
+    // fold back onto harmonics.
+    let cycles = 23;
+    let alias: Vec<Float> = modes.iter().map(|m| {
+        unwanted_level(&render_spectrum(&create_test_data(2.0 / 3.0, *m, 0), cycles), |bin| bin % cycles == 0)
+    }).collect();
+    assert!(alias[1] < alias[0], "Aliasing linear {:.1} dB, fast {:.1} dB", alias[1], alias[0]);
+    assert!(alias[2] < alias[1], "Aliasing cubic {:.1} dB, linear {:.1} dB", alias[2], alias[1]);
+    assert!(alias[3] < alias[2], "Aliasing sinc {:.1} dB, cubic {:.1} dB", alias[3], alias[2]);
+}
+
+#[test]
+fn test_octave_fade_avoids_timbre_jumps() {
+    // Just below and above the octave boundary at 261.6 Hz
+    let jump = |octave_fade: i64| {
+        let data = create_test_data(2.0 / 3.0, Interpolation::Linear, octave_fade);
+        let below = brightness(&render_spectrum(&data, 26), 26);
+        let above = brightness(&render_spectrum(&data, 27), 27);
+        (below - above).abs() / below
+    };
+    let hard = jump(0);
+    let faded = jump(1);
+    assert!(hard > 0.1, "Brightness change without fade {:.3}", hard);
+    assert!(faded < hard / 5.0, "Brightness change without fade {:.3}, with fade {:.3}", hard, faded);
+}
+
+#[test]
+fn voice_limit_applies_to_modulated_voices() {
+    let wave = wavetable::WtCreator::create_default_waves(44100.0);
+    let mut limited = WtOsc::new(44100, wave.clone());
+    let mut reference = WtOsc::new(44100, wave);
+    limited.set_voice_limit(2);
+    let mut data = WtOscData::default();
+    data.init();
+    data.voice_spread = 0.5;
+    data.num_voices = 5; // As set by a modulator, without set_voice_num()
+    let mut ref_data = data;
+    ref_data.num_voices = 2;
+
+    let (mut output, mut side, mut complete) = ([0.0; 64], [0.0; 64], [false; 64]);
+    let (mut ref_output, mut ref_side, mut ref_complete) = ([0.0; 64], [0.0; 64], [false; 64]);
+    limited.process_block(440.0, 440.0, 1, &data, &mut output, &mut side, &mut complete);
+    reference.process_block(440.0, 440.0, 1, &ref_data, &mut ref_output, &mut ref_side, &mut ref_complete);
+    assert!(output.iter().any(|s| *s != 0.0));
+    assert_eq!(output, ref_output);
+    assert_eq!(limited.get_sample(440.0, 1, &data), reference.get_sample(440.0, 1, &ref_data));
+}
+
+// Renders a sine, frequency or phase modulated by a sine one octave above.
+#[cfg(test)]
+fn render_modulated(mode: Option<WtModMode>, index: Float) -> Vec<Float> {
+    let wave = wavetable::WtCreator::create_default_waves(44100.0);
+    let mut osc = WtOsc::new(44100, wave);
+    let mut data = WtOscData::default();
+    data.init();
+    data.wave_index = 0.0; // Sine
+    let len = 4410;
+    let modulator: Vec<Float> = (0..len)
+        .map(|i| (2.0 * std::f64::consts::PI * 880.0 * (i + 1) as Float / 44100.0).sin())
+        .collect();
+    let (mut output, mut side, mut complete) = (vec![0.0; len], vec![0.0; len], vec![false; len]);
+    match mode {
+        Some(m) => osc.process_block_mod(440.0, 440.0, 1, &data, m, index, Some(&modulator), &mut output, &mut side, &mut complete),
+        None => osc.process_block(440.0, 440.0, 1, &data, &mut output, &mut side, &mut complete),
+    }
+    output
+}
+
+#[test]
+fn modulation_index_changes_the_sound() {
+    let difference = |a: &[Float], b: &[Float]| a.iter().zip(b.iter()).map(|(x, y)| (x - y).abs()).sum::<Float>();
+    let plain = render_modulated(None, 0.0);
+    assert!(plain.iter().map(|x| x.abs()).sum::<Float>() > 100.0);
+    for &mode in [WtModMode::Frequency, WtModMode::Phase].iter() {
+        // Without modulation, FM and PM sound like the plain wavetable
+        let diff = difference(&plain, &render_modulated(Some(mode), 0.0));
+        assert!(diff < 0.1, "{:?} without modulation: {}", mode, diff);
+        let diff = difference(&plain, &render_modulated(Some(mode), 2.0));
+        assert!(diff > 100.0, "{:?} with modulation: {}", mode, diff);
+    }
+}
+
+#[test]
+fn unison_width_spreads_voices_in_stereo() {
+    let wave = wavetable::WtCreator::create_default_waves(44100.0);
+    let mut data = WtOscData::default();
+    data.init();
+    data.set_voice_num(5);
+    data.set_voice_spread(0.5);
+    data.blend = 0.5;
+    for &width in [0.0, 1.0].iter() {
+        data.width = width;
+        let mut osc = WtOsc::new(44100, wave.clone());
+        let (mut output, mut side, mut complete) = ([0.0; 512], [0.0; 512], [false; 512]);
+        osc.process_block(440.0, 440.0, 1, &data, &mut output, &mut side, &mut complete);
+        let side_level = side.iter().map(|s| s.abs()).sum::<Float>();
+        assert!(output.iter().any(|s| *s != 0.0));
+        if width == 0.0 {
+            assert_eq!(side_level, 0.0);
+        } else {
+            assert!(side_level > 10.0, "Side level with full width {}", side_level);
+        }
+    }
+}
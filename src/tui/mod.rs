@@ -51,6 +51,9 @@ use super::{SoundBank, SoundPatch};
 use super::SynthMessage;
 use super::{Parameter, ParameterValue, ParamId, FunctionId, SynthParam, MenuItem, FUNCTIONS, MOD_SOURCES};
 use super::UiMessage;
-use super::WtInfo;
+use super::{WtInfo, WAVETABLE_DIR};
+use super::{WtGenDef, WtGenInfo, GENERATOR_EXTENSION};
+use super::{ResampleSettings, ResampleSweep, DEFAULT_RESAMPLE_KEY, DEFAULT_RESAMPLE_WAVES, KEY_SWEEP_RANGE};
+use super::{SampleInfo, SAMPLE_DIR};
 use super::{SOUND_DATA_VERSION, SYNTH_ENGINE_VERSION};
 use super::value_range::ValueRange;
@@ -87,6 +87,7 @@ pub struct ParamSelector {
     pub value: ParameterValue,
     pub ml: MidiLearn,
     pub wavetable_list: Vec<(usize, String)>,
+    pub sample_list: Vec<(usize, String)>,
     sound: Option<Rc<RefCell<SoundPatch>>>,
     pending_key: Option<Key>,
     history: Vec<ParamId>,
@@ -122,6 +123,8 @@ impl ParamSelector {
         let mut wavetable_list: Vec<(usize, String)> = vec!{};
         wavetable_list.push((0, "Basic".to_string()));
         wavetable_list.push((1, "PWM Square".to_string()));
+        let mut sample_list: Vec<(usize, String)> = vec!{};
+        sample_list.push((0, "None".to_string()));
         ParamSelector{value_changed: false,
                       state: SelectorState::Function,
                       func_selection: func_selection,
@@ -131,6 +134,7 @@ impl ParamSelector {
                       value: ParameterValue::Int(0),
                       ml: MidiLearn::new(),
                       wavetable_list: wavetable_list,
+                      sample_list: sample_list,
                       sound: Option::None,
                       pending_key: Option::None,
                       history: vec!{},
@@ -359,7 +363,7 @@ impl ParamSelector {
                         if let Key::Ctrl('l') = c {
                             return SmResult::ChangeState(ParamSelector::state_midi_learn);
                         }
-                        match self.param_selection.handle_input(*c, self.wavetable_list.len() - 1) {
+                        match self.param_selection.handle_input(*c, self.get_dynamic_list_max()) {
                             RetCode::KeyConsumed   => SmResult::EventHandled,
                             RetCode::KeyMissmatch  => {
                                 // Key can't be used for value, so it probably is the short cut for a
@@ -1060,6 +1064,7 @@ impl ParamSelector {
     pub fn get_dynamic_list<'a>(&'a mut self, param: Parameter) -> &'a mut Vec<(usize, String)> {
         match param {
             Parameter::Wavetable => return &mut self.wavetable_list,
+            Parameter::Sample => return &mut self.sample_list,
             _ => panic!()
         }
     }
@@ -1068,9 +1073,18 @@ impl ParamSelector {
     pub fn get_dynamic_list_no_mut<'a>(&'a self, param: Parameter) -> &'a Vec<(usize, String)> {
         match param {
             Parameter::Wavetable => return &self.wavetable_list,
+            Parameter::Sample => return &self.sample_list,
             _ => panic!()
         }
     }
+
+    /** Highest index of the dynamic list of the selected parameter. */
+    fn get_dynamic_list_max(&self) -> usize {
+        match self.param_selection.value {
+            ParameterValue::Dynamic(param, _) => self.get_dynamic_list_no_mut(param).len() - 1,
+            _ => self.wavetable_list.len() - 1,
+        }
+    }
 }
 
 // ----------------------------------------------
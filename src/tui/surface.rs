@@ -6,6 +6,7 @@ use std::rc::Rc;
 use termion::color;
 
 use super::{Parameter, ParamId, ParameterValue, SoundData, UiMessage};
+use crate::synth::MAX_LFO_FREQUENCY;
 use super::{Bar, Button, Canvas, CanvasRef, Container, ContainerRef, Controller,
             Dial, Index, Label, MouseHandler, ObserverRef, Scheme, Slider,
             Value, ValueDisplay, Widget};
@@ -90,9 +91,15 @@ impl Surface {
         let sysinfo: ContainerRef<ParamId> = Rc::new(RefCell::new(Container::new()));
         sysinfo.borrow_mut().enable_border(true);
         this.add_sysinfo(&mut sysinfo.borrow_mut(), 1, 0);
+        let sysinfo_width = sysinfo.borrow().get_width();
         //this.add_child(sysinfo, env_width + 2, osc_height + lfo_height + glfo_height + filter_height);
         this.add_child(sysinfo, env_width + 2, osc_height + lfo_height + filter_height);
 
+        let sub: ContainerRef<ParamId> = Rc::new(RefCell::new(Container::new()));
+        sub.borrow_mut().enable_border(true);
+        this.add_sub_osc(&mut sub.borrow_mut(), 1, 0);
+        this.add_child(sub, env_width + sysinfo_width + 4, osc_height + lfo_height + filter_height);
+
         this.window.set_position(1, 1);
         this.window.set_color_scheme(this.colors.clone());
         this
@@ -275,11 +282,9 @@ impl Surface {
         target.add_child(osc_sync, 14 + x_offset, 10 + y_offset);
         */
 
-        if func_id == 2 {
-            key.set(Parameter::Oscillator, func_id, Parameter::Sync);
-            let osc_sync = self.new_option("Sync", 0, &key);
-            target.add_child(osc_sync, x_offset, 10 + y_offset);
-        }
+        key.set(Parameter::Oscillator, func_id, Parameter::Sync);
+        let osc_sync = self.new_option("Sync", 0, &key);
+        target.add_child(osc_sync, x_offset, 10 + y_offset);
     }
 
     fn add_env(&mut self,
@@ -334,7 +339,7 @@ impl Surface {
         target.add_child(lfo_wave, x_offset, 1 + y_offset);
 
         key.set(Parameter::Lfo, func_id, Parameter::Frequency);
-        let lfo_freq = self.new_mod_dial_float("Speed", 0.0, 44.1, 0.01, false, &key);
+        let lfo_freq = self.new_mod_dial_float("Speed", 0.0, MAX_LFO_FREQUENCY, 0.01, false, &key);
         target.add_child(lfo_freq, x_offset, 4 + y_offset);
 
         key.set(Parameter::Lfo, func_id, Parameter::Amount);
@@ -358,7 +363,7 @@ impl Surface {
         target.add_child(glfo_wave, x_offset, 1 + y_offset);
 
         key.set(Parameter::GlobalLfo, func_id, Parameter::Frequency);
-        let glfo_freq = self.new_mod_dial_float("Speed", 0.0, 44.1, 0.01, false, &key);
+        let glfo_freq = self.new_mod_dial_float("Speed", 0.0, MAX_LFO_FREQUENCY, 0.01, false, &key);
         target.add_child(glfo_freq, x_offset, 4 + y_offset);
 
         key.set(Parameter::GlobalLfo, func_id, Parameter::Amount);
@@ -398,6 +403,32 @@ impl Surface {
         target.add_child(filter_follow, x_offset, 8 + y_offset);
     }
 
+    fn add_sub_osc(&mut self,
+                   target: &mut Container<ParamId>,
+                   x_offset: Index,
+                   y_offset: Index) {
+        let title = "Sub Oscillator";
+        let len = title.len();
+        let title = Label::new(title.to_string(), len as Index);
+        target.add_child(title, x_offset, y_offset);
+
+        let mut key = ParamId::new(Parameter::SubOscillator, 1, Parameter::Level);
+        let sub_level = self.new_mod_dial_float("Level", 0.0, 100.0, 0.0, false, &key);
+        target.add_child(sub_level, x_offset, 1 + y_offset);
+
+        key.set(Parameter::SubOscillator, 1, Parameter::Octave);
+        let sub_octave = self.new_mod_dial_int("Octave", -2, -1, -1, false, &key);
+        target.add_child(sub_octave, 14 + x_offset, 1 + y_offset);
+
+        key.set(Parameter::SubOscillator, 1, Parameter::Waveform);
+        let sub_wave = self.new_mod_dial_int("Waveform", 0, 1, 0, false, &key);
+        target.add_child(sub_wave, x_offset, 4 + y_offset);
+
+        key.set(Parameter::SubOscillator, 1, Parameter::Routing);
+        let sub_routing = self.new_mod_dial_int("Routing", 0, 2, 0, false, &key);
+        target.add_child(sub_routing, 14 + x_offset, 4 + y_offset);
+    }
+
     fn add_sysinfo(&mut self,
                    target: &mut Container<ParamId>,
                    x_offset: Index,
@@ -414,6 +445,10 @@ impl Surface {
         key.set(Parameter::System, 0, Parameter::Idle);
         let idle_value = self.new_label_value("Idle", 0, &key);
         target.add_child(idle_value, x_offset, 2 + y_offset);
+
+        key.set(Parameter::System, 0, Parameter::Dropped);
+        let dropped_value = self.new_label_value("Drop", 0, &key);
+        target.add_child(dropped_value, x_offset, 3 + y_offset);
     }
 
     fn param_to_widget_value(value: &ParameterValue) -> Value {
@@ -10,7 +10,10 @@ use super::surface::Surface;
 use super::Value;
 use super::{SOUND_DATA_VERSION, SYNTH_ENGINE_VERSION};
 use super::StateMachine;
-use super::WtInfo;
+use super::{WtInfo, WAVETABLE_DIR};
+use super::{WtGenDef, WtGenInfo, GENERATOR_EXTENSION};
+use super::{ResampleSettings, ResampleSweep, DEFAULT_RESAMPLE_KEY, DEFAULT_RESAMPLE_WAVES, KEY_SWEEP_RANGE};
+use super::{SampleInfo, SAMPLE_DIR};
 
 use crossbeam_channel::{Sender, Receiver};
 use log::info;
@@ -60,7 +63,9 @@ pub struct Tui {
     busy: Duration, // Accumulated busy times of the engine
     min_idle: Duration,
     max_busy: Duration,
+    dropped_voices: usize, // Voices dropped by the engine because of overload
     show_tui: bool,
+    error_message: Option<String>, // Last error to show below the status line
 
     bank: SoundBank,                // Bank with sound patches
     sound: Rc<RefCell<SoundPatch>>, // Current sound patch as loaded from disk
@@ -95,7 +100,9 @@ impl Tui {
             busy: Duration::new(0, 0),
             min_idle: Duration::new(10, 0),
             max_busy: Duration::new(0, 0),
+            dropped_voices: 0,
             show_tui,
+            error_message: None,
             bank: SoundBank::new(SOUND_DATA_VERSION, SYNTH_ENGINE_VERSION),
             sound,
             sound_copy: SoundPatch::new(),
@@ -111,6 +118,10 @@ impl Tui {
         tui.bank.load_bank("Yazz_FactoryBank.ysn").unwrap();
         tui.load_wavetables();
         tui.scan_wavetables();
+        tui.load_generated_wavetables();
+        tui.scan_generated_wavetables();
+        tui.load_samples();
+        tui.scan_samples();
         tui.select_sound(0);
         tui.selector_sm.init(&mut tui.selector);
         match tui.ctrl_map.load("Yazz_ControllerMapping.ysn") {
@@ -137,8 +148,15 @@ impl Tui {
             if found_entry {
                 continue;
             }
+            // Embedded wavetables don't need the file
+            if let Some(embedded) = self.bank.wt_data.iter().find(|t| t.id == entry.id) {
+                entry.valid = true;
+                self.sender.send(SynthMessage::EmbeddedWavetable(entry.clone(), embedded.clone())).unwrap();
+                list.push((entry.id, entry.name.clone()));
+                continue;
+            }
             // Check if file exists
-            let filename = "data/".to_string() + &entry.filename;
+            let filename = format!("{}/{}", WAVETABLE_DIR, entry.filename);
             if !Path::new(&filename).exists() {
                 entry.valid = false; // Invalid => Won't show up in menu, sounds get default wavetable
             }
@@ -179,10 +197,13 @@ impl Tui {
             | UiMessage::MouseHold{x: _, y: _}
             | UiMessage::MouseRelease{x: _, y: _} => self.window.handle_event(&msg),
             UiMessage::SampleBuffer(m, p) => self.handle_samplebuffer(m, p),
-            UiMessage::EngineSync(idle, busy) => {
+            UiMessage::EngineSync(idle, busy, dropped_voices) => {
                 self.update_idle_time(idle, busy);
+                self.dropped_voices = dropped_voices;
                 self.handle_engine_sync();
             }
+            UiMessage::WavetableError(id, msg) => self.handle_wavetable_error(id, msg),
+            UiMessage::Resampled(id) => self.handle_resampled(id),
             UiMessage::Exit => {
                 info!("Stopping TUI");
                 self.sender.send(SynthMessage::Exit).unwrap();
@@ -193,6 +214,9 @@ impl Tui {
     }
 
     fn handle_key_input(&mut self, key: Key) {
+        // An error stays visible until the user reacts with the next key
+        self.error_message = None;
+
         // Top-level keys that work in both modes
         if !match key {
             Key::F(1) => {
@@ -213,9 +237,25 @@ impl Tui {
                 self.select_sound(0);
                 true
             },
+            Key::F(5) => {
+                self.resample(ResampleSettings::default());
+                true
+            },
+            Key::F(6) => {
+                let sweep = ResampleSweep::ModWheel{from: 0.0, to: 127.0};
+                self.resample(ResampleSettings::sweep(sweep, DEFAULT_RESAMPLE_WAVES));
+                true
+            },
+            Key::F(7) => {
+                let sweep = ResampleSweep::Key{from: DEFAULT_RESAMPLE_KEY, to: DEFAULT_RESAMPLE_KEY + KEY_SWEEP_RANGE};
+                self.resample(ResampleSettings::sweep(sweep, DEFAULT_RESAMPLE_WAVES));
+                true
+            },
             Key::F(10) => {
-                // Scan data folder for new wavetable files
+                // Scan data and sample folders for new files
                 self.scan_wavetables();
+                self.scan_generated_wavetables();
+                self.scan_samples();
                 true
             },
             Key::Char(c) => {
@@ -238,6 +278,10 @@ impl Tui {
                         self.select_sound(self.selected_sound);
                         true
                     }
+                    'e' => { // Toggle embedding of wavetables
+                        self.bank.embed_wavetables = !self.bank.embed_wavetables;
+                        true
+                    }
                     'n' => { // Rename sound
                         self.state = TuiState::Name;
                         self.temp_name.clear();
@@ -354,17 +398,17 @@ impl Tui {
     }
 
     fn scan_wavetables(&mut self) {
-        let re = Regex::new(r"(.*).wav").unwrap();
-        if !Path::new("data").exists() {
+        let re = Regex::new(r"(?i)(.*)\.wav$").unwrap();
+        if !Path::new(WAVETABLE_DIR).exists() {
             // Create data directory
-            let result = fs::create_dir("data");
+            let result = fs::create_dir(WAVETABLE_DIR);
             match result {
                 Ok(()) => info!("Created data directory"),
                 Err(err) => info!("Error, can't create data directory: {}", err),
             }
             return;
         }
-        for entry in fs::read_dir("data").unwrap() {
+        for entry in fs::read_dir(WAVETABLE_DIR).unwrap() {
             let entry = entry.unwrap();
             let filename = entry.file_name();
             for cap in re.captures_iter(filename.to_str().unwrap()) {
@@ -379,7 +423,7 @@ impl Tui {
                 }
                 if !found {
                     info!("Adding new table {}.", table_name);
-                    let id = self.bank.wt_list.len() + 2; // Default wavetables are not in this list, so add 2
+                    let id = self.bank.next_wavetable_id();
                     let new_entry = WtInfo{
                         id,
                         valid: true,
@@ -393,6 +437,142 @@ impl Tui {
         }
     }
 
+    // Generated wavetables are stored in the bank, so they are always
+    // available, even if the definition file is gone.
+    fn load_generated_wavetables(&mut self) {
+        for entry in &self.bank.gen_list {
+            if self.selector.wavetable_list.iter().any(|(id, _)| *id == entry.id) {
+                continue;
+            }
+            self.sender.send(SynthMessage::GeneratedWavetable(entry.clone())).unwrap();
+            self.selector.wavetable_list.push((entry.id, entry.name.clone()));
+        }
+    }
+
+    // Look for new wavetable definitions in the data directory.
+    fn scan_generated_wavetables(&mut self) {
+        let re = Regex::new(&format!(r"(?i)(.*)\.{}$", GENERATOR_EXTENSION)).unwrap();
+        let entries = match fs::read_dir(WAVETABLE_DIR) {
+            Ok(e) => e,
+            Err(_) => return, // No data directory
+        };
+        for entry in entries {
+            let entry = entry.unwrap();
+            let filename = entry.file_name();
+            for cap in re.captures_iter(filename.to_str().unwrap()) {
+                let table_name = &cap[1];
+                if self.bank.gen_list.iter().any(|g| g.name == table_name) {
+                    info!("{} already in generated wavetable list, skipping.", table_name);
+                    continue;
+                }
+                let path = format!("{}/{}", WAVETABLE_DIR, filename.to_str().unwrap());
+                let def = match WtGenDef::read_file(&path) {
+                    Ok(def) => def,
+                    Err(e) => {
+                        self.error_message = Some(format!("Unable to read wavetable definition {}: {}", table_name, e));
+                        continue;
+                    }
+                };
+                info!("Adding new generated table {}.", table_name);
+                let id = self.bank.next_wavetable_id();
+                let new_entry = WtGenInfo{id, name: table_name.to_string(), def};
+                self.sender.send(SynthMessage::GeneratedWavetable(new_entry.clone())).unwrap();
+                self.bank.gen_list.push(new_entry);
+                self.selector.wavetable_list.push((id, table_name.to_string()));
+            }
+        }
+    }
+
+    // Render the current sound into a new wavetable file and add it to the
+    // wavetable list. The name is taken from the sound name, with a number
+    // appended to keep it unique.
+    //
+    // The entry stays invalid and out of the menu until the synth reports
+    // that the file has been written and loaded.
+    fn resample(&mut self, settings: ResampleSettings) {
+        let base_name: String = self.sound.borrow().name.chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let mut count = 1;
+        let name = loop {
+            let name = format!("{}_{}", base_name, count);
+            let filename = format!("{}/{}.wav", WAVETABLE_DIR, name);
+            if !self.bank.wt_list.iter().any(|wt| wt.name == name) && !Path::new(&filename).exists() {
+                break name;
+            }
+            count += 1;
+        };
+        info!("Resampling sound into new table {}.", name);
+        let id = self.bank.next_wavetable_id();
+        let filename = format!("{}.wav", name);
+        let new_entry = WtInfo{id, valid: false, name, filename};
+        self.sender.send(SynthMessage::Resample(settings, new_entry.clone())).unwrap();
+        self.bank.wt_list.push(new_entry);
+    }
+
+    // A resampled wavetable is ready, add it to the menu.
+    fn handle_resampled(&mut self, id: usize) {
+        info!("Wavetable {} resampled", id);
+        if let Some(entry) = self.bank.wt_list.iter_mut().find(|wt| wt.id == id) {
+            entry.valid = true;
+            self.selector.wavetable_list.push((id, entry.name.clone()));
+        }
+        self.window.set_dirty(true);
+    }
+
+    // A wavetable file was rejected by the synth. Sounds using it play the
+    // default table, so remove it from the menu and tell the user why.
+    fn handle_wavetable_error(&mut self, id: usize, msg: String) {
+        info!("Wavetable {} rejected: {}", id, msg);
+        if let Some(entry) = self.bank.wt_list.iter_mut().find(|wt| wt.id == id) {
+            entry.valid = false;
+        }
+        self.selector.wavetable_list.retain(|(wt_id, _)| *wt_id != id);
+        self.error_message = Some(format!("Unable to load wavetable {}", msg));
+        self.window.set_dirty(true);
+    }
+
+    fn load_samples(&mut self) {
+        for entry in &mut self.bank.sample_list {
+            let filename = format!("{}/{}", SAMPLE_DIR, entry.filename);
+            if !Path::new(&filename).exists() {
+                entry.valid = false; // Invalid => Won't show up in menu, oscillators stay silent
+                continue;
+            }
+            entry.valid = true;
+            self.sender.send(SynthMessage::Sample(entry.clone())).unwrap();
+            self.selector.sample_list.push((entry.id, entry.name.clone()));
+        }
+    }
+
+    fn scan_samples(&mut self) {
+        let re = Regex::new(r"(.*)\.wav$").unwrap();
+        let entries = match fs::read_dir(SAMPLE_DIR) {
+            Ok(e) => e,
+            Err(_) => return, // No samples available
+        };
+        for entry in entries {
+            let entry = entry.unwrap();
+            let filename = entry.file_name();
+            for cap in re.captures_iter(filename.to_str().unwrap()) {
+                let sample_name = &cap[1];
+                if self.bank.sample_list.iter().any(|s| s.name == sample_name) {
+                    continue;
+                }
+                info!("Adding new sample {}.", sample_name);
+                let id = self.bank.sample_list.len() + 1; // ID 0 is "no sample"
+                let new_entry = SampleInfo{
+                    id,
+                    valid: true,
+                    name: sample_name.to_string(),
+                    filename: filename.to_str().unwrap().to_string()};
+                self.sender.send(SynthMessage::Sample(new_entry.clone())).unwrap();
+                self.bank.sample_list.push(new_entry);
+                self.selector.sample_list.push((id, sample_name.to_string()));
+            }
+        }
+    }
+
     /** Select a sound from the loaded sound bank.
      *
      * Creates a local copy of the selected sound, which can be modified. It
@@ -613,7 +793,7 @@ impl Tui {
             Tui::display_selector(&self.selector, true);
         } else {
             print!("{}{}", cursor::Goto(1, 1), clear::CurrentLine);
-            Tui::display_last_parameter(&self.last_value, &self.selector.wavetable_list);
+            Tui::display_last_parameter(&self.last_value, &self.selector);
         }
         if self.show_tui {
             self.display_idle_time();
@@ -623,7 +803,7 @@ impl Tui {
         stdout().flush().ok();
     }
 
-    fn display_last_parameter(v: &SynthParam, wt_list: &Vec<(usize, String)>) {
+    fn display_last_parameter(v: &SynthParam, s: &ParamSelector) {
         print!("{}{}", color::Bg(Rgb(255, 255, 255)), color::Fg(Black));
 
         print!("{} {} {}", v.function, v.function_id, v.parameter);
@@ -638,8 +818,8 @@ impl Tui {
                     print!(" Unknown");
                 }
             }
-            ParameterValue::Dynamic(_, x) => {
-                for (k, v) in wt_list {
+            ParameterValue::Dynamic(p, x) => {
+                for (k, v) in s.get_dynamic_list_no_mut(p) {
                     if *k == x {
                         print!(" {}", v);
                         break;
@@ -677,7 +857,7 @@ impl Tui {
                             | ParameterValue::Float(_)
                             | ParameterValue::Choice(_)
                             | ParameterValue::Dynamic(_, _) => {
-                                Tui::display_value(&s.param_selection, false, &s.wavetable_list);
+                                Tui::display_value(&s.param_selection, false, s);
                             },
                             ParameterValue::Function(_) => {
                                 Tui::display_function(&s.value_func_selection, false);
@@ -693,7 +873,7 @@ impl Tui {
                     }
                 }
                 SelectorState::Value => {
-                    Tui::display_value(&s.param_selection, selector_state == SelectorState::Value && show_options, &s.wavetable_list);
+                    Tui::display_value(&s.param_selection, selector_state == SelectorState::Value && show_options, s);
                     x_pos = 23;
                 }
                 SelectorState::MidiLearn => {
@@ -767,7 +947,7 @@ impl Tui {
         }
     }
 
-    fn display_value(param: &ItemSelection, selected: bool, wt_list: &Vec<(usize, String)>) {
+    fn display_value(param: &ItemSelection, selected: bool, s: &ParamSelector) {
         if selected {
             print!("{}{}", color::Bg(LightWhite), color::Fg(Black));
         }
@@ -781,8 +961,8 @@ impl Tui {
                 let item = selection[x].item;
                 print!(" {}", item);
             },
-            ParameterValue::Dynamic(_, x) => {
-                for (k, v) in wt_list {
+            ParameterValue::Dynamic(p, x) => {
+                for (k, v) in s.get_dynamic_list_no_mut(p) {
                     if *k == x {
                         print!(" {}", v);
                         break;
@@ -866,6 +1046,10 @@ impl Tui {
         let key = ParamId{function: Parameter::System, function_id: 0, parameter: Parameter::Busy};
         self.window.update_value(&key, value);
 
+        let value = Value::Int(self.dropped_voices as i64);
+        let key = ParamId{function: Parameter::System, function_id: 0, parameter: Parameter::Dropped};
+        self.window.update_value(&key, value);
+
         self.idle = Duration::new(0, 0);
         self.busy = Duration::new(0, 0);
     }
@@ -873,10 +1057,16 @@ impl Tui {
     fn display_status_line(&mut self) {
         let ctrl_set = self.active_ctrl_set as u8;
         let ctrl_set = (ctrl_set + if ctrl_set <= 9 { '0' as u8 } else { 'a' as u8 - 10 }) as char;
-        print!("{}| Mode: {:?} | Active controller set: {} |                Press <F1> for help, <F12> to exit ",
+        let wavetables = if self.bank.embed_wavetables { "embedded" } else { "linked  " };
+        print!("{}| Mode: {:?} | Active controller set: {} | Wavetables: {} | Press <F1> for help, <F12> to exit ",
             cursor::Goto(1, 50),
             self.mode,
-            ctrl_set);
+            ctrl_set,
+            wavetables);
+        match &self.error_message {
+            Some(msg) => print!("{}{}| Error: {}", cursor::Goto(1, 51), clear::CurrentLine, msg),
+            None => print!("{}{}", cursor::Goto(1, 51), clear::CurrentLine),
+        }
     }
 
     fn display_help(&mut self) {
@@ -887,9 +1077,14 @@ impl Tui {
         println!("<F1>     : Show this help text\r");
         println!("<F2>     : Save default sound bank\r");
         println!("<F3>     : Load default sound bank\r");
+        println!("<F5>     : Resample one wave cycle of the current sound into a new wavetable\r");
+        println!("<F6>     : Resample the current sound across the mod wheel range into a new wavetable\r");
+        println!("<F7>     : Resample the current sound across the keyboard into a new wavetable\r");
+        println!("<F10>    : Scan the data and samples folders for new files\r");
         println!("<Ctrl-c> : Copy current sound\r");
         println!("<Ctrl-v> : Paste copied sound to current patch\r");
         println!("<Ctrl-n> : Rename the current patch\r");
+        println!("<Ctrl-e> : Toggle embedding of used wavetables when saving the sound bank\r");
         println!("<F12>    : Quit Yazz\r");
         println!("\r");
         println!("Keys in Edit mode:\r");
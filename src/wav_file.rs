@@ -0,0 +1,368 @@
+//! Reads and writes WAV files.
+//!
+//! Samples are written as interleaved 32-bit float values. The header is
+//! written with placeholder sizes on creation and updated when the writer is
+//! finalized. Since the sizes in the header are 32 bit values, writing fails
+//! once the file would exceed 4 GiB.
+//!
+//! Reading supports 8, 16, 24 and 32 bit integer PCM and 32 bit float data
+//! with any number of channels. All samples are converted to float values in
+//! the range -1.0 to 1.0. The cycle length of wavetable files is read from
+//! the "clm " chunk written by Serum and compatible wavetable editors.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+const HEADER_SIZE: u32 = 44;
+const MAX_DATA_SIZE: u64 = (u32::MAX - (HEADER_SIZE - 8)) as u64; // Largest data chunk the RIFF size fields can hold
+const FORMAT_PCM: u16 = 1;
+const FORMAT_IEEE_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Contents of a WAV file.
+pub struct WavFile {
+    pub num_channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    pub samples: Vec<f32>, // Interleaved samples of all channels
+    pub cycle_length: Option<usize>, // Samples per wave cycle, if stored in the file
+}
+
+impl WavFile {
+    /// Read a WAV file from disk.
+    pub fn read_file(filename: &str) -> std::io::Result<WavFile> {
+        let file = File::open(filename)?;
+        let mut reader = BufReader::new(file);
+        let mut data: Vec<u8> = vec!();
+        reader.read_to_end(&mut data)?;
+        WavFile::parse(&data)
+    }
+
+    /// Parse the contents of a WAV file.
+    pub fn parse(data: &[u8]) -> std::io::Result<WavFile> {
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err(WavFile::invalid("Missing RIFF/ WAVE header"));
+        }
+        let mut pos = 12;
+        let mut format: Option<(u16, u16, u32, u16)> = None;
+        let mut cycle_length = None;
+        let mut result = None;
+        while pos + 8 <= data.len() {
+            let id = &data[pos..pos + 4];
+            let size = WavFile::read_u32(&data[pos + 4..pos + 8]) as usize;
+            pos += 8;
+            // Some writers don't update the size of the last chunk
+            let end = std::cmp::min(pos + size, data.len());
+            let chunk = &data[pos..end];
+            match id {
+                b"fmt " => {
+                    if chunk.len() < 16 {
+                        return Err(WavFile::invalid("Format chunk too short"));
+                    }
+                    let mut format_tag = WavFile::read_u16(&chunk[0..2]);
+                    if format_tag == FORMAT_EXTENSIBLE && chunk.len() >= 26 {
+                        format_tag = WavFile::read_u16(&chunk[24..26]); // Start of the sub format GUID
+                    }
+                    format = Some((format_tag,
+                                   WavFile::read_u16(&chunk[2..4]),
+                                   WavFile::read_u32(&chunk[4..8]),
+                                   WavFile::read_u16(&chunk[14..16])));
+                }
+                b"data" => {
+                    let (format_tag, num_channels, sample_rate, bits_per_sample) = match format {
+                        Some(f) => f,
+                        None => return Err(WavFile::invalid("Data chunk before format chunk")),
+                    };
+                    if num_channels == 0 {
+                        return Err(WavFile::invalid("Invalid number of channels"));
+                    }
+                    let samples = WavFile::convert_samples(chunk, format_tag, bits_per_sample)?;
+                    result = Some(WavFile{num_channels, sample_rate, bits_per_sample, samples, cycle_length: None});
+                }
+                b"clm " => cycle_length = WavFile::parse_cycle_length(chunk),
+                _ => (), // Skip unknown chunks
+            }
+            pos += size + (size & 0x01); // Chunks are padded to an even size
+        }
+        match result {
+            Some(mut wav) => {
+                wav.cycle_length = cycle_length;
+                Ok(wav)
+            }
+            None => Err(WavFile::invalid("No sample data found")),
+        }
+    }
+
+    /// Number of sample frames, each containing one sample per channel.
+    pub fn get_num_frames(&self) -> usize {
+        self.samples.len() / self.num_channels as usize
+    }
+
+    // Read the cycle length from a "clm " chunk.
+    //
+    // The chunk holds a text like "<!>2048 01000000 wavetable ...", where
+    // the number after the marker is the number of samples per cycle.
+    fn parse_cycle_length(chunk: &[u8]) -> Option<usize> {
+        let text = std::str::from_utf8(chunk).ok()?;
+        let digits: String = text.strip_prefix("<!>")?.chars().take_while(|c| c.is_ascii_digit()).collect();
+        digits.parse().ok().filter(|len| *len > 0)
+    }
+
+    // Convert raw sample data to float values.
+    fn convert_samples(data: &[u8], format_tag: u16, bits_per_sample: u16) -> std::io::Result<Vec<f32>> {
+        let samples = match (format_tag, bits_per_sample) {
+            (FORMAT_PCM, 8) => {
+                data.iter().map(|b| (*b as f32 - 128.0) / 128.0).collect()
+            }
+            (FORMAT_PCM, 16) => {
+                data.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0).collect()
+            }
+            (FORMAT_PCM, 24) => {
+                // Shift into the upper bytes of an i32 to get the sign right
+                data.chunks_exact(3).map(|b| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f32 / 8_388_608.0).collect()
+            }
+            (FORMAT_PCM, 32) => {
+                data.chunks_exact(4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2_147_483_648.0).collect()
+            }
+            (FORMAT_IEEE_FLOAT, 32) => {
+                data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+            }
+            _ => {
+                let msg = format!("Unsupported sample format {} with {} bits", format_tag, bits_per_sample);
+                return Err(WavFile::invalid(&msg));
+            }
+        };
+        Ok(samples)
+    }
+
+    fn read_u16(data: &[u8]) -> u16 {
+        u16::from_le_bytes([data[0], data[1]])
+    }
+
+    fn read_u32(data: &[u8]) -> u32 {
+        u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+    }
+
+    fn invalid(msg: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, msg)
+    }
+}
+
+pub struct WavWriter<W: Write + Seek> {
+    target: W,
+    num_channels: u16,
+    sample_rate: u32,
+    num_samples: u64,     // Number of samples written (all channels)
+    header_samples: u64,  // Number of samples at the last header update
+}
+
+impl WavWriter<BufWriter<File>> {
+    /// Create a new WAV file with the given format.
+    pub fn create(filename: &str, num_channels: u16, sample_rate: u32) -> std::io::Result<Self> {
+        let file = File::create(filename)?;
+        WavWriter::new(BufWriter::new(file), num_channels, sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut target: W, num_channels: u16, sample_rate: u32) -> std::io::Result<Self> {
+        WavWriter::<W>::write_header(&mut target, num_channels, sample_rate, 0)?;
+        Ok(WavWriter{target, num_channels, sample_rate, num_samples: 0, header_samples: 0})
+    }
+
+    /// Write a single sample frame with one value per channel.
+    ///
+    /// Missing channels are filled with silence, extra values are ignored.
+    pub fn write_frame(&mut self, frame: &[f32]) -> std::io::Result<()> {
+        let num_channels = self.num_channels as usize;
+        self.check_size(num_channels)?;
+        for i in 0..num_channels {
+            let sample = frame.get(i).copied().unwrap_or(0.0);
+            self.target.write_all(&sample.to_le_bytes())?;
+        }
+        self.num_samples += num_channels as u64;
+        Ok(())
+    }
+
+    /// Write a buffer of interleaved samples.
+    pub fn write_samples(&mut self, samples: &[f32]) -> std::io::Result<()> {
+        self.check_size(samples.len())?;
+        for sample in samples {
+            self.target.write_all(&sample.to_le_bytes())?;
+        }
+        self.num_samples += samples.len() as u64;
+        Ok(())
+    }
+
+    /// Update the header with the final data size and flush the output.
+    pub fn finalize(mut self) -> std::io::Result<W> {
+        self.update_header()?;
+        Ok(self.target)
+    }
+
+    /// Update the header with the current data size and flush the output.
+    ///
+    /// The file stays open for writing more samples.
+    pub fn update_header(&mut self) -> std::io::Result<()> {
+        let data_size = (self.num_samples * 4) as u32; // Limited by check_size
+        self.header_samples = self.num_samples;
+        self.target.seek(SeekFrom::Start(4))?;
+        self.target.write_all(&(data_size + HEADER_SIZE - 8).to_le_bytes())?;
+        self.target.seek(SeekFrom::Start(40))?;
+        self.target.write_all(&data_size.to_le_bytes())?;
+        self.target.seek(SeekFrom::End(0))?;
+        self.target.flush()
+    }
+
+    /// Update the header if at least one second of audio was written since
+    /// the last update.
+    pub fn update_header_periodically(&mut self) -> std::io::Result<()> {
+        let samples_per_second = self.sample_rate as u64 * self.num_channels as u64;
+        if self.num_samples - self.header_samples >= samples_per_second {
+            self.update_header()?;
+        }
+        Ok(())
+    }
+
+    // Fail before the data grows beyond what the header can describe.
+    fn check_size(&self, num_samples: usize) -> std::io::Result<()> {
+        if (self.num_samples + num_samples as u64) * 4 > MAX_DATA_SIZE {
+            return Err(Error::new(ErrorKind::Other, "WAV file size limit of 4 GiB reached"));
+        }
+        Ok(())
+    }
+
+    fn write_header(target: &mut W, num_channels: u16, sample_rate: u32, data_size: u32) -> std::io::Result<()> {
+        let block_align = num_channels * 4;
+        target.write_all(b"RIFF")?;
+        target.write_all(&(data_size + HEADER_SIZE - 8).to_le_bytes())?;
+        target.write_all(b"WAVE")?;
+        target.write_all(b"fmt ")?;
+        target.write_all(&16u32.to_le_bytes())?;
+        target.write_all(&FORMAT_IEEE_FLOAT.to_le_bytes())?;
+        target.write_all(&num_channels.to_le_bytes())?;
+        target.write_all(&sample_rate.to_le_bytes())?;
+        target.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        target.write_all(&block_align.to_le_bytes())?;
+        target.write_all(&32u16.to_le_bytes())?;
+        target.write_all(b"data")?;
+        target.write_all(&data_size.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn header_contains_final_sizes() {
+    let buffer = std::io::Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(buffer, 2, 48000).unwrap();
+    writer.write_frame(&[0.5, -0.5]).unwrap();
+    writer.write_samples(&[1.0, -1.0, 0.0, 0.0]).unwrap();
+    let data = writer.finalize().unwrap().into_inner();
+    assert_eq!(data.len(), 44 + 6 * 4);
+    assert_eq!(&data[0..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes([data[4], data[5], data[6], data[7]]), 36 + 24);
+    assert_eq!(u32::from_le_bytes([data[24], data[25], data[26], data[27]]), 48000);
+    assert_eq!(u32::from_le_bytes([data[40], data[41], data[42], data[43]]), 24);
+    assert_eq!(f32::from_le_bytes([data[44], data[45], data[46], data[47]]), 0.5);
+}
+
+// Builds a PCM WAV file with the given raw sample data.
+#[cfg(test)]
+fn build_pcm_file(num_channels: u16, bits_per_sample: u16, data: &[u8]) -> Vec<u8> {
+    let mut file = vec!();
+    file.extend_from_slice(b"RIFF");
+    file.extend_from_slice(&(4 + 24 + 8 + data.len() as u32).to_le_bytes());
+    file.extend_from_slice(b"WAVE");
+    file.extend_from_slice(b"fmt ");
+    file.extend_from_slice(&16u32.to_le_bytes());
+    file.extend_from_slice(&FORMAT_PCM.to_le_bytes());
+    file.extend_from_slice(&num_channels.to_le_bytes());
+    file.extend_from_slice(&44100u32.to_le_bytes());
+    let block_align = num_channels * bits_per_sample / 8;
+    file.extend_from_slice(&(44100 * block_align as u32).to_le_bytes());
+    file.extend_from_slice(&block_align.to_le_bytes());
+    file.extend_from_slice(&bits_per_sample.to_le_bytes());
+    file.extend_from_slice(b"data");
+    file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    file.extend_from_slice(data);
+    file
+}
+
+#[cfg(test)]
+#[test]
+fn short_frames_are_padded() {
+    let buffer = std::io::Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(buffer, 2, 48000).unwrap();
+    writer.write_frame(&[0.5]).unwrap();
+    let data = writer.finalize().unwrap().into_inner();
+    let wav = WavFile::parse(&data).unwrap();
+    assert_eq!(wav.samples, vec!(0.5, 0.0));
+}
+
+#[cfg(test)]
+#[test]
+fn writing_beyond_size_limit_fails() {
+    let buffer = std::io::Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(buffer, 2, 48000).unwrap();
+    writer.num_samples = MAX_DATA_SIZE / 4 - 1; // Pretend the file is almost full
+    assert!(writer.write_frame(&[0.5, 0.5]).is_err());
+    assert!(writer.write_samples(&[0.5]).is_ok());
+    assert!(writer.write_samples(&[0.5]).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn integer_samples_are_converted_to_float() {
+    let data = [0x00, 0x40, 0x00, 0xC0]; // 16384, -16384
+    let wav = WavFile::parse(&build_pcm_file(2, 16, &data)).unwrap();
+    assert_eq!(wav.num_channels, 2);
+    assert_eq!(wav.get_num_frames(), 1);
+    assert_eq!(wav.samples, vec!(0.5, -0.5));
+
+    let data = [0x00, 0x00, 0x40, 0x00, 0x00, 0xC0]; // 24 bit
+    let wav = WavFile::parse(&build_pcm_file(1, 24, &data)).unwrap();
+    assert_eq!(wav.samples, vec!(0.5, -0.5));
+
+    let data = [0x00, 0x00, 0x00, 0x40, 0x00, 0x00, 0x00, 0xC0]; // 32 bit
+    let wav = WavFile::parse(&build_pcm_file(1, 32, &data)).unwrap();
+    assert_eq!(wav.samples, vec!(0.5, -0.5));
+}
+
+#[cfg(test)]
+#[test]
+fn written_file_can_be_read() {
+    let buffer = std::io::Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(buffer, 2, 48000).unwrap();
+    writer.write_frame(&[0.25, -0.75]).unwrap();
+    let data = writer.finalize().unwrap().into_inner();
+    let wav = WavFile::parse(&data).unwrap();
+    assert_eq!(wav.sample_rate, 48000);
+    assert_eq!(wav.bits_per_sample, 32);
+    assert_eq!(wav.samples, vec!(0.25, -0.75));
+}
+
+#[cfg(test)]
+#[test]
+fn cycle_length_is_read_from_clm_chunk() {
+    let mut file = build_pcm_file(1, 16, &[0x00, 0x40]);
+    let mut clm = b"clm ".to_vec();
+    let text = b"<!>1024 10000000 wavetable (www.xferrecords.com)";
+    clm.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    clm.extend_from_slice(text);
+    file.splice(12..12, clm); // Insert before the format chunk
+    let wav = WavFile::parse(&file).unwrap();
+    assert_eq!(wav.cycle_length, Some(1024));
+    assert_eq!(wav.samples, vec!(0.5));
+
+    let wav = WavFile::parse(&build_pcm_file(1, 16, &[0x00, 0x40])).unwrap();
+    assert_eq!(wav.cycle_length, None);
+}
+
+#[cfg(test)]
+#[test]
+fn unsupported_format_is_rejected() {
+    let data = [0u8; 12];
+    assert!(WavFile::parse(&build_pcm_file(1, 12, &data)).is_err());
+    assert!(WavFile::parse(b"RIFF").is_err());
+}